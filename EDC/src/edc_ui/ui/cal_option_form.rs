@@ -0,0 +1,86 @@
+use egui::Ui;
+
+use crate::edcs_client::edcs_proto::{EdcsCalOption, EdcsCalOptionType};
+
+// Same checks EDCS does, so the form can't be sent with something it rejects
+fn is_valid(option: &EdcsCalOption, value: &str) -> bool {
+    if value.is_empty() {
+        return !option.required;
+    }
+    let in_range = |v: f64| !option.has_range || (option.min..=option.max).contains(&v);
+    match option.option_type() {
+        EdcsCalOptionType::CalOptionString => true,
+        EdcsCalOptionType::CalOptionInt => {
+            value.parse::<i64>().map_or(false, |v| in_range(v as f64))
+        }
+        EdcsCalOptionType::CalOptionFloat => value
+            .parse::<f64>()
+            .map_or(false, |v| v.is_finite() && in_range(v)),
+        EdcsCalOptionType::CalOptionBool => value == "true" || value == "false",
+        EdcsCalOptionType::CalOptionEnum => option.choices.iter().any(|c| c == value),
+    }
+}
+
+/// Draws the widget for a single CAL option. Values stay strings since that is
+/// what EDCS takes, the schema only decides how they are edited. Options
+/// without a schema (eg. from a connection saved before EDCS had one) get a
+/// text box. Returns whether the value can be sent.
+pub fn cal_option_widget(ui: &mut Ui, option: Option<&EdcsCalOption>, value: &mut String) -> bool {
+    let option = match option {
+        Some(option) => option,
+        None => {
+            ui.text_edit_singleline(value);
+            return !value.is_empty();
+        }
+    };
+
+    let response = match option.option_type() {
+        EdcsCalOptionType::CalOptionString => ui.text_edit_singleline(value),
+        EdcsCalOptionType::CalOptionInt => {
+            let mut int: i64 = value
+                .parse()
+                .or_else(|_| option.default_value.parse())
+                .unwrap_or(0);
+            let mut drag = egui::DragValue::new(&mut int);
+            if option.has_range {
+                drag = drag.clamp_range(option.min..=option.max);
+            }
+            let response = ui.add_sized([170.0, 20.0], drag);
+            *value = int.to_string();
+            response
+        }
+        EdcsCalOptionType::CalOptionFloat => {
+            let mut float: f64 = value
+                .parse()
+                .or_else(|_| option.default_value.parse())
+                .unwrap_or(0.0);
+            let mut drag = egui::DragValue::new(&mut float).speed(0.1);
+            if option.has_range {
+                drag = drag.clamp_range(option.min..=option.max);
+            }
+            let response = ui.add_sized([170.0, 20.0], drag);
+            *value = float.to_string();
+            response
+        }
+        EdcsCalOptionType::CalOptionBool => {
+            let mut checked = value == "true";
+            let response = ui.checkbox(&mut checked, "");
+            *value = checked.to_string();
+            response
+        }
+        EdcsCalOptionType::CalOptionEnum => {
+            egui::ComboBox::from_id_source(&option.name)
+                .selected_text(value.as_str())
+                .show_ui(ui, |ui| {
+                    for choice in &option.choices {
+                        ui.selectable_value(value, choice.clone(), choice);
+                    }
+                })
+                .response
+        }
+    };
+    if !option.description.is_empty() {
+        response.on_hover_text(&option.description);
+    }
+    is_valid(option, value)
+}
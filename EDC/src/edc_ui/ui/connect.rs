@@ -15,13 +15,20 @@ use log::{debug, trace};
 use crate::{
     edcs_client::{
         blocking_client::{BlockingEdcsClient, ChannelEdcsRequest, ChannelEdcsResponse},
-        edcs_proto::{edcs_response::Payload, EdcsStatus},
+        client::error_message,
+        edcs_proto::{
+            edcs_response::Payload, EdcsAudioParams, EdcsCalOption, EdcsInputCapabilities,
+            EdcsRole, EdcsSetupStreamData, EdcsStatus,
+        },
+    },
+    edcs_config::{
+        default_max_message_size, ClientConfig, ConnectionConfig, ConnectionFile, StreamConfig,
+        UIConfig,
     },
-    edcs_config::{ClientConfig, ConnectionConfig, ConnectionFile, StreamConfig, UIConfig},
 };
 
 use super::{
-    control_bar::ControlBarUI, debug_area::DebugArea, mpv::MPVEvent, ui_element::UIElement,
+    cal_option_form::cal_option_widget, control_bar::ControlBarUI, debug_area::DebugArea, mpv::MPVEvent, ui_element::UIElement,
 };
 
 #[derive(PartialEq, Debug)]
@@ -29,6 +36,8 @@ enum ConnectionStage {
     Connect(bool),
     SetupEdcs,
     SetupStream,
+    // Someone else already set EDCS up, so watch their stream instead
+    JoinStream,
     // We don't start the stream here
     Handoff,
 }
@@ -51,7 +60,16 @@ pub struct ConnectUI {
     // Skip sending a request if one is pending
     pending_recv: bool,
     connection_file: ConnectionFile,
-    sdp: Option<String>,
+    setup_stream_data: Option<EdcsSetupStreamData>,
+    input_capabilities: EdcsInputCapabilities,
+    // Only known once EDCS has been set up
+    cal_option_schema: Vec<EdcsCalOption>,
+    role: EdcsRole,
+    // Whoever set the stream up starts it, so ControlBarUI mustn't
+    joined: bool,
+    // Used instead of the saved token for the next connection. Invites only
+    // work once, so they don't go in the connection file.
+    invite_token: String,
 }
 
 impl ConnectUI {
@@ -69,7 +87,12 @@ impl ConnectUI {
             pending_recv: false,
             // I think panicking here is fine
             connection_file: ConnectionFile::open().expect("Failed to open echodawn client config"),
-            sdp: None,
+            setup_stream_data: None,
+            input_capabilities: EdcsInputCapabilities::default(),
+            cal_option_schema: vec![],
+            role: EdcsRole::RoleViewer,
+            joined: false,
+            invite_token: String::new(),
         }
     }
 }
@@ -94,6 +117,8 @@ impl UIElement for ConnectUI {
                                     bitrate: 0,
                                     framerate: 0,
                                     cal_plugin_params: HashMap::new(),
+                                    audio: false,
+                                    audio_bitrate: 96000,
                                 },
 
                                 client_config: ClientConfig {
@@ -102,8 +127,10 @@ impl UIElement for ConnectUI {
                                     port: 0,
                                     cert: "".into(),
                                     disable_tls_verification: false,
+                                    token: None,
+                                    max_message_size: default_max_message_size(),
                                 },
-                                ui_config: UIConfig { host_cursor: false },
+                                ui_config: UIConfig::default(),
                             });
                             self.add_client_stage = Some(AddClientStage::ClientConfigStage((
                                 "".to_owned(),
@@ -188,6 +215,14 @@ impl UIElement for ConnectUI {
                                 ui.checkbox(&mut c.disable_tls_verification, "");
                                 ui.end_row();
 
+                                // Left empty to connect without authenticating
+                                let mut token = c.token.clone().unwrap_or_default();
+                                ui.strong("Token: ");
+                                if ui.add(egui::TextEdit::singleline(&mut token).password(true)).changed() {
+                                    c.token = if token.is_empty() { None } else { Some(token) };
+                                }
+                                ui.end_row();
+
                                 if ui
                                     .add_enabled(
                                         !invalid_cert && !invalid_ipv4 && !c.domain.is_empty(),
@@ -223,20 +258,37 @@ impl UIElement for ConnectUI {
                                 ui.add_sized([170.0, 20.0], egui::DragValue::new(&mut s.framerate));
                                 ui.end_row();
 
-                                // Loop through each CAL plugin option and give the option to set it here
-                                let mut vals_blank = false;
-                                for (k, v) in s.cal_plugin_params.iter_mut() {
+                                ui.strong("Audio: ");
+                                ui.checkbox(&mut s.audio, "");
+                                ui.end_row();
+
+                                if s.audio {
+                                    ui.strong("Audio Bitrate: ");
+                                    ui.add_sized([170.0, 20.0], egui::DragValue::new(&mut s.audio_bitrate));
+                                    ui.end_row();
+                                }
+
+                                // Loop through each CAL plugin option and give the option to set it here,
+                                // in the order the CAL lists them
+                                let schema = &self.cal_option_schema;
+                                let position = |k: &str| schema.iter().position(|o| o.name == k);
+                                let mut keys: Vec<String> = s.cal_plugin_params.keys().cloned().collect();
+                                keys.sort_by_key(|k| (position(k).unwrap_or(usize::MAX), k.clone()));
+                                let mut vals_invalid = false;
+                                for k in keys {
                                     ui.strong(k.to_owned() + ": ");
-                                    if v.is_empty() {
-                                        vals_blank = true
+                                    let option = position(&k).map(|i| &schema[i]);
+                                    let v = s.cal_plugin_params.get_mut(&k).unwrap();
+                                    if !cal_option_widget(ui, option, v) {
+                                        vals_invalid = true
                                     }
-                                    ui.text_edit_singleline(v);
+                                    ui.end_row();
                                 }
 
                                 ui.end_row();
                                 if ui
                                     .add_enabled(
-                                        s.bitrate > 0 && s.framerate > 0 && !vals_blank,
+                                        s.bitrate > 0 && s.framerate > 0 && !vals_invalid,
                                         egui::Button::new("Next"),
                                     )
                                     .clicked()
@@ -260,6 +312,14 @@ impl UIElement for ConnectUI {
                                 ui.checkbox(&mut u.host_cursor, "");
                                 ui.end_row();
 
+                                ui.strong("Share Clipboard: ");
+                                ui.checkbox(&mut u.share_clipboard, "");
+                                ui.end_row();
+
+                                ui.strong("Clipboard Size Limit (bytes): ");
+                                ui.add_sized([170.0, 20.0], egui::DragValue::new(&mut u.max_clipboard_size));
+                                ui.end_row();
+
                                 if ui.button(if self.edit_connection.0 { "Modify Connection" } else { "Add Connection" }).clicked() {
                                     trace!(
                                         "self.connection_config is {:#?}",
@@ -290,6 +350,12 @@ impl UIElement for ConnectUI {
                             }
                         }
                     } else {
+                        ui.horizontal(|ui| {
+                            ui.strong("Invite Token: ");
+                            ui.add(egui::TextEdit::singleline(&mut self.invite_token).password(true));
+                        });
+                        ui.end_row();
+
                         let mut remove_index = None;
                         let cnx_list = self
                             .connection_file
@@ -308,7 +374,12 @@ impl UIElement for ConnectUI {
                                         ui.end_row();
                                         ui.horizontal(|ui| {
                                             if ui.button("Connect").clicked() {
-                                                self.connection_config = Some(connection.clone());
+                                                let mut connection = connection.clone();
+                                                if !self.invite_token.is_empty() {
+                                                    connection.client_config.token =
+                                                        Some(std::mem::take(&mut self.invite_token));
+                                                }
+                                                self.connection_config = Some(connection);
                                                 self.connection_stage = ConnectionStage::Connect(true);
                                             }
                                             ui.add_space(7.0);
@@ -380,17 +451,29 @@ impl UIElement for ConnectUI {
                     .push("Called RPC with invalid client!"),
                 ChannelEdcsResponse::EdcsResponse(r) => match r {
                     Ok(resp) => {
-                        if resp.status() != EdcsStatus::Ok {
+                        if resp.status() == EdcsStatus::EdcsAlreadySetup
+                            && self.connection_stage == ConnectionStage::SetupEdcs
+                            && self.add_client_stage.is_none()
+                        {
                             self.debug_area
                                 .borrow_mut()
-                                .push(&format!("RPC call response was not ok! Resp: {:?}", resp));
-                            // TODO figure out what the status is and handle it accordingly
+                                .push("EDCS is already streaming, joining the stream");
+                            self.connection_stage = ConnectionStage::JoinStream;
+                        } else if resp.status() != EdcsStatus::Ok {
+                            self.debug_area
+                                .borrow_mut()
+                                .push(&format!("EDCS request failed: {}", error_message(&resp)));
                             self.connection_stage = ConnectionStage::Connect(false);
                         } else {
                             match resp.payload {
                                 Some(p) => match p {
                                     Payload::SetupEdcsData(setup_edcs_data) => {
                                         debug!("connection stage setup stream");
+                                        self.input_capabilities = setup_edcs_data
+                                            .input_capabilities
+                                            .clone()
+                                            .unwrap_or_default();
+                                        self.role = setup_edcs_data.role();
                                         if let Some(AddClientStage::ClientConfigStage(_)) =
                                             &self.add_client_stage
                                         {
@@ -400,6 +483,8 @@ impl UIElement for ConnectUI {
                                                 .stream_config
                                                 .cal_plugin_params =
                                                 setup_edcs_data.cal_option_dict.clone();
+                                            self.cal_option_schema =
+                                                setup_edcs_data.cal_option_schema.clone();
                                             self.add_client_stage =
                                                 Some(AddClientStage::StreamConfigStage);
                                         } else {
@@ -410,12 +495,14 @@ impl UIElement for ConnectUI {
                                             .push(&format!("SetupEdcsData {:?}", setup_edcs_data));
                                     }
                                     Payload::SetupStreamData(setup_stream_data) => {
+                                        self.joined =
+                                            self.connection_stage == ConnectionStage::JoinStream;
                                         self.connection_stage = ConnectionStage::Handoff;
-                                        self.sdp = Some(setup_stream_data.sdp.clone());
                                         self.debug_area.borrow_mut().push(&format!(
                                             "SetupStreamData {:?}",
                                             setup_stream_data
                                         ));
+                                        self.setup_stream_data = Some(setup_stream_data);
                                     }
                                     _ => {}
                                 },
@@ -454,11 +541,22 @@ impl UIElement for ConnectUI {
                         .expect("Failed to push SetupEdcs");
                     }
                     ConnectionStage::SetupStream => {
-                        push.send(ChannelEdcsRequest::SetupStream(
-                            cfg.stream_config.cal_plugin_params.clone(),
-                        ))
+                        push.send(ChannelEdcsRequest::SetupStream {
+                            cal_option_dict: cfg.stream_config.cal_plugin_params.clone(),
+                            // EDCS falls back to its own format if it can't do this one
+                            audio_params: cfg.stream_config.audio.then(|| EdcsAudioParams {
+                                sample_rate: 48000,
+                                channels: 2,
+                                bitrate: cfg.stream_config.audio_bitrate,
+                            }),
+                        })
                         .expect("Failed to push SetupStream");
                     }
+                    ConnectionStage::JoinStream => {
+                        // The same port the stream would go to if we had set it up
+                        push.send(ChannelEdcsRequest::JoinStream { port: 0 })
+                        .expect("Failed to push JoinStream");
+                    }
                     // Starting the stream should be done elsewhere after MPV has initialised.
                     _ => {
                         // Never sent anything to the channel on ConnectionStage::Connect(false)
@@ -475,11 +573,18 @@ impl UIElement for ConnectUI {
                 self.client.clone(),
                 self.debug_area.clone(),
                 window,
-                // SDP should never be None here
-                self.sdp
+                // Stream data should never be None here
+                self.setup_stream_data
+                    .take()
+                    .expect("No stream data set despite ConnectionStage::Handoff set"),
+                self.connection_config
                     .as_ref()
-                    .expect("No SDP set despite ConnectionStage::Handoff set")
-                    .to_owned(),
+                    .expect("No connection config set despite ConnectionStage::Handoff set")
+                    .ui_config
+                    .clone(),
+                self.input_capabilities.clone(),
+                self.role,
+                self.joined,
             )))
         } else {
             None
@@ -1,25 +1,45 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::RefCell,
+    path::PathBuf,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use egui::RichText;
 use glutin::{
     dpi::PhysicalPosition,
-    event::{ElementState, VirtualKeyCode, WindowEvent},
+    event::{ElementState, MouseScrollDelta, TouchPhase, VirtualKeyCode, WindowEvent},
     window::Window,
 };
-use log::{info, trace};
+use log::{info, trace, warn};
+use platform_dirs::UserDirs;
 
 use crate::edcs_client::{
-    blocking_client::{BlockingEdcsClient, ChannelEdcsRequest},
-    edcs_proto::EdcsMouseButton,
+    blocking_client::{BlockingEdcsClient, ChannelEdcsRequest, ChannelEdcsResponse},
+    client::error_message,
+    clipboard::ClipboardSync,
+    edcs_proto::{
+        edcs_response, edcs_server_event, EdcsAudioParams, EdcsControlState, EdcsFileInfo,
+        EdcsInputCapabilities, EdcsInvite, EdcsMouseButton, EdcsRole, EdcsSetupStreamData,
+        EdcsStatus, EdcsTouchPhase,
+    },
+    file_transfer::{FileTransferUpdate, TransferDirection, TransferState},
+    gamepad::GamepadForwarder,
     keyboard_event,
 };
+use crate::edcs_config::UIConfig;
 
 use super::{
     debug_area::DebugArea,
     mpv::{self, MPVEvent},
+    remote_cursor::RemoteCursor,
     ui_element::UIElement,
+    video_mapping::VideoMapping,
 };
 
+// Touchpads scroll in pixels, this many make up a wheel click
+const PIXELS_PER_WHEEL_CLICK: f64 = 40.0;
+
 /// The bar that lets you control everything during an active connection
 pub struct ControlBarUI {
     client: Rc<RefCell<BlockingEdcsClient>>,
@@ -27,13 +47,48 @@ pub struct ControlBarUI {
     mpv_ctx: mpv::MPVCtx,
     stream_started: bool,
     prev_pos: PhysicalPosition<f64>,
+    video_mapping: VideoMapping,
+    remote_cursor: RemoteCursor,
+    os_cursor_visible: bool,
+    // Toggled with Scroll Lock, which is never forwarded to the remote side
+    show_control_bar: bool,
+    input_capabilities: EdcsInputCapabilities,
+    // The finger driving the mouse when the CAL can't take touch input
+    touch_mouse_id: Option<u64>,
+    // Scrolling that doesn't add up to a whole wheel click yet, vertical and
+    // horizontal
+    wheel_remainder: (f64, f64),
+    gamepads: GamepadForwarder,
+    clipboard: ClipboardSync,
+    transfers: Vec<FileTransferUpdate>,
+    next_transfer_id: u64,
+    remote_files: Vec<EdcsFileInfo>,
+    // Only Some if EDCS is sending audio
+    audio_params: Option<EdcsAudioParams>,
+    volume: f64,
+    muted: bool,
+    // Viewers get the video, but EDCS rejects their input so none is sent
+    role: EdcsRole,
+    // Who else is on the stream, None until EDCS says
+    control: Option<EdcsControlState>,
+    // Outstanding invites, only for the owner
+    invites: Vec<EdcsInvite>,
+    // EDCS only hands the token out once, when the invite is created
+    new_invite_token: Option<String>,
+    invite_minutes: u32,
+    invite_view_only: bool,
 }
 impl ControlBarUI {
     pub fn new(
         client: Rc<RefCell<BlockingEdcsClient>>,
         debug_area: Rc<RefCell<DebugArea>>,
         window: &Window,
-        sdp: String,
+        setup_stream_data: EdcsSetupStreamData,
+        ui_config: UIConfig,
+        input_capabilities: EdcsInputCapabilities,
+        role: EdcsRole,
+        // Joined someone else's stream, which they already started
+        joined: bool,
     ) -> Self
     where
         Self: Sized,
@@ -49,30 +104,367 @@ impl ControlBarUI {
                 inner_size.height,
                 // TODO make this variable
                 true,
-                sdp,
+                setup_stream_data.sdp,
             )
             .expect("Failed to start MPV"),
-            stream_started: false,
+            stream_started: joined,
             prev_pos: PhysicalPosition { x: 0.0, y: 0.0 },
+            video_mapping: VideoMapping::new(
+                inner_size,
+                (setup_stream_data.width, setup_stream_data.height),
+            ),
+            remote_cursor: RemoteCursor::new(ui_config.host_cursor),
+            os_cursor_visible: true,
+            show_control_bar: false,
+            input_capabilities,
+            touch_mouse_id: None,
+            wheel_remainder: (0.0, 0.0),
+            gamepads: GamepadForwarder::new(),
+            clipboard: ClipboardSync::new(ui_config.share_clipboard, ui_config.max_clipboard_size),
+            transfers: vec![],
+            next_transfer_id: 0,
+            remote_files: vec![],
+            audio_params: setup_stream_data.audio_params,
+            volume: 100.0,
+            muted: false,
+            role,
+            control: None,
+            invites: vec![],
+            new_invite_token: None,
+            invite_minutes: 15,
+            invite_view_only: true,
+        }
+    }
+
+    fn view_only(&self) -> bool {
+        self.role == EdcsRole::RoleViewer
+    }
+
+    // EDCS drops input from everyone but the participant in control
+    fn sends_input(&self) -> bool {
+        !self.view_only()
+            && self
+                .control
+                .as_ref()
+                .map_or(true, |control| control.controller_id == control.your_id)
+    }
+
+    fn is_owner(&self) -> bool {
+        self.control.as_ref().map_or(false, |control| {
+            control
+                .participants
+                .iter()
+                .any(|p| p.id == control.your_id && p.owner)
+        })
+    }
+
+    fn role_label(role: EdcsRole) -> &'static str {
+        match role {
+            EdcsRole::RoleViewer => "viewer",
+            EdcsRole::RoleController => "controller",
+            EdcsRole::RoleAdmin => "admin",
+        }
+    }
+
+    fn set_control_state(&mut self, control: EdcsControlState) {
+        if control.your_id == 0 && !control.stream_error.is_empty() {
+            self.debug_area
+                .borrow_mut()
+                .push(&format!("The stream failed: {}", control.stream_error));
+        } else if control.your_id == 0 {
+            self.debug_area
+                .borrow_mut()
+                .push("The stream was closed by its owner");
+        }
+        // Joining doesn't tell us the role, this does
+        if let Some(me) = control
+            .participants
+            .iter()
+            .find(|p| p.id == control.your_id)
+        {
+            self.role = me.role();
+        }
+        self.control = Some(control);
+    }
+
+    fn render_participants(&mut self, ui: &mut egui::Ui) {
+        let control = match &self.control {
+            Some(control) if control.your_id != 0 => control,
+            _ => return,
+        };
+        let in_control = control.controller_id == control.your_id;
+        let owner = self.is_owner();
+        let mut request = None;
+
+        ui.heading(RichText::new("Participants").strong());
+        for participant in &control.participants {
+            ui.horizontal(|ui| {
+                let mut label = format!(
+                    "{} ({})",
+                    if participant.name.is_empty() {
+                        "anonymous"
+                    } else {
+                        &participant.name
+                    },
+                    Self::role_label(participant.role())
+                );
+                if participant.owner {
+                    label += ", owner";
+                }
+                if participant.id == control.your_id {
+                    label += ", you";
+                }
+                if participant.id == control.controller_id {
+                    ui.colored_label(egui::Color32::LIGHT_GREEN, label + ", in control");
+                } else {
+                    ui.label(label);
+                }
+                if participant.requesting_control {
+                    ui.label("wants control");
+                }
+                if (in_control || owner)
+                    && participant.id != control.controller_id
+                    && participant.role() != EdcsRole::RoleViewer
+                    && ui.button("Grant control").clicked()
+                {
+                    request = Some(ChannelEdcsRequest::GrantControl(participant.id));
+                }
+            });
+        }
+        ui.horizontal(|ui| {
+            if !in_control && !self.view_only() && ui.button("Request control").clicked() {
+                request = Some(ChannelEdcsRequest::RequestControl);
+            }
+            // Either way control goes back to the owner
+            if in_control && !owner && ui.button("Give back control").clicked() {
+                request = Some(ChannelEdcsRequest::RevokeControl);
+            }
+            if owner && !in_control && ui.button("Take back control").clicked() {
+                request = Some(ChannelEdcsRequest::RevokeControl);
+            }
+        });
+        ui.separator();
+
+        if let Some(request) = request {
+            self.client.borrow().push.send(request);
+        }
+    }
+
+    fn download_dir() -> PathBuf {
+        UserDirs::new()
+            .map(|dirs| dirs.download_dir)
+            .unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    fn start_transfer(&mut self, req: impl FnOnce(u64) -> ChannelEdcsRequest) {
+        let id = self.next_transfer_id;
+        self.next_transfer_id += 1;
+        self.client.borrow().push.send(req(id));
+    }
+
+    fn update_transfer(&mut self, update: FileTransferUpdate) {
+        let finished_upload = update.direction == TransferDirection::Upload
+            && update.state == TransferState::Finished;
+        match self.transfers.iter_mut().find(|t| t.id == update.id) {
+            Some(transfer) => *transfer = update,
+            None => self.transfers.push(update),
+        }
+        if finished_upload {
+            self.client.borrow().push.send(ChannelEdcsRequest::ListFiles);
+        }
+    }
+
+    fn render_invites(&mut self, ui: &mut egui::Ui) {
+        if !self.is_owner() {
+            return;
+        }
+        let mut requests = vec![];
+
+        ui.heading(RichText::new("Invites").strong());
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::DragValue::new(&mut self.invite_minutes)
+                    .clamp_range(1..=24 * 60)
+                    .suffix(" min"),
+            );
+            ui.checkbox(&mut self.invite_view_only, "View only");
+            if ui.button("Create invite").clicked() {
+                requests.push(ChannelEdcsRequest::CreateInvite {
+                    valid_for_secs: self.invite_minutes * 60,
+                    view_only: self.invite_view_only,
+                });
+            }
+        });
+        if let Some(token) = &self.new_invite_token {
+            ui.horizontal(|ui| {
+                ui.label("New invite, the token is only shown once");
+                if ui.button("Copy").clicked() {
+                    ui.output().copied_text = token.clone();
+                }
+            });
+            ui.monospace(token);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        for invite in &self.invites {
+            ui.horizontal(|ui| {
+                ui.label(format!(
+                    "{} ({}), {} min left",
+                    invite.id,
+                    Self::role_label(invite.role()),
+                    invite.expires_at.saturating_sub(now) / 60
+                ));
+                if ui.button("Revoke").clicked() {
+                    requests.push(ChannelEdcsRequest::RevokeInvite(invite.id.clone()));
+                }
+            });
+        }
+        ui.separator();
+
+        // EDCS answers in order, so the list comes back after the change
+        if !requests.is_empty() {
+            requests.push(ChannelEdcsRequest::ListInvites);
+        }
+        for request in requests {
+            self.client.borrow().push.send(request);
+        }
+    }
+
+    fn render_audio(&mut self, ui: &mut egui::Ui) {
+        let audio_params = match &self.audio_params {
+            Some(audio_params) => audio_params,
+            None => return,
+        };
+        ui.heading(RichText::new("Audio").strong());
+        ui.label(format!(
+            "Opus {}Hz, {} channel(s), {}kbps",
+            audio_params.sample_rate,
+            audio_params.channels,
+            audio_params.bitrate / 1000
+        ));
+        ui.horizontal(|ui| {
+            if ui
+                .add(egui::Slider::new(&mut self.volume, 0.0..=100.0).text("Volume"))
+                .changed()
+            {
+                self.mpv_ctx.set_volume(self.volume);
+            }
+            if ui.checkbox(&mut self.muted, "Mute").changed() {
+                self.mpv_ctx.set_mute(self.muted);
+            }
+        });
+        ui.separator();
+    }
+
+    fn render_files(&mut self, ui: &mut egui::Ui) {
+        ui.heading(RichText::new("Files").strong());
+        if !self.view_only() {
+            ui.label("Drop a file on the window to upload it");
+        }
+        for transfer in &self.transfers {
+            let arrow = match transfer.direction {
+                TransferDirection::Upload => "⬆",
+                TransferDirection::Download => "⬇",
+            };
+            ui.horizontal(|ui| {
+                ui.label(format!("{} {}", arrow, transfer.name));
+                match &transfer.state {
+                    TransferState::InProgress => {
+                        let progress = if transfer.total == 0 {
+                            0.0
+                        } else {
+                            transfer.done as f32 / transfer.total as f32
+                        };
+                        ui.add(egui::ProgressBar::new(progress).show_percentage());
+                    }
+                    TransferState::Finished => {
+                        ui.label("Done");
+                    }
+                    TransferState::Failed(e) => {
+                        ui.colored_label(egui::Color32::RED, e);
+                    }
+                }
+            });
+        }
+
+        ui.separator();
+        if ui.button("Refresh remote files").clicked() {
+            self.client.borrow().push.send(ChannelEdcsRequest::ListFiles);
+        }
+        let mut download = None;
+        for file in &self.remote_files {
+            ui.horizontal(|ui| {
+                ui.label(format!("{} ({} bytes)", file.name, file.size));
+                if ui.button("Download").clicked() {
+                    download = Some(file.name.clone());
+                }
+            });
+        }
+        if let Some(name) = download {
+            let dest_dir = Self::download_dir();
+            self.start_transfer(|id| ChannelEdcsRequest::DownloadFile { id, name, dest_dir });
+        }
+    }
+
+    // The CAL can't take touch input, so the first finger drives the mouse instead
+    fn emulate_touch_with_mouse(&mut self, id: u64, phase: TouchPhase, x: f64, y: f64) {
+        let client = self.client.borrow();
+        let pressed = match phase {
+            TouchPhase::Started if self.touch_mouse_id.is_none() => {
+                self.touch_mouse_id = Some(id);
+                Some(true)
+            }
+            TouchPhase::Moved if self.touch_mouse_id == Some(id) => None,
+            TouchPhase::Ended | TouchPhase::Cancelled if self.touch_mouse_id == Some(id) => {
+                self.touch_mouse_id = None;
+                Some(false)
+            }
+            _ => return,
+        };
+
+        client.push.send(ChannelEdcsRequest::WriteMouseMove { x, y });
+        if let Some(pressed) = pressed {
+            client.push.send(ChannelEdcsRequest::WriteMouseButton {
+                button_typ: EdcsMouseButton::MouseButtonLeft,
+                pressed,
+            });
         }
     }
 }
 
 impl UIElement for ControlBarUI {
     fn render_egui(&mut self) -> bool {
-        false
+        // egui draws the remote cursor on top of the video
+        true
     }
     fn render(
         &mut self,
         ui: &mut egui::Ui,
         _ctrl_flow: &mut glutin::event_loop::ControlFlow,
     ) -> egui::InnerResponse<()> {
+        self.remote_cursor.paint(ui.ctx(), &self.video_mapping);
+        if !self.show_control_bar {
+            return ui.scope(|_ui| {});
+        }
+
         egui::Frame::none()
             .fill(egui::Color32::DARK_GRAY)
             .inner_margin(10.0)
             .outer_margin(10.0)
             .show(ui, |ui| {
                 ui.heading(RichText::new("Connection").strong());
+                ui.label(match self.role {
+                    EdcsRole::RoleViewer => "Role: viewer (view only)",
+                    EdcsRole::RoleController => "Role: controller",
+                    EdcsRole::RoleAdmin => "Role: admin",
+                });
+                ui.separator();
+                self.render_participants(ui);
+                self.render_invites(ui);
+                self.render_audio(ui);
+                self.render_files(ui);
             })
     }
 
@@ -87,6 +479,52 @@ impl UIElement for ControlBarUI {
                 .expect("Failed to start video stream");
             self.stream_started = true;
         }
+
+        let client = self.client.clone();
+        let client = client.borrow();
+        for event in client.events.try_iter() {
+            self.remote_cursor.handle_event(&event);
+            self.clipboard.handle_event(&event);
+            if let Some(edcs_server_event::Payload::ControlState(control)) = event.payload {
+                self.set_control_state(control);
+            }
+        }
+        for update in client.transfers.try_iter() {
+            self.update_transfer(update);
+        }
+        // Input responses are ignored, so only replies to ListFiles and the control
+        // and invite requests end up here
+        for resp in client.recv.try_iter() {
+            match resp {
+                ChannelEdcsResponse::EdcsResponse(Ok(resp)) if resp.status() != EdcsStatus::Ok => {
+                    self.debug_area
+                        .borrow_mut()
+                        .push(&format!("EDCS request failed: {}", error_message(&resp)));
+                }
+                ChannelEdcsResponse::EdcsResponse(Ok(resp)) => match resp.payload {
+                    Some(edcs_response::Payload::FileList(list)) => {
+                        self.remote_files = list.files;
+                    }
+                    Some(edcs_response::Payload::Invite(invite)) => {
+                        self.new_invite_token = Some(invite.token);
+                    }
+                    Some(edcs_response::Payload::InviteList(list)) => {
+                        self.invites = list.invites;
+                    }
+                    _ => {}
+                },
+                ChannelEdcsResponse::EdcsResponse(Err(e)) => {
+                    warn!("Request to EDCS failed {:?}", e)
+                }
+                _ => {}
+            }
+        }
+        if self.sends_input() {
+            self.gamepads.poll(&client.push);
+        }
+        if !self.view_only() {
+            self.clipboard.poll(&client.push);
+        }
     }
 
     fn next_element(&mut self, _window: &Window) -> Option<Box<dyn UIElement>> {
@@ -100,20 +538,42 @@ impl UIElement for ControlBarUI {
         _window_id: glutin::window::WindowId,
         event: &glutin::event::WindowEvent,
     ) {
+        let view_only = self.view_only();
+        let no_input = !self.sends_input();
         match event {
-            WindowEvent::CursorMoved { position, .. } => {
+            WindowEvent::Resized(size) => {
+                self.video_mapping.set_window_size(*size);
+                self.mpv_ctx.resize(size.width, size.height);
+            }
+            WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
+                self.video_mapping.set_window_size(**new_inner_size);
+                self.mpv_ctx.resize(new_inner_size.width, new_inner_size.height);
+            }
+            WindowEvent::DroppedFile(path) if !view_only => {
+                info!("Uploading {:?}", path);
+                let path = path.clone();
+                self.start_transfer(|id| ChannelEdcsRequest::UploadFile { id, path });
+                self.show_control_bar = true;
+            }
+            WindowEvent::Focused(focused) if !no_input => {
+                self.gamepads.set_focused(*focused, &self.client.borrow().push);
+            }
+            WindowEvent::CursorMoved { position, .. } if !no_input => {
                 trace!("mouse move source {:?}", event);
+                // EDCS expects coordinates relative to the video, not the window
+                let (x, y) = match self.video_mapping.normalise(*position) {
+                    Some(coords) => coords,
+                    None => return,
+                };
+                self.remote_cursor.set_local_position(x, y);
                 let ret = self
                     .client
                     .borrow()
                     .push
-                    .send(ChannelEdcsRequest::WriteMouseMove {
-                        x: position.x as f64,
-                        y: position.y as f64,
-                    });
+                    .send(ChannelEdcsRequest::WriteMouseMove { x, y });
                 trace!("try send to self.client returns {:?}", ret);
             }
-            WindowEvent::MouseInput { state, button, .. } => {
+            WindowEvent::MouseInput { state, button, .. } if !no_input => {
                 trace!("mouse move source {:?}", event);
                 self.client
                     .borrow()
@@ -134,12 +594,77 @@ impl UIElement for ControlBarUI {
                         },
                     });
             }
+            WindowEvent::MouseWheel { delta, .. } if !no_input => {
+                trace!("mouse wheel {:?}", delta);
+                // Positive deltas scroll up and left in winit
+                let (x, y) = match delta {
+                    MouseScrollDelta::LineDelta(x, y) => (*x as f64, *y as f64),
+                    MouseScrollDelta::PixelDelta(pos) => (
+                        pos.x / PIXELS_PER_WHEEL_CLICK,
+                        pos.y / PIXELS_PER_WHEEL_CLICK,
+                    ),
+                };
+                let (vertical, horizontal) = &mut self.wheel_remainder;
+                *vertical += y;
+                *horizontal -= x;
+                let (vertical_clicks, horizontal_clicks) =
+                    (vertical.trunc() as i32, horizontal.trunc() as i32);
+                if vertical_clicks == 0 && horizontal_clicks == 0 {
+                    return;
+                }
+                *vertical -= vertical_clicks as f64;
+                *horizontal -= horizontal_clicks as f64;
+                self.client
+                    .borrow()
+                    .push
+                    .send(ChannelEdcsRequest::WriteMouseWheel {
+                        vertical: vertical_clicks,
+                        horizontal: horizontal_clicks,
+                    });
+            }
+            WindowEvent::Touch(touch) if !no_input => {
+                trace!("touch {:?}", touch);
+                let (x, y) = match self.video_mapping.normalise(touch.location) {
+                    Some(coords) => coords,
+                    None => return,
+                };
+                if !self.input_capabilities.touch {
+                    self.emulate_touch_with_mouse(touch.id, touch.phase, x, y);
+                    return;
+                }
+
+                self.client
+                    .borrow()
+                    .push
+                    .send(ChannelEdcsRequest::WriteTouchEvent {
+                        contact_id: touch.id as u32,
+                        phase: match touch.phase {
+                            TouchPhase::Started => EdcsTouchPhase::TouchStarted,
+                            TouchPhase::Moved => EdcsTouchPhase::TouchMoved,
+                            TouchPhase::Ended => EdcsTouchPhase::TouchEnded,
+                            TouchPhase::Cancelled => EdcsTouchPhase::TouchCancelled,
+                        },
+                        x,
+                        y,
+                        // Touchscreens without pressure data report a full press
+                        pressure: touch.force.map(|f| f.normalized()).unwrap_or(1.0),
+                    });
+            }
             WindowEvent::KeyboardInput {
                 device_id: _,
                 input,
                 is_synthetic: _,
             } => {
                 trace!("keyinput {:?}", input);
+                if input.virtual_keycode == Some(VirtualKeyCode::Scroll) {
+                    if input.state == ElementState::Pressed {
+                        self.show_control_bar = !self.show_control_bar;
+                    }
+                    return;
+                }
+                if no_input {
+                    return;
+                }
                 let key_typ = if cfg!(linux) {
                     input.scancode as i32
                 } else {
@@ -156,7 +681,7 @@ impl UIElement for ControlBarUI {
                         pressed: input.state == ElementState::Pressed,
                     });
             }
-            WindowEvent::ModifiersChanged(mod_state) => {
+            WindowEvent::ModifiersChanged(mod_state) if !no_input => {
                 if !cfg!(linux) {
                     let send_event = |pressed: bool, vkeycd: VirtualKeyCode| {
                         self.client
@@ -192,6 +717,13 @@ impl UIElement for ControlBarUI {
 
     fn paint_before_egui(&mut self, _gl: Rc<glow::Context>, window: &Window) {
         self.handle_messages();
+
+        let os_cursor_visible = !self.remote_cursor.replaces_os_cursor();
+        if os_cursor_visible != self.os_cursor_visible {
+            window.set_cursor_visible(os_cursor_visible);
+            self.os_cursor_visible = os_cursor_visible;
+        }
+
         self.mpv_ctx.paint(window)
     }
 
@@ -10,11 +10,14 @@ use glutin::{
 use self::{connect::ConnectUI, mpv::MPVEvent};
 use crate::edcs_client::blocking_client::{self, BlockingEdcsClient};
 
+mod cal_option_form;
 mod connect;
 mod control_bar;
 mod debug_area;
 pub(crate) mod mpv;
+mod remote_cursor;
 mod ui_element;
+mod video_mapping;
 
 use ui_element::UIElement;
 
@@ -167,6 +167,31 @@ impl MPVCtx {
         })
     }
 
+    /// Options can only be set before mpv_initialize, properties at any time.
+    pub fn set_property(&self, key: &str, val: &str) -> i32 {
+        let prop = CString::new(key).expect("Failed to set mpv prop key");
+        let val = CString::new(val).expect("Failed to set mpv prop val");
+
+        unsafe { mpv_set_property_string(self.mpv, prop.as_ptr(), val.as_ptr()) }
+    }
+
+    pub fn set_volume(&self, volume: f64) {
+        self.set_property("volume", &volume.to_string());
+    }
+
+    pub fn set_mute(&self, mute: bool) {
+        self.set_property("mute", if mute { "yes" } else { "no" });
+    }
+
+    /// The FBO size has to follow the window, otherwise MPV keeps rendering at
+    /// the size the window had when the stream started.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.mpv_ogl_fbo.w = width as i32;
+        self.mpv_ogl_fbo.h = height as i32;
+    }
+
     pub fn paint(&mut self, _window: &Window) {
         unsafe {
             let mut y = [1];
@@ -0,0 +1,112 @@
+use log::warn;
+
+use crate::edcs_client::edcs_proto::{edcs_server_event, EdcsCursorImage, EdcsServerEvent};
+
+use super::video_mapping::VideoMapping;
+
+/// The cursor of the remote machine, as reported by EDCS.
+///
+/// With `host_cursor` set, the remote cursor image is drawn over the video in
+/// place of the OS cursor. Local pointer movement moves it straight away, so it
+/// feels instant even when the video lags behind, and EDCS position updates
+/// take over when the remote side moves the cursor by itself. winit can't set
+/// a custom OS cursor image, so without `host_cursor` the OS cursor is left
+/// alone and the remote cursor is only visible in the video.
+pub struct RemoteCursor {
+    host_cursor: bool,
+    image: Option<EdcsCursorImage>,
+    texture: Option<egui::TextureHandle>,
+    // Normalised video coordinates
+    position: Option<(f64, f64)>,
+    visible: bool,
+}
+
+impl RemoteCursor {
+    pub fn new(host_cursor: bool) -> Self {
+        Self {
+            host_cursor,
+            image: None,
+            texture: None,
+            position: None,
+            visible: true,
+        }
+    }
+
+    /// Whether the OS cursor should be hidden because we are drawing our own.
+    pub fn replaces_os_cursor(&self) -> bool {
+        self.host_cursor && self.image.is_some()
+    }
+
+    pub fn handle_event(&mut self, event: &EdcsServerEvent) {
+        match &event.payload {
+            Some(edcs_server_event::Payload::CursorPosition(pos)) => {
+                self.position = Some((pos.x, pos.y));
+                self.visible = pos.visible;
+            }
+            Some(edcs_server_event::Payload::CursorImage(image)) => {
+                let expected_len = image.width as usize * image.height as usize * 4;
+                if image.rgba.len() != expected_len || expected_len == 0 {
+                    warn!(
+                        "Ignoring cursor image with {} bytes for {}x{}",
+                        image.rgba.len(),
+                        image.width,
+                        image.height
+                    );
+                    return;
+                }
+                self.image = Some(image.clone());
+                // Re-uploaded on the next paint
+                self.texture = None;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn set_local_position(&mut self, x: f64, y: f64) {
+        self.position = Some((x, y));
+    }
+
+    pub fn paint(&mut self, ctx: &egui::Context, video_mapping: &VideoMapping) {
+        if !self.host_cursor || !self.visible {
+            return;
+        }
+        let (image, (x, y)) = match (&self.image, self.position) {
+            (Some(image), Some(position)) => (image, position),
+            _ => return,
+        };
+
+        let texture = self.texture.get_or_insert_with(|| {
+            ctx.load_texture(
+                "remote_cursor",
+                egui::ColorImage::from_rgba_unmultiplied(
+                    [image.width as usize, image.height as usize],
+                    &image.rgba,
+                ),
+            )
+        });
+
+        // egui works in points, the mapping works in physical pixels
+        let pixels_per_point = ctx.pixels_per_point() as f64;
+        let scale = video_mapping.scale();
+        let position = video_mapping.denormalise(x, y);
+        let min = egui::pos2(
+            ((position.x - image.hotspot_x as f64 * scale) / pixels_per_point) as f32,
+            ((position.y - image.hotspot_y as f64 * scale) / pixels_per_point) as f32,
+        );
+        let size = egui::vec2(
+            (image.width as f64 * scale / pixels_per_point) as f32,
+            (image.height as f64 * scale / pixels_per_point) as f32,
+        );
+
+        ctx.layer_painter(egui::LayerId::new(
+            egui::Order::Tooltip,
+            egui::Id::new("remote_cursor"),
+        ))
+        .image(
+            texture.id(),
+            egui::Rect::from_min_size(min, size),
+            egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0)),
+            egui::Color32::WHITE,
+        );
+    }
+}
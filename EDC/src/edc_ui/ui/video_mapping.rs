@@ -0,0 +1,72 @@
+use glutin::dpi::{PhysicalPosition, PhysicalSize};
+
+/// Maps window coordinates onto the video frame that MPV renders. MPV keeps the
+/// aspect ratio of the stream, so the frame is letterboxed (or pillarboxed)
+/// whenever the window's aspect ratio differs from the captured framebuffer.
+#[derive(Debug, Clone, Copy)]
+pub struct VideoMapping {
+    window_size: PhysicalSize<u32>,
+    video_size: (u32, u32),
+}
+
+impl VideoMapping {
+    pub fn new(window_size: PhysicalSize<u32>, video_size: (u32, u32)) -> Self {
+        Self {
+            window_size,
+            video_size,
+        }
+    }
+
+    pub fn set_window_size(&mut self, window_size: PhysicalSize<u32>) {
+        self.window_size = window_size;
+    }
+
+    /// The (x, y, width, height) of the video inside the window in physical pixels.
+    pub fn video_rect(&self) -> (f64, f64, f64, f64) {
+        let (win_w, win_h) = (
+            self.window_size.width as f64,
+            self.window_size.height as f64,
+        );
+        let (vid_w, vid_h) = (self.video_size.0 as f64, self.video_size.1 as f64);
+        if win_w <= 0.0 || win_h <= 0.0 || vid_w <= 0.0 || vid_h <= 0.0 {
+            return (0.0, 0.0, win_w, win_h);
+        }
+
+        let scale = (win_w / vid_w).min(win_h / vid_h);
+        let (w, h) = (vid_w * scale, vid_h * scale);
+        ((win_w - w) / 2.0, (win_h - h) / 2.0, w, h)
+    }
+
+    /// Convert a window position into normalised [0, 1] video coordinates.
+    /// Positions over the letterbox bars are clamped to the nearest video edge so
+    /// the remote cursor can still reach the borders of the screen.
+    pub fn normalise(&self, position: PhysicalPosition<f64>) -> Option<(f64, f64)> {
+        let (x, y, w, h) = self.video_rect();
+        if w <= 0.0 || h <= 0.0 {
+            return None;
+        }
+        Some((
+            ((position.x - x) / w).clamp(0.0, 1.0),
+            ((position.y - y) / h).clamp(0.0, 1.0),
+        ))
+    }
+
+    /// Convert normalised video coordinates back into a window position.
+    pub fn denormalise(&self, x: f64, y: f64) -> PhysicalPosition<f64> {
+        let (rect_x, rect_y, w, h) = self.video_rect();
+        PhysicalPosition {
+            x: rect_x + x * w,
+            y: rect_y + y * h,
+        }
+    }
+
+    /// How many window pixels one video pixel takes up.
+    pub fn scale(&self) -> f64 {
+        let (_, _, w, _) = self.video_rect();
+        if self.video_size.0 == 0 {
+            1.0
+        } else {
+            w / self.video_size.0 as f64
+        }
+    }
+}
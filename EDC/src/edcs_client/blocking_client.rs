@@ -3,7 +3,11 @@
 use crate::{
     edcs_client::{
         client::EdcsClient,
-        edcs_proto::{EdcsMouseButton, EdcsResponse},
+        edcs_proto::{
+            EdcsAudioParams, EdcsClipboardData, EdcsGamepadEvent, EdcsMouseButton, EdcsPenEvent,
+            EdcsResponse, EdcsServerEvent, EdcsTouchPhase,
+        },
+        file_transfer::{self, FileTransferUpdate},
     },
     edcs_config::ClientConfig,
 };
@@ -12,7 +16,7 @@ use async_mutex::Mutex;
 use flume::{Receiver, Sender};
 
 use log::{error, trace};
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 use tokio::runtime::Builder;
 
 // At this point, we may as well get rid of the methods in EdcsClient and just have the GUI send over the structs we want
@@ -23,9 +27,27 @@ pub enum ChannelEdcsRequest {
         bitrate: u32,
         framerate: u32,
     },
-    SetupStream(HashMap<String, String>),
+    SetupStream {
+        cal_option_dict: HashMap<String, String>,
+        // None for a silent stream
+        audio_params: Option<EdcsAudioParams>,
+    },
     StartStream,
     CloseStream,
+    // For a stream someone else set up, see ConnectUI
+    JoinStream {
+        port: u16,
+    },
+    RequestControl,
+    GrantControl(u32),
+    RevokeControl,
+    CreateInvite {
+        // 0 for the EDCS default
+        valid_for_secs: u32,
+        view_only: bool,
+    },
+    ListInvites,
+    RevokeInvite(String),
     WriteMouseMove {
         x: f64,
         y: f64,
@@ -34,10 +56,36 @@ pub enum ChannelEdcsRequest {
         button_typ: EdcsMouseButton,
         pressed: bool,
     },
+    // In wheel clicks, positive is up and right
+    WriteMouseWheel {
+        vertical: i32,
+        horizontal: i32,
+    },
     WriteKeyboardEvent {
         key_typ: i32,
         pressed: bool,
     },
+    WriteTouchEvent {
+        contact_id: u32,
+        phase: EdcsTouchPhase,
+        x: f64,
+        y: f64,
+        pressure: f64,
+    },
+    WritePenEvent(EdcsPenEvent),
+    WriteGamepadEvent(EdcsGamepadEvent),
+    ClipboardUpdate(EdcsClipboardData),
+    // Transfers run in the background and report through `transfers`
+    UploadFile {
+        id: u64,
+        path: PathBuf,
+    },
+    DownloadFile {
+        id: u64,
+        name: String,
+        dest_dir: PathBuf,
+    },
+    ListFiles,
 }
 #[derive(Debug)]
 pub enum ChannelEdcsResponse {
@@ -50,6 +98,9 @@ pub enum ChannelEdcsResponse {
 pub struct BlockingEdcsClient {
     pub push: Sender<ChannelEdcsRequest>,
     pub recv: Receiver<ChannelEdcsResponse>,
+    // Events pushed by EDCS outside of the request/response flow
+    pub events: Receiver<EdcsServerEvent>,
+    pub transfers: Receiver<FileTransferUpdate>,
 }
 
 impl BlockingEdcsClient {
@@ -57,11 +108,15 @@ impl BlockingEdcsClient {
         // There may be a lot of messages in the ring
         let (ui_send, client_recv) = flume::unbounded(); // channel(32);
         let (client_send, ui_recv) = flume::unbounded(); // channel(32);
+        let (event_send, event_recv) = flume::unbounded();
+        let (transfer_send, transfer_recv) = flume::unbounded();
 
         // No client until it's requested
         let client = Self {
             push: ui_send,
             recv: ui_recv,
+            events: event_recv,
+            transfers: transfer_recv,
         };
         let runtime = Builder::new_current_thread()
             .enable_all()
@@ -78,6 +133,8 @@ impl BlockingEdcsClient {
                         req,
                         edcs_client.clone(),
                         client_send.clone(),
+                        event_send.clone(),
+                        transfer_send.clone(),
                     ))
                     .await;
                 }
@@ -91,18 +148,33 @@ impl BlockingEdcsClient {
         req: ChannelEdcsRequest,
         edcs_client_lck: Arc<Mutex<Option<EdcsClient>>>,
         client_push: Sender<ChannelEdcsResponse>,
+        event_push: Sender<EdcsServerEvent>,
+        transfer_push: Sender<FileTransferUpdate>,
     ) {
         trace!("client req: {:?}", req);
         let mut edcs_client_opt = edcs_client_lck.lock().await;
         let resp = match req {
             // TODO DRY
             ChannelEdcsRequest::SetupEdcs { .. }
-            | ChannelEdcsRequest::SetupStream(_)
+            | ChannelEdcsRequest::SetupStream { .. }
             | ChannelEdcsRequest::StartStream
             | ChannelEdcsRequest::CloseStream
+            | ChannelEdcsRequest::JoinStream { .. }
+            | ChannelEdcsRequest::RequestControl
+            | ChannelEdcsRequest::GrantControl(_)
+            | ChannelEdcsRequest::RevokeControl
+            | ChannelEdcsRequest::CreateInvite { .. }
+            | ChannelEdcsRequest::ListInvites
+            | ChannelEdcsRequest::RevokeInvite(_)
             | ChannelEdcsRequest::WriteMouseButton { .. }
             | ChannelEdcsRequest::WriteMouseMove { .. }
-            | ChannelEdcsRequest::WriteKeyboardEvent { .. } => {
+            | ChannelEdcsRequest::WriteMouseWheel { .. }
+            | ChannelEdcsRequest::WriteKeyboardEvent { .. }
+            | ChannelEdcsRequest::WriteTouchEvent { .. }
+            | ChannelEdcsRequest::WritePenEvent(_)
+            | ChannelEdcsRequest::WriteGamepadEvent(_)
+            | ChannelEdcsRequest::ClipboardUpdate(_)
+            | ChannelEdcsRequest::ListFiles => {
                 let ret = if let Some(edcs_client) = &mut *edcs_client_opt {
                     match req {
                         ChannelEdcsRequest::SetupEdcs { bitrate, framerate } => {
@@ -111,17 +183,48 @@ impl BlockingEdcsClient {
                                 ret
                             })
                         }
-                        ChannelEdcsRequest::SetupStream(ref options) => {
-                            ChannelEdcsResponse::EdcsResponse(
-                                edcs_client.setup_stream(options.clone()).await,
-                            )
-                        }
+                        ChannelEdcsRequest::SetupStream {
+                            ref cal_option_dict,
+                            ref audio_params,
+                        } => ChannelEdcsResponse::EdcsResponse(
+                            edcs_client
+                                .setup_stream(cal_option_dict.clone(), audio_params.clone())
+                                .await,
+                        ),
                         ChannelEdcsRequest::StartStream => {
                             ChannelEdcsResponse::EdcsResponse(edcs_client.init_stream().await)
                         }
                         ChannelEdcsRequest::CloseStream => {
                             ChannelEdcsResponse::EdcsResponse(edcs_client.close_stream().await)
                         }
+                        ChannelEdcsRequest::JoinStream { port } => {
+                            ChannelEdcsResponse::EdcsResponse(edcs_client.join_stream(port).await)
+                        }
+                        ChannelEdcsRequest::RequestControl => {
+                            ChannelEdcsResponse::EdcsResponse(edcs_client.request_control().await)
+                        }
+                        ChannelEdcsRequest::GrantControl(participant) => {
+                            ChannelEdcsResponse::EdcsResponse(
+                                edcs_client.grant_control(participant).await,
+                            )
+                        }
+                        ChannelEdcsRequest::RevokeControl => {
+                            ChannelEdcsResponse::EdcsResponse(edcs_client.revoke_control().await)
+                        }
+                        ChannelEdcsRequest::CreateInvite {
+                            valid_for_secs,
+                            view_only,
+                        } => ChannelEdcsResponse::EdcsResponse(
+                            edcs_client.create_invite(valid_for_secs, view_only).await,
+                        ),
+                        ChannelEdcsRequest::ListInvites => {
+                            ChannelEdcsResponse::EdcsResponse(edcs_client.list_invites().await)
+                        }
+                        ChannelEdcsRequest::RevokeInvite(ref id) => {
+                            ChannelEdcsResponse::EdcsResponse(
+                                edcs_client.revoke_invite(id.clone()).await,
+                            )
+                        }
                         // Should never go here
                         ChannelEdcsRequest::NewClient(_) => panic!(),
                         ChannelEdcsRequest::WriteMouseMove { x, y } => {
@@ -139,11 +242,49 @@ impl BlockingEdcsClient {
                             trace!("write mouse button finished");
                             ret
                         }),
+                        ChannelEdcsRequest::WriteMouseWheel {
+                            vertical,
+                            horizontal,
+                        } => ChannelEdcsResponse::EdcsResponse(
+                            edcs_client.write_mouse_wheel(vertical, horizontal).await,
+                        ),
                         ChannelEdcsRequest::WriteKeyboardEvent { key_typ, pressed } => {
                             ChannelEdcsResponse::EdcsResponse({
                                 edcs_client.write_keyboard_event(key_typ, pressed).await
                             })
                         }
+                        ChannelEdcsRequest::WriteTouchEvent {
+                            contact_id,
+                            phase,
+                            x,
+                            y,
+                            pressure,
+                        } => ChannelEdcsResponse::EdcsResponse(
+                            edcs_client
+                                .write_touch_event(contact_id, phase, x, y, pressure)
+                                .await,
+                        ),
+                        ChannelEdcsRequest::WritePenEvent(ref pen_event) => {
+                            ChannelEdcsResponse::EdcsResponse(
+                                edcs_client.write_pen_event(pen_event.clone()).await,
+                            )
+                        }
+                        ChannelEdcsRequest::WriteGamepadEvent(ref gamepad_event) => {
+                            ChannelEdcsResponse::EdcsResponse(
+                                edcs_client.write_gamepad_event(gamepad_event.clone()).await,
+                            )
+                        }
+                        ChannelEdcsRequest::ClipboardUpdate(ref clipboard_data) => {
+                            ChannelEdcsResponse::EdcsResponse(
+                                edcs_client.clipboard_update(clipboard_data.clone()).await,
+                            )
+                        }
+                        ChannelEdcsRequest::ListFiles => {
+                            ChannelEdcsResponse::EdcsResponse(edcs_client.file_list().await)
+                        }
+                        // Handled below
+                        ChannelEdcsRequest::UploadFile { .. }
+                        | ChannelEdcsRequest::DownloadFile { .. } => panic!(),
                     }
                 } else {
                     ChannelEdcsResponse::InvalidClient
@@ -152,7 +293,12 @@ impl BlockingEdcsClient {
                 match &req {
                     ChannelEdcsRequest::WriteMouseMove { .. }
                     | ChannelEdcsRequest::WriteMouseButton { .. }
-                    | ChannelEdcsRequest::WriteKeyboardEvent { .. } => {}
+                    | ChannelEdcsRequest::WriteMouseWheel { .. }
+                    | ChannelEdcsRequest::WriteKeyboardEvent { .. }
+                    | ChannelEdcsRequest::WriteTouchEvent { .. }
+                    | ChannelEdcsRequest::WritePenEvent(_)
+                    | ChannelEdcsRequest::WriteGamepadEvent(_)
+                    | ChannelEdcsRequest::ClipboardUpdate(_) => {}
                     _ => {
                         if let Err(e) = client_push.send(ret) {
                             error!("failed to push response from EDCS to UI thread {:?}", e);
@@ -161,8 +307,30 @@ impl BlockingEdcsClient {
                 }
             }
 
+            // These lock the client themselves, one chunk at a time
+            ChannelEdcsRequest::UploadFile { id, path } => {
+                tokio::spawn(file_transfer::upload(
+                    edcs_client_lck.clone(),
+                    id,
+                    path,
+                    transfer_push,
+                ));
+            }
+            ChannelEdcsRequest::DownloadFile { id, name, dest_dir } => {
+                tokio::spawn(file_transfer::download(
+                    edcs_client_lck.clone(),
+                    id,
+                    name,
+                    dest_dir,
+                    transfer_push,
+                ));
+            }
+
             ChannelEdcsRequest::NewClient(client_config) => {
-                let edcs_client_res = EdcsClient::new(client_config).await;
+                // The old connection may own a stream, which has to be gone before this one
+                // sets up another
+                *edcs_client_opt = None;
+                let edcs_client_res = EdcsClient::new(client_config, event_push).await;
                 if let Ok(c) = edcs_client_res {
                     client_push
                         .send(ChannelEdcsResponse::EdcsClientInitialised)
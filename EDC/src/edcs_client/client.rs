@@ -1,21 +1,25 @@
 use anyhow::{anyhow, Context};
-use log::trace;
+use log::{debug, error, trace};
 use prost::{decode_length_delimiter, encode_length_delimiter, Message};
 use tokio::io::{split, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 use tokio_rustls::client::TlsStream;
 use tokio_rustls::rustls::{self, OwnedTrustAnchor};
 use tokio_rustls::TlsConnector;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use crate::edcs_client::edcs_proto::{
-    edcs_message, edcs_mouse_event, EdcsCalParams, EdcsKeyData, EdcsKeyboardEvent, EdcsMessage,
-    EdcsMessageType, EdcsMouseButton, EdcsMouseEvent, EdcsMouseMove, EdcsResponse, EdcsStatus,
-    EdcsStreamParams,
+    edcs_message, edcs_mouse_event, edcs_response, EdcsAudioParams, EdcsCalParams,
+    EdcsClipboardData, EdcsFileChunk, EdcsFileChunkRequest, EdcsFileInfo, EdcsGamepadEvent,
+    EdcsInviteParams, EdcsKeyData, EdcsKeyboardEvent, EdcsMessage, EdcsMessageType,
+    EdcsMouseButton, EdcsMouseEvent, EdcsMouseMove, EdcsMouseWheel, EdcsPenEvent, EdcsResponse,
+    EdcsServerEvent, EdcsStatus, EdcsStreamParams, EdcsTouchEvent, EdcsTouchPhase,
 };
 use crate::edcs_config::ClientConfig;
 
@@ -34,17 +38,41 @@ impl rustls::client::ServerCertVerifier for NoCertVerify {
     }
 }
 
+// EDCS answers every message in order, so responses are matched to requests by position.
+// A None entry is a request whose response we don't care about.
+type PendingResponses = Arc<Mutex<VecDeque<Option<oneshot::Sender<anyhow::Result<EdcsResponse>>>>>>;
+
 #[derive(Debug)]
 pub struct EdcsClient {
-    reader: BufReader<ReadHalf<TlsStream<TcpStream>>>,
     writer: BufWriter<WriteHalf<TlsStream<TcpStream>>>,
     delimiter_buf: Vec<u8>,
+    pending: PendingResponses,
+    reader_task: JoinHandle<()>,
+}
+
+impl Drop for EdcsClient {
+    fn drop(&mut self) {
+        self.reader_task.abort();
+    }
 }
 
 unsafe impl Send for EdcsClient {}
 
+/// What went wrong with a request, for showing to the user. EDCS fills in the
+/// message for anything but OK, older ones only send the status.
+pub fn error_message(resp: &EdcsResponse) -> String {
+    if resp.message.is_empty() {
+        format!("EDCS returned {:?}", resp.status())
+    } else {
+        resp.message.clone()
+    }
+}
+
 impl EdcsClient {
-    pub async fn new(client_options: ClientConfig) -> anyhow::Result<Self> {
+    pub async fn new(
+        client_options: ClientConfig,
+        event_push: flume::Sender<EdcsServerEvent>,
+    ) -> anyhow::Result<Self> {
         let mut root_cert_store = rustls::RootCertStore::empty();
         let mut pem = std::io::BufReader::new(
             File::open(client_options.cert).with_context(|| "Failed to CA cert file")?,
@@ -95,11 +123,93 @@ impl EdcsClient {
         let reader = BufReader::new(reader);
         let writer = BufWriter::new(writer);
 
-        Ok(Self {
+        let pending: PendingResponses = Arc::new(Mutex::new(VecDeque::new()));
+        let reader_task = tokio::spawn(Self::read_loop(
             reader,
+            pending.clone(),
+            event_push,
+            client_options.max_message_size,
+        ));
+
+        let mut client = Self {
             writer,
             delimiter_buf: vec![],
-        })
+            pending,
+            reader_task,
+        };
+        if let Some(token) = client_options.token {
+            let resp = client.authenticate(token).await?;
+            if resp.status() != EdcsStatus::Ok {
+                return Err(anyhow!(
+                    "EDCS didn't accept the token: {}",
+                    error_message(&resp)
+                ));
+            }
+            debug!("Authenticated {:?}", resp.payload);
+        }
+        Ok(client)
+    }
+
+    // Server events can arrive at any time, so the reader has to run on its own and hand
+    // responses back to whoever is waiting on them.
+    async fn read_loop(
+        mut reader: BufReader<ReadHalf<TlsStream<TcpStream>>>,
+        pending: PendingResponses,
+        event_push: flume::Sender<EdcsServerEvent>,
+        max_message_size: usize,
+    ) {
+        let mut delimiter_buf = vec![0; 10];
+        while let Ok(_) = reader.read_exact(&mut delimiter_buf).await {
+            trace!("Read delimiter buf {:?}", delimiter_buf);
+            let resp_len = match decode_length_delimiter(&delimiter_buf[..]) {
+                Ok(len) => len,
+                Err(e) => {
+                    error!("Failed to decode EDCS response delimiter {:?}", e);
+                    break;
+                }
+            };
+            // Checked before allocating, the length is whatever EDCS says it is
+            if resp_len > max_message_size {
+                error!(
+                    "EDCS sent a response of {} bytes, over the limit of {}",
+                    resp_len, max_message_size
+                );
+                break;
+            }
+            let mut resp_buf = vec![0; resp_len];
+            if let Err(e) = reader.read_exact(&mut resp_buf).await {
+                error!("Failed to read EDCS response {:?}", e);
+                break;
+            }
+            trace!("EDCS response data {:?}", &resp_buf[..]);
+
+            let resp = EdcsResponse::decode(&resp_buf[..])
+                .with_context(|| "Failed to parse EDCS response");
+            let resp = match resp {
+                Ok(EdcsResponse {
+                    payload: Some(edcs_response::Payload::ServerEvent(event)),
+                    ..
+                }) => {
+                    if event_push.send(event).is_err() {
+                        debug!("Nobody is listening for server events, dropping event");
+                    }
+                    continue;
+                }
+                resp => resp,
+            };
+
+            let waiter = pending.lock().unwrap().pop_front();
+            match waiter {
+                Some(Some(waiter)) => {
+                    let _ = waiter.send(resp);
+                }
+                Some(None) => trace!("Ignoring response {:?}", resp),
+                None => error!("Received EDCS response without a request {:?}", resp),
+            }
+        }
+        debug!("EDCS connection closed");
+        // Dropping the senders wakes everyone still waiting with an error
+        pending.lock().unwrap().clear();
     }
 
     // Handle sending RPCs to the EDCS
@@ -108,6 +218,14 @@ impl EdcsClient {
         msg: EdcsMessage,
         ignore_response: bool,
     ) -> anyhow::Result<EdcsResponse> {
+        // Register before writing so the response can't beat us to it
+        let (resp_push, resp_recv) = oneshot::channel();
+        self.pending.lock().unwrap().push_back(if ignore_response {
+            None
+        } else {
+            Some(resp_push)
+        });
+
         // Write length delimiter first
         self.delimiter_buf.clear();
         encode_length_delimiter(msg.encoded_len(), &mut self.delimiter_buf)?;
@@ -129,32 +247,29 @@ impl EdcsClient {
         self.writer.flush().await?;
         trace!("Wrote data {:?} to PB", msg_buf);
 
-        if !ignore_response {
-            let mut resp_len = 0;
-            // Read response delimiter
-            while let Ok(_) = self.reader.read_exact(&mut self.delimiter_buf).await {
-                trace!("Read delimiter buf {:?}", self.delimiter_buf);
-                trace!("resp_len = {}", resp_len);
-
-                resp_len = decode_length_delimiter(&self.delimiter_buf[..])?;
-                let mut resp_buf = vec![0; resp_len];
-                while let Ok(_) = self.reader.read_exact(&mut resp_buf).await {
-                    trace!("EDCS response data {:?}", &resp_buf[..]);
-                    return EdcsResponse::decode(&resp_buf[..])
-                        .with_context(|| "Failed to parse EDCS response");
-                }
-            }
-            trace!("Resp len is {}", resp_len);
-        } else {
+        if ignore_response {
             return Ok(EdcsResponse {
                 status: EdcsStatus::Ok as i32,
                 payload: None,
+                message: String::new(),
             });
         }
 
-        Err(anyhow!("Did not read EDCS response"))
+        resp_recv
+            .await
+            .map_err(|_| anyhow!("Did not read EDCS response"))?
     }
 
+    pub async fn authenticate(&mut self, token: String) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::Authenticate as i32,
+                payload: Some(edcs_message::Payload::AuthenticateToken(token)),
+            },
+            false,
+        )
+        .await
+    }
     pub async fn setup_edcs(
         &mut self,
         framerate: u32,
@@ -175,12 +290,14 @@ impl EdcsClient {
     pub async fn setup_stream(
         &mut self,
         cal_option_dict: HashMap<String, String>,
+        audio_params: Option<EdcsAudioParams>,
     ) -> anyhow::Result<EdcsResponse> {
         self.send_message(
             EdcsMessage {
                 message_type: EdcsMessageType::SetupStream as i32,
                 payload: Some(edcs_message::Payload::SetupStreamParams(EdcsCalParams {
                     cal_option_dict,
+                    audio_params,
                 })),
             },
             false,
@@ -198,6 +315,88 @@ impl EdcsClient {
         .await
     }
 
+    // Watch a stream another client set up. A port of 0 leaves it to EDCS.
+    pub async fn join_stream(&mut self, port: u16) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::JoinStream as i32,
+                payload: Some(edcs_message::Payload::JoinStreamPort(port as u32)),
+            },
+            false,
+        )
+        .await
+    }
+    pub async fn request_control(&mut self) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::RequestControl as i32,
+                payload: None,
+            },
+            false,
+        )
+        .await
+    }
+    pub async fn grant_control(&mut self, participant: u32) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::GrantControl as i32,
+                payload: Some(edcs_message::Payload::GrantControlParticipant(participant)),
+            },
+            false,
+        )
+        .await
+    }
+    pub async fn revoke_control(&mut self) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::RevokeControl as i32,
+                payload: None,
+            },
+            false,
+        )
+        .await
+    }
+
+    pub async fn create_invite(
+        &mut self,
+        valid_for_secs: u32,
+        view_only: bool,
+    ) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::CreateInvite as i32,
+                payload: Some(edcs_message::Payload::CreateInviteParams(
+                    EdcsInviteParams {
+                        valid_for_secs,
+                        view_only,
+                    },
+                )),
+            },
+            false,
+        )
+        .await
+    }
+    pub async fn list_invites(&mut self) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::ListInvites as i32,
+                payload: None,
+            },
+            false,
+        )
+        .await
+    }
+    pub async fn revoke_invite(&mut self, id: String) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::RevokeInvite as i32,
+                payload: Some(edcs_message::Payload::RevokeInviteId(id)),
+            },
+            false,
+        )
+        .await
+    }
+
     pub async fn close_stream(&mut self) -> anyhow::Result<EdcsResponse> {
         self.send_message(
             EdcsMessage {
@@ -247,6 +446,25 @@ impl EdcsClient {
         trace!("finished writing mouse button {:?}", ret);
         ret
     }
+    pub async fn write_mouse_wheel(
+        &mut self,
+        vertical: i32,
+        horizontal: i32,
+    ) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::WriteMouseEvent as i32,
+                payload: Some(edcs_message::Payload::MouseEvent(EdcsMouseEvent {
+                    payload: Some(edcs_mouse_event::Payload::Wheel(EdcsMouseWheel {
+                        vertical,
+                        horizontal,
+                    })),
+                })),
+            },
+            true,
+        )
+        .await
+    }
     // Using the struct wholesale here seems a bit inconsistent with the other functions
     pub async fn write_keyboard_event(
         &mut self,
@@ -270,4 +488,149 @@ impl EdcsClient {
         trace!("finished writing mouse button {:?}", ret);
         ret
     }
+
+    pub async fn write_touch_event(
+        &mut self,
+        contact_id: u32,
+        phase: EdcsTouchPhase,
+        x: f64,
+        y: f64,
+        pressure: f64,
+    ) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::WriteTouchEvent as i32,
+                payload: Some(edcs_message::Payload::TouchEvent(EdcsTouchEvent {
+                    contact_id,
+                    phase: phase as i32,
+                    x,
+                    y,
+                    pressure,
+                })),
+            },
+            true,
+        )
+        .await
+    }
+    pub async fn write_pen_event(
+        &mut self,
+        pen_event: EdcsPenEvent,
+    ) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::WritePenEvent as i32,
+                payload: Some(edcs_message::Payload::PenEvent(pen_event)),
+            },
+            true,
+        )
+        .await
+    }
+    pub async fn write_gamepad_event(
+        &mut self,
+        gamepad_event: EdcsGamepadEvent,
+    ) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::WriteGamepadEvent as i32,
+                payload: Some(edcs_message::Payload::GamepadEvent(gamepad_event)),
+            },
+            true,
+        )
+        .await
+    }
+
+    pub async fn clipboard_update(
+        &mut self,
+        clipboard_data: EdcsClipboardData,
+    ) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::ClipboardUpdate as i32,
+                payload: Some(edcs_message::Payload::ClipboardUpdate(clipboard_data)),
+            },
+            true,
+        )
+        .await
+    }
+
+    pub async fn file_upload_start(&mut self, info: EdcsFileInfo) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::FileUploadStart as i32,
+                payload: Some(edcs_message::Payload::FileUploadStart(info)),
+            },
+            false,
+        )
+        .await
+    }
+    pub async fn file_upload_chunk(
+        &mut self,
+        name: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::FileUploadChunk as i32,
+                payload: Some(edcs_message::Payload::FileUploadChunk(EdcsFileChunk {
+                    name,
+                    offset,
+                    data,
+                })),
+            },
+            false,
+        )
+        .await
+    }
+    pub async fn file_upload_finish(&mut self, name: String) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::FileUploadFinish as i32,
+                payload: Some(edcs_message::Payload::FileUploadFinish(name)),
+            },
+            false,
+        )
+        .await
+    }
+    pub async fn file_list(&mut self) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::FileList as i32,
+                payload: None,
+            },
+            false,
+        )
+        .await
+    }
+    pub async fn file_download_start(&mut self, name: String) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::FileDownloadStart as i32,
+                payload: Some(edcs_message::Payload::FileDownloadStart(name)),
+            },
+            false,
+        )
+        .await
+    }
+    pub async fn file_download_chunk(
+        &mut self,
+        name: String,
+        offset: u64,
+        length: u32,
+    ) -> anyhow::Result<EdcsResponse> {
+        self.send_message(
+            EdcsMessage {
+                message_type: EdcsMessageType::FileDownloadChunk as i32,
+                payload: Some(edcs_message::Payload::FileDownloadChunk(
+                    EdcsFileChunkRequest {
+                        name,
+                        offset,
+                        length,
+                    },
+                )),
+            },
+            false,
+        )
+        .await
+    }
 }
@@ -0,0 +1,244 @@
+use std::{
+    borrow::Cow,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use arboard::{Clipboard, ImageData};
+use flume::Sender;
+use log::{debug, warn};
+
+use super::{
+    blocking_client::ChannelEdcsRequest,
+    edcs_proto::{
+        edcs_clipboard_data, edcs_server_event, EdcsClipboardData, EdcsClipboardImage,
+        EdcsServerEvent,
+    },
+};
+
+const CLIPBOARD_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const PNG_MIME_TYPE: &str = "image/png";
+
+// What was last seen on or written to the local clipboard, to tell our own
+// writes apart from the user copying something.
+#[derive(PartialEq)]
+enum LocalContents {
+    Text(String),
+    Image {
+        width: usize,
+        height: usize,
+        rgba: Vec<u8>,
+    },
+}
+
+/// Keeps the local clipboard in sync with the remote one. The local clipboard
+/// can't notify us of changes, so it is polled.
+pub struct ClipboardSync {
+    clipboard: Option<Clipboard>,
+    max_size: usize,
+    last: Option<LocalContents>,
+    last_poll: Option<Instant>,
+}
+
+fn encode_png(width: usize, height: usize, rgba: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut png_data = vec![];
+    {
+        let mut encoder = png::Encoder::new(&mut png_data, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(rgba)?;
+    }
+    Ok(png_data)
+}
+
+fn decode_png(data: &[u8], max_size: usize) -> anyhow::Result<LocalContents> {
+    // The decoded image can be much larger than the PNG, so that is limited too
+    let mut decoder = png::Decoder::new_with_limits(
+        data,
+        png::Limits {
+            bytes: max_size.saturating_mul(16),
+        },
+    );
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    buf.truncate(info.buffer_size());
+
+    let rgba = match info.color_type {
+        png::ColorType::Rgba => buf,
+        png::ColorType::Rgb => buf
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 0xff])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buf
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale => buf.iter().flat_map(|g| [*g, *g, *g, 0xff]).collect(),
+        color_type => return Err(anyhow!("Unsupported PNG color type {:?}", color_type)),
+    };
+    Ok(LocalContents::Image {
+        width: info.width as usize,
+        height: info.height as usize,
+        rgba,
+    })
+}
+
+impl ClipboardSync {
+    pub fn new(enabled: bool, max_size: usize) -> Self {
+        let clipboard = if enabled {
+            match Clipboard::new() {
+                Ok(clipboard) => Some(clipboard),
+                Err(e) => {
+                    warn!("Clipboard sharing is unavailable: {:?}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        Self {
+            clipboard,
+            max_size,
+            last: None,
+            last_poll: None,
+        }
+    }
+
+    fn read_local(clipboard: &mut Clipboard) -> Option<LocalContents> {
+        if let Ok(text) = clipboard.get_text() {
+            return Some(LocalContents::Text(text));
+        }
+        clipboard
+            .get_image()
+            .ok()
+            .map(|image| LocalContents::Image {
+                width: image.width,
+                height: image.height,
+                rgba: image.bytes.into_owned(),
+            })
+    }
+
+    pub fn poll(&mut self, push: &Sender<ChannelEdcsRequest>) {
+        let clipboard = match &mut self.clipboard {
+            Some(clipboard) => clipboard,
+            None => return,
+        };
+        let first_poll = self.last_poll.is_none();
+        if let Some(last_poll) = self.last_poll {
+            if last_poll.elapsed() < CLIPBOARD_POLL_INTERVAL {
+                return;
+            }
+        }
+        self.last_poll = Some(Instant::now());
+
+        let contents = Self::read_local(clipboard);
+        let content = if contents.is_some() && contents != self.last {
+            let content = Self::to_content(contents.as_ref().unwrap(), self.max_size);
+            self.last = contents;
+            content
+        } else {
+            None
+        };
+
+        if content.is_some() || first_poll {
+            // EDCS only shares the remote clipboard after our first update,
+            // so the first one is sent even if it's empty
+            push.send(ChannelEdcsRequest::ClipboardUpdate(EdcsClipboardData {
+                content,
+            }));
+        }
+    }
+
+    fn to_content(
+        contents: &LocalContents,
+        max_size: usize,
+    ) -> Option<edcs_clipboard_data::Content> {
+        let content = match contents {
+            LocalContents::Text(text) => edcs_clipboard_data::Content::Text(text.clone()),
+            LocalContents::Image {
+                width,
+                height,
+                rgba,
+            } => match encode_png(*width, *height, rgba) {
+                Ok(data) => edcs_clipboard_data::Content::Image(EdcsClipboardImage {
+                    mime_type: PNG_MIME_TYPE.to_owned(),
+                    data,
+                }),
+                Err(e) => {
+                    warn!("Failed to encode the clipboard image {:?}", e);
+                    return None;
+                }
+            },
+        };
+
+        let size = match &content {
+            edcs_clipboard_data::Content::Text(text) => text.len(),
+            edcs_clipboard_data::Content::Image(image) => image.data.len(),
+        };
+        if size > max_size {
+            debug!("Not sharing local clipboard of {} bytes", size);
+            return None;
+        }
+        Some(content)
+    }
+
+    pub fn handle_event(&mut self, event: &EdcsServerEvent) {
+        let content = match &event.payload {
+            Some(edcs_server_event::Payload::ClipboardUpdate(EdcsClipboardData {
+                content: Some(content),
+            })) => content,
+            _ => return,
+        };
+        let clipboard = match &mut self.clipboard {
+            Some(clipboard) => clipboard,
+            // Sharing is off, drop it
+            None => return,
+        };
+
+        let contents = match content {
+            edcs_clipboard_data::Content::Text(text) => {
+                if text.len() > self.max_size {
+                    return;
+                }
+                LocalContents::Text(text.clone())
+            }
+            edcs_clipboard_data::Content::Image(image) => {
+                if image.data.len() > self.max_size || image.mime_type != PNG_MIME_TYPE {
+                    debug!(
+                        "Ignoring remote clipboard image of type {}",
+                        image.mime_type
+                    );
+                    return;
+                }
+                match decode_png(&image.data, self.max_size) {
+                    Ok(contents) => contents,
+                    Err(e) => {
+                        warn!("Failed to decode the remote clipboard image {:?}", e);
+                        return;
+                    }
+                }
+            }
+        };
+
+        let res = match &contents {
+            LocalContents::Text(text) => clipboard.set_text(text.clone()),
+            LocalContents::Image {
+                width,
+                height,
+                rgba,
+            } => clipboard.set_image(ImageData {
+                width: *width,
+                height: *height,
+                bytes: Cow::Borrowed(rgba),
+            }),
+        };
+        match res {
+            // Don't send it straight back on the next poll
+            Ok(_) => self.last = Some(contents),
+            Err(e) => warn!("Failed to set the local clipboard {:?}", e),
+        }
+    }
+}
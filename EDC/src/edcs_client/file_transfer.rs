@@ -0,0 +1,247 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Context};
+use async_mutex::Mutex;
+use flume::Sender;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use super::{
+    client::{error_message, EdcsClient},
+    edcs_proto::{edcs_response, EdcsFileInfo, EdcsResponse, EdcsStatus},
+};
+
+// Has to be at most MAX_FILE_CHUNK_SIZE in EDCS
+pub const FILE_CHUNK_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferDirection {
+    Upload,
+    Download,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransferState {
+    InProgress,
+    Finished,
+    Failed(String),
+}
+
+/// Sent to the UI after every chunk
+#[derive(Debug, Clone)]
+pub struct FileTransferUpdate {
+    pub id: u64,
+    pub name: String,
+    pub direction: TransferDirection,
+    pub done: u64,
+    pub total: u64,
+    pub state: TransferState,
+}
+
+type SharedClient = Arc<Mutex<Option<EdcsClient>>>;
+
+fn check_response(resp: EdcsResponse) -> anyhow::Result<edcs_response::Payload> {
+    if resp.status() != EdcsStatus::Ok {
+        return Err(anyhow!("{}", error_message(&resp)));
+    }
+    resp.payload
+        .ok_or_else(|| anyhow!("EDCS returned an empty response"))
+}
+
+// The client is only locked for one request at a time, so input events can go
+// out between chunks.
+macro_rules! file_request {
+    ($client:expr, $c:ident => $call:expr) => {{
+        let mut client_opt = $client.lock().await;
+        let $c = client_opt
+            .as_mut()
+            .ok_or_else(|| anyhow!("Not connected to EDCS"))?;
+        check_response($call.await?)?
+    }};
+}
+
+// Hashing a large file takes a while, so this runs on the blocking pool
+fn sha256_file(path: &Path) -> io::Result<(u64, Vec<u8>)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; FILE_CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => {
+                hasher.update(&buf[..n]);
+                size += n as u64;
+            }
+        }
+    }
+    Ok((size, hasher.finalize().to_vec()))
+}
+
+async fn sha256_file_async(path: PathBuf) -> anyhow::Result<(u64, Vec<u8>)> {
+    Ok(tokio::task::spawn_blocking(move || sha256_file(&path)).await??)
+}
+
+// EDCS checks names too, but the client shouldn't write wherever it's told
+fn is_plain_file_name(name: &str) -> bool {
+    !name.starts_with('.') && Path::new(name).file_name().and_then(|n| n.to_str()) == Some(name)
+}
+
+async fn run_transfer<F>(mut update: FileTransferUpdate, updates: Sender<FileTransferUpdate>, f: F)
+where
+    F: std::future::Future<Output = anyhow::Result<()>>,
+{
+    match f.await {
+        Ok(()) => {
+            info!("Transfer of {} finished", update.name);
+            update.done = update.total;
+            update.state = TransferState::Finished;
+        }
+        Err(e) => {
+            warn!("Transfer of {} failed {:?}", update.name, e);
+            update.state = TransferState::Failed(format!("{:#}", e));
+        }
+    }
+    let _ = updates.send(update);
+}
+
+/// Sends a file to the staging directory on the server. Dropping the same file
+/// again after a failure resumes where it stopped.
+pub async fn upload(
+    client: SharedClient,
+    id: u64,
+    path: PathBuf,
+    updates: Sender<FileTransferUpdate>,
+) {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default()
+        .to_owned();
+    let update = FileTransferUpdate {
+        id,
+        name: name.clone(),
+        direction: TransferDirection::Upload,
+        done: 0,
+        total: 0,
+        state: TransferState::InProgress,
+    };
+    let mut progress = update.clone();
+    let progress_updates = updates.clone();
+    run_transfer(update, updates, async move {
+        let (size, sha256) = sha256_file_async(path.clone())
+            .await
+            .with_context(|| format!("Failed to read {:?}", path))?;
+        progress.total = size;
+
+        let mut offset = match file_request!(client, c => c.file_upload_start(EdcsFileInfo {
+            name: name.clone(),
+            size,
+            sha256,
+        })) {
+            edcs_response::Payload::FileProgress(p) => p.offset,
+            _ => return Err(anyhow!("Unexpected response to FILE_UPLOAD_START")),
+        };
+
+        let mut file = tokio::fs::File::open(&path).await?;
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        let mut buf = vec![0; FILE_CHUNK_SIZE];
+        while offset < size {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                return Err(anyhow!("{:?} got shorter while it was being sent", path));
+            }
+            let data = buf[..n].to_vec();
+            let resp = file_request!(client, c => c.file_upload_chunk(name.clone(), offset, data));
+            offset = match resp {
+                edcs_response::Payload::FileProgress(p) => p.offset,
+                _ => return Err(anyhow!("Unexpected response to FILE_UPLOAD_CHUNK")),
+            };
+            progress.done = offset;
+            let _ = progress_updates.send(progress.clone());
+        }
+
+        file_request!(client, c => c.file_upload_finish(name.clone()));
+        Ok(())
+    })
+    .await
+}
+
+/// Fetches a file from the staging directory on the server into `dest_dir`.
+/// A partial download is kept next to it and picked up again on the next try.
+pub async fn download(
+    client: SharedClient,
+    id: u64,
+    name: String,
+    dest_dir: PathBuf,
+    updates: Sender<FileTransferUpdate>,
+) {
+    let update = FileTransferUpdate {
+        id,
+        name: name.clone(),
+        direction: TransferDirection::Download,
+        done: 0,
+        total: 0,
+        state: TransferState::InProgress,
+    };
+    let mut progress = update.clone();
+    let progress_updates = updates.clone();
+    run_transfer(update, updates, async move {
+        if !is_plain_file_name(&name) {
+            return Err(anyhow!("Refusing to download to {:?}", name));
+        }
+        let info = match file_request!(client, c => c.file_download_start(name.clone())) {
+            edcs_response::Payload::FileInfo(info) => info,
+            _ => return Err(anyhow!("Unexpected response to FILE_DOWNLOAD_START")),
+        };
+        progress.total = info.size;
+
+        let partial_path = dest_dir.join(format!(".{}.part", name));
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&partial_path)
+            .await
+            .with_context(|| format!("Failed to open {:?}", partial_path))?;
+        let mut offset = file.metadata().await?.len();
+        if offset > info.size {
+            file.set_len(0).await?;
+            offset = 0;
+        }
+        file.seek(io::SeekFrom::Start(offset)).await?;
+
+        while offset < info.size {
+            let length = (info.size - offset).min(FILE_CHUNK_SIZE as u64) as u32;
+            let resp =
+                file_request!(client, c => c.file_download_chunk(name.clone(), offset, length));
+            let chunk = match resp {
+                edcs_response::Payload::FileChunk(chunk) => chunk,
+                _ => return Err(anyhow!("Unexpected response to FILE_DOWNLOAD_CHUNK")),
+            };
+            if chunk.offset != offset || chunk.data.is_empty() {
+                return Err(anyhow!("EDCS sent the wrong chunk of {}", name));
+            }
+            file.write_all(&chunk.data).await?;
+            offset += chunk.data.len() as u64;
+            progress.done = offset;
+            let _ = progress_updates.send(progress.clone());
+        }
+        file.flush().await?;
+        drop(file);
+
+        let (_, sha256) = sha256_file_async(partial_path.clone()).await?;
+        if sha256 != info.sha256 {
+            tokio::fs::remove_file(&partial_path).await?;
+            return Err(anyhow!("Checksum mismatch for {}", name));
+        }
+        tokio::fs::rename(&partial_path, dest_dir.join(&name)).await?;
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,259 @@
+use std::collections::{HashMap, HashSet};
+
+use flume::Sender;
+use gilrs::{Axis, Button, EventType, GamepadId, Gilrs};
+use log::{info, warn};
+
+use super::{
+    blocking_client::ChannelEdcsRequest,
+    edcs_proto::{
+        edcs_gamepad_event, EdcsGamepadAxis, EdcsGamepadAxisEvent, EdcsGamepadButton,
+        EdcsGamepadButtonEvent, EdcsGamepadConnected, EdcsGamepadDisconnected, EdcsGamepadEvent,
+    },
+};
+
+// Has to match MAX_GAMEPAD_SLOTS in EDCS
+const MAX_GAMEPAD_SLOTS: u32 = 4;
+
+#[derive(Default)]
+struct SlotState {
+    pressed: HashSet<i32>,
+    moved_axes: HashSet<i32>,
+}
+
+/// Reads local controllers with gilrs and forwards them to EDCS, one slot per
+/// controller. Input is only forwarded while the window has focus, and losing
+/// focus releases everything so nothing stays held on the remote side.
+pub struct GamepadForwarder {
+    gilrs: Option<Gilrs>,
+    slots: HashMap<GamepadId, u32>,
+    state: HashMap<u32, SlotState>,
+    focused: bool,
+}
+
+fn map_button(button: Button) -> Option<EdcsGamepadButton> {
+    Some(match button {
+        Button::South => EdcsGamepadButton::GamepadButtonSouth,
+        Button::East => EdcsGamepadButton::GamepadButtonEast,
+        Button::North => EdcsGamepadButton::GamepadButtonNorth,
+        Button::West => EdcsGamepadButton::GamepadButtonWest,
+        Button::LeftTrigger => EdcsGamepadButton::GamepadButtonLeftBumper,
+        Button::RightTrigger => EdcsGamepadButton::GamepadButtonRightBumper,
+        Button::Select => EdcsGamepadButton::GamepadButtonSelect,
+        Button::Start => EdcsGamepadButton::GamepadButtonStart,
+        Button::Mode => EdcsGamepadButton::GamepadButtonMode,
+        Button::LeftThumb => EdcsGamepadButton::GamepadButtonLeftThumb,
+        Button::RightThumb => EdcsGamepadButton::GamepadButtonRightThumb,
+        Button::DPadUp => EdcsGamepadButton::GamepadButtonDpadUp,
+        Button::DPadDown => EdcsGamepadButton::GamepadButtonDpadDown,
+        Button::DPadLeft => EdcsGamepadButton::GamepadButtonDpadLeft,
+        Button::DPadRight => EdcsGamepadButton::GamepadButtonDpadRight,
+        _ => return None,
+    })
+}
+
+// gilrs has Y pointing up, EDCS wants it pointing down like evdev
+fn map_axis(axis: Axis, value: f32) -> Option<(EdcsGamepadAxis, f64)> {
+    let value = value as f64;
+    Some(match axis {
+        Axis::LeftStickX => (EdcsGamepadAxis::GamepadAxisLeftX, value),
+        Axis::LeftStickY => (EdcsGamepadAxis::GamepadAxisLeftY, -value),
+        Axis::RightStickX => (EdcsGamepadAxis::GamepadAxisRightX, value),
+        Axis::RightStickY => (EdcsGamepadAxis::GamepadAxisRightY, -value),
+        _ => return None,
+    })
+}
+
+impl GamepadForwarder {
+    pub fn new() -> Self {
+        let gilrs = match Gilrs::new() {
+            Ok(gilrs) => Some(gilrs),
+            Err(e) => {
+                warn!("Gamepad support is unavailable: {:?}", e);
+                None
+            }
+        };
+        Self {
+            gilrs,
+            slots: HashMap::new(),
+            state: HashMap::new(),
+            focused: true,
+        }
+    }
+
+    fn send(push: &Sender<ChannelEdcsRequest>, slot: u32, payload: edcs_gamepad_event::Payload) {
+        push.send(ChannelEdcsRequest::WriteGamepadEvent(EdcsGamepadEvent {
+            slot,
+            payload: Some(payload),
+        }));
+    }
+
+    // Slots are handed out lazily, so pads that were plugged in before the
+    // session started get one on their first event.
+    fn slot_for(&mut self, id: GamepadId, push: &Sender<ChannelEdcsRequest>) -> Option<u32> {
+        if let Some(slot) = self.slots.get(&id) {
+            return Some(*slot);
+        }
+        let slot = (0..MAX_GAMEPAD_SLOTS).find(|s| !self.slots.values().any(|v| v == s))?;
+        let name = self
+            .gilrs
+            .as_ref()
+            .map(|gilrs| gilrs.gamepad(id).name().to_owned())
+            .unwrap_or_default();
+        info!("Forwarding gamepad {} on slot {}", name, slot);
+        Self::send(
+            push,
+            slot,
+            edcs_gamepad_event::Payload::Connected(EdcsGamepadConnected { name }),
+        );
+        self.slots.insert(id, slot);
+        self.state.insert(slot, SlotState::default());
+        Some(slot)
+    }
+
+    fn button(
+        &mut self,
+        slot: u32,
+        button: EdcsGamepadButton,
+        pressed: bool,
+        push: &Sender<ChannelEdcsRequest>,
+    ) {
+        let state = self.state.entry(slot).or_default();
+        if pressed {
+            state.pressed.insert(button as i32);
+        } else {
+            state.pressed.remove(&(button as i32));
+        }
+        Self::send(
+            push,
+            slot,
+            edcs_gamepad_event::Payload::Button(EdcsGamepadButtonEvent {
+                button: button as i32,
+                pressed,
+            }),
+        );
+    }
+
+    fn axis(
+        &mut self,
+        slot: u32,
+        axis: EdcsGamepadAxis,
+        value: f64,
+        push: &Sender<ChannelEdcsRequest>,
+    ) {
+        let state = self.state.entry(slot).or_default();
+        if value != 0.0 {
+            state.moved_axes.insert(axis as i32);
+        } else {
+            state.moved_axes.remove(&(axis as i32));
+        }
+        Self::send(
+            push,
+            slot,
+            edcs_gamepad_event::Payload::Axis(EdcsGamepadAxisEvent {
+                axis: axis as i32,
+                value,
+            }),
+        );
+    }
+
+    pub fn set_focused(&mut self, focused: bool, push: &Sender<ChannelEdcsRequest>) {
+        self.focused = focused;
+        if focused {
+            return;
+        }
+        // Let go of everything the remote side thinks is still held
+        for (slot, state) in self.state.iter_mut() {
+            for button in state.pressed.drain() {
+                Self::send(
+                    push,
+                    *slot,
+                    edcs_gamepad_event::Payload::Button(EdcsGamepadButtonEvent {
+                        button,
+                        pressed: false,
+                    }),
+                );
+            }
+            for axis in state.moved_axes.drain() {
+                Self::send(
+                    push,
+                    *slot,
+                    edcs_gamepad_event::Payload::Axis(EdcsGamepadAxisEvent { axis, value: 0.0 }),
+                );
+            }
+        }
+    }
+
+    pub fn poll(&mut self, push: &Sender<ChannelEdcsRequest>) {
+        let mut events = vec![];
+        if let Some(gilrs) = &mut self.gilrs {
+            while let Some(event) = gilrs.next_event() {
+                events.push(event);
+            }
+        }
+
+        for event in events {
+            // Hot-plugging is tracked even without focus, input is not
+            match event.event {
+                EventType::Disconnected => {
+                    if let Some(slot) = self.slots.remove(&event.id) {
+                        info!("Gamepad on slot {} disconnected", slot);
+                        self.state.remove(&slot);
+                        Self::send(
+                            push,
+                            slot,
+                            edcs_gamepad_event::Payload::Disconnected(EdcsGamepadDisconnected {}),
+                        );
+                    }
+                    continue;
+                }
+                EventType::Connected => {
+                    self.slot_for(event.id, push);
+                    continue;
+                }
+                _ if !self.focused => continue,
+                _ => {}
+            }
+
+            let slot = match self.slot_for(event.id, push) {
+                Some(slot) => slot,
+                None => continue,
+            };
+            match event.event {
+                EventType::ButtonPressed(button, _) => {
+                    if let Some(button) = map_button(button) {
+                        self.button(slot, button, true, push);
+                    }
+                }
+                EventType::ButtonReleased(button, _) => {
+                    if let Some(button) = map_button(button) {
+                        self.button(slot, button, false, push);
+                    }
+                }
+                // The analog triggers only show up as button values in gilrs
+                EventType::ButtonChanged(Button::LeftTrigger2, value, _) => {
+                    self.axis(
+                        slot,
+                        EdcsGamepadAxis::GamepadAxisLeftTrigger,
+                        value as f64,
+                        push,
+                    );
+                }
+                EventType::ButtonChanged(Button::RightTrigger2, value, _) => {
+                    self.axis(
+                        slot,
+                        EdcsGamepadAxis::GamepadAxisRightTrigger,
+                        value as f64,
+                        push,
+                    );
+                }
+                EventType::AxisChanged(axis, value, _) => {
+                    if let Some((axis, value)) = map_axis(axis, value) {
+                        self.axis(slot, axis, value, push);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -1,4 +1,7 @@
 pub mod blocking_client;
 pub mod client;
+pub mod clipboard;
 pub mod edcs_proto;
+pub mod file_transfer;
+pub mod gamepad;
 pub mod keyboard_event;
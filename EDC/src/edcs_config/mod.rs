@@ -9,11 +9,41 @@ pub struct ClientConfig {
     pub port: u16,
     pub cert: PathBuf,
     pub disable_tls_verification: bool,
+    // Sent with AUTHENTICATE right after connecting, EDCS decides the role from it
+    #[serde(default)]
+    pub token: Option<String>,
+    // In bytes, EDCS is disconnected from if it sends anything larger
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+}
+
+pub fn default_max_message_size() -> usize {
+    4 << 20
 }
 
 #[derive(Debug, Serialize, PartialEq, Deserialize, Clone)]
 pub struct UIConfig {
     pub host_cursor: bool,
+    // Defaulted so that existing connection files still parse
+    #[serde(default)]
+    pub share_clipboard: bool,
+    // In bytes, larger clipboard contents aren't shared in either direction
+    #[serde(default = "default_max_clipboard_size")]
+    pub max_clipboard_size: usize,
+}
+
+fn default_max_clipboard_size() -> usize {
+    1 << 20
+}
+
+impl Default for UIConfig {
+    fn default() -> Self {
+        Self {
+            host_cursor: false,
+            share_clipboard: false,
+            max_clipboard_size: default_max_clipboard_size(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, PartialEq, Deserialize, Clone)]
@@ -21,6 +51,15 @@ pub struct StreamConfig {
     pub bitrate: u32,
     pub framerate: u32,
     pub cal_plugin_params: HashMap<String, String>,
+    // Ask EDCS for an Opus audio stream, which it may not have
+    #[serde(default)]
+    pub audio: bool,
+    #[serde(default = "default_audio_bitrate")]
+    pub audio_bitrate: u32,
+}
+
+fn default_audio_bitrate() -> u32 {
+    96000
 }
 
 pub mod connection_config;
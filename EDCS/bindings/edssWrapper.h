@@ -1 +1,2 @@
 #include "../../EDSS/inc/edssInterface.h"
+#include "../../EDSS/inc/edssCALInterface.h"
@@ -540,6 +540,7 @@ pub const INET6_ADDRSTRLEN: u32 = 46;
 pub const true_: u32 = 1;
 pub const false_: u32 = 0;
 pub const __bool_true_false_are_defined: u32 = 1;
+pub const EDSS_MAX_VIEWERS: u32 = 8;
 pub type size_t = ::std::os::raw::c_ulong;
 pub type wchar_t = ::std::os::raw::c_int;
 pub type _Float32 = f32;
@@ -3599,6 +3600,15 @@ pub const EDSS_STATUS_EDSS_PTHREAD_FAILURE: EDSS_STATUS = 10;
 pub const EDSS_STATUS_EDSS_STRMAP_FAILURE: EDSS_STATUS = 11;
 #[doc = " When EDSS receives invalid mouse data"]
 pub const EDSS_STATUS_EDSS_INVALID_MOUSE_DATA: EDSS_STATUS = 12;
+#[doc = " When the CAL does not implement an optional function."]
+pub const EDSS_STATUS_EDSS_CAL_UNSUPPORTED: EDSS_STATUS = 13;
+#[doc = " When EDSS receives invalid touch or pen data"]
+pub const EDSS_STATUS_EDSS_INVALID_INPUT_DATA: EDSS_STATUS = 14;
+#[doc = " When the requested audio source does not exist or cannot be opened."]
+pub const EDSS_STATUS_EDSS_INVALID_AUDIO_SOURCE: EDSS_STATUS = 15;
+#[doc = " When `edssAddViewer` is called with EDSS_MAX_VIEWERS viewers already"]
+#[doc = " added."]
+pub const EDSS_STATUS_EDSS_TOO_MANY_VIEWERS: EDSS_STATUS = 16;
 #[doc = " Status enum that allows callees to understand why a call failed."]
 pub type EDSS_STATUS = ::std::os::raw::c_uint;
 #[repr(C)]
@@ -5631,12 +5641,16 @@ pub struct edssConfig_t {
     pub framerate: u32,
     pub srtpOutParams: [::std::os::raw::c_char; 41usize],
     pub calOptionDict: *mut StrMap,
+    pub audioSampleRate: u32,
+    pub audioBitrate: u32,
+    pub audioChannels: u8,
+    pub audioSource: [::std::os::raw::c_char; 32usize],
 }
 #[test]
 fn bindgen_test_layout_edssConfig_t() {
     assert_eq!(
         ::std::mem::size_of::<edssConfig_t>(),
-        112usize,
+        160usize,
         concat!("Size of: ", stringify!(edssConfig_t))
     );
     assert_eq!(
@@ -5803,7 +5817,9 @@ fn bindgen_test_layout_edssKeyData_t() {
 }
 pub const edssMouseEventType_t_CLICK: edssMouseEventType_t = 0;
 pub const edssMouseEventType_t_MOVE: edssMouseEventType_t = 1;
-#[doc = " Mouse event struct"]
+pub const edssMouseEventType_t_WHEEL: edssMouseEventType_t = 2;
+pub const edssMouseEventType_t_REL_MOVE: edssMouseEventType_t = 3;
+#[doc = " Mouse event struct. CALs return EDSS_CAL_UNSUPPORTED for the types they can't\n do anything with."]
 pub type edssMouseEventType_t = ::std::os::raw::c_uint;
 #[repr(C)]
 #[derive(Copy, Clone)]
@@ -5816,6 +5832,8 @@ pub struct edssMouseEvent_t {
 pub union edssMouseEvent_t__bindgen_ty_1 {
     pub move_: edssMouseEvent_t__bindgen_ty_1_move,
     pub button: edssKeyData_t,
+    pub wheel: edssMouseEvent_t__bindgen_ty_1_wheel,
+    pub relMove: edssMouseEvent_t__bindgen_ty_1_relMove,
 }
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -5875,6 +5893,124 @@ fn bindgen_test_layout_edssMouseEvent_t__bindgen_ty_1_move() {
     }
     test_field_y();
 }
+#[doc = " In wheel clicks, positive is away from the user and to the right"]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct edssMouseEvent_t__bindgen_ty_1_wheel {
+    pub vertical: i32,
+    pub horizontal: i32,
+}
+#[test]
+fn bindgen_test_layout_edssMouseEvent_t__bindgen_ty_1_wheel() {
+    assert_eq!(
+        ::std::mem::size_of::<edssMouseEvent_t__bindgen_ty_1_wheel>(),
+        8usize,
+        concat!("Size of: ", stringify!(edssMouseEvent_t__bindgen_ty_1_wheel))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<edssMouseEvent_t__bindgen_ty_1_wheel>(),
+        4usize,
+        concat!(
+            "Alignment of ",
+            stringify!(edssMouseEvent_t__bindgen_ty_1_wheel)
+        )
+    );
+    fn test_field_vertical() {
+        assert_eq!(
+            unsafe {
+                let uninit =
+                    ::std::mem::MaybeUninit::<edssMouseEvent_t__bindgen_ty_1_wheel>::uninit();
+                let ptr = uninit.as_ptr();
+                ::std::ptr::addr_of!((*ptr).vertical) as usize - ptr as usize
+            },
+            0usize,
+            concat!(
+                "Offset of field: ",
+                stringify!(edssMouseEvent_t__bindgen_ty_1_wheel),
+                "::",
+                stringify!(vertical)
+            )
+        );
+    }
+    test_field_vertical();
+    fn test_field_horizontal() {
+        assert_eq!(
+            unsafe {
+                let uninit =
+                    ::std::mem::MaybeUninit::<edssMouseEvent_t__bindgen_ty_1_wheel>::uninit();
+                let ptr = uninit.as_ptr();
+                ::std::ptr::addr_of!((*ptr).horizontal) as usize - ptr as usize
+            },
+            4usize,
+            concat!(
+                "Offset of field: ",
+                stringify!(edssMouseEvent_t__bindgen_ty_1_wheel),
+                "::",
+                stringify!(horizontal)
+            )
+        );
+    }
+    test_field_horizontal();
+}
+#[doc = " In CAL pixels"]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct edssMouseEvent_t__bindgen_ty_1_relMove {
+    pub dx: i32,
+    pub dy: i32,
+}
+#[test]
+fn bindgen_test_layout_edssMouseEvent_t__bindgen_ty_1_relMove() {
+    assert_eq!(
+        ::std::mem::size_of::<edssMouseEvent_t__bindgen_ty_1_relMove>(),
+        8usize,
+        concat!("Size of: ", stringify!(edssMouseEvent_t__bindgen_ty_1_relMove))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<edssMouseEvent_t__bindgen_ty_1_relMove>(),
+        4usize,
+        concat!(
+            "Alignment of ",
+            stringify!(edssMouseEvent_t__bindgen_ty_1_relMove)
+        )
+    );
+    fn test_field_dx() {
+        assert_eq!(
+            unsafe {
+                let uninit =
+                    ::std::mem::MaybeUninit::<edssMouseEvent_t__bindgen_ty_1_relMove>::uninit();
+                let ptr = uninit.as_ptr();
+                ::std::ptr::addr_of!((*ptr).dx) as usize - ptr as usize
+            },
+            0usize,
+            concat!(
+                "Offset of field: ",
+                stringify!(edssMouseEvent_t__bindgen_ty_1_relMove),
+                "::",
+                stringify!(dx)
+            )
+        );
+    }
+    test_field_dx();
+    fn test_field_dy() {
+        assert_eq!(
+            unsafe {
+                let uninit =
+                    ::std::mem::MaybeUninit::<edssMouseEvent_t__bindgen_ty_1_relMove>::uninit();
+                let ptr = uninit.as_ptr();
+                ::std::ptr::addr_of!((*ptr).dy) as usize - ptr as usize
+            },
+            4usize,
+            concat!(
+                "Offset of field: ",
+                stringify!(edssMouseEvent_t__bindgen_ty_1_relMove),
+                "::",
+                stringify!(dy)
+            )
+        );
+    }
+    test_field_dy();
+}
 #[test]
 fn bindgen_test_layout_edssMouseEvent_t__bindgen_ty_1() {
     assert_eq!(
@@ -5921,6 +6057,40 @@ fn bindgen_test_layout_edssMouseEvent_t__bindgen_ty_1() {
         );
     }
     test_field_button();
+    fn test_field_wheel() {
+        assert_eq!(
+            unsafe {
+                let uninit = ::std::mem::MaybeUninit::<edssMouseEvent_t__bindgen_ty_1>::uninit();
+                let ptr = uninit.as_ptr();
+                ::std::ptr::addr_of!((*ptr).wheel) as usize - ptr as usize
+            },
+            0usize,
+            concat!(
+                "Offset of field: ",
+                stringify!(edssMouseEvent_t__bindgen_ty_1),
+                "::",
+                stringify!(wheel)
+            )
+        );
+    }
+    test_field_wheel();
+    fn test_field_relMove() {
+        assert_eq!(
+            unsafe {
+                let uninit = ::std::mem::MaybeUninit::<edssMouseEvent_t__bindgen_ty_1>::uninit();
+                let ptr = uninit.as_ptr();
+                ::std::ptr::addr_of!((*ptr).relMove) as usize - ptr as usize
+            },
+            0usize,
+            concat!(
+                "Offset of field: ",
+                stringify!(edssMouseEvent_t__bindgen_ty_1),
+                "::",
+                stringify!(relMove)
+            )
+        );
+    }
+    test_field_relMove();
 }
 #[test]
 fn bindgen_test_layout_edssMouseEvent_t() {
@@ -6005,9 +6175,101 @@ fn bindgen_test_layout_edssKeyboardEvent_t() {
     }
     test_field_keyData();
 }
+pub const edssTouchPhase_t_TOUCH_START: edssTouchPhase_t = 0;
+pub const edssTouchPhase_t_TOUCH_MOVE: edssTouchPhase_t = 1;
+pub const edssTouchPhase_t_TOUCH_END: edssTouchPhase_t = 2;
+pub const edssTouchPhase_t_TOUCH_CANCEL: edssTouchPhase_t = 3;
+#[doc = " Touch event struct. Each finger is identified by a contact ID that stays the"]
+#[doc = " same from TOUCH_START until TOUCH_END or TOUCH_CANCEL. Coordinates are in"]
+#[doc = " CAL pixels and pressure is normalised to [0, 1]."]
+pub type edssTouchPhase_t = ::std::os::raw::c_uint;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct edssTouchEvent_t {
+    pub contactId: u32,
+    pub phase: edssTouchPhase_t,
+    pub x: f64,
+    pub y: f64,
+    pub pressure: f64,
+}
+#[doc = " Pen/tablet event struct. Coordinates are in CAL pixels, pressure is"]
+#[doc = " normalised to [0, 1] and tilt is in degrees from -90 to 90."]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct edssPenEvent_t {
+    pub x: f64,
+    pub y: f64,
+    pub pressure: f64,
+    pub tiltX: f64,
+    pub tiltY: f64,
+    #[doc = " The pen is hovering within range of the tablet"]
+    pub inRange: bool,
+    #[doc = " The pen tip is touching the tablet"]
+    pub touching: bool,
+    pub eraser: bool,
+    pub barrelButton: bool,
+}
+#[doc = " Input capabilities of the CAL beyond mouse and keyboard, so that clients can"]
+#[doc = " tell whether touch and pen input will go anywhere."]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct edssInputCapabilities_t {
+    pub touch: bool,
+    pub pen: bool,
+    pub maxTouchContacts: u8,
+}
+#[doc = " Remote cursor struct, filled in by CALs that can report their cursor. The"]
+#[doc = " image is an RGBA bitmap of `width * height` pixels owned by the CAL. It only"]
+#[doc = " has to be re-read when `serial` changes."]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct edssCursorData_t {
+    pub x: i32,
+    pub y: i32,
+    pub visible: bool,
+    pub serial: u32,
+    pub width: u16,
+    pub height: u16,
+    pub hotspotX: u16,
+    pub hotspotY: u16,
+    pub image: *mut u8,
+}
+pub const edssCALOptionType_t_CAL_OPTION_STRING: edssCALOptionType_t = 0;
+#[doc = " A base 10 integer"]
+pub const edssCALOptionType_t_CAL_OPTION_INT: edssCALOptionType_t = 1;
+pub const edssCALOptionType_t_CAL_OPTION_FLOAT: edssCALOptionType_t = 2;
+#[doc = " \"true\" or \"false\""]
+pub const edssCALOptionType_t_CAL_OPTION_BOOL: edssCALOptionType_t = 3;
+#[doc = " One of `choices`"]
+pub const edssCALOptionType_t_CAL_OPTION_ENUM: edssCALOptionType_t = 4;
+#[doc = " Type of a CAL option. Option values are always passed around as strings,"]
+#[doc = " this tells clients and the control server how to interpret them."]
+pub type edssCALOptionType_t = ::std::os::raw::c_uint;
+#[doc = " Description of a single CAL option, so that clients can show a proper form"]
+#[doc = " and the control server can reject invalid values before `calInit` sees"]
+#[doc = " them. Everything is owned by the CAL and has to stay valid until the CAL is"]
+#[doc = " closed."]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct edssCALOption_t {
+    pub name: *const ::std::os::raw::c_char,
+    pub description: *const ::std::os::raw::c_char,
+    pub type_: edssCALOptionType_t,
+    #[doc = " Used when the client leaves the option out"]
+    pub defaultValue: *const ::std::os::raw::c_char,
+    #[doc = " NULL terminated list of values for CAL_OPTION_ENUM"]
+    pub choices: *const *const ::std::os::raw::c_char,
+    #[doc = " Inclusive range for CAL_OPTION_INT and CAL_OPTION_FLOAT"]
+    pub hasRange: bool,
+    pub min: f64,
+    pub max: f64,
+    #[doc = " The option can't be left empty"]
+    pub required: bool,
+}
 extern "C" {
     #[doc = " Initialize the server. This will allocate and initialize various FFmpeg"]
-    #[doc = " structures with the values provided from the provided `cfg` variable."]
+    #[doc = " structures with the values provided from the provided `cfg` variable. When"]
+    #[doc = " audio is enabled the SDP carries a second, Opus media line."]
     #[doc = " Furthermore, any capture abstraction libraries will have their initializers"]
     #[doc = " called, for example initing Xlib, PipeWire, or acquiring a framebuffer. This"]
     #[doc = " function takes a pointer to a char* (sdpBuffer), which will be allocated in"]
@@ -6025,9 +6287,21 @@ extern "C" {
     pub fn edssInitStreaming() -> EDSS_STATUS;
 }
 extern "C" {
-    #[doc = " Stop the SRTP server main loop, and stop the capture thread."]
+    #[doc = " Stop the SRTP server main loop, and stop the capture thread. This also"]
+    #[doc = " does everything `edssCloseServer` does."]
     pub fn edssCloseStreaming() -> EDSS_STATUS;
 }
+extern "C" {
+    #[doc = " Free everything `edssInitServer` allocated and shut the CAL down, for a"]
+    #[doc = " server that was initialised but never started streaming, or whose"]
+    #[doc = " initialisation failed halfway. Does nothing if there is nothing to free."]
+    pub fn edssCloseServer() -> EDSS_STATUS;
+}
+extern "C" {
+    #[doc = " Close the CAL opened with `edssOpenCAL`, closing the server first if needed."]
+    #[doc = " `edssOpenCAL` has to be called again before EDSS can be used."]
+    pub fn edssCloseCAL() -> EDSS_STATUS;
+}
 extern "C" {
     #[doc = " Write a mouse event to the CAL"]
     pub fn edssWriteMouseEvent(ev: *mut edssMouseEvent_t) -> EDSS_STATUS;
@@ -6036,11 +6310,46 @@ extern "C" {
     #[doc = " Write a keyboard event to the CAL"]
     pub fn edssWriteKeyboardEvent(ev: *mut edssKeyboardEvent_t) -> EDSS_STATUS;
 }
+extern "C" {
+    #[doc = " Write a touch event to the CAL"]
+    pub fn edssWriteTouchEvent(ev: *mut edssTouchEvent_t) -> EDSS_STATUS;
+}
+extern "C" {
+    #[doc = " Write a pen event to the CAL"]
+    pub fn edssWritePenEvent(ev: *mut edssPenEvent_t) -> EDSS_STATUS;
+}
+extern "C" {
+    #[doc = " Retrieve the input capabilities of the opened CAL. This is valid after"]
+    #[doc = " `edssOpenCAL` has returned EDSS_OK. CALs that don't report their"]
+    #[doc = " capabilities only accept mouse and keyboard input, and touch or pen is only"]
+    #[doc = " reported if the CAL has the entry point to write it."]
+    pub fn edssGetInputCapabilities(caps: *mut edssInputCapabilities_t) -> EDSS_STATUS;
+}
 extern "C" {
     #[doc = " Update the SRTP stream's to the new cfg pointer (we only pass a new pointer"]
     #[doc = " since it makes Rust FFI easier)."]
     pub fn edssUpdateStreaming(cfg: *mut edssConfig_t) -> EDSS_STATUS;
 }
+extern "C" {
+    #[doc = " Send the stream to another viewer as well, on top of the destination from"]
+    #[doc = " `edssConfig_t`. The viewer gets the same video (and audio, on `port + 2`)"]
+    #[doc = " with its own SRTP keys, and the next frame is encoded as a keyframe so it"]
+    #[doc = " doesn't have to wait for one. `sdpBuffer` is allocated like in"]
+    #[doc = " `edssInitServer`. Valid after `edssInitServer` has returned EDSS_OK, and at"]
+    #[doc = " most EDSS_MAX_VIEWERS viewers can be added."]
+    pub fn edssAddViewer(
+        socketAddr: *const ::std::os::raw::c_char,
+        port: u16,
+        srtpOutParams: *const ::std::os::raw::c_char,
+        viewerId: *mut u32,
+        sdpBuffer: *mut *mut ::std::os::raw::c_char,
+    ) -> EDSS_STATUS;
+}
+extern "C" {
+    #[doc = " Stop sending the stream to a viewer added with `edssAddViewer`. Viewers are"]
+    #[doc = " removed by `edssCloseServer` as well."]
+    pub fn edssRemoveViewer(viewerId: u32) -> EDSS_STATUS;
+}
 extern "C" {
     #[doc = " Capture abstraction libraries (CALs) may expose options to the client which"]
     #[doc = " they can set. This function allows the control server to retrieve CAL options"]
@@ -6054,6 +6363,158 @@ extern "C" {
         calOptionDict: *mut *mut StrMap,
     ) -> EDSS_STATUS;
 }
+extern "C" {
+    #[doc = " Retrieve the resolution of the captured frames, as reported by the CAL in"]
+    #[doc = " `calInit`. This is only valid after `edssInitServer` has returned EDSS_OK,"]
+    #[doc = " otherwise EDSS_UNINITIALISED is returned. The control server uses this to"]
+    #[doc = " scale client mouse coordinates to CAL pixels."]
+    pub fn edssGetStreamResolution(width: *mut u16, height: *mut u16) -> EDSS_STATUS;
+}
+extern "C" {
+    #[doc = " Retrieve the option schema of the opened CAL. This is valid after"]
+    #[doc = " `edssOpenCAL` has returned EDSS_OK. Returns EDSS_CAL_UNSUPPORTED if the CAL"]
+    #[doc = " doesn't describe its options, in which case they are all plain strings."]
+    pub fn edssGetCALOptionSchema(
+        options: *mut *const edssCALOption_t,
+        count: *mut u32,
+    ) -> EDSS_STATUS;
+}
+extern "C" {
+    #[doc = " Read the remote cursor position and image from the CAL. Returns"]
+    #[doc = " EDSS_CAL_UNSUPPORTED if the CAL cannot report its cursor."]
+    pub fn edssReadCursor(cursor: *mut edssCursorData_t) -> EDSS_STATUS;
+}
+pub const AVPixelFormat_AV_PIX_FMT_NONE: AVPixelFormat = -1;
+pub const AVPixelFormat_AV_PIX_FMT_YUV420P: AVPixelFormat = 0;
+pub const AVPixelFormat_AV_PIX_FMT_YUYV422: AVPixelFormat = 1;
+pub const AVPixelFormat_AV_PIX_FMT_RGB24: AVPixelFormat = 2;
+pub const AVPixelFormat_AV_PIX_FMT_BGR24: AVPixelFormat = 3;
+pub const AVPixelFormat_AV_PIX_FMT_ARGB: AVPixelFormat = 25;
+pub const AVPixelFormat_AV_PIX_FMT_RGBA: AVPixelFormat = 26;
+pub const AVPixelFormat_AV_PIX_FMT_ABGR: AVPixelFormat = 27;
+pub const AVPixelFormat_AV_PIX_FMT_BGRA: AVPixelFormat = 28;
+#[doc = " Pixel format aka PIX_FMT_* description"]
+pub type AVPixelFormat = ::std::os::raw::c_int;
+#[doc = " Audio source structure. Built-in sources live in edssAudio.c, and CALs can"]
+#[doc = " provide their own through `calAudioSource`."]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct edssAudioSource_t {
+    #[doc = " The name the control server uses to pick this source."]
+    pub name: *const ::std::os::raw::c_char,
+    #[doc = " Prepare the source to produce samples in the given format. Whatever is"]
+    #[doc = " stored in `ctx` is handed back to `read` and `close`."]
+    pub open: ::std::option::Option<
+        unsafe extern "C" fn(
+            sampleRate: u32,
+            channels: u8,
+            ctx: *mut *mut ::std::os::raw::c_void,
+        ) -> EDSS_STATUS,
+    >,
+    #[doc = " Fill `samples` with `frames` interleaved frames. This should block until"]
+    #[doc = " the samples are available, since it is what paces the audio thread."]
+    pub read: ::std::option::Option<
+        unsafe extern "C" fn(
+            ctx: *mut ::std::os::raw::c_void,
+            samples: *mut i16,
+            frames: ::std::os::raw::c_int,
+        ) -> EDSS_STATUS,
+    >,
+    #[doc = " Free whatever was allocated in `open`."]
+    pub close: ::std::option::Option<unsafe extern "C" fn(ctx: *mut ::std::os::raw::c_void)>,
+}
+extern "C" {
+    #[doc = " Find an audio source by name. \"cal\" refers to `calSource`, which may be NULL"]
+    #[doc = " if the CAL has no audio. Returns NULL if there is no such source."]
+    pub fn edssFindAudioSource(
+        name: *const ::std::os::raw::c_char,
+        calSource: *const edssAudioSource_t,
+    ) -> *const edssAudioSource_t;
+}
+#[doc = " CAL config struct. NOTE that CAL config != CAL options. This is for the"]
+#[doc = " streaming server to understand the CAL better. CAL options are set by the"]
+#[doc = " client."]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct calConfig_t {
+    pub pixFmt: AVPixelFormat,
+    #[doc = " CAL config tells EDSS how big the frame width is."]
+    pub height: u16,
+    #[doc = " CAL config tells EDSS how big the frame height is."]
+    pub width: u16,
+    #[doc = " The framerate of the video."]
+    pub framerate: u16,
+    #[doc = " The `uint8_t*` is provided without a size since we are not aware of the"]
+    #[doc = " size of the frame without looking at CAL config (plus it is more"]
+    #[doc = " efficient to re-use the frame pointer than allocate a new frame every"]
+    #[doc = " capture cycle). This variable is allocated by CAL."]
+    pub frame: *mut u8,
+}
+#[doc = " CAL plugin structure. Define the functions here for the CAL plugin to work."]
+#[doc = " New members go at the end, so CALs built against an older version of this"]
+#[doc = " header still line up."]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct calPlugin_t {
+    #[doc = " Called by `edssGetCALOptionsDict`. Effectively the \"implementation\" for"]
+    #[doc = " that function. See documentation in edssInterface.h."]
+    pub calOptions:
+        ::std::option::Option<unsafe extern "C" fn(arg1: *mut *mut StrMap) -> EDSS_STATUS>,
+    #[doc = " Initialize CAL (eg. init Xlib/etc). This should be set up such that when"]
+    #[doc = " `calReadFrame` is run, no more initialization is done. We want to"]
+    #[doc = " maximise the speed of reading frames. This function will also return the"]
+    #[doc = " CAL configuration for the frame type (eg. BGRA, RGBA, YUV, whatever) so"]
+    #[doc = " EDSS can act accordingly when deciding how to process and encode frames."]
+    #[doc = " For example, if YUV frames are captured, EDSS can skip the step of"]
+    #[doc = " converting pixel formats to YUV."]
+    pub calInit: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *mut StrMap, arg2: *mut calConfig_t) -> EDSS_STATUS,
+    >,
+    #[doc = " Read a frame from CAL. This should be in the pixFmt provided in CAL"]
+    #[doc = " config. The resulting frame is stored in calConfig's frame pointer."]
+    pub calReadFrame: ::std::option::Option<unsafe extern "C" fn() -> EDSS_STATUS>,
+    #[doc = " Write a mouse event to CAL."]
+    pub calWriteMouseEvent:
+        ::std::option::Option<unsafe extern "C" fn(ev: *mut edssMouseEvent_t) -> EDSS_STATUS>,
+    #[doc = " Write a keyboard event to CAL."]
+    pub calWriteKeyboardEvent:
+        ::std::option::Option<unsafe extern "C" fn(ev: *mut edssKeyboardEvent_t) -> EDSS_STATUS>,
+    #[doc = " Free memory that was allocated in `calInit`. For example, close files or"]
+    #[doc = " free any handles that are being used."]
+    pub calShutdown: ::std::option::Option<unsafe extern "C" fn() -> EDSS_STATUS>,
+    #[doc = " Read the cursor position and image (optional). CALs that cannot report"]
+    #[doc = " the remote cursor can leave this as NULL."]
+    pub calReadCursor:
+        ::std::option::Option<unsafe extern "C" fn(cursor: *mut edssCursorData_t) -> EDSS_STATUS>,
+    #[doc = " Write a touch event to CAL (optional)."]
+    pub calWriteTouchEvent:
+        ::std::option::Option<unsafe extern "C" fn(ev: *mut edssTouchEvent_t) -> EDSS_STATUS>,
+    #[doc = " Write a pen event to CAL (optional)."]
+    pub calWritePenEvent:
+        ::std::option::Option<unsafe extern "C" fn(ev: *mut edssPenEvent_t) -> EDSS_STATUS>,
+    #[doc = " Report which input types beyond mouse and keyboard the CAL accepts"]
+    #[doc = " (optional). This is called right after `calOptions`, before `calInit`."]
+    pub calInputCapabilities: ::std::option::Option<
+        unsafe extern "C" fn(caps: *mut edssInputCapabilities_t) -> EDSS_STATUS,
+    >,
+    #[doc = " Audio captured alongside the frames (optional). Used when the control"]
+    #[doc = " server asks for the \"cal\" audio source."]
+    pub calAudioSource: *const edssAudioSource_t,
+    #[doc = " Describe the options returned by `calOptions` (optional). The array is"]
+    #[doc = " owned by the CAL and has to live until `calShutdown`."]
+    pub calOptionSchema: ::std::option::Option<
+        unsafe extern "C" fn(options: *mut *const edssCALOption_t, count: *mut u32) -> EDSS_STATUS,
+    >,
+}
+extern "C" {
+    #[doc = " Open a CAL that isn't loaded with dlopen, eg. one implemented by the control"]
+    #[doc = " server itself. This works the same way as `edssOpenCAL` otherwise. `plugin`"]
+    #[doc = " has to stay valid until `edssCloseCAL`."]
+    pub fn edssOpenCALPlugin(
+        plugin: *const calPlugin_t,
+        calOptionDict: *mut *mut StrMap,
+    ) -> EDSS_STATUS;
+}
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct __locale_data {
@@ -27,6 +27,21 @@ fn main() {
         .join("EDCSProtocol")
         .join("proto");
 
-    prost_build::compile_protos(&[proto_path.join("edcs_proto.proto")], &[proto_path])
+    prost_build::compile_protos(&[proto_path.join("edcs_proto.proto")], &[&proto_path])
         .expect("Failed to compile protobufs");
+
+    // Only between EDCS and its worker processes, it reuses the EDCS messages.
+    // It goes in a directory of its own, prost would write an empty
+    // edcs_protocol.rs over the one above otherwise.
+    let worker_proto_path = manifest_dir.join("proto");
+    let worker_out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("worker");
+    std::fs::create_dir_all(&worker_out_dir).expect("Failed to create the worker proto directory");
+    prost_build::Config::new()
+        .out_dir(worker_out_dir)
+        .extern_path(".edcs_protocol", "crate::edcs_server::edcs_proto")
+        .compile_protos(
+            &[worker_proto_path.join("edss_worker.proto")],
+            &[&worker_proto_path, &proto_path],
+        )
+        .expect("Failed to compile the worker protobufs");
 }
@@ -0,0 +1,26 @@
+#![no_main]
+
+use edcs::edcs_server::edcs_proto::EdcsMessage;
+use edcs::edcs_server::frame::{self, DELIMITER_LEN};
+use libfuzzer_sys::fuzz_target;
+use prost::Message;
+
+// Same as the server's default
+const MAX_MESSAGE_SIZE: usize = 4 << 20;
+
+// Reads the input the way the server reads a connection, frame by frame
+fuzz_target!(|data: &[u8]| {
+    let mut data = data;
+    while data.len() >= DELIMITER_LEN {
+        let len = match frame::message_len(&data[..DELIMITER_LEN], MAX_MESSAGE_SIZE) {
+            Ok(len) => len,
+            Err(_) => return,
+        };
+        data = &data[DELIMITER_LEN..];
+        if data.len() < len {
+            return;
+        }
+        let _ = EdcsMessage::decode(&data[..len]);
+        data = &data[len..];
+    }
+});
@@ -0,0 +1,75 @@
+#![no_main]
+
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use edcs::edcs_server::config::EdcsConfig;
+use edcs::edcs_server::edcs_proto::EdcsMessage;
+use edcs::edcs_server::handler::{EdcsHandler, Handled};
+use libfuzzer_sys::fuzz_target;
+use prost::Message;
+
+// No [auth], so every message is let through. The worker can't be started,
+// which keeps SETUP_EDCS from reaching a CAL.
+const CONFIG: &str = r#"
+ip = "127.0.0.1"
+port = 9000
+cert_path = "/nonexistent/cert.pem"
+key_path = "/nonexistent/key.pem"
+
+[edss_config]
+plugin_name = "fuzz"
+port = 9001
+worker_path = "/nonexistent/edcs"
+
+[file_transfer]
+quota = 1048576
+"#;
+
+static NEXT_RUN: AtomicUsize = AtomicUsize::new(0);
+
+// The messages of one connection, each after a length byte. Every run
+// uploads to a staging directory of its own, which is removed afterwards.
+fuzz_target!(|data: &[u8]| {
+    let staging_dir = std::env::temp_dir().join(format!(
+        "edcs-fuzz-{}-{}",
+        std::process::id(),
+        NEXT_RUN.fetch_add(1, Ordering::Relaxed)
+    ));
+    let config = format!("{}staging_dir = {:?}\n", CONFIG, staging_dir);
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let cfg: Arc<EdcsConfig> = Arc::new(toml::from_str(&config).unwrap());
+    let mut handler = EdcsHandler::new(&cfg).unwrap();
+    let addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let connection = handler.new_connection(addr);
+    let mut identity = handler.anonymous_identity();
+
+    let mut data = data;
+    while let Some((&len, rest)) = data.split_first() {
+        let len = (len as usize).min(rest.len());
+        let msg = EdcsMessage::decode(&rest[..len]);
+        data = &rest[len..];
+        if let Ok(msg) = msg {
+            // Waited on like the server does, so no stream job is still
+            // queued once the connection goes
+            match handler.handle_message(Arc::clone(&cfg), msg, addr, connection, &mut identity) {
+                Ok(Handled::Stream(pending)) => {
+                    handler.stream_done(rt.block_on(pending));
+                }
+                Ok(Handled::File(pending)) => {
+                    handler.file_done(rt.block_on(pending));
+                }
+                _ => {}
+            }
+        }
+    }
+    // Drops the stream actor, which ends its thread
+    handler.disconnect(connection);
+    drop(handler);
+    let _ = fs::remove_dir_all(&staging_dir);
+});
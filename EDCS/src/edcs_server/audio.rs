@@ -0,0 +1,74 @@
+use log::{debug, warn};
+
+use super::config::AudioConfig;
+use super::edcs_proto::EdcsAudioParams;
+
+pub const OPUS_SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+const MIN_OPUS_BITRATE: u32 = 6000;
+const MAX_OPUS_BITRATE: u32 = 510000;
+// Has to fit in edssConfig_t.audioSource along with the terminator
+pub const MAX_AUDIO_SOURCE_LEN: usize = 31;
+
+/// Audio settings handed to EDSS once both sides agreed on them
+#[derive(Debug, Clone, PartialEq)]
+pub struct NegotiatedAudio {
+    pub source: String,
+    pub params: EdcsAudioParams,
+}
+
+/// Works out the audio stream from what the client asked for and what the
+/// server is configured with. Anything the client asks for that Opus can't do
+/// falls back to the server's defaults. Returns None if either side doesn't
+/// want audio.
+pub fn negotiate(
+    cfg: &AudioConfig,
+    requested: Option<&EdcsAudioParams>,
+) -> Option<NegotiatedAudio> {
+    let requested = requested?;
+    let source = match &cfg.source {
+        Some(source) if source.len() <= MAX_AUDIO_SOURCE_LEN => source.clone(),
+        Some(source) => {
+            warn!(
+                "Audio source name {} is too long, not sending audio",
+                source
+            );
+            return None;
+        }
+        None => {
+            debug!("The client asked for audio, but no audio source is configured");
+            return None;
+        }
+    };
+
+    let sample_rate = if OPUS_SAMPLE_RATES.contains(&requested.sample_rate) {
+        requested.sample_rate
+    } else {
+        cfg.sample_rate
+    };
+    let channels = match requested.channels {
+        1 | 2 => requested.channels,
+        _ => cfg.channels,
+    };
+    let bitrate = match requested.bitrate {
+        0 => cfg.bitrate,
+        bitrate => bitrate,
+    }
+    .clamp(MIN_OPUS_BITRATE, MAX_OPUS_BITRATE);
+
+    if !OPUS_SAMPLE_RATES.contains(&sample_rate) || !(1..=2).contains(&channels) {
+        warn!(
+            "Configured audio format {}Hz/{} channels isn't supported by Opus",
+            sample_rate, channels
+        );
+        return None;
+    }
+
+    Some(NegotiatedAudio {
+        source,
+        params: EdcsAudioParams {
+            sample_rate,
+            channels,
+            bitrate,
+        },
+    })
+}
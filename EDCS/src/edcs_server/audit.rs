@@ -0,0 +1,621 @@
+//! Append-only record of who connected and what they did, as JSON lines. The
+//! log is rotated by size, `audit.jsonl.1` being the newest rotated file.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use chrono::{DateTime, FixedOffset, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::auth::{Identity, Role};
+use super::config::{AuditConfig, EdcsConfig};
+use super::edcs_proto::EdcsMessageType;
+use super::session::ConnectionId;
+
+/// Input is only counted, logging every key press would make the log a
+/// keylogger.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct InputCounts {
+    pub mouse: u64,
+    pub keyboard: u64,
+    pub touch: u64,
+    pub pen: u64,
+    pub gamepad: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Connected,
+    Authenticated {
+        role: Role,
+        invite: Option<String>,
+    },
+    // SETUP_EDCS, the connection owns the stream from here on
+    SessionStart {
+        framerate: u32,
+        bitrate: u32,
+    },
+    StreamSetup {
+        cal_options: BTreeMap<String, String>,
+        audio: bool,
+    },
+    StreamJoined {
+        viewer_id: u32,
+    },
+    ControlRequested,
+    ControlGranted {
+        participant: ConnectionId,
+    },
+    ControlRevoked,
+    InviteCreated {
+        id: String,
+        role: Role,
+    },
+    InviteRevoked {
+        id: String,
+    },
+    FileUploaded {
+        name: String,
+        size: u64,
+    },
+    FileDownloadStarted {
+        name: String,
+        size: u64,
+    },
+    SessionEnd,
+    // The stream worker crashed, recorded for the owner
+    StreamFailed {
+        reason: String,
+    },
+    Disconnected {
+        input_events: InputCounts,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    // RFC 3339
+    pub time: String,
+    pub vm: String,
+    pub peer: String,
+    pub connection: ConnectionId,
+    // None until the connection authenticates, or if it never does
+    pub user: Option<String>,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+#[derive(Debug)]
+struct ConnectionAudit {
+    peer: SocketAddr,
+    user: Option<String>,
+    input: InputCounts,
+}
+
+fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    PathBuf::from(rotated)
+}
+
+#[derive(Debug)]
+struct AuditWriter {
+    path: PathBuf,
+    max_size: u64,
+    max_files: u32,
+    file: File,
+    size: u64,
+}
+
+impl AuditWriter {
+    fn open(cfg: &AuditConfig) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&cfg.path)
+            .with_context(|| format!("Failed to open audit log {}", cfg.path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: cfg.path.clone(),
+            max_size: cfg.max_size,
+            max_files: cfg.max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            // Nothing is kept, the log just starts over
+            self.file = File::create(&self.path)?;
+            self.size = 0;
+            return Ok(());
+        }
+        let oldest = rotated_path(&self.path, self.max_files);
+        if oldest.exists() {
+            fs::remove_file(oldest)?;
+        }
+        for n in (1..self.max_files).rev() {
+            let from = rotated_path(&self.path, n);
+            if from.exists() {
+                fs::rename(from, rotated_path(&self.path, n + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, record: &AuditRecord) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()
+                .with_context(|| format!("Failed to rotate audit log {}", self.path.display()))?;
+        }
+        self.file.write_all(&line)?;
+        // There are only a handful of records per session, so they can all
+        // make it to the disk
+        self.file.sync_data()?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Does nothing without an `[audit]` config.
+#[derive(Debug)]
+pub struct AuditLog {
+    writer: Option<AuditWriter>,
+    vm: String,
+    connections: HashMap<ConnectionId, ConnectionAudit>,
+}
+
+impl AuditLog {
+    pub fn from_config(cfg: Option<&AuditConfig>, plugin_name: &str) -> anyhow::Result<Self> {
+        let writer = cfg.map(AuditWriter::open).transpose()?;
+        Ok(Self {
+            writer,
+            vm: cfg
+                .and_then(|cfg| cfg.vm.clone())
+                .unwrap_or_else(|| plugin_name.to_owned()),
+            connections: HashMap::new(),
+        })
+    }
+
+    pub fn record(&mut self, connection: ConnectionId, event: AuditEvent) {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => return,
+        };
+        let (peer, user) = match self.connections.get(&connection) {
+            Some(audit) => (audit.peer.to_string(), audit.user.clone()),
+            None => (String::new(), None),
+        };
+        let record = AuditRecord {
+            time: Utc::now().to_rfc3339(),
+            vm: self.vm.clone(),
+            peer,
+            connection,
+            user,
+            event,
+        };
+        // Losing the audit log shouldn't take the sessions down with it
+        if let Err(e) = writer.write(&record) {
+            warn!("Failed to write audit record {:?}: {:?}", record, e);
+        }
+    }
+
+    pub fn connected(&mut self, connection: ConnectionId, peer: SocketAddr) {
+        self.connections.insert(
+            connection,
+            ConnectionAudit {
+                peer,
+                user: None,
+                input: InputCounts::default(),
+            },
+        );
+        self.record(connection, AuditEvent::Connected);
+    }
+
+    pub fn authenticated(&mut self, connection: ConnectionId, identity: &Identity) {
+        if let Some(audit) = self.connections.get_mut(&connection) {
+            audit.user = Some(identity.name.clone());
+        }
+        self.record(
+            connection,
+            AuditEvent::Authenticated {
+                role: identity.role,
+                invite: identity.invite.clone(),
+            },
+        );
+    }
+
+    pub fn count_input(&mut self, connection: ConnectionId, message_type: EdcsMessageType) {
+        let input = match self.connections.get_mut(&connection) {
+            Some(audit) => &mut audit.input,
+            None => return,
+        };
+        let count = match message_type {
+            EdcsMessageType::WriteMouseEvent => &mut input.mouse,
+            EdcsMessageType::WriteKeyboardEvent => &mut input.keyboard,
+            EdcsMessageType::WriteTouchEvent => &mut input.touch,
+            EdcsMessageType::WritePenEvent => &mut input.pen,
+            EdcsMessageType::WriteGamepadEvent => &mut input.gamepad,
+            _ => return,
+        };
+        *count += 1;
+    }
+
+    pub fn disconnected(&mut self, connection: ConnectionId) {
+        let input_events = match self.connections.get(&connection) {
+            Some(audit) => audit.input.clone(),
+            None => return,
+        };
+        self.record(connection, AuditEvent::Disconnected { input_events });
+        self.connections.remove(&connection);
+    }
+}
+
+/// What `edcs audit` looks for. Everything that is set has to match.
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub since: Option<DateTime<FixedOffset>>,
+    pub until: Option<DateTime<FixedOffset>>,
+    pub user: Option<String>,
+    pub vm: Option<String>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        if self.since.is_some() || self.until.is_some() {
+            let time = match DateTime::parse_from_rfc3339(&record.time) {
+                Ok(time) => time,
+                Err(_) => return false,
+            };
+            if self.since.is_some_and(|since| time < since)
+                || self.until.is_some_and(|until| time > until)
+            {
+                return false;
+            }
+        }
+        self.user
+            .as_ref()
+            .is_none_or(|user| record.user.as_ref() == Some(user))
+            && self.vm.as_ref().is_none_or(|vm| &record.vm == vm)
+    }
+}
+
+/// Every record in the log and its rotated files that matches, oldest first.
+pub fn query(cfg: &AuditConfig, filter: &AuditFilter) -> anyhow::Result<Vec<AuditRecord>> {
+    let mut paths: Vec<PathBuf> = (1..=cfg.max_files)
+        .rev()
+        .map(|n| rotated_path(&cfg.path, n))
+        .collect();
+    paths.push(cfg.path.clone());
+
+    let mut records = vec![];
+    for path in paths {
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to open {}", path.display())),
+        };
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<AuditRecord>(&line) {
+                Ok(record) if filter.matches(&record) => records.push(record),
+                Ok(_) => {}
+                // A record cut short by a crash shouldn't hide the rest
+                Err(e) => warn!("Skipping {}:{}: {}", path.display(), i + 1, e),
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// `edcs audit`, prints the matching records as JSON lines.
+pub fn print(config_file_path: PathBuf, filter: &AuditFilter) -> anyhow::Result<()> {
+    let edcs_config: EdcsConfig = toml::from_str(
+        &fs::read_to_string(config_file_path).with_context(|| "Failed to read EDCS config file")?,
+    )?;
+    let cfg = edcs_config
+        .audit
+        .as_ref()
+        .context("The EDCS config has no [audit] section")?;
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for record in query(cfg, filter)? {
+        serde_json::to_writer(&mut stdout, &record)?;
+        stdout.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+    // A log directory of its own, removed again afterwards
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "edcs-audit-{}-{}",
+                std::process::id(),
+                NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+            ));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn config(&self, max_size: u64, max_files: u32) -> AuditConfig {
+            AuditConfig {
+                path: self.0.join("audit.jsonl"),
+                max_size,
+                max_files,
+                vm: None,
+            }
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn record(time: &str, vm: &str, user: &str, connection: ConnectionId) -> AuditRecord {
+        AuditRecord {
+            time: time.to_owned(),
+            vm: vm.to_owned(),
+            peer: "127.0.0.1:4000".to_owned(),
+            connection,
+            user: Some(user.to_owned()),
+            event: AuditEvent::SessionEnd,
+        }
+    }
+
+    fn read_lines(path: &Path) -> Vec<serde_json::Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn connections(records: &[AuditRecord]) -> Vec<ConnectionId> {
+        records.iter().map(|record| record.connection).collect()
+    }
+
+    #[test]
+    fn a_connection_is_written_as_json_lines() {
+        let dir = TempDir::new();
+        let cfg = dir.config(1 << 20, 2);
+        let mut log = AuditLog::from_config(Some(&cfg), "vgpu").unwrap();
+
+        log.connected(1, "10.0.0.2:5000".parse().unwrap());
+        log.authenticated(
+            1,
+            &Identity {
+                name: "alice".to_owned(),
+                role: Role::Controller,
+                invite: None,
+            },
+        );
+        log.count_input(1, EdcsMessageType::WriteMouseEvent);
+        log.count_input(1, EdcsMessageType::WriteMouseEvent);
+        log.count_input(1, EdcsMessageType::WriteKeyboardEvent);
+        // Not input, so not counted
+        log.count_input(1, EdcsMessageType::UpdateStream);
+        log.disconnected(1);
+
+        let lines = read_lines(&cfg.path);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["event"], "connected");
+        assert_eq!(lines[0]["vm"], "vgpu");
+        assert_eq!(lines[0]["peer"], "10.0.0.2:5000");
+        assert!(lines[0]["user"].is_null());
+        assert_eq!(lines[1]["event"], "authenticated");
+        assert_eq!(lines[1]["user"], "alice");
+
+        let records = query(&cfg, &AuditFilter::default()).unwrap();
+        assert_eq!(
+            records[2].event,
+            AuditEvent::Disconnected {
+                input_events: InputCounts {
+                    mouse: 2,
+                    keyboard: 1,
+                    ..InputCounts::default()
+                }
+            }
+        );
+        assert_eq!(records[2].user.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn nothing_is_written_without_an_audit_config() {
+        let mut log = AuditLog::from_config(None, "vgpu").unwrap();
+        log.connected(1, "10.0.0.2:5000".parse().unwrap());
+        log.disconnected(1);
+        assert!(log.writer.is_none());
+    }
+
+    #[test]
+    fn the_oldest_rotated_file_is_dropped() {
+        let dir = TempDir::new();
+        // Every record after the first goes over, one record per file
+        let cfg = dir.config(1, 2);
+        let mut writer = AuditWriter::open(&cfg).unwrap();
+        for connection in 1..=4 {
+            writer
+                .write(&record(
+                    "2024-01-01T00:00:00+00:00",
+                    "vm",
+                    "alice",
+                    connection,
+                ))
+                .unwrap();
+        }
+
+        let connection_of = |path: &Path| read_lines(path)[0]["connection"].clone();
+        assert_eq!(connection_of(&cfg.path), 4);
+        assert_eq!(connection_of(&rotated_path(&cfg.path, 1)), 3);
+        assert_eq!(connection_of(&rotated_path(&cfg.path, 2)), 2);
+        assert!(!rotated_path(&cfg.path, 3).exists());
+
+        let records = query(&cfg, &AuditFilter::default()).unwrap();
+        assert_eq!(connections(&records), [2, 3, 4]);
+    }
+
+    #[test]
+    fn without_rotated_files_the_log_starts_over() {
+        let dir = TempDir::new();
+        let cfg = dir.config(1, 0);
+        let mut writer = AuditWriter::open(&cfg).unwrap();
+        for connection in 1..=3 {
+            writer
+                .write(&record(
+                    "2024-01-01T00:00:00+00:00",
+                    "vm",
+                    "alice",
+                    connection,
+                ))
+                .unwrap();
+        }
+
+        assert!(!rotated_path(&cfg.path, 1).exists());
+        let records = query(&cfg, &AuditFilter::default()).unwrap();
+        assert_eq!(connections(&records), [3]);
+    }
+
+    #[test]
+    fn reopening_appends_to_the_log() {
+        let dir = TempDir::new();
+        let cfg = dir.config(1 << 20, 2);
+        for connection in 1..=2 {
+            AuditWriter::open(&cfg)
+                .unwrap()
+                .write(&record(
+                    "2024-01-01T00:00:00+00:00",
+                    "vm",
+                    "alice",
+                    connection,
+                ))
+                .unwrap();
+        }
+
+        let records = query(&cfg, &AuditFilter::default()).unwrap();
+        assert_eq!(connections(&records), [1, 2]);
+    }
+
+    #[test]
+    fn queries_filter_across_rotated_files() {
+        let dir = TempDir::new();
+        let cfg = dir.config(1, 4);
+        let mut writer = AuditWriter::open(&cfg).unwrap();
+        for record in [
+            record("2024-01-01T12:00:00+00:00", "win10", "alice", 1),
+            record("2024-01-02T12:00:00+00:00", "win10", "bob", 2),
+            record("2024-01-03T12:00:00+00:00", "ubuntu", "alice", 3),
+            record("2024-01-04T12:00:00+00:00", "ubuntu", "bob", 4),
+        ] {
+            writer.write(&record).unwrap();
+        }
+        // A record cut short by a crash, in one of the rotated files
+        OpenOptions::new()
+            .append(true)
+            .open(rotated_path(&cfg.path, 2))
+            .unwrap()
+            .write_all(b"{\"time\":\"2024-01-02T13:00:00+00:00\",\"vm\"\n")
+            .unwrap();
+
+        let time = |time| Some(DateTime::parse_from_rfc3339(time).unwrap());
+        let matching = |filter: AuditFilter| connections(&query(&cfg, &filter).unwrap());
+
+        assert_eq!(matching(AuditFilter::default()), [1, 2, 3, 4]);
+        assert_eq!(
+            matching(AuditFilter {
+                since: time("2024-01-02T12:00:00+00:00"),
+                until: time("2024-01-03T12:00:00+00:00"),
+                ..AuditFilter::default()
+            }),
+            [2, 3]
+        );
+        // Other offsets are compared by the instant, not the text
+        assert_eq!(
+            matching(AuditFilter {
+                since: time("2024-01-03T13:00:00+02:00"),
+                ..AuditFilter::default()
+            }),
+            [3, 4]
+        );
+        assert_eq!(
+            matching(AuditFilter {
+                user: Some("alice".to_owned()),
+                ..AuditFilter::default()
+            }),
+            [1, 3]
+        );
+        assert_eq!(
+            matching(AuditFilter {
+                vm: Some("win10".to_owned()),
+                ..AuditFilter::default()
+            }),
+            [1, 2]
+        );
+        assert_eq!(
+            matching(AuditFilter {
+                user: Some("bob".to_owned()),
+                vm: Some("ubuntu".to_owned()),
+                ..AuditFilter::default()
+            }),
+            [4]
+        );
+        assert!(matching(AuditFilter {
+            user: Some("carol".to_owned()),
+            ..AuditFilter::default()
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn records_without_a_user_or_time_dont_match_those_filters() {
+        let mut record = record("yesterday", "vm", "alice", 1);
+        let by_user = AuditFilter {
+            user: Some("alice".to_owned()),
+            ..AuditFilter::default()
+        };
+        let by_time = AuditFilter {
+            since: Some(DateTime::parse_from_rfc3339("2024-01-01T00:00:00+00:00").unwrap()),
+            ..AuditFilter::default()
+        };
+        assert!(by_user.matches(&record));
+        assert!(!by_time.matches(&record));
+
+        // Never authenticated
+        record.user = None;
+        assert!(!by_user.matches(&record));
+    }
+}
@@ -0,0 +1,341 @@
+use std::fs;
+
+use anyhow::{anyhow, Context};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::config::AuthConfig;
+use super::edcs_proto::{EdcsMessageType, EdcsRole};
+
+/// What a connection may do, every role can do everything the ones before it
+/// can.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Viewer,
+    Controller,
+    Admin,
+}
+
+impl Role {
+    pub fn to_proto(self) -> EdcsRole {
+        match self {
+            Role::Viewer => EdcsRole::RoleViewer,
+            Role::Controller => EdcsRole::RoleController,
+            Role::Admin => EdcsRole::RoleAdmin,
+        }
+    }
+
+    pub fn from_proto(role: EdcsRole) -> Self {
+        match role {
+            EdcsRole::RoleViewer => Role::Viewer,
+            EdcsRole::RoleController => Role::Controller,
+            EdcsRole::RoleAdmin => Role::Admin,
+        }
+    }
+
+    /// The least a connection needs to send the message. None for messages
+    /// anyone may send, even before authenticating.
+    pub fn required_for(message_type: EdcsMessageType) -> Option<Role> {
+        match message_type {
+            EdcsMessageType::Authenticate => None,
+            // Viewers still have to set up a stream to get the video
+            EdcsMessageType::SetupEdcs
+            | EdcsMessageType::SetupStream
+            | EdcsMessageType::StartStream
+            | EdcsMessageType::CloseStream
+            | EdcsMessageType::JoinStream
+            | EdcsMessageType::FileList
+            | EdcsMessageType::FileDownloadStart
+            | EdcsMessageType::FileDownloadChunk => Some(Role::Viewer),
+            // Anything that changes the remote side
+            EdcsMessageType::WriteMouseEvent
+            | EdcsMessageType::WriteKeyboardEvent
+            | EdcsMessageType::WriteTouchEvent
+            | EdcsMessageType::WritePenEvent
+            | EdcsMessageType::WriteGamepadEvent
+            | EdcsMessageType::ClipboardUpdate
+            | EdcsMessageType::FileUploadStart
+            | EdcsMessageType::FileUploadChunk
+            | EdcsMessageType::FileUploadFinish
+            | EdcsMessageType::RequestControl
+            | EdcsMessageType::GrantControl
+            | EdcsMessageType::RevokeControl
+            | EdcsMessageType::CreateInvite
+            | EdcsMessageType::ListInvites
+            | EdcsMessageType::RevokeInvite => Some(Role::Controller),
+            EdcsMessageType::UpdateStream => Some(Role::Admin),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+    pub role: Role,
+    // The invite it came from, see Invites::redeem
+    pub invite: Option<String>,
+}
+
+/// Checks whether a connection may send a message. The error is meant for the
+/// client.
+pub fn check_permission(
+    identity: Option<&Identity>,
+    message_type: EdcsMessageType,
+) -> Result<(), String> {
+    let required = match Role::required_for(message_type) {
+        Some(required) => required,
+        None => return Ok(()),
+    };
+    match identity {
+        Some(identity) if identity.role >= required => Ok(()),
+        Some(identity) => Err(format!(
+            "{:?} needs the {:?} role, {} is a {:?}",
+            message_type, required, identity.name, identity.role
+        )),
+        None => Err(format!("{:?} needs authentication", message_type)),
+    }
+}
+
+// Compare without an early exit, so the time taken doesn't give the token away
+pub fn token_matches(expected: &[u8], given: &[u8]) -> bool {
+    expected.len() == given.len()
+        && expected
+            .iter()
+            .zip(given)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Maps tokens from the config to identities.
+#[derive(Debug)]
+pub struct Authenticator {
+    users: Vec<(Vec<u8>, Identity)>,
+    // What connections are before they authenticate
+    anonymous: Option<Identity>,
+}
+
+impl Authenticator {
+    pub fn from_config(cfg: Option<&AuthConfig>) -> anyhow::Result<Self> {
+        let cfg = match cfg {
+            Some(cfg) => cfg,
+            None => {
+                // Everything worked without authentication before there was any
+                warn!("No [auth] config, every client is an admin");
+                return Ok(Self {
+                    users: vec![],
+                    anonymous: Some(Identity {
+                        name: "anonymous".to_owned(),
+                        role: Role::Admin,
+                        invite: None,
+                    }),
+                });
+            }
+        };
+
+        let mut users = Vec::with_capacity(cfg.users.len());
+        for user in &cfg.users {
+            let token = fs::read_to_string(&user.token_file).with_context(|| {
+                format!("Failed to read token file {}", user.token_file.display())
+            })?;
+            let token = token.trim();
+            if token.is_empty() {
+                return Err(anyhow!("Token file {} is empty", user.token_file.display()));
+            }
+            users.push((
+                token.as_bytes().to_vec(),
+                Identity {
+                    name: user.name.clone(),
+                    role: user.role,
+                    invite: None,
+                },
+            ));
+        }
+        Ok(Self {
+            users,
+            anonymous: cfg.anonymous_role.map(|role| Identity {
+                name: "anonymous".to_owned(),
+                role,
+                invite: None,
+            }),
+        })
+    }
+
+    pub fn anonymous(&self) -> Option<Identity> {
+        self.anonymous.clone()
+    }
+
+    pub fn authenticate(&self, token: &str) -> Option<Identity> {
+        // Every token is compared, so which one matched doesn't show either
+        let mut found = None;
+        for (expected, identity) in &self.users {
+            if token_matches(expected, token.as_bytes()) {
+                found = Some(identity.clone());
+            }
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::edcs_server::config::AuthUserConfig;
+
+    const WRITES: [EdcsMessageType; 5] = [
+        EdcsMessageType::WriteMouseEvent,
+        EdcsMessageType::WriteKeyboardEvent,
+        EdcsMessageType::WriteTouchEvent,
+        EdcsMessageType::WritePenEvent,
+        EdcsMessageType::WriteGamepadEvent,
+    ];
+
+    static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+    // A token file that is removed again afterwards
+    struct TokenFile(PathBuf);
+
+    impl TokenFile {
+        fn new(contents: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "edcs-auth-{}-{}",
+                std::process::id(),
+                NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::write(&path, contents).unwrap();
+            TokenFile(path)
+        }
+
+        fn user(&self, name: &str, role: Role) -> AuthUserConfig {
+            AuthUserConfig {
+                name: name.to_owned(),
+                role,
+                token_file: self.0.clone(),
+            }
+        }
+    }
+
+    impl Drop for TokenFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn identity(role: Role) -> Identity {
+        Identity {
+            name: "someone".to_owned(),
+            role,
+            invite: None,
+        }
+    }
+
+    // Every message type there is, so none can be left out of required_for
+    fn all_message_types() -> impl Iterator<Item = EdcsMessageType> {
+        (0..).map_while(EdcsMessageType::from_i32)
+    }
+
+    #[test]
+    fn viewers_cant_write_input() {
+        for message_type in WRITES {
+            assert!(check_permission(Some(&identity(Role::Viewer)), message_type).is_err());
+            assert_eq!(
+                check_permission(Some(&identity(Role::Controller)), message_type),
+                Ok(())
+            );
+        }
+    }
+
+    #[test]
+    fn only_admins_update_the_stream() {
+        for (role, allowed) in [
+            (Role::Viewer, false),
+            (Role::Controller, false),
+            (Role::Admin, true),
+        ] {
+            assert_eq!(
+                check_permission(Some(&identity(role)), EdcsMessageType::UpdateStream).is_ok(),
+                allowed,
+                "{:?}",
+                role
+            );
+        }
+    }
+
+    #[test]
+    fn only_authenticate_works_without_an_identity() {
+        for message_type in all_message_types() {
+            assert_eq!(
+                check_permission(None, message_type).is_ok(),
+                message_type == EdcsMessageType::Authenticate,
+                "{:?}",
+                message_type
+            );
+        }
+    }
+
+    #[test]
+    fn admins_can_send_everything() {
+        assert!(all_message_types().any(|t| t == EdcsMessageType::RevokeInvite));
+        for message_type in all_message_types() {
+            assert_eq!(
+                check_permission(Some(&identity(Role::Admin)), message_type),
+                Ok(())
+            );
+        }
+    }
+
+    #[test]
+    fn finds_the_user_of_a_token() {
+        let alice = TokenFile::new("alice-token\n");
+        let bob = TokenFile::new("bob-token");
+        let auth = Authenticator::from_config(Some(&AuthConfig {
+            anonymous_role: None,
+            users: vec![
+                alice.user("alice", Role::Admin),
+                bob.user("bob", Role::Viewer),
+            ],
+        }))
+        .unwrap();
+
+        let found = auth.authenticate("alice-token").unwrap();
+        assert_eq!((found.name.as_str(), found.role), ("alice", Role::Admin));
+        let found = auth.authenticate("bob-token").unwrap();
+        assert_eq!((found.name.as_str(), found.role), ("bob", Role::Viewer));
+        assert_eq!(auth.authenticate("alice-token\n"), None);
+        assert_eq!(auth.authenticate(""), None);
+    }
+
+    #[test]
+    fn refuses_an_empty_token_file() {
+        let empty = TokenFile::new(" \n");
+        let result = Authenticator::from_config(Some(&AuthConfig {
+            anonymous_role: None,
+            users: vec![empty.user("nobody", Role::Admin)],
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn applies_the_anonymous_role() {
+        let auth = Authenticator::from_config(Some(&AuthConfig {
+            anonymous_role: Some(Role::Viewer),
+            users: vec![],
+        }))
+        .unwrap();
+        assert_eq!(auth.anonymous().map(|i| i.role), Some(Role::Viewer));
+
+        let auth = Authenticator::from_config(Some(&AuthConfig {
+            anonymous_role: None,
+            users: vec![],
+        }))
+        .unwrap();
+        assert_eq!(auth.anonymous(), None);
+
+        // Without any auth config things work like they did before there was any
+        let auth = Authenticator::from_config(None).unwrap();
+        assert_eq!(auth.anonymous().map(|i| i.role), Some(Role::Admin));
+    }
+}
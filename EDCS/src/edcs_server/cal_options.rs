@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+
+use super::config::LimitsConfig;
+use super::edcs_proto::{EdcsCalOption, EdcsCalOptionType};
+
+fn check_range(option: &EdcsCalOption, value: f64) -> Result<(), String> {
+    if option.has_range && !(option.min..=option.max).contains(&value) {
+        return Err(format!(
+            "{} has to be between {} and {}",
+            option.name, option.min, option.max
+        ));
+    }
+    Ok(())
+}
+
+fn check_value(option: &EdcsCalOption, value: &str) -> Result<(), String> {
+    if value.is_empty() {
+        if option.required {
+            return Err(format!("{} is required", option.name));
+        }
+        return Ok(());
+    }
+
+    match option.option_type() {
+        EdcsCalOptionType::CalOptionString => Ok(()),
+        EdcsCalOptionType::CalOptionInt => {
+            let int: i64 = value
+                .parse()
+                .map_err(|_| format!("{} has to be an integer", option.name))?;
+            check_range(option, int as f64)
+        }
+        EdcsCalOptionType::CalOptionFloat => {
+            let float: f64 = value
+                .parse()
+                .ok()
+                .filter(|f: &f64| f.is_finite())
+                .ok_or_else(|| format!("{} has to be a number", option.name))?;
+            check_range(option, float)
+        }
+        EdcsCalOptionType::CalOptionBool => match value {
+            "true" | "false" => Ok(()),
+            _ => Err(format!("{} has to be true or false", option.name)),
+        },
+        EdcsCalOptionType::CalOptionEnum => {
+            if option.choices.iter().any(|c| c == value) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "{} has to be one of {}",
+                    option.name,
+                    option.choices.join(", ")
+                ))
+            }
+        }
+    }
+}
+
+/// Checks the client's CAL options against the CAL's schema before they get
+/// anywhere near calInit. Options the client left out get their default.
+/// The error is meant for the client.
+pub fn validate(
+    schema: &[EdcsCalOption],
+    options: &HashMap<String, String>,
+    limits: &LimitsConfig,
+) -> Result<HashMap<String, String>, String> {
+    if options.len() > limits.max_cal_options {
+        return Err(format!(
+            "There can be at most {} CAL options",
+            limits.max_cal_options
+        ));
+    }
+    // Not named in the error, it would be just as long
+    if options.iter().any(|(key, value)| {
+        key.len() > limits.max_cal_option_len || value.len() > limits.max_cal_option_len
+    }) {
+        return Err(format!(
+            "CAL options and their values can be at most {} bytes",
+            limits.max_cal_option_len
+        ));
+    }
+
+    if let Some(unknown) = options
+        .keys()
+        .find(|key| !schema.iter().any(|option| &option.name == *key))
+    {
+        return Err(format!("Unknown CAL option {}", unknown));
+    }
+
+    let mut validated = HashMap::with_capacity(schema.len());
+    for option in schema {
+        let value = options.get(&option.name).unwrap_or(&option.default_value);
+        check_value(option, value)?;
+        validated.insert(option.name.clone(), value.clone());
+    }
+    Ok(validated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn option(name: &str, option_type: EdcsCalOptionType, default_value: &str) -> EdcsCalOption {
+        EdcsCalOption {
+            name: name.to_owned(),
+            option_type: option_type as i32,
+            default_value: default_value.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn schema() -> Vec<EdcsCalOption> {
+        vec![
+            EdcsCalOption {
+                has_range: true,
+                min: 0.0,
+                max: 999.0,
+                required: true,
+                ..option("vgpuId", EdcsCalOptionType::CalOptionInt, "0")
+            },
+            EdcsCalOption {
+                has_range: true,
+                min: 0.5,
+                max: 2.0,
+                ..option("scale", EdcsCalOptionType::CalOptionFloat, "1.0")
+            },
+            option("drawCursor", EdcsCalOptionType::CalOptionBool, "true"),
+            EdcsCalOption {
+                choices: vec!["fast".to_owned(), "slow".to_owned()],
+                ..option("mode", EdcsCalOptionType::CalOptionEnum, "fast")
+            },
+            option("display", EdcsCalOptionType::CalOptionString, ""),
+        ]
+    }
+
+    fn options(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn check(pairs: &[(&str, &str)]) -> Result<HashMap<String, String>, String> {
+        validate(&schema(), &options(pairs), &LimitsConfig::default())
+    }
+
+    #[test]
+    fn left_out_options_get_their_default() {
+        let validated = check(&[("vgpuId", "3"), ("mode", "slow")]).unwrap();
+        assert_eq!(validated["vgpuId"], "3");
+        assert_eq!(validated["mode"], "slow");
+        assert_eq!(validated["scale"], "1.0");
+        assert_eq!(validated["drawCursor"], "true");
+        assert_eq!(validated["display"], "");
+    }
+
+    #[test]
+    fn unknown_options_are_rejected() {
+        assert_eq!(
+            check(&[("vgpuID", "3")]).unwrap_err(),
+            "Unknown CAL option vgpuID"
+        );
+    }
+
+    #[test]
+    fn values_of_the_wrong_type_are_rejected() {
+        for (options, err) in [
+            (("vgpuId", "three"), "vgpuId has to be an integer"),
+            (("vgpuId", "3.5"), "vgpuId has to be an integer"),
+            (("scale", "big"), "scale has to be a number"),
+            (("scale", "NaN"), "scale has to be a number"),
+            (("drawCursor", "yes"), "drawCursor has to be true or false"),
+            (("mode", "medium"), "mode has to be one of fast, slow"),
+        ] {
+            assert_eq!(check(&[options]).unwrap_err(), err);
+        }
+    }
+
+    #[test]
+    fn values_out_of_range_are_rejected() {
+        assert!(check(&[("vgpuId", "999")]).is_ok());
+        assert_eq!(
+            check(&[("vgpuId", "1000")]).unwrap_err(),
+            "vgpuId has to be between 0 and 999"
+        );
+        assert_eq!(
+            check(&[("vgpuId", "-1")]).unwrap_err(),
+            "vgpuId has to be between 0 and 999"
+        );
+        assert!(check(&[("scale", "0.5")]).is_ok());
+        assert_eq!(
+            check(&[("scale", "0.25")]).unwrap_err(),
+            "scale has to be between 0.5 and 2"
+        );
+    }
+
+    #[test]
+    fn required_options_cant_be_empty() {
+        assert_eq!(check(&[("vgpuId", "")]).unwrap_err(), "vgpuId is required");
+        // Nor can their default, if the CAL has none
+        let mut schema = schema();
+        schema[0].default_value.clear();
+        assert_eq!(
+            validate(&schema, &HashMap::new(), &LimitsConfig::default()).unwrap_err(),
+            "vgpuId is required"
+        );
+        // Optional ones can
+        assert!(check(&[("scale", "")]).is_ok());
+    }
+
+    #[test]
+    fn options_over_the_limits_are_rejected() {
+        let limits = LimitsConfig {
+            max_cal_options: 2,
+            max_cal_option_len: 4,
+            ..LimitsConfig::default()
+        };
+
+        assert_eq!(
+            validate(
+                &schema(),
+                &options(&[("mode", "fast"), ("vgpuId", "1"), ("scale", "1")]),
+                &limits
+            )
+            .unwrap_err(),
+            "There can be at most 2 CAL options"
+        );
+        assert_eq!(
+            validate(&schema(), &options(&[("mode", "faster")]), &limits).unwrap_err(),
+            "CAL options and their values can be at most 4 bytes"
+        );
+    }
+}
@@ -0,0 +1,473 @@
+use std::{
+    fmt::Debug,
+    io::Write,
+    process::{Command, Stdio},
+    sync::{Arc, Once},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use log::{debug, warn};
+use parking_lot::Mutex;
+use tokio::sync::watch;
+
+use super::config::{ClipboardConfig, ClipboardProviderConfig};
+use super::edcs_proto::{
+    edcs_clipboard_data, edcs_server_event, EdcsClipboardData, EdcsServerEvent,
+};
+use super::session::ConnectionId;
+
+// The provider may have to spawn a process, so don't ask too often
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Where the remote side of the clipboard lives. EDCS hands it whatever the
+/// client copied and asks it for what was copied on the remote side.
+pub trait ClipboardProvider: Send + Debug {
+    fn set(&mut self, data: EdcsClipboardData) -> anyhow::Result<()>;
+    /// The remote clipboard contents, if they changed since the last call.
+    fn poll(&mut self) -> anyhow::Result<Option<EdcsClipboardData>>;
+}
+
+#[derive(Debug, Default)]
+struct MemoryClipboardState {
+    contents: Option<EdcsClipboardData>,
+    remote_changed: bool,
+}
+
+/// Keeps the clipboard in memory. Clones share the same clipboard, so a test
+/// can keep one around to play the remote side.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryClipboard(Arc<Mutex<MemoryClipboardState>>);
+
+impl MemoryClipboard {
+    pub fn contents(&self) -> Option<EdcsClipboardData> {
+        self.0.lock().contents.clone()
+    }
+
+    /// Pretend something was copied on the remote side
+    pub fn copy_remote(&self, data: EdcsClipboardData) {
+        let mut state = self.0.lock();
+        state.contents = Some(data);
+        state.remote_changed = true;
+    }
+}
+
+impl ClipboardProvider for MemoryClipboard {
+    fn set(&mut self, data: EdcsClipboardData) -> anyhow::Result<()> {
+        let mut state = self.0.lock();
+        state.contents = Some(data);
+        // Whatever the client sent doesn't need to go back to it
+        state.remote_changed = false;
+        Ok(())
+    }
+
+    fn poll(&mut self) -> anyhow::Result<Option<EdcsClipboardData>> {
+        let mut state = self.0.lock();
+        if !state.remote_changed {
+            return Ok(None);
+        }
+        state.remote_changed = false;
+        Ok(state.contents.clone())
+    }
+}
+
+/// Shells out to something like xclip or wl-copy/wl-paste. Only text is
+/// supported.
+#[derive(Debug)]
+pub struct CommandClipboard {
+    get_command: Vec<String>,
+    set_command: Vec<String>,
+    last_text: Option<String>,
+}
+
+impl CommandClipboard {
+    pub fn new(get_command: Vec<String>, set_command: Vec<String>) -> anyhow::Result<Self> {
+        if get_command.is_empty() || set_command.is_empty() {
+            return Err(anyhow!(
+                "Clipboard get_command and set_command can't be empty"
+            ));
+        }
+        Ok(Self {
+            get_command,
+            set_command,
+            last_text: None,
+        })
+    }
+}
+
+impl ClipboardProvider for CommandClipboard {
+    fn set(&mut self, data: EdcsClipboardData) -> anyhow::Result<()> {
+        let text = match data.content {
+            Some(edcs_clipboard_data::Content::Text(text)) => text,
+            Some(edcs_clipboard_data::Content::Image(_)) => {
+                return Err(anyhow!("The command clipboard provider only supports text"))
+            }
+            None => return Ok(()),
+        };
+        let mut child = Command::new(&self.set_command[0])
+            .args(&self.set_command[1..])
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run {}", self.set_command[0]))?;
+        child
+            .stdin
+            .take()
+            .unwrap() // Always piped
+            .write_all(text.as_bytes())?;
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(anyhow!("{} exited with {}", self.set_command[0], status));
+        }
+        self.last_text = Some(text);
+        Ok(())
+    }
+
+    fn poll(&mut self) -> anyhow::Result<Option<EdcsClipboardData>> {
+        let output = Command::new(&self.get_command[0])
+            .args(&self.get_command[1..])
+            .stderr(Stdio::null())
+            .output()
+            .with_context(|| format!("Failed to run {}", self.get_command[0]))?;
+        if !output.status.success() {
+            // Usually just means the clipboard is empty
+            return Ok(None);
+        }
+        let text = String::from_utf8_lossy(&output.stdout).into_owned();
+        if self.last_text.as_ref() == Some(&text) {
+            return Ok(None);
+        }
+        self.last_text = Some(text.clone());
+        Ok(Some(EdcsClipboardData {
+            content: Some(edcs_clipboard_data::Content::Text(text)),
+        }))
+    }
+}
+
+pub fn clipboard_size(data: &EdcsClipboardData) -> usize {
+    match &data.content {
+        Some(edcs_clipboard_data::Content::Text(text)) => text.len(),
+        Some(edcs_clipboard_data::Content::Image(image)) => image.data.len(),
+        None => 0,
+    }
+}
+
+/// What the session clipboard holds. Every change gets a serial, so each
+/// connection can tell whether it has sent it already.
+#[derive(Debug, Clone, Default)]
+pub struct ClipboardContents {
+    serial: u64,
+    data: Option<EdcsClipboardData>,
+    // None for changes on the remote side
+    set_by: Option<ConnectionId>,
+}
+
+#[derive(Debug)]
+struct ClipboardShared {
+    provider: Mutex<Box<dyn ClipboardProvider>>,
+    max_size: usize,
+    contents: watch::Sender<ClipboardContents>,
+    // None if it's polled by hand
+    poll_interval: Option<Duration>,
+    poller: Once,
+}
+
+/// The session clipboard. The provider may have to run a command to be
+/// polled, so that happens on a thread of its own, and connections only read
+/// what it found.
+#[derive(Debug)]
+pub struct SharedClipboard(Arc<ClipboardShared>);
+
+#[derive(Debug)]
+pub enum ClipboardError {
+    TooLarge(usize),
+    Provider(anyhow::Error),
+}
+
+impl SharedClipboard {
+    /// A clipboard that is only polled when `poll` is called.
+    pub fn new(provider: Box<dyn ClipboardProvider>, max_size: usize) -> Self {
+        Self::with_poll_interval(provider, max_size, None)
+    }
+
+    fn with_poll_interval(
+        provider: Box<dyn ClipboardProvider>,
+        max_size: usize,
+        poll_interval: Option<Duration>,
+    ) -> Self {
+        let (contents, _) = watch::channel(ClipboardContents::default());
+        Self(Arc::new(ClipboardShared {
+            provider: Mutex::new(provider),
+            max_size,
+            contents,
+            poll_interval,
+            poller: Once::new(),
+        }))
+    }
+
+    pub fn from_config(cfg: &ClipboardConfig) -> anyhow::Result<Self> {
+        let provider: Box<dyn ClipboardProvider> = match &cfg.provider {
+            ClipboardProviderConfig::Memory => Box::new(MemoryClipboard::default()),
+            ClipboardProviderConfig::Command {
+                get_command,
+                set_command,
+            } => Box::new(CommandClipboard::new(
+                get_command.clone(),
+                set_command.clone(),
+            )?),
+        };
+        Ok(Self::with_poll_interval(
+            provider,
+            cfg.max_size,
+            Some(POLL_INTERVAL),
+        ))
+    }
+
+    // The poller is started by the first connection that wants the clipboard
+    fn subscribe(&self) -> watch::Receiver<ClipboardContents> {
+        if let Some(interval) = self.0.poll_interval {
+            self.0.poller.call_once(|| {
+                if let Err(e) = self.spawn_poller(interval) {
+                    warn!("Failed to start polling the clipboard: {}", e);
+                }
+            });
+        }
+        self.0.contents.subscribe()
+    }
+
+    /// Poll the provider every `interval`, for as long as the clipboard is
+    /// around.
+    fn spawn_poller(&self, interval: Duration) -> std::io::Result<()> {
+        let shared = Arc::downgrade(&self.0);
+        thread::Builder::new()
+            .name("clipboard".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                match shared.upgrade() {
+                    Some(shared) => SharedClipboard(shared).poll(),
+                    None => break,
+                }
+            })?;
+        Ok(())
+    }
+
+    fn publish(&self, data: EdcsClipboardData, set_by: Option<ConnectionId>) {
+        self.0.contents.send_modify(|contents| {
+            contents.serial += 1;
+            contents.data = Some(data);
+            contents.set_by = set_by;
+        });
+    }
+
+    pub fn set(
+        &self,
+        connection: ConnectionId,
+        data: EdcsClipboardData,
+    ) -> Result<(), ClipboardError> {
+        let size = clipboard_size(&data);
+        if size > self.0.max_size {
+            return Err(ClipboardError::TooLarge(size));
+        }
+        if data.content.is_none() {
+            return Ok(());
+        }
+        // Held while publishing, so a poll can't get in between
+        let mut provider = self.0.provider.lock();
+        provider
+            .set(data.clone())
+            .map_err(ClipboardError::Provider)?;
+        // Other connections see this as a remote change
+        self.publish(data, Some(connection));
+        Ok(())
+    }
+
+    /// Look for remote changes. The provider is left alone while no
+    /// connection wants the clipboard.
+    pub fn poll(&self) {
+        if self.0.contents.receiver_count() == 0 {
+            return;
+        }
+        let mut provider = self.0.provider.lock();
+        match provider.poll() {
+            Ok(Some(data)) => {
+                let size = clipboard_size(&data);
+                if size > self.0.max_size {
+                    debug!("Not sharing remote clipboard of {} bytes", size);
+                    return;
+                }
+                self.publish(data, None);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to read the remote clipboard {:?}", e),
+        }
+    }
+}
+
+/// Per connection clipboard state, like CursorTracker. Nothing is sent to a
+/// client before it sends a clipboard update itself.
+#[derive(Debug)]
+pub struct ClipboardTracker {
+    connection: ConnectionId,
+    contents: Option<watch::Receiver<ClipboardContents>>,
+}
+
+impl ClipboardTracker {
+    pub fn new(connection: ConnectionId) -> Self {
+        Self {
+            connection,
+            contents: None,
+        }
+    }
+
+    /// The client sent an update, from now on it gets the remote ones.
+    pub fn sent_by_client(&mut self, clipboard: &SharedClipboard) {
+        if self.contents.is_none() {
+            // Whatever is there already counts as sent
+            self.contents = Some(clipboard.subscribe());
+        }
+    }
+
+    pub fn poll(&mut self) -> Option<EdcsServerEvent> {
+        let contents = self.contents.as_mut()?;
+        if !contents.has_changed().unwrap_or(false) {
+            return None;
+        }
+        let contents = contents.borrow_and_update();
+        // The client's own update doesn't go back to it
+        if contents.set_by == Some(self.connection) {
+            return None;
+        }
+        contents.data.clone().map(|data| EdcsServerEvent {
+            payload: Some(edcs_server_event::Payload::ClipboardUpdate(data)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> EdcsClipboardData {
+        EdcsClipboardData {
+            content: Some(edcs_clipboard_data::Content::Text(text.to_string())),
+        }
+    }
+
+    fn sent(event: Option<EdcsServerEvent>) -> Option<EdcsClipboardData> {
+        match event?.payload {
+            Some(edcs_server_event::Payload::ClipboardUpdate(data)) => Some(data),
+            payload => panic!("Not a clipboard update: {:?}", payload),
+        }
+    }
+
+    // Without the poller thread, the tests poll themselves
+    fn clipboard(max_size: usize) -> (SharedClipboard, MemoryClipboard) {
+        let remote = MemoryClipboard::default();
+        let clipboard = SharedClipboard::new(Box::new(remote.clone()), max_size);
+        (clipboard, remote)
+    }
+
+    // A connection that has sent an update of its own
+    fn enabled(clipboard: &SharedClipboard, connection: ConnectionId) -> ClipboardTracker {
+        let mut tracker = ClipboardTracker::new(connection);
+        clipboard.set(connection, text("hello")).unwrap();
+        tracker.sent_by_client(clipboard);
+        tracker
+    }
+
+    #[test]
+    fn remote_changes_reach_enabled_connections_once() {
+        let (clipboard, remote) = clipboard(1024);
+        let mut tracker = enabled(&clipboard, 1);
+        let mut disabled = ClipboardTracker::new(2);
+
+        remote.copy_remote(text("remote"));
+        clipboard.poll();
+        assert_eq!(sent(tracker.poll()), Some(text("remote")));
+        assert_eq!(tracker.poll(), None);
+        assert_eq!(disabled.poll(), None);
+
+        // Nothing changed on the remote side
+        clipboard.poll();
+        assert_eq!(tracker.poll(), None);
+    }
+
+    #[test]
+    fn updates_are_not_echoed_back() {
+        let (clipboard, remote) = clipboard(1024);
+        let mut first = enabled(&clipboard, 1);
+        let mut second = enabled(&clipboard, 2);
+        // What the second one copied
+        assert_eq!(sent(first.poll()), Some(text("hello")));
+
+        clipboard.set(1, text("from first")).unwrap();
+        first.sent_by_client(&clipboard);
+        // The provider doesn't report what it was given as a remote change
+        clipboard.poll();
+        assert_eq!(remote.contents(), Some(text("from first")));
+        assert_eq!(first.poll(), None);
+        assert_eq!(sent(second.poll()), Some(text("from first")));
+        assert_eq!(second.poll(), None);
+    }
+
+    #[test]
+    fn only_the_latest_change_is_sent() {
+        let (clipboard, remote) = clipboard(1024);
+        let mut first = enabled(&clipboard, 1);
+        remote.copy_remote(text("remote"));
+        clipboard.poll();
+        clipboard.set(1, text("newer")).unwrap();
+        // The remote change was overwritten by the client's own
+        assert_eq!(first.poll(), None);
+    }
+
+    #[test]
+    fn oversized_contents_are_not_shared() {
+        let (clipboard, remote) = clipboard(4);
+        let mut tracker = ClipboardTracker::new(1);
+        clipboard.set(1, text("ok")).unwrap();
+        tracker.sent_by_client(&clipboard);
+
+        assert!(matches!(
+            clipboard.set(1, text("too large")),
+            Err(ClipboardError::TooLarge(9))
+        ));
+        assert_eq!(remote.contents(), Some(text("ok")));
+
+        remote.copy_remote(text("also too large"));
+        clipboard.poll();
+        assert_eq!(tracker.poll(), None);
+    }
+
+    #[test]
+    fn the_provider_is_left_alone_until_a_connection_is_enabled() {
+        let (clipboard, remote) = clipboard(1024);
+        remote.copy_remote(text("remote"));
+        clipboard.poll();
+
+        let mut tracker = ClipboardTracker::new(1);
+        tracker.sent_by_client(&clipboard);
+        clipboard.poll();
+        assert_eq!(sent(tracker.poll()), Some(text("remote")));
+    }
+
+    #[test]
+    fn the_poller_picks_up_remote_changes() {
+        let remote = MemoryClipboard::default();
+        let clipboard = SharedClipboard::with_poll_interval(
+            Box::new(remote.clone()),
+            1024,
+            Some(Duration::from_millis(1)),
+        );
+        let mut tracker = enabled(&clipboard, 1);
+        remote.copy_remote(text("remote"));
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        let mut update = None;
+        while update.is_none() && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+            update = tracker.poll();
+        }
+        assert_eq!(sent(update), Some(text("remote")));
+    }
+}
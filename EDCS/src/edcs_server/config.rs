@@ -2,6 +2,8 @@ use std::{net::Ipv4Addr, path::PathBuf};
 
 use serde::Deserialize;
 
+use super::auth::Role;
+
 #[derive(Deserialize)]
 pub struct EdcsConfig {
     pub ip: Ipv4Addr,
@@ -9,10 +11,171 @@ pub struct EdcsConfig {
     pub cert_path: PathBuf,
     pub key_path: PathBuf,
     pub edss_config: EdssConfig,
+    #[serde(default)]
+    pub clipboard: ClipboardConfig,
+    #[serde(default)]
+    pub file_transfer: FileTransferConfig,
+    #[serde(default)]
+    pub audio: AudioConfig,
+    // Input goes through evdev-proxy for sessions that set evdevProxyVm
+    pub evdev_proxy: Option<EvdevProxyConfig>,
+    // Without it every client is an admin
+    pub auth: Option<AuthConfig>,
+    // Without it nothing is audited
+    pub audit: Option<AuditConfig>,
+    #[serde(default)]
+    pub limits: LimitsConfig,
 }
 
 #[derive(Deserialize)]
 pub struct EdssConfig {
     pub plugin_name: String,
     pub port: u16,
+    // What runs the streams, see edss_safe/worker.rs. The edcs binary that is
+    // running if left out.
+    pub worker_path: Option<PathBuf>,
+}
+
+impl EdssConfig {
+    pub fn worker_binary(&self) -> PathBuf {
+        self.worker_path
+            .clone()
+            .or_else(|| std::env::current_exe().ok())
+            .unwrap_or_else(|| PathBuf::from("edcs"))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ClipboardConfig {
+    // In bytes, for both directions
+    pub max_size: usize,
+    pub provider: ClipboardProviderConfig,
+}
+
+impl Default for ClipboardConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 1 << 20,
+            provider: ClipboardProviderConfig::Memory,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClipboardProviderConfig {
+    Memory,
+    // eg. get_command = ["xclip", "-selection", "clipboard", "-o"]
+    Command {
+        get_command: Vec<String>,
+        set_command: Vec<String>,
+    },
+}
+
+#[derive(Deserialize)]
+pub struct FileTransferConfig {
+    // Every session gets a subdirectory of this
+    pub staging_dir: PathBuf,
+    // In bytes, per session
+    pub quota: u64,
+}
+
+impl Default for FileTransferConfig {
+    fn default() -> Self {
+        Self {
+            staging_dir: std::env::temp_dir().join("edcs-staging"),
+            quota: 4 << 30,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct AudioConfig {
+    // An EDSS audio source, eg. "tone" or "cal". No audio is sent without one.
+    pub source: Option<String>,
+    // Used for whatever the client doesn't ask for itself
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub bitrate: u32,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            source: None,
+            sample_rate: 48000,
+            channels: 2,
+            bitrate: 96000,
+        }
+    }
+}
+
+// What a client can make EDCS take in. Anything over them is refused before
+// it is allocated.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    // In bytes, clients that send a larger message are disconnected. Has to
+    // leave room for the clipboard's max_size.
+    pub max_message_size: usize,
+    // Entries in the cal_option_dict of SETUP_STREAM
+    pub max_cal_options: usize,
+    // In bytes, for each key and value of the cal_option_dict
+    pub max_cal_option_len: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_message_size: 4 << 20,
+            max_cal_options: 256,
+            max_cal_option_len: 4096,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct EvdevProxyConfig {
+    pub socket_path: PathBuf,
+    // Same token file as the proxy's
+    pub token_file: PathBuf,
+}
+
+#[derive(Deserialize)]
+pub struct AuthConfig {
+    // Role of connections that haven't authenticated, None to refuse them
+    pub anonymous_role: Option<Role>,
+    #[serde(default)]
+    pub users: Vec<AuthUserConfig>,
+}
+
+#[derive(Deserialize)]
+pub struct AuditConfig {
+    // Rotated files go next to it, with .1 being the newest
+    pub path: PathBuf,
+    // In bytes, the log is rotated before it goes over this
+    #[serde(default = "default_audit_max_size")]
+    pub max_size: u64,
+    // Rotated files that are kept
+    #[serde(default = "default_audit_max_files")]
+    pub max_files: u32,
+    // What records from this EDCS are tagged with, the CAL plugin name if left out
+    pub vm: Option<String>,
+}
+
+fn default_audit_max_size() -> u64 {
+    16 << 20
+}
+
+fn default_audit_max_files() -> u32 {
+    8
+}
+
+#[derive(Deserialize)]
+pub struct AuthUserConfig {
+    pub name: String,
+    pub role: Role,
+    // The client sends what's in this file with AUTHENTICATE
+    pub token_file: PathBuf,
 }
@@ -0,0 +1,72 @@
+use log::{debug, trace};
+
+use super::edcs_proto::{edcs_server_event, EdcsCursorImage, EdcsCursorPosition, EdcsServerEvent};
+use super::stream_actor::StreamAdapter;
+
+/// Tracks the remote cursor for one connection, so that only changes get pushed
+/// to the client.
+#[derive(Debug, Default)]
+pub struct CursorTracker {
+    last_serial: Option<u32>,
+    last_position: Option<EdcsCursorPosition>,
+    unsupported: bool,
+}
+
+impl CursorTracker {
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn poll(&mut self, adapter: &mut dyn StreamAdapter) -> Vec<EdcsServerEvent> {
+        let mut events = vec![];
+        if self.unsupported {
+            return events;
+        }
+        let (width, height) = match adapter.stream_resolution() {
+            Some(res) => res,
+            None => return events,
+        };
+
+        let cursor = match adapter.read_cursor(self.last_serial) {
+            Ok(cursor) => cursor,
+            Err(e) => {
+                if e.is_unsupported() {
+                    // No point asking again until the stream is set up with another CAL
+                    debug!("CAL does not report its cursor, stopping cursor updates");
+                    self.unsupported = true;
+                } else {
+                    trace!("edssReadCursor failed with {}", e.0);
+                }
+                return events;
+            }
+        };
+
+        if let Some(rgba) = cursor.image {
+            self.last_serial = Some(cursor.serial);
+            events.push(EdcsServerEvent {
+                payload: Some(edcs_server_event::Payload::CursorImage(EdcsCursorImage {
+                    width: cursor.width,
+                    height: cursor.height,
+                    hotspot_x: cursor.hotspot_x,
+                    hotspot_y: cursor.hotspot_y,
+                    rgba,
+                })),
+            });
+        }
+
+        // Normalise the same way CalSink scales input back to pixels
+        let position = EdcsCursorPosition {
+            x: cursor.x as f64 / width.saturating_sub(1).max(1) as f64,
+            y: cursor.y as f64 / height.saturating_sub(1).max(1) as f64,
+            visible: cursor.visible,
+        };
+        if self.last_position.as_ref() != Some(&position) {
+            self.last_position = Some(position.clone());
+            events.push(EdcsServerEvent {
+                payload: Some(edcs_server_event::Payload::CursorPosition(position)),
+            });
+        }
+
+        events
+    }
+}
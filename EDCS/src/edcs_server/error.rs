@@ -0,0 +1,170 @@
+//! What goes wrong while handling a message, and how it is put to the client:
+//! `status` for programs and `message` for people.
+
+use std::fmt;
+
+use super::edcs_proto::{edcs_response, EdcsResponse, EdcsStatus};
+use crate::edss_safe::edss::EdssError;
+use crate::edss_safe::edss_unsafe;
+use crate::edss_safe::stream_state::{InvalidTransition, StreamEvent, StreamState};
+
+#[derive(Debug)]
+pub enum EdcsError {
+    /// The message itself is wrong, eg. its payload is of the wrong type. The
+    /// details go in invalid_request_data.
+    Protocol(EdcsStatus, String),
+    /// The message is fine, but not from this connection or not right now. The
+    /// details go in generic_err_data.
+    State(EdcsStatus, String),
+    Edss(EdssError),
+    /// EDCS is set up wrong, eg. a directory from its config can't be created.
+    Config(String),
+}
+
+impl EdcsError {
+    pub fn status(&self) -> EdcsStatus {
+        match self {
+            EdcsError::Protocol(status, _) | EdcsError::State(status, _) => *status,
+            EdcsError::Edss(e) => edss_status(e),
+            EdcsError::Config(_) => EdcsStatus::GenericErr,
+        }
+    }
+
+    pub fn into_response(self) -> EdcsResponse {
+        let status = self.status();
+        let message = self.to_string();
+        let payload = match self {
+            EdcsError::Protocol(_, detail) => edcs_response::Payload::InvalidRequestData(detail),
+            EdcsError::State(_, detail) | EdcsError::Config(detail) => {
+                edcs_response::Payload::GenericErrData(detail)
+            }
+            EdcsError::Edss(e) => edcs_response::Payload::EdssErrData(e.0),
+        };
+        EdcsResponse {
+            status: status as i32,
+            payload: Some(payload),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for EdcsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EdcsError::Protocol(status, detail) | EdcsError::State(status, detail) => {
+                write!(f, "{}: {}", describe(*status), detail)
+            }
+            EdcsError::Edss(e) => write!(f, "{}: {}", describe(edss_status(e)), e),
+            EdcsError::Config(detail) => write!(f, "EDCS is set up wrong: {}", detail),
+        }
+    }
+}
+
+impl std::error::Error for EdcsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EdcsError::Edss(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<EdssError> for EdcsError {
+    fn from(e: EdssError) -> Self {
+        EdcsError::Edss(e)
+    }
+}
+
+impl From<InvalidTransition> for EdcsError {
+    fn from(e: InvalidTransition) -> Self {
+        let status = match (e.from, e.event) {
+            (_, StreamEvent::OpenCal) => EdcsStatus::EdcsAlreadySetup,
+            (StreamState::Connected | StreamState::Closing, _) => EdcsStatus::UninitialisedEdss,
+            (_, StreamEvent::InitServer) => EdcsStatus::StreamAlreadySetup,
+            (StreamState::Streaming, StreamEvent::InitStreaming) => {
+                EdcsStatus::StreamAlreadyStarted
+            }
+            // SETUP_STREAM has to come first
+            (_, StreamEvent::InitStreaming) => EdcsStatus::UninitialisedEdss,
+            _ => EdcsStatus::StreamNotStarted,
+        };
+        EdcsError::State(status, e.to_string())
+    }
+}
+
+/// The status for an EDSS_STATUS. Anything the client can do something about
+/// gets a status of its own, the rest is EDSS_ERR with the EDSS_STATUS in
+/// edss_err_data.
+fn edss_status(e: &EdssError) -> EdcsStatus {
+    match e.0 {
+        edss_unsafe::EDSS_STATUS_EDSS_INVALID_CAL_OPTIONS => EdcsStatus::InvalidCalOptions,
+        edss_unsafe::EDSS_STATUS_EDSS_UNINITIALISED => EdcsStatus::UninitialisedEdss,
+        edss_unsafe::EDSS_STATUS_EDSS_INVALID_MOUSE_DATA
+        | edss_unsafe::EDSS_STATUS_EDSS_INVALID_INPUT_DATA => EdcsStatus::InvalidRequest,
+        edss_unsafe::EDSS_STATUS_EDSS_CAL_UNSUPPORTED => EdcsStatus::InputUnsupported,
+        edss_unsafe::EDSS_STATUS_EDSS_TOO_MANY_VIEWERS => EdcsStatus::TooManyViewers,
+        edss_unsafe::EDSS_STATUS_EDSS_OK
+        | edss_unsafe::EDSS_STATUS_EDSS_INVALID_CONFIG
+        | edss_unsafe::EDSS_STATUS_EDSS_CAL_FILE_NOT_FOUND
+        | edss_unsafe::EDSS_STATUS_EDSS_CAL_LIBRARY_FAILURE
+        | edss_unsafe::EDSS_STATUS_EDSS_INVALID_CAL
+        | edss_unsafe::EDSS_STATUS_EDSS_LIBAV_FAILURE
+        | edss_unsafe::EDSS_STATUS_EDSS_ENCODE_FAILURE
+        | edss_unsafe::EDSS_STATUS_EDSS_ALLOCATION_FAILURE
+        | edss_unsafe::EDSS_STATUS_EDSS_PTHREAD_FAILURE
+        | edss_unsafe::EDSS_STATUS_EDSS_STRMAP_FAILURE
+        | edss_unsafe::EDSS_STATUS_EDSS_INVALID_AUDIO_SOURCE => EdcsStatus::EdssErr,
+        // Newer than this EDCS
+        _ => EdcsStatus::EdssErr,
+    }
+}
+
+pub fn describe(status: EdcsStatus) -> &'static str {
+    match status {
+        EdcsStatus::Ok => "OK",
+        EdcsStatus::GenericErr => "Something went wrong",
+        EdcsStatus::EdssErr => "The stream failed",
+        EdcsStatus::UninitialisedEdss => "There is no stream set up",
+        EdcsStatus::InvalidRequest => "Invalid request",
+        EdcsStatus::StreamAlreadyStarted => "The stream is already running",
+        EdcsStatus::EdcsAlreadySetup => "EDCS is already set up",
+        EdcsStatus::StreamAlreadySetup => "The stream is already set up",
+        EdcsStatus::StreamNotStarted => "The stream isn't running",
+        EdcsStatus::InputUnsupported => "The CAL doesn't take this kind of input",
+        EdcsStatus::GamepadSlotUnavailable => "No gamepad slot is free",
+        EdcsStatus::ClipboardTooLarge => "The clipboard is too large",
+        EdcsStatus::FileTransferErr => "The file transfer failed",
+        EdcsStatus::InvalidFileName => "Invalid file name",
+        EdcsStatus::QuotaExceeded => "Not enough space left for the upload",
+        EdcsStatus::ChecksumMismatch => "The upload doesn't match its checksum",
+        EdcsStatus::InvalidCalOptions => "Invalid CAL options",
+        EdcsStatus::PermissionDenied => "Permission denied",
+        EdcsStatus::NotInControl => "Not in control of the input",
+        EdcsStatus::StreamWorkerCrashed => "The stream's worker crashed",
+        EdcsStatus::TooManyViewers => "The stream has as many viewers as it takes",
+    }
+}
+
+/// A response with the message filled in from `status` and the details in
+/// `payload`, for errors that didn't come as an EdcsError.
+pub fn response(status: EdcsStatus, payload: Option<edcs_response::Payload>) -> EdcsResponse {
+    let message = match (status, &payload) {
+        (EdcsStatus::Ok, _) => String::new(),
+        (
+            _,
+            Some(
+                edcs_response::Payload::GenericErrData(detail)
+                | edcs_response::Payload::InvalidRequestData(detail),
+            ),
+        ) => format!("{}: {}", describe(status), detail),
+        (_, Some(edcs_response::Payload::EdssErrData(code))) => {
+            format!("{}: {}", describe(status), EdssError(*code))
+        }
+        _ => describe(status).to_owned(),
+    };
+    EdcsResponse {
+        status: status as i32,
+        payload,
+        message,
+    }
+}
@@ -0,0 +1,614 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Context};
+use log::{debug, info};
+use sha2::{Digest, Sha256};
+
+use super::config::FileTransferConfig;
+use super::edcs_proto::{
+    EdcsFileChunk, EdcsFileChunkRequest, EdcsFileInfo, EdcsFileList, EdcsFileTransferProgress,
+};
+
+pub const MAX_FILE_CHUNK_SIZE: usize = 256 * 1024;
+const MAX_FILE_NAME_LEN: usize = 255;
+const PARTIAL_DIR: &str = ".partial";
+
+#[derive(Debug)]
+pub enum FileTransferError {
+    InvalidName(String),
+    QuotaExceeded { needed: u64, available: u64 },
+    ChecksumMismatch(String),
+    Io(anyhow::Error),
+}
+
+impl From<anyhow::Error> for FileTransferError {
+    fn from(e: anyhow::Error) -> Self {
+        FileTransferError::Io(e)
+    }
+}
+
+impl From<io::Error> for FileTransferError {
+    fn from(e: io::Error) -> Self {
+        FileTransferError::Io(e.into())
+    }
+}
+
+/// A name that is safe to join onto the staging directory: a single path
+/// component that isn't hidden, so it can't escape the directory or clash with
+/// the partial uploads.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FileName(String);
+
+impl FileName {
+    pub fn new(name: &str) -> Result<FileName, FileTransferError> {
+        let valid = !name.is_empty()
+            && name.len() <= MAX_FILE_NAME_LEN
+            && !name.starts_with('.')
+            && !name.contains(|c: char| c == '/' || c == '\\' || c == '\0' || c.is_control());
+        if !valid {
+            return Err(FileTransferError::InvalidName(name.to_owned()));
+        }
+        Ok(FileName(name.to_owned()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Where transferred files end up. Uploads are written to a partial file that
+/// only becomes visible once committed.
+pub trait FileSink: Send + Debug {
+    /// Bytes of an interrupted upload that are already stored
+    fn partial_size(&mut self, name: &FileName) -> anyhow::Result<u64>;
+    fn write_partial(&mut self, name: &FileName, offset: u64, data: &[u8]) -> anyhow::Result<()>;
+    fn read_partial(
+        &mut self,
+        name: &FileName,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> anyhow::Result<usize>;
+    fn discard_partial(&mut self, name: &FileName) -> anyhow::Result<()>;
+    /// Turn the partial upload into a finished file, replacing any old one
+    fn commit(&mut self, name: &FileName) -> anyhow::Result<()>;
+    /// Size of a finished file
+    fn size(&mut self, name: &FileName) -> anyhow::Result<u64>;
+    fn read(&mut self, name: &FileName, offset: u64, buf: &mut [u8]) -> anyhow::Result<usize>;
+    fn list(&mut self) -> anyhow::Result<Vec<(FileName, u64)>>;
+    /// Everything stored, partial uploads included
+    fn used_bytes(&mut self) -> anyhow::Result<u64>;
+}
+
+/// Keeps the files of a session in its own directory.
+#[derive(Debug)]
+pub struct StagingDirSink {
+    root: PathBuf,
+}
+
+impl StagingDirSink {
+    pub fn new(root: PathBuf) -> anyhow::Result<Self> {
+        fs::create_dir_all(root.join(PARTIAL_DIR))
+            .with_context(|| format!("Failed to create staging directory {:?}", root))?;
+        Ok(Self { root })
+    }
+
+    fn path(&self, name: &FileName) -> PathBuf {
+        self.root.join(name.as_str())
+    }
+
+    fn partial_path(&self, name: &FileName) -> PathBuf {
+        self.root.join(PARTIAL_DIR).join(name.as_str())
+    }
+
+    // Symlinks planted in the staging directory are not followed
+    fn open(path: &PathBuf, write: bool) -> io::Result<File> {
+        OpenOptions::new()
+            .read(!write)
+            .write(write)
+            .create(write)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)
+    }
+
+    fn read_at(path: &PathBuf, offset: u64, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let mut file = Self::open(path, false)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut read = 0;
+        while read < buf.len() {
+            match file.read(&mut buf[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        Ok(read)
+    }
+
+    fn dir_size(path: &PathBuf) -> anyhow::Result<u64> {
+        let mut total = 0;
+        for entry in fs::read_dir(path)? {
+            let metadata = entry?.metadata()?;
+            if metadata.is_file() {
+                total += metadata.len();
+            }
+        }
+        Ok(total)
+    }
+}
+
+impl FileSink for StagingDirSink {
+    fn partial_size(&mut self, name: &FileName) -> anyhow::Result<u64> {
+        match fs::symlink_metadata(self.partial_path(name)) {
+            Ok(metadata) if metadata.is_file() => Ok(metadata.len()),
+            Ok(_) => Err(anyhow!("{} is not a regular file", name.as_str())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write_partial(&mut self, name: &FileName, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        let mut file = Self::open(&self.partial_path(name), true)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        Ok(())
+    }
+
+    fn read_partial(
+        &mut self,
+        name: &FileName,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> anyhow::Result<usize> {
+        Self::read_at(&self.partial_path(name), offset, buf)
+    }
+
+    fn discard_partial(&mut self, name: &FileName) -> anyhow::Result<()> {
+        match fs::remove_file(self.partial_path(name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn commit(&mut self, name: &FileName) -> anyhow::Result<()> {
+        fs::rename(self.partial_path(name), self.path(name))?;
+        Ok(())
+    }
+
+    fn size(&mut self, name: &FileName) -> anyhow::Result<u64> {
+        let metadata = fs::symlink_metadata(self.path(name))?;
+        if !metadata.is_file() {
+            return Err(anyhow!("{} is not a regular file", name.as_str()));
+        }
+        Ok(metadata.len())
+    }
+
+    fn read(&mut self, name: &FileName, offset: u64, buf: &mut [u8]) -> anyhow::Result<usize> {
+        Self::read_at(&self.path(name), offset, buf)
+    }
+
+    fn list(&mut self) -> anyhow::Result<Vec<(FileName, u64)>> {
+        let mut files = vec![];
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            if let Some(name) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| FileName::new(name).ok())
+            {
+                files.push((name, metadata.len()));
+            }
+        }
+        files.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+        Ok(files)
+    }
+
+    fn used_bytes(&mut self) -> anyhow::Result<u64> {
+        Ok(Self::dir_size(&self.root)? + Self::dir_size(&self.root.join(PARTIAL_DIR))?)
+    }
+}
+
+#[derive(Debug)]
+struct Upload {
+    size: u64,
+    sha256: Vec<u8>,
+}
+
+/// File transfers of one session. The protocol is stateless enough that a
+/// client can pick up an interrupted transfer after reconnecting.
+#[derive(Debug)]
+pub struct FileTransfers {
+    sink: Box<dyn FileSink>,
+    quota: u64,
+    uploads: HashMap<FileName, Upload>,
+}
+
+impl FileTransfers {
+    pub fn new(sink: Box<dyn FileSink>, quota: u64) -> Self {
+        Self {
+            sink,
+            quota,
+            uploads: HashMap::new(),
+        }
+    }
+
+    /// One staging directory per client address, so a client that reconnects
+    /// finds its interrupted uploads again.
+    pub fn for_session(cfg: &FileTransferConfig, session: &str) -> anyhow::Result<Self> {
+        let sink = StagingDirSink::new(cfg.staging_dir.join(session))?;
+        Ok(Self::new(Box::new(sink), cfg.quota))
+    }
+
+    // Of the finished file, or of the partial upload if `partial`
+    fn checksum(&mut self, name: &FileName, size: u64, partial: bool) -> anyhow::Result<Vec<u8>> {
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; MAX_FILE_CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < size {
+            let read = if partial {
+                self.sink.read_partial(name, offset, &mut buf)?
+            } else {
+                self.sink.read(name, offset, &mut buf)?
+            };
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+            offset += read as u64;
+        }
+        Ok(hasher.finalize().to_vec())
+    }
+
+    pub fn upload_start(
+        &mut self,
+        info: EdcsFileInfo,
+    ) -> Result<EdcsFileTransferProgress, FileTransferError> {
+        let name = FileName::new(&info.name)?;
+        if info.sha256.len() != 32 {
+            return Err(FileTransferError::Io(anyhow!(
+                "The checksum has to be a SHA-256 digest"
+            )));
+        }
+
+        let mut offset = self.sink.partial_size(&name)?;
+        let same_upload = match self.uploads.get(&name) {
+            Some(upload) => upload.size == info.size && upload.sha256 == info.sha256,
+            // EDCS restarted, the checksum on finish will catch a different file
+            None => offset <= info.size,
+        };
+        if !same_upload {
+            debug!("Restarting upload of {}", name.as_str());
+            self.sink.discard_partial(&name)?;
+            offset = 0;
+        }
+
+        let used = self.sink.used_bytes()?;
+        let needed = info.size - offset;
+        let available = self.quota.saturating_sub(used);
+        if needed > available {
+            return Err(FileTransferError::QuotaExceeded { needed, available });
+        }
+
+        info!(
+            "Receiving {} ({} bytes) from offset {}",
+            name.as_str(),
+            info.size,
+            offset
+        );
+        self.uploads.insert(
+            name,
+            Upload {
+                size: info.size,
+                sha256: info.sha256,
+            },
+        );
+        Ok(EdcsFileTransferProgress {
+            name: info.name,
+            offset,
+            size: info.size,
+        })
+    }
+
+    pub fn upload_chunk(
+        &mut self,
+        chunk: EdcsFileChunk,
+    ) -> Result<EdcsFileTransferProgress, FileTransferError> {
+        let name = FileName::new(&chunk.name)?;
+        let size = match self.uploads.get(&name) {
+            Some(upload) => upload.size,
+            None => {
+                return Err(FileTransferError::Io(anyhow!(
+                    "No upload of {} was started",
+                    name.as_str()
+                )))
+            }
+        };
+        if chunk.data.len() > MAX_FILE_CHUNK_SIZE {
+            return Err(FileTransferError::Io(anyhow!(
+                "Chunks can be at most {} bytes",
+                MAX_FILE_CHUNK_SIZE
+            )));
+        }
+        // Chunks come in order, anything else means the client lost track
+        let offset = self.sink.partial_size(&name)?;
+        if chunk.offset != offset {
+            return Err(FileTransferError::Io(anyhow!(
+                "Expected a chunk at offset {}, got {}",
+                offset,
+                chunk.offset
+            )));
+        }
+        let end = offset + chunk.data.len() as u64;
+        if end > size {
+            return Err(FileTransferError::Io(anyhow!(
+                "The chunk goes past the end of {}",
+                name.as_str()
+            )));
+        }
+
+        // Checked again here since several uploads can be going at once
+        let available = self.quota.saturating_sub(self.sink.used_bytes()?);
+        if chunk.data.len() as u64 > available {
+            return Err(FileTransferError::QuotaExceeded {
+                needed: chunk.data.len() as u64,
+                available,
+            });
+        }
+
+        self.sink.write_partial(&name, offset, &chunk.data)?;
+        Ok(EdcsFileTransferProgress {
+            name: chunk.name,
+            offset: end,
+            size,
+        })
+    }
+
+    pub fn upload_finish(
+        &mut self,
+        name: String,
+    ) -> Result<EdcsFileTransferProgress, FileTransferError> {
+        let name = FileName::new(&name)?;
+        let upload = match self.uploads.get(&name) {
+            Some(upload) => upload,
+            None => {
+                return Err(FileTransferError::Io(anyhow!(
+                    "No upload of {} was started",
+                    name.as_str()
+                )))
+            }
+        };
+        let (size, expected) = (upload.size, upload.sha256.clone());
+        if self.sink.partial_size(&name)? != size {
+            return Err(FileTransferError::Io(anyhow!(
+                "The upload of {} is not complete",
+                name.as_str()
+            )));
+        }
+
+        // Checked before the commit, so a bad upload never replaces a good file
+        if self.checksum(&name, size, true)? != expected {
+            self.uploads.remove(&name);
+            self.sink.discard_partial(&name)?;
+            return Err(FileTransferError::ChecksumMismatch(name.0));
+        }
+        self.sink.commit(&name)?;
+        self.uploads.remove(&name);
+        info!("Received {}", name.as_str());
+        Ok(EdcsFileTransferProgress {
+            name: name.0,
+            offset: size,
+            size,
+        })
+    }
+
+    pub fn list(&mut self) -> Result<EdcsFileList, FileTransferError> {
+        Ok(EdcsFileList {
+            files: self
+                .sink
+                .list()?
+                .into_iter()
+                .map(|(name, size)| EdcsFileInfo {
+                    name: name.0,
+                    size,
+                    sha256: vec![],
+                })
+                .collect(),
+        })
+    }
+
+    pub fn download_start(&mut self, name: String) -> Result<EdcsFileInfo, FileTransferError> {
+        let name = FileName::new(&name)?;
+        let size = self.sink.size(&name)?;
+        let sha256 = self.checksum(&name, size, false)?;
+        Ok(EdcsFileInfo {
+            name: name.0,
+            size,
+            sha256,
+        })
+    }
+
+    pub fn download_chunk(
+        &mut self,
+        req: EdcsFileChunkRequest,
+    ) -> Result<EdcsFileChunk, FileTransferError> {
+        let name = FileName::new(&req.name)?;
+        let mut data = vec![0; (req.length as usize).min(MAX_FILE_CHUNK_SIZE)];
+        let read = self.sink.read(&name, req.offset, &mut data)?;
+        data.truncate(read);
+        Ok(EdcsFileChunk {
+            name: req.name,
+            offset: req.offset,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+    // A staging directory of its own, removed again afterwards
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "edcs-file-transfer-{}-{}",
+                std::process::id(),
+                NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+            ));
+            let _ = fs::remove_dir_all(&path);
+            TempDir(path)
+        }
+
+        fn transfers(&self, quota: u64) -> FileTransfers {
+            FileTransfers::new(
+                Box::new(StagingDirSink::new(self.0.clone()).unwrap()),
+                quota,
+            )
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn upload(
+        transfers: &mut FileTransfers,
+        name: &str,
+        data: &[u8],
+        sha256: Vec<u8>,
+    ) -> Result<EdcsFileTransferProgress, FileTransferError> {
+        transfers.upload_start(EdcsFileInfo {
+            name: name.to_string(),
+            size: data.len() as u64,
+            sha256,
+        })?;
+        transfers.upload_chunk(EdcsFileChunk {
+            name: name.to_string(),
+            offset: 0,
+            data: data.to_vec(),
+        })?;
+        transfers.upload_finish(name.to_string())
+    }
+
+    #[test]
+    fn uploads_and_downloads() {
+        let dir = TempDir::new();
+        let mut transfers = dir.transfers(1024);
+        let sha256 = Sha256::digest(b"hello").to_vec();
+        upload(&mut transfers, "hello.txt", b"hello", sha256.clone()).unwrap();
+
+        let info = transfers.download_start("hello.txt".to_string()).unwrap();
+        assert_eq!((info.size, info.sha256), (5, sha256));
+        let chunk = transfers
+            .download_chunk(EdcsFileChunkRequest {
+                name: "hello.txt".to_string(),
+                offset: 1,
+                length: 100,
+            })
+            .unwrap();
+        assert_eq!(chunk.data, b"ello");
+    }
+
+    #[test]
+    fn a_bad_upload_leaves_the_old_file() {
+        let dir = TempDir::new();
+        let mut transfers = dir.transfers(1024);
+        upload(
+            &mut transfers,
+            "a.txt",
+            b"good",
+            Sha256::digest(b"good").to_vec(),
+        )
+        .unwrap();
+
+        let result = upload(
+            &mut transfers,
+            "a.txt",
+            b"evil",
+            Sha256::digest(b"good").to_vec(),
+        );
+        assert!(matches!(
+            result,
+            Err(FileTransferError::ChecksumMismatch(_))
+        ));
+        assert_eq!(fs::read(dir.0.join("a.txt")).unwrap(), b"good");
+        assert!(!dir.0.join(PARTIAL_DIR).join("a.txt").exists());
+    }
+
+    #[test]
+    fn resumes_after_reconnecting() {
+        let dir = TempDir::new();
+        let info = EdcsFileInfo {
+            name: "big.bin".to_string(),
+            size: 6,
+            sha256: Sha256::digest(b"abcdef").to_vec(),
+        };
+        let mut transfers = dir.transfers(1024);
+        transfers.upload_start(info.clone()).unwrap();
+        transfers
+            .upload_chunk(EdcsFileChunk {
+                name: "big.bin".to_string(),
+                offset: 0,
+                data: b"abc".to_vec(),
+            })
+            .unwrap();
+
+        // A new connection starts out without the upload
+        let mut transfers = dir.transfers(1024);
+        assert_eq!(transfers.upload_start(info).unwrap().offset, 3);
+        transfers
+            .upload_chunk(EdcsFileChunk {
+                name: "big.bin".to_string(),
+                offset: 3,
+                data: b"def".to_vec(),
+            })
+            .unwrap();
+        transfers.upload_finish("big.bin".to_string()).unwrap();
+        assert_eq!(fs::read(dir.0.join("big.bin")).unwrap(), b"abcdef");
+    }
+
+    #[test]
+    fn enforces_the_quota() {
+        let dir = TempDir::new();
+        let mut transfers = dir.transfers(4);
+        let result = upload(
+            &mut transfers,
+            "big.bin",
+            b"too big",
+            Sha256::digest(b"too big").to_vec(),
+        );
+        assert!(matches!(
+            result,
+            Err(FileTransferError::QuotaExceeded {
+                needed: 7,
+                available: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn refuses_names_outside_the_directory() {
+        for name in ["", "../etc/passwd", "a/b", ".partial", "a\0b", "a\nb"] {
+            assert!(FileName::new(name).is_err(), "{:?}", name);
+        }
+        assert!(FileName::new(&"a".repeat(MAX_FILE_NAME_LEN + 1)).is_err());
+        assert!(FileName::new("report.pdf").is_ok());
+    }
+}
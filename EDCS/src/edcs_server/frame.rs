@@ -0,0 +1,101 @@
+//! Every message on the wire is a length delimiter, padded to
+//! `DELIMITER_LEN` bytes, followed by the protobuf. The length is whatever
+//! the peer says it is, so it is checked before anything is allocated for it.
+
+use std::fmt;
+
+use prost::{decode_length_delimiter, DecodeError};
+
+pub const DELIMITER_LEN: usize = 10;
+
+#[derive(Debug)]
+pub enum FrameError {
+    Delimiter(DecodeError),
+    TooLarge { len: usize, max: usize },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Delimiter(e) => write!(f, "invalid length delimiter: {}", e),
+            FrameError::TooLarge { len, max } => {
+                write!(f, "message of {} bytes is over the limit of {}", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::Delimiter(e) => Some(e),
+            FrameError::TooLarge { .. } => None,
+        }
+    }
+}
+
+/// The length of the message that follows `delimiter`, if it's at most
+/// `max_len` bytes.
+pub fn message_len(delimiter: &[u8], max_len: usize) -> Result<usize, FrameError> {
+    let len = decode_length_delimiter(delimiter).map_err(FrameError::Delimiter)?;
+    if len > max_len {
+        return Err(FrameError::TooLarge { len, max: max_len });
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::encode_length_delimiter;
+
+    use super::*;
+
+    // A delimiter the way peers send it, padded with zeros
+    fn delimiter(len: usize) -> [u8; DELIMITER_LEN] {
+        let mut delimiter = [0; DELIMITER_LEN];
+        encode_length_delimiter(len, &mut &mut delimiter[..]).unwrap();
+        delimiter
+    }
+
+    #[test]
+    fn a_message_of_exactly_the_max_is_accepted() {
+        assert_eq!(message_len(&delimiter(4096), 4096).unwrap(), 4096);
+        assert_eq!(message_len(&delimiter(0), 4096).unwrap(), 0);
+    }
+
+    #[test]
+    fn a_message_one_over_the_max_is_rejected() {
+        assert!(matches!(
+            message_len(&delimiter(4097), 4096),
+            Err(FrameError::TooLarge {
+                len: 4097,
+                max: 4096
+            })
+        ));
+    }
+
+    #[test]
+    fn malformed_delimiters_are_rejected() {
+        // Every byte says another one follows
+        assert!(matches!(
+            message_len(&[0xff; DELIMITER_LEN], usize::MAX),
+            Err(FrameError::Delimiter(_))
+        ));
+        // Over the 64 bits a varint can hold
+        let mut overlong = [0xff; DELIMITER_LEN];
+        overlong[DELIMITER_LEN - 1] = 0x7f;
+        assert!(matches!(
+            message_len(&overlong, usize::MAX),
+            Err(FrameError::Delimiter(_))
+        ));
+        // Cut off in the middle
+        assert!(matches!(
+            message_len(&[0x80, 0x80], usize::MAX),
+            Err(FrameError::Delimiter(_))
+        ));
+        assert!(matches!(
+            message_len(&[], usize::MAX),
+            Err(FrameError::Delimiter(_))
+        ));
+    }
+}
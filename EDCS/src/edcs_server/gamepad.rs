@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+
+use input_event_codes::*;
+use log::{debug, info};
+
+use super::edcs_proto::{edcs_gamepad_event, EdcsGamepadAxis, EdcsGamepadButton, EdcsGamepadEvent};
+use evdev_proxy::uinput::{AbsAxis, DeviceId, UinputDevice};
+
+pub const MAX_GAMEPAD_SLOTS: u32 = 4;
+
+// Pretend to be an Xbox 360 pad, since that's what most games have a mapping for
+const GAMEPAD_ID: DeviceId = DeviceId {
+    vendor: 0x045e,
+    product: 0x028e,
+};
+const STICK_MAX: i32 = 32767;
+const TRIGGER_MAX: i32 = 255;
+
+#[derive(Debug)]
+pub enum GamepadError {
+    SlotUnavailable(u32),
+    // Unknown buttons or axes and values that aren't numbers
+    InvalidRequest(String),
+    Device(anyhow::Error),
+}
+
+/// One virtual gamepad. The D-pad is reported as a hat like xpad does, so the
+/// pressed directions have to be remembered.
+#[derive(Debug)]
+struct VirtualGamepad {
+    device: UinputDevice,
+    // up, down, left, right
+    dpad: [bool; 4],
+}
+
+impl VirtualGamepad {
+    fn new(slot: u32, name: &str) -> anyhow::Result<VirtualGamepad> {
+        let stick = |code| AbsAxis {
+            code,
+            min: -STICK_MAX - 1,
+            max: STICK_MAX,
+            flat: 128,
+        };
+        let trigger = |code| AbsAxis {
+            code,
+            min: 0,
+            max: TRIGGER_MAX,
+            flat: 0,
+        };
+        let hat = |code| AbsAxis {
+            code,
+            min: -1,
+            max: 1,
+            flat: 0,
+        };
+        let device = UinputDevice::create(
+            &format!("EDCS Gamepad {} ({})", slot, name),
+            GAMEPAD_ID,
+            &[
+                BTN_SOUTH!(),
+                BTN_EAST!(),
+                BTN_NORTH!(),
+                BTN_WEST!(),
+                BTN_TL!(),
+                BTN_TR!(),
+                BTN_SELECT!(),
+                BTN_START!(),
+                BTN_MODE!(),
+                BTN_THUMBL!(),
+                BTN_THUMBR!(),
+            ],
+            &[],
+            &[
+                stick(ABS_X!()),
+                stick(ABS_Y!()),
+                stick(ABS_RX!()),
+                stick(ABS_RY!()),
+                trigger(ABS_Z!()),
+                trigger(ABS_RZ!()),
+                hat(ABS_HAT0X!()),
+                hat(ABS_HAT0Y!()),
+            ],
+        )?;
+        Ok(VirtualGamepad {
+            device,
+            dpad: [false; 4],
+        })
+    }
+
+    fn button(&mut self, button: EdcsGamepadButton, pressed: bool) -> anyhow::Result<()> {
+        let code = match button {
+            EdcsGamepadButton::GamepadButtonSouth => BTN_SOUTH!(),
+            EdcsGamepadButton::GamepadButtonEast => BTN_EAST!(),
+            EdcsGamepadButton::GamepadButtonNorth => BTN_NORTH!(),
+            EdcsGamepadButton::GamepadButtonWest => BTN_WEST!(),
+            EdcsGamepadButton::GamepadButtonLeftBumper => BTN_TL!(),
+            EdcsGamepadButton::GamepadButtonRightBumper => BTN_TR!(),
+            EdcsGamepadButton::GamepadButtonSelect => BTN_SELECT!(),
+            EdcsGamepadButton::GamepadButtonStart => BTN_START!(),
+            EdcsGamepadButton::GamepadButtonMode => BTN_MODE!(),
+            EdcsGamepadButton::GamepadButtonLeftThumb => BTN_THUMBL!(),
+            EdcsGamepadButton::GamepadButtonRightThumb => BTN_THUMBR!(),
+            EdcsGamepadButton::GamepadButtonDpadUp => return self.dpad(0, pressed),
+            EdcsGamepadButton::GamepadButtonDpadDown => return self.dpad(1, pressed),
+            EdcsGamepadButton::GamepadButtonDpadLeft => return self.dpad(2, pressed),
+            EdcsGamepadButton::GamepadButtonDpadRight => return self.dpad(3, pressed),
+        };
+        self.device.emit(EV_KEY!(), code, pressed as i32)?;
+        self.device.sync()?;
+        Ok(())
+    }
+
+    // `index` is into `self.dpad`
+    fn dpad(&mut self, index: usize, pressed: bool) -> anyhow::Result<()> {
+        self.dpad[index] = pressed;
+        let [up, down, left, right] = self.dpad;
+        let hat_y = down as i32 - up as i32;
+        let hat_x = right as i32 - left as i32;
+        self.device.emit(EV_ABS!(), ABS_HAT0X!(), hat_x)?;
+        self.device.emit(EV_ABS!(), ABS_HAT0Y!(), hat_y)?;
+        self.device.sync()?;
+        Ok(())
+    }
+
+    fn axis(&mut self, axis: EdcsGamepadAxis, value: f64) -> anyhow::Result<()> {
+        let stick = |v: f64| (v.clamp(-1.0, 1.0) * STICK_MAX as f64).round() as i32;
+        let trigger = |v: f64| (v.clamp(0.0, 1.0) * TRIGGER_MAX as f64).round() as i32;
+        let (code, value) = match axis {
+            EdcsGamepadAxis::GamepadAxisLeftX => (ABS_X!(), stick(value)),
+            EdcsGamepadAxis::GamepadAxisLeftY => (ABS_Y!(), stick(value)),
+            EdcsGamepadAxis::GamepadAxisRightX => (ABS_RX!(), stick(value)),
+            EdcsGamepadAxis::GamepadAxisRightY => (ABS_RY!(), stick(value)),
+            EdcsGamepadAxis::GamepadAxisLeftTrigger => (ABS_Z!(), trigger(value)),
+            EdcsGamepadAxis::GamepadAxisRightTrigger => (ABS_RZ!(), trigger(value)),
+        };
+        self.device.emit(EV_ABS!(), code, value)?;
+        self.device.sync()?;
+        Ok(())
+    }
+}
+
+/// The virtual gamepads of a session, one uinput device per client slot.
+#[derive(Debug, Default)]
+pub struct GamepadSlots {
+    slots: HashMap<u32, VirtualGamepad>,
+}
+
+impl GamepadSlots {
+    pub fn handle_event(&mut self, ev: EdcsGamepadEvent) -> Result<(), GamepadError> {
+        if ev.slot >= MAX_GAMEPAD_SLOTS {
+            return Err(GamepadError::SlotUnavailable(ev.slot));
+        }
+        match ev.payload {
+            Some(edcs_gamepad_event::Payload::Connected(connected)) => {
+                // A reconnect on the same slot replaces the old device
+                self.slots.remove(&ev.slot);
+                let pad =
+                    VirtualGamepad::new(ev.slot, &connected.name).map_err(GamepadError::Device)?;
+                info!(
+                    "Created virtual gamepad for slot {} ({})",
+                    ev.slot, connected.name
+                );
+                self.slots.insert(ev.slot, pad);
+                Ok(())
+            }
+            Some(edcs_gamepad_event::Payload::Disconnected(_)) => {
+                if self.slots.remove(&ev.slot).is_some() {
+                    info!("Removed virtual gamepad for slot {}", ev.slot);
+                }
+                Ok(())
+            }
+            Some(edcs_gamepad_event::Payload::Button(button)) => {
+                // button() would turn unknown values into the first button
+                let button_typ = EdcsGamepadButton::from_i32(button.button).ok_or_else(|| {
+                    GamepadError::InvalidRequest(format!(
+                        "{} is not a gamepad button",
+                        button.button
+                    ))
+                })?;
+                self.slot(ev.slot)?
+                    .button(button_typ, button.pressed)
+                    .map_err(GamepadError::Device)
+            }
+            Some(edcs_gamepad_event::Payload::Axis(axis)) => {
+                let axis_typ = EdcsGamepadAxis::from_i32(axis.axis).ok_or_else(|| {
+                    GamepadError::InvalidRequest(format!("{} is not a gamepad axis", axis.axis))
+                })?;
+                if !axis.value.is_finite() {
+                    return Err(GamepadError::InvalidRequest(format!(
+                        "Gamepad axis value {} is not finite",
+                        axis.value
+                    )));
+                }
+                self.slot(ev.slot)?
+                    .axis(axis_typ, axis.value)
+                    .map_err(GamepadError::Device)
+            }
+            None => Err(GamepadError::InvalidRequest(format!(
+                "Gamepad event for slot {} has no payload",
+                ev.slot
+            ))),
+        }
+    }
+
+    fn slot(&mut self, slot: u32) -> Result<&mut VirtualGamepad, GamepadError> {
+        self.slots
+            .get_mut(&slot)
+            .ok_or(GamepadError::SlotUnavailable(slot))
+    }
+
+    pub fn clear(&mut self) {
+        if !self.slots.is_empty() {
+            debug!("Removing {} virtual gamepads", self.slots.len());
+        }
+        self.slots.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edcs_server::edcs_proto::{EdcsGamepadAxisEvent, EdcsGamepadButtonEvent};
+
+    fn handle(payload: Option<edcs_gamepad_event::Payload>) -> Result<(), GamepadError> {
+        GamepadSlots::default().handle_event(EdcsGamepadEvent { slot: 0, payload })
+    }
+
+    // These are checked before the slot, so no uinput device is needed
+    #[test]
+    fn rejects_unknown_buttons_and_axes() {
+        let button = |button| {
+            handle(Some(edcs_gamepad_event::Payload::Button(
+                EdcsGamepadButtonEvent {
+                    button,
+                    pressed: true,
+                },
+            )))
+        };
+        let axis = |axis, value| {
+            handle(Some(edcs_gamepad_event::Payload::Axis(
+                EdcsGamepadAxisEvent { axis, value },
+            )))
+        };
+
+        assert!(matches!(button(-1), Err(GamepadError::InvalidRequest(_))));
+        assert!(matches!(button(1000), Err(GamepadError::InvalidRequest(_))));
+        assert!(matches!(
+            axis(1000, 0.0),
+            Err(GamepadError::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            axis(EdcsGamepadAxis::GamepadAxisLeftX as i32, f64::NAN),
+            Err(GamepadError::InvalidRequest(_))
+        ));
+        assert!(matches!(handle(None), Err(GamepadError::InvalidRequest(_))));
+        // Valid ones get as far as the missing slot
+        assert!(matches!(
+            button(EdcsGamepadButton::GamepadButtonDpadRight as i32),
+            Err(GamepadError::SlotUnavailable(0))
+        ));
+    }
+}
@@ -1,201 +1,489 @@
+use std::collections::HashMap;
+use std::future::Future;
 use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use log::{debug, info};
+use log::{debug, info, warn};
+use parking_lot::Mutex;
 
+use super::audio;
+use super::audit::{AuditEvent, AuditLog};
+use super::auth::{self, Authenticator, Identity};
+use super::cal_options;
+use super::clipboard::{ClipboardError, ClipboardTracker, SharedClipboard};
 use super::config::{self, EdcsConfig};
+use super::cursor::CursorTracker;
 use super::edcs_proto::{
-    edcs_message, edcs_response, EdcsMessage, EdcsMessageType, EdcsResponse, EdcsSetupEdcsData,
+    edcs_message, edcs_response, EdcsAuthData, EdcsFileChunk, EdcsFileChunkRequest, EdcsFileInfo,
+    EdcsInviteList, EdcsMessage, EdcsMessageType, EdcsResponse, EdcsServerEvent, EdcsSetupEdcsData,
     EdcsSetupStreamData, EdcsStatus, EdcsStreamParams,
 };
-use crate::edss_safe::edss::EdssAdapter;
+use super::error::{self, EdcsError};
+use super::file_transfer::{FileTransferError, FileTransfers};
+use super::gamepad::{GamepadError, GamepadSlots};
+use super::invites::Invites;
+use super::session::{self, ConnectionId, ControlTracker, Left, Session};
+use super::stream_actor::{self, OpenParams, OpenStream, StreamActor, StreamInfo};
+use crate::edss_safe::edss::EdssError;
+use crate::edss_safe::stream_state::{StreamEvent, StreamState};
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
+struct Stream {
+    // Tells apart the StreamDones of an earlier stream
+    id: u64,
+    // Who sent SETUP_EDCS, the session only has an owner once it's open
+    opened_by: ConnectionId,
+    actor: StreamActor,
+    info: StreamInfo,
+}
+
+/// What became of a job on the stream actor.
+enum StreamOutcome {
+    Opened {
+        bitrate: u32,
+        framerate: u32,
+        result: Result<StreamInfo, EdssError>,
+    },
+    ServerInit(Result<StreamInfo, EdssError>),
+    Started(Result<StreamInfo, EdssError>),
+    Closed(Result<(), EdssError>),
+    Input(Result<(), EdssError>),
+    Joined(Result<(u32, String), EdssError>),
+}
+
+pub struct StreamDone {
+    stream_id: u64,
+    connection: ConnectionId,
+    identity: Option<Identity>,
+    outcome: StreamOutcome,
+}
+
+/// What became of a file transfer request.
+pub struct FileDone {
+    connection: ConnectionId,
+    message_type: EdcsMessageType,
+    result: Result<edcs_response::Payload, FileTransferError>,
+}
+
+pub enum Handled {
+    Response(Option<EdcsResponse>),
+    /// Waits on the stream actor, the response comes from
+    /// `EdcsHandler::stream_done`. Nothing is locked in the meantime.
+    Stream(Pin<Box<dyn Future<Output = StreamDone> + Send>>),
+    /// Runs on a blocking thread, since it reads and hashes whole files. The
+    /// response comes from `EdcsHandler::file_done`.
+    File(Pin<Box<dyn Future<Output = FileDone> + Send>>),
+}
+
+// Opened by the first request, on the blocking thread like the rest
+type FileTransferSlot = Arc<Mutex<Option<FileTransfers>>>;
+
+#[derive(Debug)]
 pub struct EdcsHandler {
-    adapter: Option<EdssAdapter>,
+    // The stream runs in its own process, driven from its own thread, see
+    // stream_actor.rs
+    stream: Option<Stream>,
+    last_stream_id: u64,
+    gamepads: GamepadSlots,
+    clipboard: SharedClipboard,
+    // Per connection, the files are kept per client address though, see
+    // FileTransfers::for_session
+    file_transfers: HashMap<ConnectionId, FileTransferSlot>,
+    auth: Authenticator,
+    session: Session,
+    invites: Invites,
+    audit: AuditLog,
+    // Starts a worker, the tests open a stub instead
+    open_stream: OpenStream,
 }
 
 impl EdcsHandler {
+    pub fn new(cfg: &EdcsConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            stream: None,
+            last_stream_id: 0,
+            gamepads: GamepadSlots::default(),
+            clipboard: SharedClipboard::from_config(&cfg.clipboard)?,
+            file_transfers: HashMap::new(),
+            auth: Authenticator::from_config(cfg.auth.as_ref())?,
+            session: Session::default(),
+            invites: Invites::default(),
+            audit: AuditLog::from_config(cfg.audit.as_ref(), &cfg.edss_config.plugin_name)?,
+            open_stream: stream_actor::open_worker,
+        })
+    }
+
+    pub fn new_connection(&mut self, peer: SocketAddr) -> ConnectionId {
+        let connection = self.session.new_connection_id();
+        self.audit.connected(connection, peer);
+        connection
+    }
+
+    // Who a new connection is until it sends AUTHENTICATE
+    pub fn anonymous_identity(&self) -> Option<Identity> {
+        self.auth.anonymous()
+    }
+
     pub fn handle_message(
         &mut self,
         cfg: Arc<config::EdcsConfig>,
         msg: EdcsMessage,
         addr: SocketAddr,
-        // Some of the events (eg. keyboard/mouse) won't return a response since we don't want to waste time sending back thousands of "Ok" messages
-        // In the future if we want to find failed K/M events we can create a separate request for that
-    ) -> anyhow::Result<Option<EdcsResponse>> {
+        connection: ConnectionId,
+        // The connection's, AUTHENTICATE replaces it
+        identity: &mut Option<Identity>,
+    ) -> Result<Handled, EdcsError> {
         // Handle the input
 
         let mut response_payload = None;
         let mut edcs_status = EdcsStatus::Ok;
 
+        if let Err(e) = auth::check_permission(identity.as_ref(), msg.message_type()) {
+            debug!("Denied {:?} from {}: {}", msg.message_type(), addr, e);
+            return Err(EdcsError::State(EdcsStatus::PermissionDenied, e));
+        }
+
+        if self.stream.is_some() {
+            // Viewers only close their own part of the stream
+            if msg.message_type() == EdcsMessageType::CloseStream
+                && self.session.is_participant(connection)
+                && !self.session.is_owner(connection)
+            {
+                self.disconnect(connection);
+                return Ok(Handled::Response(Some(error::response(
+                    EdcsStatus::Ok,
+                    None,
+                ))));
+            }
+            if matches!(
+                msg.message_type(),
+                EdcsMessageType::SetupStream
+                    | EdcsMessageType::StartStream
+                    | EdcsMessageType::CloseStream
+                    | EdcsMessageType::UpdateStream
+            ) && !self.session.is_owner(connection)
+            {
+                return Err(EdcsError::State(
+                    EdcsStatus::PermissionDenied,
+                    "Only the participant that set the stream up can change it".to_string(),
+                ));
+            }
+            if session::is_input(msg.message_type()) && !self.session.has_control(connection) {
+                return Err(EdcsError::State(
+                    EdcsStatus::NotInControl,
+                    "Another participant holds input control".to_string(),
+                ));
+            }
+            self.audit.count_input(connection, msg.message_type());
+        }
+
         match msg.message_type() {
+            EdcsMessageType::Authenticate => {
+                let token = match msg.payload {
+                    Some(edcs_message::Payload::AuthenticateToken(token)) => token,
+                    _ => {
+                        return Err(EdcsError::Protocol(
+                            EdcsStatus::InvalidRequest,
+                            "The given payload is not of type AuthenticateToken".to_string(),
+                        ))
+                    }
+                };
+                let authenticated = self
+                    .auth
+                    .authenticate(&token)
+                    .or_else(|| self.invites.redeem(&token, SystemTime::now()));
+                match authenticated {
+                    Some(authenticated) => {
+                        match &authenticated.invite {
+                            Some(invite) => info!(
+                                "{} used invite {} and is {} with role {:?}",
+                                addr, invite, authenticated.name, authenticated.role
+                            ),
+                            None => info!(
+                                "{} authenticated as {} with role {:?}",
+                                addr, authenticated.name, authenticated.role
+                            ),
+                        }
+                        response_payload = Some(edcs_response::Payload::AuthData(EdcsAuthData {
+                            identity: authenticated.name.clone(),
+                            role: authenticated.role.to_proto() as i32,
+                        }));
+                        self.session.set_identity(connection, &authenticated);
+                        self.audit.authenticated(connection, &authenticated);
+                        *identity = Some(authenticated);
+                    }
+                    None => {
+                        warn!("{} sent an unknown token", addr);
+                        edcs_status = EdcsStatus::PermissionDenied;
+                        response_payload = Some(edcs_response::Payload::GenericErrData(
+                            "Unknown token".to_string(),
+                        ));
+                    }
+                }
+            }
             EdcsMessageType::SetupEdcs => {
                 // Initialize EDSS, don't start stream.
 
-                match self.adapter {
-                    None => {
+                match self.state().transition(StreamEvent::OpenCal) {
+                    // Invites are for the stream that exists, not for setting up another one
+                    Ok(_) if matches!(identity, Some(Identity { invite: Some(_), .. })) => {
+                        edcs_status = EdcsStatus::PermissionDenied;
+                        response_payload = Some(edcs_response::Payload::GenericErrData(
+                            "The stream this invite was for has ended".to_string(),
+                        ));
+                    }
+                    Ok(cal_opened) => {
                         debug!("HANDLER Setting up EDCS.");
                         let stream_params = match msg.payload {
                             Some(edcs_message::Payload::SetupEdcsParams(p)) => p,
                             _ => {
-                                return Ok(Some(EdcsResponse {
-                                    status: EdcsStatus::InvalidRequest as i32,
-                                    payload: Some(edcs_response::Payload::InvalidRequestData(
-                                        "The given payload is not of type SetupEdcsParams"
-                                            .to_string(),
-                                    )),
-                                }))
+                                return Err(EdcsError::Protocol(
+                                    EdcsStatus::InvalidRequest,
+                                    "The given payload is not of type SetupEdcsParams".to_string(),
+                                ))
                             }
                         };
 
                         // TODO autogenerate a random key and return it through the response.
-                        match EdssAdapter::new(
-                            cfg.edss_config.plugin_name.clone(),
+                        let (bitrate, framerate) = (stream_params.bitrate, stream_params.framerate);
+                        let params = OpenParams {
+                            worker_path: cfg.edss_config.worker_binary(),
+                            plugin_name: cfg.edss_config.plugin_name.clone(),
                             addr,
-                            cfg.edss_config.port,
-                            stream_params.bitrate,
-                            stream_params.framerate,
-                        ) {
-                            Ok(adapter) => {
-                                self.adapter = Some(adapter);
-                                response_payload = Some(edcs_response::Payload::SetupEdcsData(
-                                    EdcsSetupEdcsData {
-                                        cal_option_dict: self
-                                            .adapter
-                                            .as_ref()
-                                            .unwrap()
-                                            .cal_option_dict
-                                            .clone(), // unwrap will never fail here
-                                    },
-                                ));
-                            }
-                            Err(e) => {
-                                edcs_status = EdcsStatus::EdssErr;
-                                response_payload = Some(edcs_response::Payload::EdssErrData(e.0));
-                            }
+                            port: cfg.edss_config.port,
+                            bitrate,
+                            framerate,
+                            evdev_proxy: cfg.evdev_proxy.clone(),
                         };
-                        debug!("HANDLER Finished setting up stream.");
-                    }
-                    Some(_) => {
-                        edcs_status = EdcsStatus::EdcsAlreadySetup;
+                        let open_stream = self.open_stream;
+                        let (actor, opened) = StreamActor::spawn(move || open_stream(params));
+                        // Taken right away, so nobody else sets one up in the meantime
+                        self.last_stream_id += 1;
+                        self.stream = Some(Stream {
+                            id: self.last_stream_id,
+                            opened_by: connection,
+                            actor,
+                            info: StreamInfo {
+                                state: cal_opened,
+                                ..Default::default()
+                            },
+                        });
+                        return Ok(self.pending(connection, identity, async move {
+                            StreamOutcome::Opened {
+                                bitrate,
+                                framerate,
+                                result: opened.await,
+                            }
+                        }));
                     }
+                    Err(e) => return Err(e.into()),
                 }
             }
             EdcsMessageType::SetupStream
             | EdcsMessageType::StartStream
             | EdcsMessageType::CloseStream
             | EdcsMessageType::WriteMouseEvent
-            | EdcsMessageType::WriteKeyboardEvent => {
-                // TODO: DRY here
+            | EdcsMessageType::WriteKeyboardEvent
+            | EdcsMessageType::WriteTouchEvent
+            | EdcsMessageType::WritePenEvent
+            | EdcsMessageType::WriteGamepadEvent => {
+                // Anything that touches EDSS runs on the stream actor, the
+                // response is made by stream_done once it's done
 
-                if let Some(adapter) = &mut self.adapter {
+                if let Some(stream) = &mut self.stream {
+                    let state = stream.info.state;
                     match msg.message_type() {
                         EdcsMessageType::SetupStream => {
-                            if !adapter.stream_setup() {
-                                adapter.cal_option_dict = match msg.payload {
-                                    Some(edcs_message::Payload::SetupStreamParams(d)) => {
-                                        d.cal_option_dict
-                                    }
-                                    // TODO keep it dry (we will have to check requestss for all message types)
-                                    _ => return Ok(Some(EdcsResponse {
-                                        status: EdcsStatus::InvalidRequest as i32,
-                                        payload: Some(edcs_response::Payload::InvalidRequestData(
-                                            "The given payload is not of type SetupEdcsParams"
-                                                .to_string(),
-                                        )),
-                                    })),
-                                };
-                                match adapter.init_server() {
-                                    Ok(_) => {
-                                        response_payload =
-                                            Some(edcs_response::Payload::SetupStreamData(
-                                                EdcsSetupStreamData {
-                                                    out_stream_params: Some(EdcsStreamParams {
-                                                        framerate: adapter.framerate,
-                                                        bitrate: adapter.bitrate,
-                                                    }),
-                                                    sdp: adapter.sdp.clone().unwrap(), // Guaranteed to be Some at this point
-                                                },
+                            state.transition(StreamEvent::InitServer)?;
+                            let (cal_option_dict, audio) = match msg.payload {
+                                Some(edcs_message::Payload::SetupStreamParams(d)) => {
+                                    // calInit gets whatever we give it, so check it first
+                                    match cal_options::validate(
+                                        &stream.info.cal_option_schema,
+                                        &d.cal_option_dict,
+                                        &cfg.limits,
+                                    ) {
+                                        Ok(options) => (
+                                            options,
+                                            audio::negotiate(&cfg.audio, d.audio_params.as_ref()),
+                                        ),
+                                        Err(e) => {
+                                            return Err(EdcsError::Protocol(
+                                                EdcsStatus::InvalidCalOptions,
+                                                e,
                                             ))
-                                    }
-                                    Err(e) => {
-                                        edcs_status = EdcsStatus::EdssErr;
-                                        response_payload =
-                                            Some(edcs_response::Payload::EdssErrData(e.0));
+                                        }
                                     }
                                 }
-                            } else {
-                                edcs_status = EdcsStatus::StreamAlreadySetup;
-                            }
+                                // TODO keep it dry (we will have to check requestss for all message types)
+                                _ => {
+                                    return Err(EdcsError::Protocol(
+                                        EdcsStatus::InvalidRequest,
+                                        "The given payload is not of type SetupEdcsParams"
+                                            .to_string(),
+                                    ))
+                                }
+                            };
+                            let job = stream.actor.run(move |adapter| {
+                                adapter.init_server(cal_option_dict, audio)?;
+                                Ok(adapter.info())
+                            });
+                            return Ok(self.pending(connection, identity, async move {
+                                StreamOutcome::ServerInit(job.await)
+                            }));
                         }
                         EdcsMessageType::StartStream => {
-                            if !adapter.streaming() {
-                                info!("Initialising streaming");
-                                match adapter.init_streaming() {
-                                    Ok(_) => {
-                                        response_payload = None;
-                                    }
-                                    Err(e) => {
-                                        edcs_status = EdcsStatus::EdssErr;
-                                        response_payload =
-                                            Some(edcs_response::Payload::EdssErrData(e.0));
+                            state.transition(StreamEvent::InitStreaming)?;
+                            info!("Initialising streaming");
+                            let job = stream.actor.run(|adapter| {
+                                adapter.init_streaming()?;
+                                Ok(adapter.info())
+                            });
+                            return Ok(self.pending(connection, identity, async move {
+                                StreamOutcome::Started(job.await)
+                            }));
+                        }
+                        EdcsMessageType::CloseStream => {
+                            // Whatever state it is in, the adapter knows what to undo,
+                            // and the CAL goes along with the stream in end_stream
+                            stream.info.state = state.transition(StreamEvent::Close)?;
+                            let job = stream.actor.run(|adapter| adapter.close());
+                            return Ok(self.pending(connection, identity, async move {
+                                StreamOutcome::Closed(job.await)
+                            }));
+                        }
+                        EdcsMessageType::WriteMouseEvent => {
+                            if state == StreamState::Streaming {
+                                let mev = match msg.payload {
+                                    Some(edcs_message::Payload::MouseEvent(mev)) => mev,
+                                    // Every message gets a response, otherwise the client can't match them up
+                                    _ => {
+                                        return Err(EdcsError::Protocol(
+                                            EdcsStatus::InvalidRequest,
+                                            "The given payload is not of type MouseEvent"
+                                                .to_string(),
+                                        ))
                                     }
-                                }
+                                };
+                                let job = stream
+                                    .actor
+                                    .run(move |adapter| adapter.write_mouse_event(mev));
+                                return Ok(self.pending(connection, identity, async move {
+                                    StreamOutcome::Input(job.await)
+                                }));
                             } else {
-                                edcs_status = EdcsStatus::StreamAlreadyStarted;
+                                edcs_status = EdcsStatus::StreamNotStarted;
                             }
                         }
-                        EdcsMessageType::CloseStream => {
-                            // TODO there is a bug here --> we need to figure out how to handle an already setup EDCS/stream that hasn't
-                            // started streaming, since EDSS will try to free invalid pointers in some cases.
-                            // It may be a good idea to free resources on client disconnect.
-                            if adapter.streaming() {
-                                match adapter.close_streaming() {
-                                    Err(e) => {
-                                        edcs_status = EdcsStatus::EdssErr;
-                                        response_payload =
-                                            Some(edcs_response::Payload::EdssErrData(e.0));
+                        EdcsMessageType::WriteKeyboardEvent => {
+                            if state == StreamState::Streaming {
+                                let kev = match msg.payload {
+                                    Some(edcs_message::Payload::KeyboardEvent(kev)) => kev,
+                                    // Every message gets a response, otherwise the client can't match them up
+                                    _ => {
+                                        return Err(EdcsError::Protocol(
+                                            EdcsStatus::InvalidRequest,
+                                            "The given payload is not of type KeyboardEvent"
+                                                .to_string(),
+                                        ))
                                     }
-                                    Ok(_) => {
-                                        // No more adapter, I guess
-                                        // When we having multiple clients connec to the same server, we will change this
-                                        self.adapter = None;
+                                };
+                                let job = stream
+                                    .actor
+                                    .run(move |adapter| adapter.write_keyboard_event(kev));
+                                return Ok(self.pending(connection, identity, async move {
+                                    StreamOutcome::Input(job.await)
+                                }));
+                            } else {
+                                edcs_status = EdcsStatus::StreamNotStarted;
+                            }
+                        }
+                        EdcsMessageType::WriteTouchEvent => {
+                            if !stream.info.input_capabilities.touch {
+                                edcs_status = EdcsStatus::InputUnsupported;
+                            } else if state == StreamState::Streaming {
+                                let tev = match msg.payload {
+                                    Some(edcs_message::Payload::TouchEvent(tev)) => tev,
+                                    // Every message gets a response, otherwise the client can't match them up
+                                    _ => {
+                                        return Err(EdcsError::Protocol(
+                                            EdcsStatus::InvalidRequest,
+                                            "The given payload is not of type TouchEvent"
+                                                .to_string(),
+                                        ))
                                     }
-                                }
+                                };
+                                let job = stream
+                                    .actor
+                                    .run(move |adapter| adapter.write_touch_event(tev));
+                                return Ok(self.pending(connection, identity, async move {
+                                    StreamOutcome::Input(job.await)
+                                }));
                             } else {
-                                // Edcs might be setup, who knows
                                 edcs_status = EdcsStatus::StreamNotStarted;
                             }
                         }
-                        // TODO for both of these events, we can make the EDSS/EDCS return void
-                        EdcsMessageType::WriteMouseEvent => {
-                            if adapter.streaming() {
-                                match adapter.write_mouse_event(match msg.payload {
-                                    Some(edcs_message::Payload::MouseEvent(mev)) => mev,
-                                    _ => return Ok(None),
-                                }) {
-                                    Err(e) => {
-                                        edcs_status = EdcsStatus::EdssErr;
-                                        response_payload =
-                                            Some(edcs_response::Payload::EdssErrData(e.0));
+                        EdcsMessageType::WritePenEvent => {
+                            if !stream.info.input_capabilities.pen {
+                                edcs_status = EdcsStatus::InputUnsupported;
+                            } else if state == StreamState::Streaming {
+                                let pev = match msg.payload {
+                                    Some(edcs_message::Payload::PenEvent(pev)) => pev,
+                                    // Every message gets a response, otherwise the client can't match them up
+                                    _ => {
+                                        return Err(EdcsError::Protocol(
+                                            EdcsStatus::InvalidRequest,
+                                            "The given payload is not of type PenEvent".to_string(),
+                                        ))
                                     }
-                                    Ok(_) => response_payload = None,
-                                }
+                                };
+                                let job = stream
+                                    .actor
+                                    .run(move |adapter| adapter.write_pen_event(pev));
+                                return Ok(self.pending(connection, identity, async move {
+                                    StreamOutcome::Input(job.await)
+                                }));
                             } else {
                                 edcs_status = EdcsStatus::StreamNotStarted;
                             }
                         }
-                        EdcsMessageType::WriteKeyboardEvent => {
-                            if adapter.streaming() {
-                                match adapter.write_keyboard_event(match msg.payload {
-                                    Some(edcs_message::Payload::KeyboardEvent(kev)) => kev,
-                                    _ => return Ok(None),
+                        // Gamepads are separate uinput devices, so they don't go through EDSS
+                        EdcsMessageType::WriteGamepadEvent => {
+                            if state == StreamState::Streaming {
+                                match self.gamepads.handle_event(match msg.payload {
+                                    Some(edcs_message::Payload::GamepadEvent(gev)) => gev,
+                                    _ => {
+                                        return Err(EdcsError::Protocol(
+                                            EdcsStatus::InvalidRequest,
+                                            "The given payload is not of type GamepadEvent"
+                                                .to_string(),
+                                        ))
+                                    }
                                 }) {
-                                    Err(e) => {
-                                        edcs_status = EdcsStatus::EdssErr;
+                                    Err(GamepadError::SlotUnavailable(slot)) => {
+                                        edcs_status = EdcsStatus::GamepadSlotUnavailable;
                                         response_payload =
-                                            Some(edcs_response::Payload::EdssErrData(e.0));
+                                            Some(edcs_response::Payload::GenericErrData(format!(
+                                                "Gamepad slot {} is not available",
+                                                slot
+                                            )));
+                                    }
+                                    Err(GamepadError::InvalidRequest(detail)) => {
+                                        edcs_status = EdcsStatus::InvalidRequest;
+                                        response_payload = Some(
+                                            edcs_response::Payload::InvalidRequestData(detail),
+                                        );
+                                    }
+                                    Err(GamepadError::Device(e)) => {
+                                        edcs_status = EdcsStatus::GenericErr;
+                                        response_payload = Some(
+                                            edcs_response::Payload::GenericErrData(format!(
+                                                "{:?}",
+                                                e
+                                            )),
+                                        );
                                     }
                                     Ok(_) => response_payload = None,
                                 }
@@ -209,24 +497,1081 @@ impl EdcsHandler {
                     edcs_status = EdcsStatus::UninitialisedEdss;
                 }
             }
+            // The clipboard doesn't need EDSS, so this works before the stream is set up
+            EdcsMessageType::ClipboardUpdate => {
+                let data = match msg.payload {
+                    Some(edcs_message::Payload::ClipboardUpdate(data)) => data,
+                    _ => {
+                        return Err(EdcsError::Protocol(
+                            EdcsStatus::InvalidRequest,
+                            "The given payload is not of type ClipboardUpdate".to_string(),
+                        ))
+                    }
+                };
+                match self.clipboard.set(connection, data) {
+                    Err(ClipboardError::TooLarge(size)) => {
+                        edcs_status = EdcsStatus::ClipboardTooLarge;
+                        response_payload = Some(edcs_response::Payload::GenericErrData(format!(
+                            "The clipboard contents are {} bytes",
+                            size
+                        )));
+                    }
+                    Err(ClipboardError::Provider(e)) => {
+                        edcs_status = EdcsStatus::GenericErr;
+                        response_payload =
+                            Some(edcs_response::Payload::GenericErrData(format!("{:?}", e)));
+                    }
+                    Ok(_) => response_payload = None,
+                }
+            }
+            // File transfers don't need EDSS either
+            EdcsMessageType::FileUploadStart
+            | EdcsMessageType::FileUploadChunk
+            | EdcsMessageType::FileUploadFinish
+            | EdcsMessageType::FileList
+            | EdcsMessageType::FileDownloadStart
+            | EdcsMessageType::FileDownloadChunk => {
+                let message_type = msg.message_type();
+                let request = match (message_type, msg.payload) {
+                    (
+                        EdcsMessageType::FileUploadStart,
+                        Some(edcs_message::Payload::FileUploadStart(info)),
+                    ) => FileRequest::UploadStart(info),
+                    (
+                        EdcsMessageType::FileUploadChunk,
+                        Some(edcs_message::Payload::FileUploadChunk(chunk)),
+                    ) => FileRequest::UploadChunk(chunk),
+                    (
+                        EdcsMessageType::FileUploadFinish,
+                        Some(edcs_message::Payload::FileUploadFinish(name)),
+                    ) => FileRequest::UploadFinish(name),
+                    (EdcsMessageType::FileList, _) => FileRequest::List,
+                    (
+                        EdcsMessageType::FileDownloadStart,
+                        Some(edcs_message::Payload::FileDownloadStart(name)),
+                    ) => FileRequest::DownloadStart(name),
+                    (
+                        EdcsMessageType::FileDownloadChunk,
+                        Some(edcs_message::Payload::FileDownloadChunk(req)),
+                    ) => FileRequest::DownloadChunk(req),
+                    _ => {
+                        return Err(EdcsError::Protocol(
+                            EdcsStatus::InvalidRequest,
+                            "The given payload does not match the message type".to_string(),
+                        ))
+                    }
+                };
+                let slot = Arc::clone(self.file_transfers.entry(connection).or_default());
+                let session = addr.ip().to_string();
+                return Ok(Handled::File(Box::pin(async move {
+                    let job = tokio::task::spawn_blocking(move || {
+                        let mut slot = slot.lock();
+                        let transfers = match &mut *slot {
+                            Some(transfers) => transfers,
+                            slot => slot
+                                .insert(FileTransfers::for_session(&cfg.file_transfer, &session)?),
+                        };
+                        request.run(transfers)
+                    });
+                    let result = job.await.unwrap_or_else(|e| {
+                        Err(FileTransferError::Io(anyhow::anyhow!(
+                            "The file transfer failed: {}",
+                            e
+                        )))
+                    });
+                    FileDone {
+                        connection,
+                        message_type,
+                        result,
+                    }
+                })));
+            }
+            EdcsMessageType::JoinStream => {
+                let port = match msg.payload {
+                    Some(edcs_message::Payload::JoinStreamPort(port)) if port != 0 => port,
+                    _ => cfg.edss_config.port as u32,
+                };
+                let port = match u16::try_from(port) {
+                    Ok(port) => port,
+                    Err(_) => {
+                        return Err(EdcsError::Protocol(
+                            EdcsStatus::InvalidRequest,
+                            format!("{} is not a valid port", port),
+                        ))
+                    }
+                };
+                match &self.stream {
+                    Some(_) if self.session.is_participant(connection) => {
+                        edcs_status = EdcsStatus::StreamAlreadySetup;
+                    }
+                    Some(stream) if stream.info.state.is_set_up() => {
+                        let job = stream
+                            .actor
+                            .run(move |adapter| adapter.add_viewer(addr, port));
+                        return Ok(self.pending(connection, identity, async move {
+                            StreamOutcome::Joined(job.await)
+                        }));
+                    }
+                    _ => edcs_status = EdcsStatus::UninitialisedEdss,
+                }
+            }
+            EdcsMessageType::RequestControl
+            | EdcsMessageType::GrantControl
+            | EdcsMessageType::RevokeControl => {
+                let message_type = msg.message_type();
+                let result = match (message_type, msg.payload) {
+                    (EdcsMessageType::RequestControl, _) => self
+                        .session
+                        .request_control(connection)
+                        .map(|_| AuditEvent::ControlRequested),
+                    (
+                        EdcsMessageType::GrantControl,
+                        Some(edcs_message::Payload::GrantControlParticipant(to)),
+                    ) => self
+                        .session
+                        .grant_control(connection, to)
+                        .map(|_| AuditEvent::ControlGranted { participant: to }),
+                    (EdcsMessageType::RevokeControl, _) => self
+                        .session
+                        .revoke_control(connection)
+                        .map(|_| AuditEvent::ControlRevoked),
+                    _ => {
+                        return Err(EdcsError::Protocol(
+                            EdcsStatus::InvalidRequest,
+                            "The given payload is not of type GrantControlParticipant".to_string(),
+                        ))
+                    }
+                };
+                match result {
+                    Ok(event) => {
+                        info!("{:?} from {} went through", message_type, addr);
+                        self.audit.record(connection, event);
+                    }
+                    Err(e) => {
+                        edcs_status = EdcsStatus::NotInControl;
+                        response_payload = Some(edcs_response::Payload::GenericErrData(e));
+                    }
+                }
+            }
+            EdcsMessageType::CreateInvite
+            | EdcsMessageType::ListInvites
+            | EdcsMessageType::RevokeInvite => {
+                if !self.session.is_owner(connection) {
+                    return Err(EdcsError::State(
+                        EdcsStatus::PermissionDenied,
+                        "Only the owner of the stream can manage invites".to_string(),
+                    ));
+                }
+                let now = SystemTime::now();
+                match (msg.message_type(), msg.payload) {
+                    (
+                        EdcsMessageType::CreateInvite,
+                        Some(edcs_message::Payload::CreateInviteParams(params)),
+                    ) => {
+                        // The owner has to have an identity, see check_permission
+                        let inviter = identity.as_ref().unwrap();
+                        let invite = self.invites.create(
+                            inviter,
+                            params.view_only,
+                            Duration::from_secs(params.valid_for_secs as u64),
+                            now,
+                        );
+                        info!(
+                            "{} created invite {} with role {:?}",
+                            inviter.name,
+                            invite.id,
+                            invite.role()
+                        );
+                        self.audit.record(
+                            connection,
+                            AuditEvent::InviteCreated {
+                                id: invite.id.clone(),
+                                role: auth::Role::from_proto(invite.role()),
+                            },
+                        );
+                        response_payload = Some(edcs_response::Payload::Invite(invite));
+                    }
+                    (EdcsMessageType::ListInvites, _) => {
+                        response_payload =
+                            Some(edcs_response::Payload::InviteList(EdcsInviteList {
+                                invites: self.invites.list(now),
+                            }));
+                    }
+                    (
+                        EdcsMessageType::RevokeInvite,
+                        Some(edcs_message::Payload::RevokeInviteId(id)),
+                    ) => {
+                        if self.invites.revoke(&id) {
+                            info!("{} revoked invite {}", addr, id);
+                            self.audit
+                                .record(connection, AuditEvent::InviteRevoked { id });
+                        } else {
+                            edcs_status = EdcsStatus::InvalidRequest;
+                            response_payload = Some(edcs_response::Payload::InvalidRequestData(
+                                format!("There is no invite {}", id),
+                            ));
+                        }
+                    }
+                    _ => {
+                        return Err(EdcsError::Protocol(
+                            EdcsStatus::InvalidRequest,
+                            "The given payload does not match the message type".to_string(),
+                        ))
+                    }
+                }
+            }
             EdcsMessageType::UpdateStream => {
-                todo!()
+                return Err(EdcsError::Protocol(
+                    EdcsStatus::InvalidRequest,
+                    "UPDATE_STREAM is not supported yet".to_string(),
+                ));
             }
         }
 
+        // Whatever the request was, the stream is gone with the worker
+        if let Some(reason) = self.reap_crashed_worker() {
+            return Err(EdcsError::State(EdcsStatus::StreamWorkerCrashed, reason));
+        }
+
         // Send out the response
-        Ok(Some(EdcsResponse {
-            status: edcs_status as i32, // NOTE is there a better way to do this?
-            payload: response_payload,
+        Ok(Handled::Response(Some(error::response(
+            edcs_status,
+            response_payload,
+        ))))
+    }
+
+    fn pending(
+        &self,
+        connection: ConnectionId,
+        identity: &Option<Identity>,
+        outcome: impl Future<Output = StreamOutcome> + Send + 'static,
+    ) -> Handled {
+        let stream_id = self.stream.as_ref().map_or(0, |stream| stream.id);
+        let identity = identity.clone();
+        Handled::Stream(Box::pin(async move {
+            StreamDone {
+                stream_id,
+                connection,
+                identity,
+                outcome: outcome.await,
+            }
         }))
     }
 
-    // Figure out if the EDSS stream was shutdown for better cleanup
-    pub fn adapter_streaming(&self) -> bool {
-        if let Some(adapter) = &self.adapter {
-            adapter.streaming()
-        } else {
-            false
+    /// The response to the message that queued the job.
+    pub fn stream_done(&mut self, done: StreamDone) -> Option<EdcsResponse> {
+        let mut response_payload = None;
+        let StreamDone {
+            stream_id,
+            connection,
+            identity,
+            outcome,
+        } = done;
+
+        if let Some(reason) = self.reap_crashed_worker() {
+            return Some(EdcsError::State(EdcsStatus::StreamWorkerCrashed, reason).into_response());
+        }
+        let stream = match &mut self.stream {
+            Some(stream) if stream.id == stream_id => stream,
+            // It ended while the job ran, eg. because its owner left
+            _ => return Some(error::response(EdcsStatus::UninitialisedEdss, None)),
+        };
+
+        match outcome {
+            StreamOutcome::Opened {
+                bitrate,
+                framerate,
+                result: Ok(info),
+            } => {
+                stream.info = info;
+                response_payload = Some(edcs_response::Payload::SetupEdcsData(EdcsSetupEdcsData {
+                    cal_option_dict: stream.info.cal_option_dict.clone(),
+                    input_capabilities: Some(stream.info.input_capabilities.clone()),
+                    cal_option_schema: stream.info.cal_option_schema.clone(),
+                    // Can't get here without an identity, see check_permission
+                    role: identity
+                        .as_ref()
+                        .map(|identity| identity.role.to_proto() as i32)
+                        .unwrap_or_default(),
+                }));
+                self.session.start(connection, identity);
+                self.audit
+                    .record(connection, AuditEvent::SessionStart { framerate, bitrate });
+                debug!("HANDLER Finished setting up stream.");
+            }
+            StreamOutcome::Opened { result: Err(e), .. } => {
+                // Nothing to close, the worker already gave up
+                self.stream = None;
+                return Some(EdcsError::from(e).into_response());
+            }
+            StreamOutcome::ServerInit(Ok(info)) => {
+                stream.info = info;
+                let info = &stream.info;
+                self.audit.record(
+                    connection,
+                    AuditEvent::StreamSetup {
+                        cal_options: info
+                            .cal_option_dict
+                            .iter()
+                            .map(|(k, v)| (k.clone(), v.clone()))
+                            .collect(),
+                        audio: info.audio.is_some(),
+                    },
+                );
+                response_payload = Some(edcs_response::Payload::SetupStreamData(
+                    EdcsSetupStreamData {
+                        out_stream_params: Some(EdcsStreamParams {
+                            framerate: info.framerate,
+                            bitrate: info.bitrate,
+                        }),
+                        sdp: info.sdp.clone().unwrap(), // Guaranteed to be Some at this point
+                        width: info.stream_resolution.unwrap().0,
+                        height: info.stream_resolution.unwrap().1,
+                        audio_params: info.audio.as_ref().map(|audio| audio.params.clone()),
+                    },
+                ));
+            }
+            StreamOutcome::Started(Ok(info)) => stream.info = info,
+            StreamOutcome::Closed(result) => {
+                // No more stream, and the viewers' streams went with it. The
+                // worker gave up on the stream even if closing failed.
+                self.end_stream(connection);
+                if let Err(e) = result {
+                    return Some(EdcsError::from(e).into_response());
+                }
+            }
+            StreamOutcome::Input(Ok(_)) => {}
+            StreamOutcome::Joined(Ok((viewer_id, sdp))) => {
+                let info = &stream.info;
+                info!(
+                    "Connection {} joined the stream as viewer {}",
+                    connection, viewer_id
+                );
+                response_payload = Some(edcs_response::Payload::SetupStreamData(
+                    EdcsSetupStreamData {
+                        out_stream_params: Some(EdcsStreamParams {
+                            framerate: info.framerate,
+                            bitrate: info.bitrate,
+                        }),
+                        sdp,
+                        // Set along with StreamConfigured
+                        width: info.stream_resolution.unwrap().0,
+                        height: info.stream_resolution.unwrap().1,
+                        audio_params: info.audio.as_ref().map(|audio| audio.params.clone()),
+                    },
+                ));
+                self.session.join(connection, identity, viewer_id);
+                self.audit
+                    .record(connection, AuditEvent::StreamJoined { viewer_id });
+            }
+            StreamOutcome::ServerInit(Err(e))
+            | StreamOutcome::Started(Err(e))
+            | StreamOutcome::Input(Err(e))
+            | StreamOutcome::Joined(Err(e)) => return Some(EdcsError::from(e).into_response()),
+        }
+
+        Some(error::response(EdcsStatus::Ok, response_payload))
+    }
+
+    // Connected while there is no stream
+    fn state(&self) -> StreamState {
+        self.stream
+            .as_ref()
+            .map_or(StreamState::Connected, |stream| stream.info.state)
+    }
+
+    /// The response to the file transfer request.
+    pub fn file_done(&mut self, done: FileDone) -> Option<EdcsResponse> {
+        let FileDone {
+            connection,
+            message_type,
+            result,
+        } = done;
+        let (status, payload) = match result {
+            Ok(payload) => {
+                match (message_type, &payload) {
+                    (
+                        EdcsMessageType::FileUploadFinish,
+                        edcs_response::Payload::FileProgress(progress),
+                    ) => self.audit.record(
+                        connection,
+                        AuditEvent::FileUploaded {
+                            name: progress.name.clone(),
+                            size: progress.size,
+                        },
+                    ),
+                    (
+                        EdcsMessageType::FileDownloadStart,
+                        edcs_response::Payload::FileInfo(info),
+                    ) => self.audit.record(
+                        connection,
+                        AuditEvent::FileDownloadStarted {
+                            name: info.name.clone(),
+                            size: info.size,
+                        },
+                    ),
+                    _ => {}
+                }
+                (EdcsStatus::Ok, payload)
+            }
+            Err(e) => file_transfer_error(e),
+        };
+        Some(error::response(status, Some(payload)))
+    }
+
+    // Everyone leaves with the stream
+    fn end_stream(&mut self, connection: ConnectionId) {
+        self.stream = None;
+        self.gamepads.clear();
+        self.session.end();
+        self.invites.clear();
+        self.audit.record(connection, AuditEvent::SessionEnd);
+    }
+
+    /// Cursor updates for the connection that owns `tracker`, which are pushed
+    /// to the client as server events. They are read on the stream actor, so
+    /// the tracker goes with the job and comes back with its events.
+    pub fn poll_cursor(
+        &mut self,
+        tracker: &mut CursorTracker,
+    ) -> Option<impl Future<Output = Result<(CursorTracker, Vec<EdcsServerEvent>), EdssError>>>
+    {
+        // This is usually where a crash shows up first
+        self.reap_crashed_worker();
+        match &self.stream {
+            Some(stream) if stream.info.state == StreamState::Streaming => {
+                let mut polled = std::mem::take(tracker);
+                Some(stream.actor.run(move |adapter| {
+                    let events = polled.poll(adapter);
+                    Ok((polled, events))
+                }))
+            }
+            _ => {
+                tracker.reset();
+                None
+            }
+        }
+    }
+
+    // Called after this connection's CLIPBOARD_UPDATE went through
+    pub fn clipboard_sent_by_client(&self, tracker: &mut ClipboardTracker) {
+        tracker.sent_by_client(&self.clipboard);
+    }
+
+    pub fn poll_control(
+        &self,
+        connection: ConnectionId,
+        tracker: &mut ControlTracker,
+    ) -> Option<EdcsServerEvent> {
+        tracker.poll(&self.session, connection)
+    }
+
+    /// Ends the stream if its worker crashed, everyone on it hears why with
+    /// their last EdcsControlState. The owner can set up a new one, which
+    /// starts a new worker.
+    fn reap_crashed_worker(&mut self) -> Option<String> {
+        let reason = self.stream.as_ref()?.actor.crashed()?;
+        warn!("{}, ending the stream", reason);
+        if let Some(owner) = self.session.owner() {
+            self.audit.record(
+                owner,
+                AuditEvent::StreamFailed {
+                    reason: reason.clone(),
+                },
+            );
+        }
+        self.stream = None;
+        self.gamepads.clear();
+        self.session.fail(reason.clone());
+        self.invites.clear();
+        Some(reason)
+    }
+
+    // Whatever the connection had on the stream goes with it, the whole stream if it owns it
+    pub fn disconnect(&mut self, connection: ConnectionId) {
+        match self.session.leave(connection) {
+            Left::Owner => {
+                info!("The owner of the stream left, closing it");
+                // The actor drops the adapter, which undoes however far the stream got
+                self.stream = None;
+                self.gamepads.clear();
+                self.invites.clear();
+                self.audit.record(connection, AuditEvent::SessionEnd);
+            }
+            Left::Viewer(viewer_id) => {
+                if let Some(stream) = &self.stream {
+                    stream.actor.send(move |adapter| {
+                        if let Err(e) = adapter.remove_viewer(viewer_id) {
+                            warn!("Failed to remove viewer {}, status {}", viewer_id, e.0);
+                        }
+                    });
+                }
+            }
+            // Nobody else can take over a stream that is still being opened
+            Left::NotParticipant => {
+                if matches!(&self.stream, Some(stream) if stream.opened_by == connection) {
+                    self.stream = None;
+                }
+            }
+        }
+        // Unfinished uploads stay on disk, to be picked up after reconnecting
+        self.file_transfers.remove(&connection);
+        self.audit.disconnected(connection);
+    }
+}
+
+enum FileRequest {
+    UploadStart(EdcsFileInfo),
+    UploadChunk(EdcsFileChunk),
+    UploadFinish(String),
+    List,
+    DownloadStart(String),
+    DownloadChunk(EdcsFileChunkRequest),
+}
+
+impl FileRequest {
+    fn run(
+        self,
+        transfers: &mut FileTransfers,
+    ) -> Result<edcs_response::Payload, FileTransferError> {
+        match self {
+            FileRequest::UploadStart(info) => transfers
+                .upload_start(info)
+                .map(edcs_response::Payload::FileProgress),
+            FileRequest::UploadChunk(chunk) => transfers
+                .upload_chunk(chunk)
+                .map(edcs_response::Payload::FileProgress),
+            FileRequest::UploadFinish(name) => transfers
+                .upload_finish(name)
+                .map(edcs_response::Payload::FileProgress),
+            FileRequest::List => transfers.list().map(edcs_response::Payload::FileList),
+            FileRequest::DownloadStart(name) => transfers
+                .download_start(name)
+                .map(edcs_response::Payload::FileInfo),
+            FileRequest::DownloadChunk(req) => transfers
+                .download_chunk(req)
+                .map(edcs_response::Payload::FileChunk),
+        }
+    }
+}
+
+fn file_transfer_error(e: FileTransferError) -> (EdcsStatus, edcs_response::Payload) {
+    match e {
+        FileTransferError::InvalidName(name) => (
+            EdcsStatus::InvalidFileName,
+            edcs_response::Payload::GenericErrData(format!("Invalid file name {:?}", name)),
+        ),
+        FileTransferError::QuotaExceeded { needed, available } => (
+            EdcsStatus::QuotaExceeded,
+            edcs_response::Payload::GenericErrData(format!(
+                "Need {} bytes but only {} are left in the quota",
+                needed, available
+            )),
+        ),
+        FileTransferError::ChecksumMismatch(name) => (
+            EdcsStatus::ChecksumMismatch,
+            edcs_response::Payload::GenericErrData(format!("Checksum mismatch for {}", name)),
+        ),
+        FileTransferError::Io(e) => (
+            EdcsStatus::FileTransferErr,
+            edcs_response::Payload::GenericErrData(format!("{:?}", e)),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Instant;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::edcs_server::edcs_proto::{
+        edcs_mouse_event, EdcsCalParams, EdcsKeyData, EdcsKeyboardEvent, EdcsMouseEvent,
+        EdcsPenEvent, EdcsTouchEvent,
+    };
+    use crate::edcs_server::stream_actor::StreamAdapter;
+    use crate::edss_safe::edss::CursorState;
+    use crate::edss_safe::fake_edss::FakeEdss;
+    use crate::edss_safe::stream_state::Teardown;
+
+    const CONFIG: &str = r#"
+ip = "127.0.0.1"
+port = 9000
+cert_path = "/nonexistent/cert.pem"
+key_path = "/nonexistent/key.pem"
+
+[edss_config]
+plugin_name = "stub"
+port = 9001
+"#;
+
+    const CONNECTIONS: usize = 3;
+
+    // The stubs live on the actor threads, this is how they report back
+    struct Ledger {
+        live: usize,
+        violations: Vec<String>,
+        started: usize,
+        // The next EDSS call that can fail does
+        fail_next: bool,
+    }
+
+    impl Ledger {
+        const fn new() -> Self {
+            Ledger {
+                live: 0,
+                violations: Vec::new(),
+                started: 0,
+                fail_next: false,
+            }
+        }
+    }
+
+    static LEDGER: Mutex<Ledger> = Mutex::new(Ledger::new());
+
+    fn record(result: Result<(), String>) {
+        if let Err(e) = result {
+            LEDGER.lock().unwrap().violations.push(e);
+        }
+    }
+
+    fn take_failure() -> bool {
+        std::mem::take(&mut LEDGER.lock().unwrap().fail_next)
+    }
+
+    /// Goes through the same states as EdssAdapter, against a FakeEdss.
+    struct StubAdapter {
+        state: StreamState,
+        edss: FakeEdss,
+        bitrate: u32,
+        framerate: u32,
+    }
+
+    fn open_stub(params: OpenParams) -> Result<Box<dyn StreamAdapter>, EdssError> {
+        // Like a worker that didn't start
+        if take_failure() {
+            return Err(EdssError::uninitialised());
+        }
+        let mut edss = FakeEdss::default();
+        record(edss.open_cal());
+        LEDGER.lock().unwrap().live += 1;
+        Ok(Box::new(StubAdapter {
+            state: StreamState::CalOpened,
+            edss,
+            bitrate: params.bitrate,
+            framerate: params.framerate,
+        }))
+    }
+
+    impl StubAdapter {
+        fn next_state(&self, event: StreamEvent) -> Result<StreamState, EdssError> {
+            self.state
+                .transition(event)
+                .map_err(|_| EdssError::uninitialised())
+        }
+    }
+
+    impl StreamAdapter for StubAdapter {
+        fn info(&self) -> StreamInfo {
+            StreamInfo {
+                bitrate: self.bitrate,
+                framerate: self.framerate,
+                sdp: self.state.is_set_up().then(|| "v=0".to_string()),
+                stream_resolution: self.stream_resolution(),
+                state: self.state,
+                ..Default::default()
+            }
+        }
+
+        fn stream_resolution(&self) -> Option<(u32, u32)> {
+            self.state.is_set_up().then_some((1920, 1080))
+        }
+
+        fn crashed(&self) -> Option<&str> {
+            None
+        }
+
+        fn init_server(
+            &mut self,
+            _cal_option_dict: HashMap<String, String>,
+            _audio: Option<audio::NegotiatedAudio>,
+        ) -> Result<(), EdssError> {
+            let configured = self.next_state(StreamEvent::InitServer)?;
+            match self.edss.init_server(take_failure()) {
+                Ok(true) => {
+                    self.state = configured;
+                    Ok(())
+                }
+                Ok(false) => {
+                    record(self.edss.close_server());
+                    Err(EdssError::uninitialised())
+                }
+                Err(e) => {
+                    record(Err(e));
+                    Err(EdssError::uninitialised())
+                }
+            }
+        }
+
+        fn init_streaming(&mut self) -> Result<(), EdssError> {
+            self.state = self.next_state(StreamEvent::InitStreaming)?;
+            match self.edss.init_streaming(take_failure()) {
+                Ok(true) => {
+                    LEDGER.lock().unwrap().started += 1;
+                    Ok(())
+                }
+                Ok(false) => Err(EdssError::uninitialised()),
+                Err(e) => {
+                    record(Err(e));
+                    Err(EdssError::uninitialised())
+                }
+            }
+        }
+
+        fn close(&mut self) -> Result<(), EdssError> {
+            let teardown = self.state.teardown();
+            if teardown == Teardown::Nothing {
+                return Ok(());
+            }
+            self.state = self.next_state(StreamEvent::Close)?;
+            record(match teardown {
+                Teardown::CloseStreaming => self.edss.close_streaming(),
+                _ => self.edss.close_server(),
+            });
+            self.state = self.next_state(StreamEvent::Closed)?;
+            Ok(())
+        }
+
+        fn write_mouse_event(&mut self, _ev: EdcsMouseEvent) -> Result<(), EdssError> {
+            self.write_input()
+        }
+
+        fn write_keyboard_event(&mut self, _kev: EdcsKeyboardEvent) -> Result<(), EdssError> {
+            self.write_input()
+        }
+
+        fn write_touch_event(&mut self, _tev: EdcsTouchEvent) -> Result<(), EdssError> {
+            self.write_input()
+        }
+
+        fn write_pen_event(&mut self, _pev: EdcsPenEvent) -> Result<(), EdssError> {
+            self.write_input()
+        }
+
+        fn read_cursor(&mut self, _last_serial: Option<u32>) -> Result<CursorState, EdssError> {
+            Err(EdssError::unsupported())
+        }
+
+        fn add_viewer(&mut self, _ip: SocketAddr, _port: u16) -> Result<(u32, String), EdssError> {
+            match self.edss.add_viewer() {
+                Ok(viewer_id) => Ok((viewer_id, "v=0".to_string())),
+                Err(e) => {
+                    record(Err(e));
+                    Err(EdssError::uninitialised())
+                }
+            }
+        }
+
+        fn remove_viewer(&mut self, viewer_id: u32) -> Result<(), EdssError> {
+            // Viewers go with the server, so one that is gone already is fine
+            self.edss.remove_viewer(viewer_id);
+            Ok(())
+        }
+    }
+
+    impl StubAdapter {
+        fn write_input(&self) -> Result<(), EdssError> {
+            if !self.state.is_set_up() {
+                record(Err(format!("input written while {:?}", self.state)));
+                return Err(EdssError::uninitialised());
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for StubAdapter {
+        fn drop(&mut self) {
+            let _ = self.close();
+            if self.next_state(StreamEvent::CloseCal).is_ok() {
+                record(self.edss.close_cal());
+                self.state = StreamState::Connected;
+            }
+            let mut ledger = LEDGER.lock().unwrap();
+            if let Some(leaked) = self.edss.leaked() {
+                ledger
+                    .violations
+                    .push(format!("dropped holding {}", leaked));
+            }
+            // A stub of an earlier run, the snapshot is already taken
+            ledger.live = ledger.live.saturating_sub(1);
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    enum Action {
+        Send(usize, EdcsMessageType),
+        // What the server does with a connection's job when it is done
+        Finish(usize),
+        Disconnect(usize),
+        FailNext,
+    }
+
+    fn action() -> impl Strategy<Value = Action> {
+        let message_type = prop_oneof![
+            Just(EdcsMessageType::SetupEdcs),
+            Just(EdcsMessageType::SetupStream),
+            Just(EdcsMessageType::StartStream),
+            Just(EdcsMessageType::CloseStream),
+            Just(EdcsMessageType::JoinStream),
+            Just(EdcsMessageType::WriteMouseEvent),
+        ];
+        prop_oneof![
+            4 => (0..CONNECTIONS, message_type).prop_map(|(i, t)| Action::Send(i, t)),
+            2 => (0..CONNECTIONS).prop_map(Action::Finish),
+            1 => (0..CONNECTIONS).prop_map(Action::Disconnect),
+            1 => Just(Action::FailNext),
+        ]
+    }
+
+    fn message(message_type: EdcsMessageType) -> EdcsMessage {
+        let payload = match message_type {
+            EdcsMessageType::SetupEdcs => {
+                Some(edcs_message::Payload::SetupEdcsParams(EdcsStreamParams {
+                    framerate: 30,
+                    bitrate: 4000,
+                }))
+            }
+            EdcsMessageType::SetupStream => Some(edcs_message::Payload::SetupStreamParams(
+                EdcsCalParams::default(),
+            )),
+            EdcsMessageType::WriteMouseEvent => {
+                Some(edcs_message::Payload::MouseEvent(EdcsMouseEvent {
+                    payload: Some(edcs_mouse_event::Payload::Button(EdcsKeyData {
+                        btn_typ: 0x110,
+                        pressed: true,
+                    })),
+                }))
+            }
+            _ => None,
+        };
+        EdcsMessage {
+            message_type: message_type as i32,
+            payload,
+        }
+    }
+
+    struct Connection {
+        id: ConnectionId,
+        identity: Option<Identity>,
+        // The server reads nothing else from a connection while it waits
+        pending: Option<Pin<Box<dyn Future<Output = StreamDone> + Send>>>,
+    }
+
+    fn connect(handler: &mut EdcsHandler, addr: SocketAddr) -> Connection {
+        Connection {
+            id: handler.new_connection(addr),
+            identity: handler.anonymous_identity(),
+            pending: None,
+        }
+    }
+
+    #[test]
+    fn viewers_are_denied_every_kind_of_input() {
+        use EdcsMessageType::*;
+        let cfg: Arc<EdcsConfig> = Arc::new(toml::from_str(CONFIG).unwrap());
+        let mut handler = EdcsHandler::new(&cfg).unwrap();
+        let addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let connection = handler.new_connection(addr);
+        let mut identity = Some(Identity {
+            name: "viewer".to_string(),
+            role: auth::Role::Viewer,
+            invite: None,
+        });
+        for message_type in [
+            WriteMouseEvent,
+            WriteKeyboardEvent,
+            WriteTouchEvent,
+            WritePenEvent,
+            WriteGamepadEvent,
+        ] {
+            let status = match handler.handle_message(
+                Arc::clone(&cfg),
+                message(message_type),
+                addr,
+                connection,
+                &mut identity,
+            ) {
+                Ok(_) => EdcsStatus::Ok,
+                Err(e) => e.status(),
+            };
+            assert_eq!(status, EdcsStatus::PermissionDenied, "{:?}", message_type);
+        }
+    }
+
+    // Every run uses LEDGER, so the tests that run messages take turns, even
+    // when the test harness runs them in parallel
+    static RUNS: Mutex<()> = Mutex::new(());
+
+    fn run(actions: Vec<Action>) -> Ledger {
+        let _turn = RUNS.lock().unwrap_or_else(|e| e.into_inner());
+        *LEDGER.lock().unwrap() = Ledger::new();
+        let cfg: Arc<EdcsConfig> = Arc::new(toml::from_str(CONFIG).unwrap());
+        let mut handler = EdcsHandler {
+            open_stream: open_stub,
+            ..EdcsHandler::new(&cfg).unwrap()
+        };
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let mut connections: Vec<Connection> = (0..CONNECTIONS)
+            .map(|_| connect(&mut handler, addr))
+            .collect();
+
+        let finish = |handler: &mut EdcsHandler, connection: &mut Connection| {
+            if let Some(job) = connection.pending.take() {
+                handler.stream_done(rt.block_on(job));
+            }
+        };
+        for action in actions {
+            match action {
+                Action::Send(i, message_type) => {
+                    let connection = &mut connections[i];
+                    finish(&mut handler, connection);
+                    if let Ok(Handled::Stream(job)) = handler.handle_message(
+                        Arc::clone(&cfg),
+                        message(message_type),
+                        addr,
+                        connection.id,
+                        &mut connection.identity,
+                    ) {
+                        connection.pending = Some(job);
+                    }
+                }
+                Action::Finish(i) => finish(&mut handler, &mut connections[i]),
+                Action::Disconnect(i) => {
+                    handler.disconnect(connections[i].id);
+                    connections[i] = connect(&mut handler, addr);
+                }
+                Action::FailNext => LEDGER.lock().unwrap().fail_next = true,
+            }
+        }
+        for connection in &mut connections {
+            finish(&mut handler, connection);
+            handler.disconnect(connection.id);
+        }
+
+        // Nobody is left to own a stream. The actors drop their stubs on
+        // their own threads.
+        wait_for_stubs();
+        let ledger = std::mem::replace(&mut *LEDGER.lock().unwrap(), Ledger::new());
+        // So that the next run starts without any
+        drop(handler);
+        wait_for_stubs();
+        ledger
+    }
+
+    fn wait_for_stubs() {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while LEDGER.lock().unwrap().live > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn a_stream_goes_through_its_whole_life() {
+        use EdcsMessageType::*;
+        let ledger = run(vec![
+            Action::Send(0, SetupEdcs),
+            Action::Send(0, SetupStream),
+            Action::Send(0, StartStream),
+            Action::Send(1, JoinStream),
+            Action::Send(0, WriteMouseEvent),
+            Action::Send(0, CloseStream),
+        ]);
+        assert_eq!(ledger.violations, Vec::<String>::new());
+        assert_eq!(ledger.started, 1);
+        assert_eq!(ledger.live, 0);
+    }
+
+    #[test]
+    fn file_transfers_belong_to_their_connection() {
+        let staging_dir =
+            std::env::temp_dir().join(format!("edcs-handler-files-{}", std::process::id()));
+        let mut cfg: EdcsConfig = toml::from_str(CONFIG).unwrap();
+        cfg.file_transfer.staging_dir = staging_dir.clone();
+        let cfg = Arc::new(cfg);
+        let mut handler = EdcsHandler::new(&cfg).unwrap();
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let send = |handler: &mut EdcsHandler, connection: &mut Connection, payload| {
+            let message_type = match &payload {
+                edcs_message::Payload::FileUploadStart(_) => EdcsMessageType::FileUploadStart,
+                _ => EdcsMessageType::FileUploadChunk,
+            };
+            let msg = EdcsMessage {
+                message_type: message_type as i32,
+                payload: Some(payload),
+            };
+            let handled = handler.handle_message(
+                Arc::clone(&cfg),
+                msg,
+                addr,
+                connection.id,
+                &mut connection.identity,
+            );
+            match handled {
+                Ok(Handled::File(pending)) => handler.file_done(rt.block_on(pending)).unwrap(),
+                _ => panic!("{:?} wasn't run as a file transfer", message_type),
+            }
+        };
+        let chunk = || {
+            edcs_message::Payload::FileUploadChunk(EdcsFileChunk {
+                name: "a.txt".to_string(),
+                offset: 0,
+                data: b"a".to_vec(),
+            })
+        };
+
+        let mut first = connect(&mut handler, addr);
+        let mut second = connect(&mut handler, addr);
+        let start = edcs_message::Payload::FileUploadStart(EdcsFileInfo {
+            name: "a.txt".to_string(),
+            size: 1,
+            sha256: vec![0; 32],
+        });
+        assert_eq!(
+            send(&mut handler, &mut first, start).status(),
+            EdcsStatus::Ok
+        );
+        assert_eq!(
+            send(&mut handler, &mut second, chunk()).status(),
+            EdcsStatus::FileTransferErr
+        );
+        assert_eq!(
+            send(&mut handler, &mut first, chunk()).status(),
+            EdcsStatus::Ok
+        );
+
+        handler.disconnect(first.id);
+        assert!(!handler.file_transfers.contains_key(&first.id));
+        let _ = std::fs::remove_dir_all(staging_dir);
+    }
+
+    proptest! {
+        #[test]
+        fn no_message_sequence_leaks_or_double_frees(
+            actions in proptest::collection::vec(action(), 0..40)
+        ) {
+            let ledger = run(actions);
+            prop_assert_eq!(ledger.violations, Vec::<String>::new());
+            prop_assert_eq!(ledger.live, 0, "stubs never dropped");
         }
     }
 }
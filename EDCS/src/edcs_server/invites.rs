@@ -0,0 +1,237 @@
+//! Single-use tokens that the owner of a stream hands out to bring others in.
+//! They only live in memory, and only as long as the stream.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use super::auth::{self, Identity, Role};
+use super::edcs_proto::EdcsInvite;
+
+pub const DEFAULT_VALIDITY: Duration = Duration::from_secs(15 * 60);
+pub const MAX_VALIDITY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug)]
+struct Invite {
+    id: String,
+    token: Vec<u8>,
+    // Name of whoever created it, for the logs and the invitee's name
+    inviter: String,
+    role: Role,
+    expires_at: SystemTime,
+}
+
+impl Invite {
+    fn to_proto(&self) -> EdcsInvite {
+        EdcsInvite {
+            id: self.id.clone(),
+            token: String::new(),
+            expires_at: self
+                .expires_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            role: self.role.to_proto() as i32,
+        }
+    }
+}
+
+/// The outstanding invites. Everything takes the current time as an argument,
+/// so expiry doesn't depend on the clock.
+#[derive(Debug, Default)]
+pub struct Invites {
+    invites: Vec<Invite>,
+}
+
+impl Invites {
+    fn prune(&mut self, now: SystemTime) {
+        self.invites.retain(|invite| invite.expires_at > now);
+    }
+
+    /// Returns the invite with its token, which isn't shown anywhere after
+    /// this.
+    pub fn create(
+        &mut self,
+        inviter: &Identity,
+        view_only: bool,
+        valid_for: Duration,
+        now: SystemTime,
+    ) -> EdcsInvite {
+        self.prune(now);
+        let valid_for = if valid_for.is_zero() {
+            DEFAULT_VALIDITY
+        } else {
+            valid_for.min(MAX_VALIDITY)
+        };
+        // Nobody can invite someone who may do more than they can, and
+        // changing the stream params stays with the owner
+        let role = if view_only {
+            Role::Viewer
+        } else {
+            inviter.role.min(Role::Controller)
+        };
+
+        let mut token = [0u8; 32];
+        OsRng.fill_bytes(&mut token);
+        let token = base64::encode(token);
+        let invite = Invite {
+            id: format!("{:08x}", OsRng.next_u32()),
+            token: token.as_bytes().to_vec(),
+            inviter: inviter.name.clone(),
+            role,
+            expires_at: now + valid_for,
+        };
+        let mut created = invite.to_proto();
+        created.token = token;
+        self.invites.push(invite);
+        created
+    }
+
+    /// Uses up the invite the token belongs to.
+    pub fn redeem(&mut self, token: &str, now: SystemTime) -> Option<Identity> {
+        self.prune(now);
+        // Like Authenticator::authenticate, every invite is compared
+        let mut found = None;
+        for (i, invite) in self.invites.iter().enumerate() {
+            if auth::token_matches(&invite.token, token.as_bytes()) {
+                found = Some(i);
+            }
+        }
+        let invite = self.invites.remove(found?);
+        Some(Identity {
+            name: format!("{}'s guest", invite.inviter),
+            role: invite.role,
+            invite: Some(invite.id),
+        })
+    }
+
+    pub fn revoke(&mut self, id: &str) -> bool {
+        let before = self.invites.len();
+        self.invites.retain(|invite| invite.id != id);
+        self.invites.len() != before
+    }
+
+    pub fn list(&mut self, now: SystemTime) -> Vec<EdcsInvite> {
+        self.prune(now);
+        self.invites.iter().map(Invite::to_proto).collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.invites.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inviter(role: Role) -> Identity {
+        Identity {
+            name: "owner".to_owned(),
+            role,
+            invite: None,
+        }
+    }
+
+    fn start() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_000_000)
+    }
+
+    fn expires_in(invite: &EdcsInvite) -> Duration {
+        (UNIX_EPOCH + Duration::from_secs(invite.expires_at))
+            .duration_since(start())
+            .unwrap()
+    }
+
+    #[test]
+    fn an_invite_is_redeemed_once() {
+        let mut invites = Invites::default();
+        let invite = invites.create(&inviter(Role::Admin), false, Duration::ZERO, start());
+        assert!(invites.list(start())[0].token.is_empty());
+
+        let guest = invites.redeem(&invite.token, start()).unwrap();
+        assert_eq!(guest.name, "owner's guest");
+        assert_eq!(guest.invite, Some(invite.id));
+        assert_eq!(invites.redeem(&invite.token, start()), None);
+        assert!(invites.list(start()).is_empty());
+    }
+
+    #[test]
+    fn an_expired_invite_cant_be_redeemed() {
+        let mut invites = Invites::default();
+        let invite = invites.create(
+            &inviter(Role::Admin),
+            false,
+            Duration::from_secs(60),
+            start(),
+        );
+        let expires_at = start() + Duration::from_secs(60);
+        assert_eq!(invites.redeem(&invite.token, expires_at), None);
+        assert!(invites.list(start()).is_empty());
+    }
+
+    #[test]
+    fn an_invite_works_until_it_expires() {
+        let mut invites = Invites::default();
+        let invite = invites.create(
+            &inviter(Role::Admin),
+            false,
+            Duration::from_secs(60),
+            start(),
+        );
+        let just_before = start() + Duration::from_secs(59);
+        assert!(invites.redeem(&invite.token, just_before).is_some());
+    }
+
+    #[test]
+    fn validity_is_limited() {
+        let mut invites = Invites::default();
+        let owner = inviter(Role::Admin);
+        let invite = invites.create(&owner, false, Duration::ZERO, start());
+        assert_eq!(expires_in(&invite), DEFAULT_VALIDITY);
+        let invite = invites.create(&owner, false, MAX_VALIDITY * 2, start());
+        assert_eq!(expires_in(&invite), MAX_VALIDITY);
+        let invite = invites.create(&owner, false, Duration::from_secs(90), start());
+        assert_eq!(expires_in(&invite), Duration::from_secs(90));
+    }
+
+    #[test]
+    fn guests_get_at_most_the_inviters_role() {
+        let mut invites = Invites::default();
+        for (role, view_only, expected) in [
+            (Role::Admin, true, Role::Viewer),
+            (Role::Admin, false, Role::Controller),
+            (Role::Controller, true, Role::Viewer),
+            (Role::Controller, false, Role::Controller),
+            (Role::Viewer, false, Role::Viewer),
+        ] {
+            let invite = invites.create(&inviter(role), view_only, Duration::ZERO, start());
+            assert_eq!(invite.role, expected.to_proto() as i32);
+            let guest = invites.redeem(&invite.token, start()).unwrap();
+            assert_eq!(guest.role, expected, "{:?} view_only {}", role, view_only);
+        }
+    }
+
+    #[test]
+    fn a_revoked_invite_cant_be_redeemed() {
+        let mut invites = Invites::default();
+        let owner = inviter(Role::Admin);
+        let revoked = invites.create(&owner, false, Duration::ZERO, start());
+        let kept = invites.create(&owner, false, Duration::ZERO, start());
+
+        assert!(invites.revoke(&revoked.id));
+        assert!(!invites.revoke(&revoked.id));
+        assert_eq!(invites.redeem(&revoked.token, start()), None);
+        assert!(invites.redeem(&kept.token, start()).is_some());
+    }
+
+    #[test]
+    fn unknown_tokens_are_refused() {
+        let mut invites = Invites::default();
+        invites.create(&inviter(Role::Admin), false, Duration::ZERO, start());
+        assert_eq!(invites.redeem("", start()), None);
+        assert_eq!(invites.redeem("not a token", start()), None);
+        assert_eq!(invites.list(start()).len(), 1);
+    }
+}
@@ -1,4 +1,17 @@
+pub mod audio;
+pub mod audit;
+pub mod auth;
+pub mod cal_options;
+pub mod clipboard;
 pub mod config;
+pub mod cursor;
 pub mod edcs_proto;
+pub mod error;
+pub mod file_transfer;
+pub mod frame;
+pub mod gamepad;
 pub mod handler;
+pub mod invites;
 pub mod server;
+pub mod session;
+pub mod stream_actor;
@@ -1,34 +1,52 @@
+use super::clipboard::ClipboardTracker;
 use super::config::EdcsConfig;
-use super::edcs_proto::{EdcsMessage, EdcsMessageType, EdcsResponse};
-use super::handler::EdcsHandler;
+use super::cursor::CursorTracker;
+use super::edcs_proto::{edcs_response, EdcsMessage, EdcsMessageType, EdcsResponse, EdcsStatus};
+use super::error::{self, EdcsError};
+use super::frame;
+use super::handler::{EdcsHandler, Handled};
+use super::session::{ConnectionId, ControlTracker};
 use anyhow::anyhow;
 use anyhow::Context;
 use log::debug;
 use log::error;
 use log::info;
 use log::trace;
+use log::warn;
 use parking_lot::Mutex;
-use prost::decode_length_delimiter;
 use prost::encode_length_delimiter;
 use prost::length_delimiter_len;
 use prost::Message;
-use rand::rngs::adapter;
 use rustls_pemfile::{certs, pkcs8_private_keys};
-use std::borrow::BorrowMut;
 use std::fs;
 use std::io;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::AsyncReadExt;
 use tokio::io::WriteHalf;
 use tokio::io::{split, AsyncWriteExt};
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task::JoinHandle;
 use tokio_rustls::rustls::{self, Certificate, PrivateKey};
 use tokio_rustls::{server::TlsStream, TlsAcceptor};
 // Somewhat inspired by https://github.com/tokio-rs/tls/blob/master/tokio-rustls/examples/server/src/main.rs
 
+// Roughly once a frame at 60fps
+const CURSOR_POLL_INTERVAL: Duration = Duration::from_millis(16);
+
+// Background tasks of a connection must not outlive it, even if the handler bails early.
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
 // get_certs and get_keys are directly copied from the tokio-rs codebase since they are just boilerplate.
 fn get_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
     certs(&mut io::BufReader::new(
@@ -51,17 +69,28 @@ pub async fn start(config_file_path: PathBuf) -> anyhow::Result<()> {
         &fs::read_to_string(config_file_path).with_context(|| "Failed to read EDCS config file")?,
     )?);
 
+    if edcs_config.clipboard.max_size >= edcs_config.limits.max_message_size {
+        warn!(
+            "The clipboard's max_size of {} doesn't fit in max_message_size, large clipboards will disconnect clients",
+            edcs_config.clipboard.max_size
+        );
+    }
+
+    // Always available, they don't touch anything unless a client picks them
+    crate::test_sources::register();
+    crate::vnc::register();
+
     let mut keys = get_keys(&edcs_config.key_path)?;
     let certs = get_certs(&edcs_config.cert_path)?;
 
     debug!("number of keys == {}", keys.len());
     debug!("number of certs == {}", certs.len());
-    if keys.len() == 0 {
+    if keys.is_empty() {
         return Err(anyhow!(
             "Zero private keys were found in the file, bailing."
         ));
     }
-    if certs.len() == 0 {
+    if certs.is_empty() {
         return Err(anyhow!(
             "Zero certificate keys were found in the file, bailing."
         ));
@@ -75,7 +104,7 @@ pub async fn start(config_file_path: PathBuf) -> anyhow::Result<()> {
     let acceptor = TlsAcceptor::from(Arc::new(s_config));
     let listener =
         TcpListener::bind(edcs_config.ip.to_string() + ":" + &edcs_config.port.to_string()).await?;
-    let handler = Arc::new(Mutex::new(EdcsHandler::default()));
+    let handler = Arc::new(Mutex::new(EdcsHandler::new(&edcs_config)?));
 
     info!("Server bound and main loop starting");
     loop {
@@ -92,13 +121,51 @@ pub async fn start(config_file_path: PathBuf) -> anyhow::Result<()> {
             let stream = acceptor.accept(stream).await?;
             let (reader, mut writer) = split(stream);
             let mut reader = tokio::io::BufReader::new(reader);
+
+            // Responses and server events share the writer, so everything is written from one task
+            let (resp_push, mut resp_recv) = mpsc::unbounded_channel::<EdcsResponse>();
+            let mut writer_task = AbortOnDrop(tokio::spawn(async move {
+                while let Some(edcs_response) = resp_recv.recv().await {
+                    if let Err(e) = write_pb(&mut writer, edcs_response).await {
+                        error!("Failed to send response to client {:?}", e);
+                    }
+                }
+            }));
+            let (connection, mut identity) = {
+                let mut handler_unlock = handler_copy.lock();
+                (
+                    handler_unlock.new_connection(peer_addr),
+                    handler_unlock.anonymous_identity(),
+                )
+            };
+            let clipboard_tracker = Arc::new(Mutex::new(ClipboardTracker::new(connection)));
+            let event_task = AbortOnDrop(tokio::spawn(poll_server_events(
+                Arc::clone(&handler_copy),
+                connection,
+                Arc::clone(&clipboard_tracker),
+                resp_push.clone(),
+            )));
             // Handle things with stream.read_buf/write_buf
 
             // Get the length delimiter
-            let mut delimiter_buf: Vec<u8> = vec![0; 10]; // I hate heap allocations
+            let mut delimiter_buf = [0; frame::DELIMITER_LEN];
             while let Ok(sz) = reader.read_exact(&mut delimiter_buf).await {
-                let pb_len = decode_length_delimiter(&delimiter_buf[..])
-                    .with_context(|| "Failed to decode the protobuf length delimiter")?;
+                let pb_len = match frame::message_len(
+                    &delimiter_buf[..],
+                    cfg_copy.limits.max_message_size,
+                ) {
+                    Ok(pb_len) => pb_len,
+                    Err(e) => {
+                        // Nothing after it can be trusted to line up with a
+                        // message, so the client is told why and let go
+                        info!("Closing connection from {}: {}", peer_addr, e);
+                        let _ = resp_push.send(
+                            EdcsError::Protocol(EdcsStatus::InvalidRequest, e.to_string())
+                                .into_response(),
+                        );
+                        break;
+                    }
+                };
 
                 trace!(
                     "Protobuf length delimiter slice is {:?}, size read is {}, pb_len is {}",
@@ -107,48 +174,79 @@ pub async fn start(config_file_path: PathBuf) -> anyhow::Result<()> {
                     pb_len
                 );
                 let mut message_buffer = vec![0; pb_len];
-                while let Ok(_) = reader.read_exact(&mut message_buffer).await {
-                    trace!("Protobuf data {:?}", message_buffer);
-                    let edcs_message = EdcsMessage::decode(&message_buffer[..])?;
-                    debug!("EDCS Message {:#?}", edcs_message);
-
-                    // So that the locked mutex gets unlocked when it goes out of scope
-                    let edcs_response = {
-                        let mut handler_unlock = handler_copy.lock();
-                        handler_unlock
-                            .handle_message(Arc::clone(&cfg_copy), edcs_message, peer_addr)
-                            .with_context(|| "Failed to get EDCS response")
-                            .expect("Failed to get EDCS resp")
-                    };
-
-                    // For performance reasons, not all requests return a response since it would be
-                    // unnecessary to respond to a mouse move event.
-                    if let Some(edcs_response) = edcs_response {
-                        if let Err(e) = write_pb(&mut writer, edcs_response).await {
-                            error!("Failed to send response to client {:?}", e);
-                        }
-                    }
+                if reader.read_exact(&mut message_buffer).await.is_err() {
                     break;
                 }
-            }
+                trace!("Protobuf data {:?}", message_buffer);
+                let edcs_message = match EdcsMessage::decode(&message_buffer[..]) {
+                    Ok(edcs_message) => edcs_message,
+                    // The frame was fine, so the next one can still be read
+                    Err(e) => {
+                        debug!("Invalid message from {}: {}", peer_addr, e);
+                        let _ = resp_push.send(
+                            EdcsError::Protocol(EdcsStatus::InvalidRequest, e.to_string())
+                                .into_response(),
+                        );
+                        continue;
+                    }
+                };
+                debug!("EDCS Message {:#?}", edcs_message);
 
-            {
-                // Shut down any remaining streams not shut down by the client
-                let mut handler_unlock = handler_copy.lock();
-                if handler_unlock.adapter_streaming() {
-                    info!("The client failed to shut down the EDSS stream, doing it after client disconnect");
-                    // This will be ignored if another CloseStream was already handled, and the previous line may
-                    // or may not be inaccurate
-                    handler_unlock.handle_message(
-                        cfg_copy,
-                        EdcsMessage {
-                            message_type: EdcsMessageType::CloseStream as i32,
-                            payload: None,
-                        },
-                        peer_addr,
+                // So that the locked mutex gets unlocked when it goes out of scope
+                let handled = {
+                    let mut handler_unlock = handler_copy.lock();
+                    let is_clipboard_update =
+                        edcs_message.message_type() == EdcsMessageType::ClipboardUpdate;
+                    let handled = handler_unlock
+                        .handle_message(
+                            Arc::clone(&cfg_copy),
+                            edcs_message,
+                            peer_addr,
+                            connection,
+                            &mut identity,
+                        )
+                        .unwrap_or_else(|e| {
+                            debug!("Failed to handle message from {}: {}", peer_addr, e);
+                            Handled::Response(Some(e.into_response()))
+                        });
+                    let went_through = matches!(
+                        &handled,
+                        Handled::Response(Some(r)) if r.status() == EdcsStatus::Ok
                     );
+                    if is_clipboard_update && went_through {
+                        handler_unlock.clipboard_sent_by_client(&mut clipboard_tracker.lock());
+                    }
+                    handled
+                };
+                // Stream jobs and file transfers are waited on without the
+                // handler locked, so other connections aren't held up by them
+                let edcs_response = match handled {
+                    Handled::Response(edcs_response) => edcs_response,
+                    Handled::Stream(pending) => {
+                        let done = pending.await;
+                        handler_copy.lock().stream_done(done)
+                    }
+                    Handled::File(pending) => {
+                        let done = pending.await;
+                        handler_copy.lock().file_done(done)
+                    }
+                };
+
+                // Every message is answered, input included, so the client
+                // can match the responses up with its requests
+                if let Some(edcs_response) = edcs_response {
+                    if let Err(e) = resp_push.send(edcs_response) {
+                        error!("Failed to queue response to client {:?}", e);
+                    }
                 }
             }
+
+            // Shut down whatever the client didn't, which is the whole stream if it set it up
+            handler_copy.lock().disconnect(connection);
+            // Let the writer flush whatever is left before the connection goes away
+            drop(event_task);
+            drop(resp_push);
+            let _ = (&mut writer_task.0).await;
             debug!("Finished RPC handler.");
             Ok(()) as anyhow::Result<()>
         };
@@ -160,6 +258,48 @@ pub async fn start(config_file_path: PathBuf) -> anyhow::Result<()> {
     }
 }
 
+async fn poll_server_events(
+    handler: Arc<Mutex<EdcsHandler>>,
+    connection: ConnectionId,
+    clipboard_tracker: Arc<Mutex<ClipboardTracker>>,
+    resp_push: UnboundedSender<EdcsResponse>,
+) {
+    let mut tracker = CursorTracker::default();
+    let mut control_tracker = ControlTracker::default();
+    let mut interval = tokio::time::interval(CURSOR_POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let (cursor_job, mut events) = {
+            let mut handler_unlock = handler.lock();
+            let cursor_job = handler_unlock.poll_cursor(&mut tracker);
+            let events: Vec<_> = handler_unlock
+                .poll_control(connection, &mut control_tracker)
+                .into_iter()
+                .collect();
+            (cursor_job, events)
+        };
+        // Polled in the background, this only reads what was found
+        events.extend(clipboard_tracker.lock().poll());
+        // Read on the stream actor, see EdcsHandler::poll_cursor
+        if let Some(cursor_job) = cursor_job {
+            if let Ok((polled, cursor_events)) = cursor_job.await {
+                tracker = polled;
+                events.extend(cursor_events);
+            }
+        }
+        for event in events {
+            let sent = resp_push.send(error::response(
+                EdcsStatus::Ok,
+                Some(edcs_response::Payload::ServerEvent(event)),
+            ));
+            if sent.is_err() {
+                // The writer is gone, so is the client
+                return;
+            }
+        }
+    }
+}
+
 async fn write_pb(
     writer: &mut WriteHalf<TlsStream<TcpStream>>,
     edcs_response: EdcsResponse,
@@ -181,11 +321,11 @@ async fn write_pb(
         "Need {} bytes for delim buffer",
         length_delimiter_len(encoded_len)
     );
-    writer.write_all(&mut send_delimiter_buf).await?;
+    writer.write_all(&send_delimiter_buf).await?;
 
-    let mut response_buffer = edcs_response.encode_to_vec();
+    let response_buffer = edcs_response.encode_to_vec();
     trace!("Response buffer is {:?}", response_buffer);
-    writer.write_all(&mut response_buffer).await?;
+    writer.write_all(&response_buffer).await?;
 
     Ok(())
 }
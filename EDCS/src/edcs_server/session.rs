@@ -0,0 +1,430 @@
+//! Who is on the stream and which of them holds input control. EDSS only knows
+//! about the extra SRTP destinations, everything else is kept here.
+
+use super::auth::{Identity, Role};
+use super::edcs_proto::{
+    edcs_server_event, EdcsControlState, EdcsMessageType, EdcsParticipant, EdcsServerEvent,
+};
+
+/// Every connection gets one, whether it joins the stream or not. It doubles as
+/// the participant id, so 0 is never handed out, EdcsControlState uses it for
+/// nobody.
+pub type ConnectionId = u32;
+
+#[derive(Debug)]
+struct Participant {
+    id: ConnectionId,
+    identity: Option<Identity>,
+    requesting_control: bool,
+    // The EDSS viewer, None for the owner, who gets the main destination
+    viewer_id: Option<u32>,
+}
+
+impl Participant {
+    fn can_send_input(&self) -> bool {
+        matches!(&self.identity, Some(identity) if identity.role >= Role::Controller)
+    }
+}
+
+/// What `Session::leave` removed, the handler undoes the EDSS side of it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Left {
+    NotParticipant,
+    // The stream goes with the owner
+    Owner,
+    Viewer(u32),
+}
+
+/// Messages that only the participant holding control may send.
+pub fn is_input(message_type: EdcsMessageType) -> bool {
+    matches!(
+        message_type,
+        EdcsMessageType::WriteMouseEvent
+            | EdcsMessageType::WriteKeyboardEvent
+            | EdcsMessageType::WriteTouchEvent
+            | EdcsMessageType::WritePenEvent
+            | EdcsMessageType::WriteGamepadEvent
+    )
+}
+
+#[derive(Debug, Default)]
+pub struct Session {
+    participants: Vec<Participant>,
+    owner: Option<ConnectionId>,
+    controller: Option<ConnectionId>,
+    last_connection_id: ConnectionId,
+    // Bumped on every change, so ControlTracker knows when to tell the client
+    serial: u32,
+    // Why the last stream ended, if it didn't end the normal way
+    error: Option<String>,
+}
+
+impl Session {
+    pub fn new_connection_id(&mut self) -> ConnectionId {
+        self.last_connection_id += 1;
+        self.last_connection_id
+    }
+
+    fn changed(&mut self) {
+        self.serial = self.serial.wrapping_add(1);
+    }
+
+    fn position(&self, id: ConnectionId) -> Option<usize> {
+        self.participants.iter().position(|p| p.id == id)
+    }
+
+    pub fn is_participant(&self, id: ConnectionId) -> bool {
+        self.position(id).is_some()
+    }
+
+    pub fn is_owner(&self, id: ConnectionId) -> bool {
+        self.owner == Some(id)
+    }
+
+    pub fn owner(&self) -> Option<ConnectionId> {
+        self.owner
+    }
+
+    pub fn has_control(&self, id: ConnectionId) -> bool {
+        self.controller == Some(id)
+    }
+
+    /// The connection that set the stream up owns it, and starts out with
+    /// control.
+    pub fn start(&mut self, id: ConnectionId, identity: Option<Identity>) {
+        self.participants = vec![Participant {
+            id,
+            identity,
+            requesting_control: false,
+            viewer_id: None,
+        }];
+        self.owner = Some(id);
+        self.controller = Some(id);
+        self.error = None;
+        self.changed();
+    }
+
+    pub fn join(&mut self, id: ConnectionId, identity: Option<Identity>, viewer_id: u32) {
+        self.participants.push(Participant {
+            id,
+            identity,
+            requesting_control: false,
+            viewer_id: Some(viewer_id),
+        });
+        self.changed();
+    }
+
+    /// Everyone leaves at once, eg. because the owner closed the stream.
+    pub fn end(&mut self) {
+        if self.owner.is_none() {
+            return;
+        }
+        self.participants.clear();
+        self.owner = None;
+        self.controller = None;
+        self.changed();
+    }
+
+    /// Like `end`, but the participants are told why with their last
+    /// EdcsControlState.
+    pub fn fail(&mut self, reason: String) {
+        self.error = Some(reason);
+        self.end();
+    }
+
+    pub fn leave(&mut self, id: ConnectionId) -> Left {
+        let index = match self.position(id) {
+            Some(index) => index,
+            None => return Left::NotParticipant,
+        };
+        if self.is_owner(id) {
+            self.end();
+            return Left::Owner;
+        }
+        let participant = self.participants.remove(index);
+        if self.has_control(id) {
+            self.controller = self.owner;
+        }
+        self.changed();
+        // Only the owner doesn't have one
+        Left::Viewer(participant.viewer_id.unwrap_or_default())
+    }
+
+    // AUTHENTICATE may come after joining
+    pub fn set_identity(&mut self, id: ConnectionId, identity: &Identity) {
+        if let Some(index) = self.position(id) {
+            self.participants[index].identity = Some(identity.clone());
+            self.changed();
+        }
+    }
+
+    // The errors from here on are meant for the client, like check_permission's
+
+    pub fn request_control(&mut self, id: ConnectionId) -> Result<(), String> {
+        let index = self
+            .position(id)
+            .ok_or_else(|| "Only participants can request control".to_owned())?;
+        if self.has_control(id) {
+            return Ok(());
+        }
+        if self.controller.is_none() {
+            self.controller = Some(id);
+        } else {
+            self.participants[index].requesting_control = true;
+        }
+        self.changed();
+        Ok(())
+    }
+
+    pub fn grant_control(&mut self, id: ConnectionId, to: ConnectionId) -> Result<(), String> {
+        if !self.has_control(id) && !self.is_owner(id) {
+            return Err(
+                "Only the participant in control or the owner can grant control".to_owned(),
+            );
+        }
+        let index = self
+            .position(to)
+            .ok_or_else(|| format!("{} is not a participant", to))?;
+        if !self.participants[index].can_send_input() {
+            return Err(format!("Participant {} can't send input", to));
+        }
+        self.participants[index].requesting_control = false;
+        self.controller = Some(to);
+        self.changed();
+        Ok(())
+    }
+
+    /// Control goes back to the owner.
+    pub fn revoke_control(&mut self, id: ConnectionId) -> Result<(), String> {
+        if !self.has_control(id) && !self.is_owner(id) {
+            return Err(
+                "Only the participant in control or the owner can revoke control".to_owned(),
+            );
+        }
+        self.controller = self.owner;
+        self.changed();
+        Ok(())
+    }
+
+    /// The session as `id` sees it. Connections that aren't participants get
+    /// an empty one, with the error the last stream ended with.
+    pub fn control_state(&self, id: ConnectionId) -> EdcsControlState {
+        if !self.is_participant(id) {
+            return EdcsControlState {
+                stream_error: self.error.clone().unwrap_or_default(),
+                ..Default::default()
+            };
+        }
+        EdcsControlState {
+            participants: self
+                .participants
+                .iter()
+                .map(|p| EdcsParticipant {
+                    id: p.id,
+                    name: p
+                        .identity
+                        .as_ref()
+                        .map(|identity| identity.name.clone())
+                        .unwrap_or_default(),
+                    role: p
+                        .identity
+                        .as_ref()
+                        .map(|identity| identity.role.to_proto() as i32)
+                        .unwrap_or_default(),
+                    owner: self.is_owner(p.id),
+                    requesting_control: p.requesting_control,
+                })
+                .collect(),
+            controller_id: self.controller.unwrap_or_default(),
+            your_id: id,
+            stream_error: String::new(),
+        }
+    }
+}
+
+/// Tracks what one connection was last told about the session, so it only
+/// hears about changes.
+#[derive(Debug, Default)]
+pub struct ControlTracker {
+    last_serial: Option<u32>,
+}
+
+impl ControlTracker {
+    pub fn poll(&mut self, session: &Session, id: ConnectionId) -> Option<EdcsServerEvent> {
+        let participant = session.is_participant(id);
+        // Connections that never joined don't hear about the stream, the ones
+        // that did still hear that they aren't on it any more
+        if !participant && self.last_serial.is_none() {
+            return None;
+        }
+        if self.last_serial == Some(session.serial) {
+            return None;
+        }
+        self.last_serial = participant.then_some(session.serial);
+        Some(EdcsServerEvent {
+            payload: Some(edcs_server_event::Payload::ControlState(
+                session.control_state(id),
+            )),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(name: &str, role: Role) -> Option<Identity> {
+        Some(Identity {
+            name: name.to_owned(),
+            role,
+            invite: None,
+        })
+    }
+
+    // An owner with id 1 and controllers with ids 2 and 3
+    fn session() -> Session {
+        let mut session = Session::default();
+        let ids: Vec<_> = (0..3).map(|_| session.new_connection_id()).collect();
+        assert_eq!(ids, [1, 2, 3]);
+        session.start(1, identity("owner", Role::Admin));
+        session.join(2, identity("second", Role::Controller), 10);
+        session.join(3, identity("third", Role::Controller), 11);
+        session
+    }
+
+    fn requesting(session: &Session, id: ConnectionId) -> bool {
+        session
+            .control_state(id)
+            .participants
+            .iter()
+            .any(|p| p.id == id && p.requesting_control)
+    }
+
+    #[test]
+    fn the_owner_starts_with_control() {
+        let session = session();
+        assert!(session.is_owner(1));
+        assert!(session.has_control(1));
+        let state = session.control_state(2);
+        assert_eq!(state.controller_id, 1);
+        assert_eq!(state.your_id, 2);
+        assert_eq!(state.participants.len(), 3);
+    }
+
+    #[test]
+    fn a_request_is_queued_while_someone_has_control() {
+        let mut session = session();
+        session.request_control(2).unwrap();
+        assert!(session.has_control(1));
+        assert!(requesting(&session, 2));
+
+        session.grant_control(1, 2).unwrap();
+        assert!(session.has_control(2));
+        assert!(!requesting(&session, 2));
+    }
+
+    #[test]
+    fn only_participants_request_control() {
+        let mut session = session();
+        assert!(session.request_control(4).is_err());
+    }
+
+    #[test]
+    fn only_the_controller_or_the_owner_grant_control() {
+        let mut session = session();
+        assert!(session.grant_control(3, 2).is_err());
+        assert!(session.has_control(1));
+
+        session.grant_control(1, 2).unwrap();
+        // The controller can pass it on
+        session.grant_control(2, 3).unwrap();
+        assert!(session.has_control(3));
+        // And the owner can take it from whoever has it
+        session.grant_control(1, 2).unwrap();
+        assert!(session.has_control(2));
+        assert!(session.revoke_control(3).is_err());
+    }
+
+    #[test]
+    fn viewers_cant_be_granted_control() {
+        let mut session = session();
+        session.join(4, identity("viewer", Role::Viewer), 12);
+        session.join(5, None, 13);
+        assert!(session.grant_control(1, 4).is_err());
+        assert!(session.grant_control(1, 5).is_err());
+        assert!(session.grant_control(1, 6).is_err());
+        assert!(session.has_control(1));
+    }
+
+    #[test]
+    fn control_goes_back_to_the_owner_when_the_controller_leaves() {
+        let mut session = session();
+        session.grant_control(1, 2).unwrap();
+        assert_eq!(session.leave(2), Left::Viewer(10));
+        assert!(session.has_control(1));
+        assert!(!session.is_participant(2));
+        assert_eq!(session.leave(2), Left::NotParticipant);
+    }
+
+    #[test]
+    fn the_session_ends_when_the_owner_leaves() {
+        let mut session = session();
+        session.grant_control(1, 2).unwrap();
+        assert_eq!(session.leave(1), Left::Owner);
+        assert_eq!(session.owner(), None);
+        for id in 1..=3 {
+            assert!(!session.is_participant(id));
+            assert!(!session.has_control(id));
+        }
+    }
+
+    #[test]
+    fn a_failed_session_says_why() {
+        let mut session = session();
+        session.fail("The worker crashed".to_owned());
+        assert_eq!(session.control_state(2).stream_error, "The worker crashed");
+        // The next stream starts without it
+        session.start(2, identity("second", Role::Controller));
+        assert_eq!(session.control_state(3).stream_error, "");
+    }
+
+    #[test]
+    fn the_tracker_only_reports_changes() {
+        let mut session = session();
+        let mut tracker = ControlTracker::default();
+        assert!(tracker.poll(&session, 2).is_some());
+        assert!(tracker.poll(&session, 2).is_none());
+        session.request_control(3).unwrap();
+        assert!(tracker.poll(&session, 2).is_some());
+        assert!(tracker.poll(&session, 2).is_none());
+    }
+
+    #[test]
+    fn the_tracker_ignores_connections_that_never_joined() {
+        let session = session();
+        let mut tracker = ControlTracker::default();
+        assert!(tracker.poll(&session, 4).is_none());
+    }
+
+    #[test]
+    fn the_tracker_sends_one_last_state_to_whoever_left() {
+        let mut session = session();
+        let mut tracker = ControlTracker::default();
+        tracker.poll(&session, 2).unwrap();
+
+        session.leave(2);
+        let last = match tracker.poll(&session, 2) {
+            Some(EdcsServerEvent {
+                payload: Some(edcs_server_event::Payload::ControlState(state)),
+            }) => state,
+            other => panic!("Expected a control state, got {:?}", other),
+        };
+        assert!(last.participants.is_empty());
+        assert_eq!(last.your_id, 0);
+
+        // Nothing more, however the session changes afterwards
+        assert!(tracker.poll(&session, 2).is_none());
+        session.request_control(3).unwrap();
+        assert!(tracker.poll(&session, 2).is_none());
+    }
+}
@@ -0,0 +1,262 @@
+//! Every stream is driven from a thread of its own, which owns the stream's
+//! adapter and runs the jobs it is sent one after another. Setting the
+//! stream up or tearing it down can take seconds, this way neither the handler
+//! nor the tokio runtime waits on it.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use log::warn;
+use parking_lot::Mutex;
+use tokio::sync::oneshot;
+
+use super::audio::NegotiatedAudio;
+use super::config::EvdevProxyConfig;
+use super::edcs_proto::{
+    EdcsCalOption, EdcsInputCapabilities, EdcsKeyboardEvent, EdcsMouseEvent, EdcsPenEvent,
+    EdcsTouchEvent,
+};
+use crate::edss_safe::edss::{CursorState, EdssError};
+use crate::edss_safe::stream_state::StreamState;
+use crate::edss_safe::worker::WorkerAdapter;
+
+type Job = Box<dyn FnOnce(&mut dyn StreamAdapter) + Send>;
+
+/// What a stream actor drives. That's a `WorkerAdapter`, except in the
+/// handler's tests.
+pub trait StreamAdapter: Send {
+    fn info(&self) -> StreamInfo;
+    fn stream_resolution(&self) -> Option<(u32, u32)>;
+    /// Why the stream is gone, None while it is up.
+    fn crashed(&self) -> Option<&str>;
+    fn init_server(
+        &mut self,
+        cal_option_dict: HashMap<String, String>,
+        audio: Option<NegotiatedAudio>,
+    ) -> Result<(), EdssError>;
+    fn init_streaming(&mut self) -> Result<(), EdssError>;
+    fn close(&mut self) -> Result<(), EdssError>;
+    fn write_mouse_event(&mut self, ev: EdcsMouseEvent) -> Result<(), EdssError>;
+    fn write_keyboard_event(&mut self, kev: EdcsKeyboardEvent) -> Result<(), EdssError>;
+    fn write_touch_event(&mut self, tev: EdcsTouchEvent) -> Result<(), EdssError>;
+    fn write_pen_event(&mut self, pev: EdcsPenEvent) -> Result<(), EdssError>;
+    fn read_cursor(&mut self, last_serial: Option<u32>) -> Result<CursorState, EdssError>;
+    fn add_viewer(&mut self, ip: SocketAddr, port: u16) -> Result<(u32, String), EdssError>;
+    fn remove_viewer(&mut self, viewer_id: u32) -> Result<(), EdssError>;
+}
+
+/// What SETUP_EDCS opens a stream with.
+#[derive(Debug, Clone)]
+pub struct OpenParams {
+    pub worker_path: PathBuf,
+    pub plugin_name: String,
+    pub addr: SocketAddr,
+    pub port: u16,
+    pub bitrate: u32,
+    pub framerate: u32,
+    pub evdev_proxy: Option<EvdevProxyConfig>,
+}
+
+pub type OpenStream = fn(OpenParams) -> Result<Box<dyn StreamAdapter>, EdssError>;
+
+/// Opens the stream in a worker process, see edss_safe/worker.rs.
+pub fn open_worker(params: OpenParams) -> Result<Box<dyn StreamAdapter>, EdssError> {
+    let worker = WorkerAdapter::spawn(
+        &params.worker_path,
+        params.plugin_name,
+        params.addr,
+        params.port,
+        params.bitrate,
+        params.framerate,
+        params.evdev_proxy.as_ref(),
+    )?;
+    Ok(Box::new(worker))
+}
+
+/// The handler's copy of the stream's state, as of the last job that changed
+/// it.
+#[derive(Debug, Clone, Default)]
+pub struct StreamInfo {
+    pub bitrate: u32,
+    pub framerate: u32,
+    pub cal_option_dict: HashMap<String, String>,
+    pub cal_option_schema: Vec<EdcsCalOption>,
+    pub sdp: Option<String>,
+    pub stream_resolution: Option<(u32, u32)>,
+    pub input_capabilities: EdcsInputCapabilities,
+    pub audio: Option<NegotiatedAudio>,
+    // Closing is only ever set by the handler, while the close job runs
+    pub state: StreamState,
+}
+
+impl StreamAdapter for WorkerAdapter {
+    fn info(&self) -> StreamInfo {
+        StreamInfo {
+            bitrate: self.bitrate,
+            framerate: self.framerate,
+            cal_option_dict: self.cal_option_dict.clone(),
+            cal_option_schema: self.cal_option_schema.clone(),
+            sdp: self.sdp.clone(),
+            stream_resolution: self.stream_resolution,
+            input_capabilities: self.input_capabilities.clone(),
+            audio: self.audio.clone(),
+            state: self.state(),
+        }
+    }
+
+    fn stream_resolution(&self) -> Option<(u32, u32)> {
+        self.stream_resolution
+    }
+
+    fn crashed(&self) -> Option<&str> {
+        WorkerAdapter::crashed(self)
+    }
+
+    fn init_server(
+        &mut self,
+        cal_option_dict: HashMap<String, String>,
+        audio: Option<NegotiatedAudio>,
+    ) -> Result<(), EdssError> {
+        self.cal_option_dict = cal_option_dict;
+        self.audio = audio;
+        WorkerAdapter::init_server(self)
+    }
+
+    fn init_streaming(&mut self) -> Result<(), EdssError> {
+        WorkerAdapter::init_streaming(self)
+    }
+
+    fn close(&mut self) -> Result<(), EdssError> {
+        WorkerAdapter::close(self)
+    }
+
+    fn write_mouse_event(&mut self, ev: EdcsMouseEvent) -> Result<(), EdssError> {
+        WorkerAdapter::write_mouse_event(self, ev)
+    }
+
+    fn write_keyboard_event(&mut self, kev: EdcsKeyboardEvent) -> Result<(), EdssError> {
+        WorkerAdapter::write_keyboard_event(self, kev)
+    }
+
+    fn write_touch_event(&mut self, tev: EdcsTouchEvent) -> Result<(), EdssError> {
+        WorkerAdapter::write_touch_event(self, tev)
+    }
+
+    fn write_pen_event(&mut self, pev: EdcsPenEvent) -> Result<(), EdssError> {
+        WorkerAdapter::write_pen_event(self, pev)
+    }
+
+    fn read_cursor(&mut self, last_serial: Option<u32>) -> Result<CursorState, EdssError> {
+        WorkerAdapter::read_cursor(self, last_serial)
+    }
+
+    fn add_viewer(&mut self, ip: SocketAddr, port: u16) -> Result<(u32, String), EdssError> {
+        WorkerAdapter::add_viewer(self, ip, port)
+    }
+
+    fn remove_viewer(&mut self, viewer_id: u32) -> Result<(), EdssError> {
+        WorkerAdapter::remove_viewer(self, viewer_id)
+    }
+}
+
+/// Dropping the last one stops the thread, which drops the adapter and with it
+/// the stream.
+#[derive(Debug, Clone)]
+pub struct StreamActor {
+    jobs: mpsc::Sender<Job>,
+    // Set by the thread once the worker is gone, see StreamAdapter::crashed
+    crashed: Arc<Mutex<Option<String>>>,
+}
+
+impl StreamActor {
+    /// Starts the thread, which opens the stream with `open` before it takes
+    /// any jobs. The returned future resolves once it did.
+    pub fn spawn<F>(open: F) -> (Self, impl Future<Output = Result<StreamInfo, EdssError>>)
+    where
+        F: FnOnce() -> Result<Box<dyn StreamAdapter>, EdssError> + Send + 'static,
+    {
+        let (jobs_send, jobs_recv) = mpsc::channel::<Job>();
+        let (opened_send, opened_recv) = oneshot::channel();
+        let crashed = Arc::new(Mutex::new(None));
+        let crashed_copy = Arc::clone(&crashed);
+
+        let spawned = thread::Builder::new()
+            .name("stream".to_owned())
+            .spawn(move || {
+                let mut adapter = match open() {
+                    Ok(adapter) => {
+                        let _ = opened_send.send(Ok(adapter.info()));
+                        adapter
+                    }
+                    Err(e) => {
+                        let _ = opened_send.send(Err(e));
+                        return;
+                    }
+                };
+                while let Ok(job) = jobs_recv.recv() {
+                    job(adapter.as_mut());
+                    if let Some(reason) = adapter.crashed() {
+                        *crashed_copy.lock() = Some(reason.to_owned());
+                    }
+                }
+            });
+        if let Err(e) = spawned {
+            // The sender went with the closure, so the future below fails
+            warn!("Failed to start the stream thread: {:?}", e);
+        }
+
+        let opened = async move {
+            opened_recv
+                .await
+                .unwrap_or_else(|_| Err(EdssError::uninitialised()))
+        };
+        (
+            Self {
+                jobs: jobs_send,
+                crashed,
+            },
+            opened,
+        )
+    }
+
+    /// Queues `job`, the returned future resolves with what it returned. The
+    /// job runs whether the future is awaited or not.
+    pub fn run<T, F>(&self, job: F) -> impl Future<Output = Result<T, EdssError>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut dyn StreamAdapter) -> Result<T, EdssError> + Send + 'static,
+    {
+        let (result_send, result_recv) = oneshot::channel();
+        let queued = self
+            .jobs
+            .send(Box::new(move |adapter: &mut dyn StreamAdapter| {
+                let _ = result_send.send(job(adapter));
+            }))
+            .is_ok();
+        async move {
+            if !queued {
+                return Err(EdssError::uninitialised());
+            }
+            // Only dropped without a result if the thread stopped
+            result_recv
+                .await
+                .unwrap_or_else(|_| Err(EdssError::uninitialised()))
+        }
+    }
+
+    /// Queues `job` without waiting for it.
+    pub fn send<F>(&self, job: F)
+    where
+        F: FnOnce(&mut dyn StreamAdapter) + Send + 'static,
+    {
+        let _ = self.jobs.send(Box::new(job));
+    }
+
+    pub fn crashed(&self) -> Option<String> {
+        self.crashed.lock().clone()
+    }
+}
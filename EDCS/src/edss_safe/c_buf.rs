@@ -0,0 +1,88 @@
+//! Conversions from Rust strings into the fixed size buffers in EDSS structs.
+//! Nothing here calls into libedss, so it can run under Miri.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+
+use super::edss::EdssError;
+use super::edss_unsafe;
+
+fn invalid_config() -> EdssError {
+    EdssError(edss_unsafe::EDSS_STATUS_EDSS_INVALID_CONFIG)
+}
+
+/// Copy `s` into a NUL terminated buffer of `N` bytes. Strings that don't fit
+/// or contain a NUL are rejected rather than truncated.
+pub fn str_to_c_buf<const N: usize>(s: &str) -> Result<[c_char; N], EdssError> {
+    let bytes = s.as_bytes();
+    if bytes.len() >= N || bytes.contains(&0) {
+        return Err(invalid_config());
+    }
+    let mut buf = [0 as c_char; N];
+    for (dst, src) in buf.iter_mut().zip(bytes) {
+        *dst = *src as c_char;
+    }
+    Ok(buf)
+}
+
+pub fn str_to_cstring(s: &str) -> Result<CString, EdssError> {
+    CString::new(s).map_err(|_| invalid_config())
+}
+
+/// Copy a string owned by EDSS or a CAL. NULL becomes an empty string, since
+/// that is what optional fields in EDSS structs use.
+///
+/// # Safety
+/// `s` has to be NULL or point to a NUL terminated string.
+pub unsafe fn c_str_to_string(s: *const c_char) -> String {
+    if s.is_null() {
+        return String::new();
+    }
+    CStr::from_ptr(s).to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes<const N: usize>(buf: [c_char; N]) -> Vec<u8> {
+        buf.iter().map(|c| *c as u8).collect()
+    }
+
+    #[test]
+    fn copies_and_terminates() {
+        assert_eq!(bytes(str_to_c_buf::<5>("abc").unwrap()), b"abc\0\0");
+        assert_eq!(bytes(str_to_c_buf::<3>("").unwrap()), b"\0\0\0");
+        // Bytes, not chars
+        assert_eq!(bytes(str_to_c_buf::<4>("é").unwrap()), b"\xc3\xa9\0\0");
+    }
+
+    #[test]
+    fn refuses_instead_of_truncating() {
+        assert_eq!(bytes(str_to_c_buf::<4>("abc").unwrap()), b"abc\0");
+        // No room left for the NUL
+        assert!(str_to_c_buf::<4>("abcd").is_err());
+        assert!(str_to_c_buf::<4>("abcdefgh").is_err());
+        assert!(str_to_c_buf::<0>("").is_err());
+        // Would be cut in the middle of the character
+        assert!(str_to_c_buf::<3>("aé").is_err());
+    }
+
+    #[test]
+    fn refuses_interior_nuls() {
+        assert!(str_to_c_buf::<8>("a\0b").is_err());
+        assert!(str_to_c_buf::<8>("\0").is_err());
+        assert!(str_to_cstring("a\0b").is_err());
+        assert_eq!(str_to_cstring("ab").unwrap().as_bytes(), b"ab");
+    }
+
+    #[test]
+    fn copies_c_strings() {
+        assert_eq!(unsafe { c_str_to_string(std::ptr::null()) }, "");
+        let s = CString::new("hello").unwrap();
+        assert_eq!(unsafe { c_str_to_string(s.as_ptr()) }, "hello");
+        // Bad UTF-8 from a CAL doesn't fail the whole call
+        let s = CString::new(vec![b'a', 0xff]).unwrap();
+        assert_eq!(unsafe { c_str_to_string(s.as_ptr()) }, "a\u{fffd}");
+    }
+}
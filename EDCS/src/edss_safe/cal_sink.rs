@@ -0,0 +1,152 @@
+use super::edss::EdssError;
+use super::edss_unsafe;
+use crate::edcs_server::edcs_proto::EdcsInputCapabilities;
+use crate::input_sink::{InputEvent, InputSink, TouchPhase};
+
+/// Hands input to the open CAL through EDSS, which is how input always worked
+/// before there were other sinks.
+#[derive(Debug)]
+pub struct CalSink {
+    // The CAL works in its own pixels
+    resolution: (u32, u32),
+    capabilities: EdcsInputCapabilities,
+}
+
+// Map normalised [0, 1] coordinates onto the CAL's pixel grid.
+fn scale_coords(x: f64, y: f64, (width, height): (u32, u32)) -> (f64, f64) {
+    let max_x = width.saturating_sub(1) as f64;
+    let max_y = height.saturating_sub(1) as f64;
+    (
+        (x.clamp(0.0, 1.0) * max_x).round(),
+        (y.clamp(0.0, 1.0) * max_y).round(),
+    )
+}
+
+fn check(result: edss_unsafe::EDSS_STATUS) -> Result<(), EdssError> {
+    if result != edss_unsafe::EDSS_STATUS_EDSS_OK {
+        return Err(EdssError(result));
+    }
+    Ok(())
+}
+
+impl CalSink {
+    pub fn new(resolution: (u32, u32), capabilities: EdcsInputCapabilities) -> Self {
+        Self {
+            resolution,
+            capabilities,
+        }
+    }
+}
+
+impl InputSink for CalSink {
+    fn input_capabilities(&self) -> EdcsInputCapabilities {
+        self.capabilities.clone()
+    }
+
+    fn write(&mut self, ev: InputEvent) -> Result<(), EdssError> {
+        match ev {
+            InputEvent::MouseMove { x, y } => {
+                let (x, y) = scale_coords(x, y, self.resolution);
+                let mut ev_c = edss_unsafe::edssMouseEvent_t {
+                    type_: edss_unsafe::edssMouseEventType_t_MOVE,
+                    payload: edss_unsafe::edssMouseEvent_t__bindgen_ty_1 {
+                        move_: edss_unsafe::edssMouseEvent_t__bindgen_ty_1_move { x, y },
+                    },
+                };
+                check(unsafe { edss_unsafe::edssWriteMouseEvent(&mut ev_c as *mut _) })
+            }
+            InputEvent::MouseButton { button, pressed } => {
+                let mut ev_c = edss_unsafe::edssMouseEvent_t {
+                    type_: edss_unsafe::edssMouseEventType_t_CLICK,
+                    payload: edss_unsafe::edssMouseEvent_t__bindgen_ty_1 {
+                        button: edss_unsafe::edssKeyData_t {
+                            pressed,
+                            button: button as i32,
+                        },
+                    },
+                };
+                check(unsafe { edss_unsafe::edssWriteMouseEvent(&mut ev_c as *mut _) })
+            }
+            InputEvent::Wheel {
+                vertical,
+                horizontal,
+            } => {
+                let mut ev_c = edss_unsafe::edssMouseEvent_t {
+                    type_: edss_unsafe::edssMouseEventType_t_WHEEL,
+                    payload: edss_unsafe::edssMouseEvent_t__bindgen_ty_1 {
+                        wheel: edss_unsafe::edssMouseEvent_t__bindgen_ty_1_wheel {
+                            vertical,
+                            horizontal,
+                        },
+                    },
+                };
+                check(unsafe { edss_unsafe::edssWriteMouseEvent(&mut ev_c as *mut _) })
+            }
+            InputEvent::RelMotion { dx, dy } => {
+                let mut ev_c = edss_unsafe::edssMouseEvent_t {
+                    type_: edss_unsafe::edssMouseEventType_t_REL_MOVE,
+                    payload: edss_unsafe::edssMouseEvent_t__bindgen_ty_1 {
+                        relMove: edss_unsafe::edssMouseEvent_t__bindgen_ty_1_relMove { dx, dy },
+                    },
+                };
+                check(unsafe { edss_unsafe::edssWriteMouseEvent(&mut ev_c as *mut _) })
+            }
+            InputEvent::Key { code, pressed } => {
+                let mut ev_c = edss_unsafe::edssKeyboardEvent_t {
+                    keyData: edss_unsafe::edssKeyData_t {
+                        button: code as i32,
+                        pressed,
+                    },
+                };
+                check(unsafe { edss_unsafe::edssWriteKeyboardEvent(&mut ev_c as *mut _) })
+            }
+            InputEvent::Touch {
+                contact_id,
+                phase,
+                x,
+                y,
+                pressure,
+            } => {
+                let (x, y) = scale_coords(x, y, self.resolution);
+                let mut ev_c = edss_unsafe::edssTouchEvent_t {
+                    contactId: contact_id,
+                    phase: match phase {
+                        TouchPhase::Start => edss_unsafe::edssTouchPhase_t_TOUCH_START,
+                        TouchPhase::Move => edss_unsafe::edssTouchPhase_t_TOUCH_MOVE,
+                        TouchPhase::End => edss_unsafe::edssTouchPhase_t_TOUCH_END,
+                        TouchPhase::Cancel => edss_unsafe::edssTouchPhase_t_TOUCH_CANCEL,
+                    },
+                    x,
+                    y,
+                    pressure,
+                };
+                check(unsafe { edss_unsafe::edssWriteTouchEvent(&mut ev_c as *mut _) })
+            }
+            InputEvent::Pen {
+                x,
+                y,
+                pressure,
+                tilt_x,
+                tilt_y,
+                in_range,
+                touching,
+                eraser,
+                barrel_button,
+            } => {
+                let (x, y) = scale_coords(x, y, self.resolution);
+                let mut ev_c = edss_unsafe::edssPenEvent_t {
+                    x,
+                    y,
+                    pressure,
+                    tiltX: tilt_x,
+                    tiltY: tilt_y,
+                    inRange: in_range,
+                    touching,
+                    eraser,
+                    barrelButton: barrel_button,
+                };
+                check(unsafe { edss_unsafe::edssWritePenEvent(&mut ev_c as *mut _) })
+            }
+        }
+    }
+}
@@ -0,0 +1,408 @@
+//! Capture sources written in Rust. EDSS only knows about `calPlugin_t`, so a
+//! registered source is handed to it as a plugin whose functions forward to the
+//! source. From EDSS' side it is no different to a CAL loaded with dlopen.
+//!
+//! EDSS keeps a single CAL open at a time, which is why the open source lives
+//! in a global as well.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::mem::ManuallyDrop;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, MutexGuard};
+
+use log::error;
+
+use super::c_buf::str_to_cstring;
+pub use super::edss::EdssError;
+use super::edss_unsafe;
+use super::str_map::StrMap;
+use crate::edcs_server::edcs_proto::{EdcsCalOption, EdcsCalOptionType, EdcsInputCapabilities};
+
+/// Pixel formats EDSS can take frames in. The capture thread copies 4 bytes a
+/// pixel, so only packed 32-bit formats are allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Bgra,
+    Rgba,
+    Argb,
+    Abgr,
+}
+
+impl PixelFormat {
+    pub const BYTES_PER_PIXEL: usize = 4;
+
+    fn to_av(self) -> edss_unsafe::AVPixelFormat {
+        match self {
+            PixelFormat::Bgra => edss_unsafe::AVPixelFormat_AV_PIX_FMT_BGRA,
+            PixelFormat::Rgba => edss_unsafe::AVPixelFormat_AV_PIX_FMT_RGBA,
+            PixelFormat::Argb => edss_unsafe::AVPixelFormat_AV_PIX_FMT_ARGB,
+            PixelFormat::Abgr => edss_unsafe::AVPixelFormat_AV_PIX_FMT_ABGR,
+        }
+    }
+}
+
+/// What `calInit` reports in `calConfig_t`. The frame buffer itself is
+/// allocated by the bridge.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameConfig {
+    pub width: u16,
+    pub height: u16,
+    pub framerate: u16,
+    pub pixel_format: PixelFormat,
+}
+
+impl FrameConfig {
+    pub fn frame_len(&self) -> usize {
+        self.width as usize * self.height as usize * PixelFormat::BYTES_PER_PIXEL
+    }
+}
+
+/// Mouse input in CAL pixels, like `edssMouseEvent_t`.
+#[derive(Debug, Clone, Copy)]
+pub enum MouseInput {
+    Move { x: f64, y: f64 },
+    // Linux input button code, eg. BTN_LEFT
+    Button { button: i32, pressed: bool },
+    // In wheel clicks, positive is away from the user and to the right
+    Wheel { vertical: i32, horizontal: i32 },
+    RelMove { dx: i32, dy: i32 },
+}
+
+// These are plain data already, so they are passed on as they are
+pub type KeyInput = edss_unsafe::edssKeyData_t;
+pub type TouchInput = edss_unsafe::edssTouchEvent_t;
+pub type PenInput = edss_unsafe::edssPenEvent_t;
+
+/// The Rust equivalent of `calPlugin_t`, see edssCALInterface.h for what each
+/// function is expected to do. Everything is called with the source locked, so
+/// input never arrives in the middle of `read_frame`.
+pub trait CaptureSource: Send {
+    /// Options the client can set and their defaults, like `calOptions`.
+    fn options(&self) -> HashMap<String, String>;
+
+    /// Describe the options from `options`, like `calOptionSchema`. Without a
+    /// schema every option is a plain string.
+    fn option_schema(&self) -> Option<Vec<EdcsCalOption>> {
+        None
+    }
+
+    /// Input beyond mouse and keyboard the source accepts.
+    fn input_capabilities(&self) -> EdcsInputCapabilities {
+        EdcsInputCapabilities::default()
+    }
+
+    /// Start capturing with the options the client picked, like `calInit`.
+    fn init(&mut self, options: HashMap<String, String>) -> Result<FrameConfig, EdssError>;
+
+    /// Fill `frame` with the current frame, in the format `init` returned. The
+    /// capture thread does its own pacing, so this shouldn't block.
+    fn read_frame(&mut self, frame: &mut [u8]) -> Result<(), EdssError>;
+
+    fn write_mouse_event(&mut self, ev: MouseInput) -> Result<(), EdssError>;
+
+    fn write_keyboard_event(&mut self, ev: KeyInput) -> Result<(), EdssError>;
+
+    fn write_touch_event(&mut self, _ev: TouchInput) -> Result<(), EdssError> {
+        Err(EdssError::unsupported())
+    }
+
+    fn write_pen_event(&mut self, _ev: PenInput) -> Result<(), EdssError> {
+        Err(EdssError::unsupported())
+    }
+
+    /// Undo `init`, like `calShutdown`. `init` may be called again afterwards.
+    fn shutdown(&mut self) -> Result<(), EdssError> {
+        Ok(())
+    }
+}
+
+type Factory = Box<dyn Fn() -> Box<dyn CaptureSource> + Send + Sync>;
+
+static REGISTRY: Mutex<Vec<(String, Factory)>> = Mutex::new(Vec::new());
+
+/// Make a source available under `name`, which clients then use in place of a
+/// CAL library path. A new source is created every time the CAL is opened.
+pub fn register<F>(name: &str, factory: F)
+where
+    F: Fn() -> Box<dyn CaptureSource> + Send + Sync + 'static,
+{
+    let mut registry = lock(&REGISTRY);
+    registry.retain(|(n, _)| n != name);
+    registry.push((name.to_owned(), Box::new(factory)));
+}
+
+// The C strings `options` points to
+struct CalOptionSchema {
+    options: Vec<edss_unsafe::edssCALOption_t>,
+    _strings: Vec<CString>,
+    _choices: Vec<Vec<*const c_char>>,
+}
+
+// The pointers only point into the vectors next to them
+unsafe impl Send for CalOptionSchema {}
+
+struct OpenSource {
+    source: Box<dyn CaptureSource>,
+    // calConfig_t.frame points in here, so it is only replaced in calInit
+    frame: Vec<u8>,
+    schema: Option<CalOptionSchema>,
+}
+
+static OPEN_SOURCE: Mutex<Option<OpenSource>> = Mutex::new(None);
+
+// A panic in a source must not poison EDSS for the next one
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Open the source registered as `name` in EDSS, replacing whatever source was
+/// open. Returns `None` if there is no such source, so `name` must be a CAL
+/// library instead.
+pub(super) fn open(
+    name: &str,
+    cal_option_dict: *mut *mut edss_unsafe::StrMap,
+) -> Option<edss_unsafe::EDSS_STATUS> {
+    let source = {
+        let registry = lock(&REGISTRY);
+        let (_, factory) = registry.iter().find(|(n, _)| n == name)?;
+        factory()
+    };
+    // EDSS calls straight back into calOptions, so the lock can't be held here
+    *lock(&OPEN_SOURCE) = Some(OpenSource {
+        source,
+        frame: Vec::new(),
+        schema: None,
+    });
+    Some(unsafe { edss_unsafe::edssOpenCALPlugin(&PLUGIN.0, cal_option_dict) })
+}
+
+/// Drop the open source. Only call this after `edssCloseCAL`.
+pub(super) fn close() {
+    lock(&OPEN_SOURCE).take();
+}
+
+// Run `f` on the open source, keeping panics from unwinding into EDSS
+fn with_source<F>(f: F) -> edss_unsafe::EDSS_STATUS
+where
+    F: FnOnce(&mut OpenSource) -> Result<(), EdssError>,
+{
+    let result = panic::catch_unwind(AssertUnwindSafe(|| match lock(&OPEN_SOURCE).as_mut() {
+        Some(open) => f(open),
+        None => Err(EdssError(edss_unsafe::EDSS_STATUS_EDSS_UNINITIALISED)),
+    }));
+    match result {
+        Ok(Ok(())) => edss_unsafe::EDSS_STATUS_EDSS_OK,
+        Ok(Err(e)) => e.0,
+        Err(_) => {
+            error!("The capture source panicked");
+            edss_unsafe::EDSS_STATUS_EDSS_CAL_LIBRARY_FAILURE
+        }
+    }
+}
+
+fn convert_cal_option_schema(schema: &[EdcsCalOption]) -> Result<CalOptionSchema, EdssError> {
+    let mut strings = vec![];
+    let mut choices = vec![];
+    let mut options = vec![];
+    // Moving a CString doesn't move its buffer, so the pointers stay valid
+    let mut c_str = |s: &str| -> Result<*const c_char, EdssError> {
+        let s = str_to_cstring(s)?;
+        let ptr = s.as_ptr();
+        strings.push(s);
+        Ok(ptr)
+    };
+    for option in schema {
+        let mut option_choices = option
+            .choices
+            .iter()
+            .map(|choice| c_str(choice))
+            .collect::<Result<Vec<_>, _>>()?;
+        option_choices.push(std::ptr::null());
+        options.push(edss_unsafe::edssCALOption_t {
+            name: c_str(&option.name)?,
+            description: c_str(&option.description)?,
+            type_: match option.option_type() {
+                EdcsCalOptionType::CalOptionString => {
+                    edss_unsafe::edssCALOptionType_t_CAL_OPTION_STRING
+                }
+                EdcsCalOptionType::CalOptionInt => edss_unsafe::edssCALOptionType_t_CAL_OPTION_INT,
+                EdcsCalOptionType::CalOptionFloat => {
+                    edss_unsafe::edssCALOptionType_t_CAL_OPTION_FLOAT
+                }
+                EdcsCalOptionType::CalOptionBool => {
+                    edss_unsafe::edssCALOptionType_t_CAL_OPTION_BOOL
+                }
+                EdcsCalOptionType::CalOptionEnum => {
+                    edss_unsafe::edssCALOptionType_t_CAL_OPTION_ENUM
+                }
+            },
+            defaultValue: c_str(&option.default_value)?,
+            choices: option_choices.as_ptr(),
+            hasRange: option.has_range,
+            min: option.min,
+            max: option.max,
+            required: option.required,
+        });
+        // Same as the CStrings, the heap buffer stays where it is
+        choices.push(option_choices);
+    }
+    Ok(CalOptionSchema {
+        options,
+        _strings: strings,
+        _choices: choices,
+    })
+}
+
+unsafe extern "C" fn cal_options(
+    cal_option_dict: *mut *mut edss_unsafe::StrMap,
+) -> edss_unsafe::EDSS_STATUS {
+    with_source(|open| {
+        let map = StrMap::from_hash_map(&open.source.options())?;
+        // EDSS takes the map over, same as with a C CAL
+        *cal_option_dict = map.into_raw();
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn cal_option_schema(
+    options: *mut *const edss_unsafe::edssCALOption_t,
+    count: *mut u32,
+) -> edss_unsafe::EDSS_STATUS {
+    with_source(|open| {
+        if open.schema.is_none() {
+            let schema = open
+                .source
+                .option_schema()
+                .ok_or_else(EdssError::unsupported)?;
+            open.schema = Some(convert_cal_option_schema(&schema)?);
+        }
+        let schema = &open.schema.as_ref().unwrap().options;
+        *options = schema.as_ptr();
+        *count = schema.len() as u32;
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn cal_input_capabilities(
+    caps: *mut edss_unsafe::edssInputCapabilities_t,
+) -> edss_unsafe::EDSS_STATUS {
+    with_source(|open| {
+        let source_caps = open.source.input_capabilities();
+        *caps = edss_unsafe::edssInputCapabilities_t {
+            touch: source_caps.touch,
+            pen: source_caps.pen,
+            maxTouchContacts: source_caps.max_touch_contacts.min(u8::MAX as u32) as u8,
+        };
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn cal_init(
+    cal_option_dict: *mut edss_unsafe::StrMap,
+    cal_cfg: *mut edss_unsafe::calConfig_t,
+) -> edss_unsafe::EDSS_STATUS {
+    with_source(|open| {
+        // The map still belongs to EDSS
+        let options = match StrMap::from_raw(cal_option_dict) {
+            Some(map) => ManuallyDrop::new(map).to_hash_map()?,
+            None => HashMap::new(),
+        };
+        let config = open.source.init(options)?;
+        if config.width == 0 || config.height == 0 || config.framerate == 0 {
+            error!("Capture source returned an empty frame config {:?}", config);
+            return Err(EdssError(edss_unsafe::EDSS_STATUS_EDSS_INVALID_CAL));
+        }
+        open.frame = vec![0; config.frame_len()];
+        *cal_cfg = edss_unsafe::calConfig_t {
+            pixFmt: config.pixel_format.to_av(),
+            height: config.height,
+            width: config.width,
+            framerate: config.framerate,
+            frame: open.frame.as_mut_ptr(),
+        };
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn cal_read_frame() -> edss_unsafe::EDSS_STATUS {
+    with_source(|open| open.source.read_frame(&mut open.frame))
+}
+
+unsafe extern "C" fn cal_write_mouse_event(
+    ev: *mut edss_unsafe::edssMouseEvent_t,
+) -> edss_unsafe::EDSS_STATUS {
+    let ev = &*ev;
+    let input = match ev.type_ {
+        edss_unsafe::edssMouseEventType_t_CLICK => MouseInput::Button {
+            button: ev.payload.button.button,
+            pressed: ev.payload.button.pressed,
+        },
+        edss_unsafe::edssMouseEventType_t_MOVE => MouseInput::Move {
+            x: ev.payload.move_.x,
+            y: ev.payload.move_.y,
+        },
+        edss_unsafe::edssMouseEventType_t_WHEEL => MouseInput::Wheel {
+            vertical: ev.payload.wheel.vertical,
+            horizontal: ev.payload.wheel.horizontal,
+        },
+        edss_unsafe::edssMouseEventType_t_REL_MOVE => MouseInput::RelMove {
+            dx: ev.payload.relMove.dx,
+            dy: ev.payload.relMove.dy,
+        },
+        _ => return edss_unsafe::EDSS_STATUS_EDSS_INVALID_MOUSE_DATA,
+    };
+    with_source(|open| open.source.write_mouse_event(input))
+}
+
+unsafe extern "C" fn cal_write_keyboard_event(
+    ev: *mut edss_unsafe::edssKeyboardEvent_t,
+) -> edss_unsafe::EDSS_STATUS {
+    let key = (*ev).keyData;
+    with_source(|open| open.source.write_keyboard_event(key))
+}
+
+unsafe extern "C" fn cal_write_touch_event(
+    ev: *mut edss_unsafe::edssTouchEvent_t,
+) -> edss_unsafe::EDSS_STATUS {
+    let ev = *ev;
+    with_source(|open| open.source.write_touch_event(ev))
+}
+
+unsafe extern "C" fn cal_write_pen_event(
+    ev: *mut edss_unsafe::edssPenEvent_t,
+) -> edss_unsafe::EDSS_STATUS {
+    let ev = *ev;
+    with_source(|open| open.source.write_pen_event(ev))
+}
+
+unsafe extern "C" fn cal_shutdown() -> edss_unsafe::EDSS_STATUS {
+    with_source(|open| {
+        let result = open.source.shutdown();
+        // calCfg is freed after this, nothing points at the frame anymore
+        open.frame = Vec::new();
+        result
+    })
+}
+
+struct BridgePlugin(edss_unsafe::calPlugin_t);
+
+// Only function pointers and a NULL audio source, which are fine to share
+unsafe impl Sync for BridgePlugin {}
+
+static PLUGIN: BridgePlugin = BridgePlugin(edss_unsafe::calPlugin_t {
+    calOptions: Some(cal_options),
+    calInit: Some(cal_init),
+    calReadFrame: Some(cal_read_frame),
+    calWriteMouseEvent: Some(cal_write_mouse_event),
+    calWriteKeyboardEvent: Some(cal_write_keyboard_event),
+    calShutdown: Some(cal_shutdown),
+    // TODO let sources report their cursor and audio
+    calReadCursor: None,
+    calAudioSource: std::ptr::null(),
+    calWriteTouchEvent: Some(cal_write_touch_event),
+    calWritePenEvent: Some(cal_write_pen_event),
+    calInputCapabilities: Some(cal_input_capabilities),
+    calOptionSchema: Some(cal_option_schema),
+});
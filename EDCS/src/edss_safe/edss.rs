@@ -1,18 +1,105 @@
-use log::{debug, info, trace};
+use log::{debug, trace, warn};
 use rand::RngCore;
 
+use super::c_buf::{c_str_to_string, str_to_c_buf, str_to_cstring};
+use super::cal_sink::CalSink;
+use super::capture_source;
 use super::edss_unsafe;
+use super::str_map::StrMap;
+use super::stream_state::{StreamEvent, StreamState, Teardown};
+use crate::edcs_server::audio::NegotiatedAudio;
+use crate::edcs_server::config::EvdevProxyConfig;
 use crate::edcs_server::edcs_proto::{
-    edcs_mouse_event, EdcsKeyData, EdcsKeyboardEvent, EdcsMouseButton, EdcsMouseEvent,
+    edcs_mouse_event, EdcsCalOption, EdcsCalOptionType, EdcsInputCapabilities, EdcsKeyData,
+    EdcsKeyboardEvent, EdcsMouseButton, EdcsMouseEvent, EdcsPenEvent, EdcsTouchEvent,
+    EdcsTouchPhase,
+};
+use crate::input_sink::{
+    self, EvdevProxySink, InputEvent, InputSink, QmpSink, TouchPhase, UinputSink, MAX_WHEEL_CLICKS,
 };
 use rand::rngs::OsRng;
 use std::collections::HashMap;
 use std::ffi::CStr;
+use std::fmt;
 use std::net::SocketAddr;
 use std::os::raw::{c_char, c_void};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct EdssError(pub edss_unsafe::EDSS_STATUS);
 
+impl EdssError {
+    // Optional CAL functions return this when they are not implemented
+    pub fn is_unsupported(&self) -> bool {
+        self.0 == edss_unsafe::EDSS_STATUS_EDSS_CAL_UNSUPPORTED
+    }
+
+    pub fn unsupported() -> Self {
+        Self(edss_unsafe::EDSS_STATUS_EDSS_CAL_UNSUPPORTED)
+    }
+
+    pub fn uninitialised() -> Self {
+        Self(edss_unsafe::EDSS_STATUS_EDSS_UNINITIALISED)
+    }
+
+    pub fn invalid_cal_options() -> Self {
+        Self(edss_unsafe::EDSS_STATUS_EDSS_INVALID_CAL_OPTIONS)
+    }
+
+    pub fn cal_file_not_found() -> Self {
+        Self(edss_unsafe::EDSS_STATUS_EDSS_CAL_FILE_NOT_FOUND)
+    }
+
+    pub fn cal_library_failure() -> Self {
+        Self(edss_unsafe::EDSS_STATUS_EDSS_CAL_LIBRARY_FAILURE)
+    }
+}
+
+impl fmt::Display for EdssError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Worded after the comments in edssStatus.h
+        let description = match self.0 {
+            edss_unsafe::EDSS_STATUS_EDSS_OK => "no error",
+            edss_unsafe::EDSS_STATUS_EDSS_INVALID_CONFIG => "invalid server configuration",
+            edss_unsafe::EDSS_STATUS_EDSS_INVALID_CAL_OPTIONS => "invalid CAL options",
+            edss_unsafe::EDSS_STATUS_EDSS_UNINITIALISED => "EDSS isn't initialised",
+            edss_unsafe::EDSS_STATUS_EDSS_CAL_FILE_NOT_FOUND => "the CAL is missing a file",
+            edss_unsafe::EDSS_STATUS_EDSS_CAL_LIBRARY_FAILURE => "a library the CAL uses failed",
+            edss_unsafe::EDSS_STATUS_EDSS_INVALID_CAL => "the CAL can't be opened",
+            edss_unsafe::EDSS_STATUS_EDSS_LIBAV_FAILURE => "libav failed",
+            edss_unsafe::EDSS_STATUS_EDSS_ENCODE_FAILURE => "a frame couldn't be encoded",
+            edss_unsafe::EDSS_STATUS_EDSS_ALLOCATION_FAILURE => "an allocation failed",
+            edss_unsafe::EDSS_STATUS_EDSS_PTHREAD_FAILURE => "pthreads failed",
+            edss_unsafe::EDSS_STATUS_EDSS_STRMAP_FAILURE => "StrMap failed",
+            edss_unsafe::EDSS_STATUS_EDSS_INVALID_MOUSE_DATA => "invalid mouse data",
+            edss_unsafe::EDSS_STATUS_EDSS_CAL_UNSUPPORTED => "the CAL doesn't support this",
+            edss_unsafe::EDSS_STATUS_EDSS_INVALID_INPUT_DATA => "invalid touch or pen data",
+            edss_unsafe::EDSS_STATUS_EDSS_INVALID_AUDIO_SOURCE => {
+                "the audio source doesn't exist or can't be opened"
+            }
+            edss_unsafe::EDSS_STATUS_EDSS_TOO_MANY_VIEWERS => "the stream has too many viewers",
+            status => return write!(f, "unknown EDSS status {}", status),
+        };
+        f.write_str(description)
+    }
+}
+
+impl std::error::Error for EdssError {}
+
+/// Snapshot of the remote cursor. The image is only copied out of the CAL when
+/// its serial changes.
+#[derive(Debug, Clone)]
+pub struct CursorState {
+    pub x: i32,
+    pub y: i32,
+    pub visible: bool,
+    pub serial: u32,
+    pub width: u32,
+    pub height: u32,
+    pub hotspot_x: u32,
+    pub hotspot_y: u32,
+    pub image: Option<Vec<u8>>,
+}
+
 #[derive(Debug)]
 pub struct EdssAdapter {
     pub ip: SocketAddr,
@@ -21,126 +108,249 @@ pub struct EdssAdapter {
     pub framerate: u32,
     pub srtp_out_params: String, // Maximum length is 32
     pub cal_option_dict: HashMap<String, String>,
+    pub cal_option_schema: Vec<EdcsCalOption>,
     pub sdp: Option<String>, // Only Some if init_server was called
-    streaming: bool,
-    stream_setup: bool,
+    pub stream_resolution: Option<(u32, u32)>, // Only Some if init_server was called
+    pub input_capabilities: EdcsInputCapabilities,
+    pub audio: Option<NegotiatedAudio>, // Set before init_server, None for video only
+    // What the CAL itself takes, input_capabilities follows the input sink
+    cal_input_capabilities: EdcsInputCapabilities,
+    // Picked with the inputSink option in init_server
+    input_sink: Option<Box<dyn InputSink>>,
+    // Only Some after enable_evdev_proxy, which adds the evdev-proxy sink
+    evdev_proxy_config: Option<EvdevProxyConfig>,
+    // Only changed through StreamState::transition, see next_state
+    state: StreamState,
 }
 
-union MouseData {
-    key_code: u32,
-    coords: (u32, u32),
+/// Not CAL options as such, EDCS adds them to every CAL.
+pub const INPUT_SINK_OPTION: &str = "inputSink";
+pub const QMP_SOCKET_OPTION: &str = "qmpSocket";
+pub const EVDEV_PROXY_VM_OPTION: &str = "evdevProxyVm";
+
+// AES_CM_128_HMAC_SHA1_80 takes a 16 byte key and a 14 byte salt, which is
+// exactly 40 characters of base64
+fn new_srtp_out_params() -> String {
+    let mut srtp_out_params_buf = [0u8; 30];
+    OsRng.fill_bytes(&mut srtp_out_params_buf);
+    base64::encode(srtp_out_params_buf)
 }
 
 impl EdssAdapter {
     // I'm sure there is some standardised method of doing this, but I guess
     // I'm not really doing that.
 
-    // Prevent directly writing to this field, but allow access still.
-    pub fn streaming(&self) -> bool {
-        self.streaming
+    // Anything out of order is refused before it gets to EDSS
+    fn next_state(&self, event: StreamEvent) -> Result<StreamState, EdssError> {
+        self.state.transition(event).map_err(|e| {
+            debug!("Refusing to go on with the stream: {}", e);
+            EdssError::uninitialised()
+        })
     }
-    // Prevent directly writing to this field, but allow access still.
-    pub fn stream_setup(&self) -> bool {
-        self.stream_setup
+
+    fn advance(&mut self, event: StreamEvent) -> Result<(), EdssError> {
+        self.state = self.next_state(event)?;
+        Ok(())
     }
 
-    fn to_c_struct(&self) -> edss_unsafe::edssConfig_t {
-        // C requires the octets to be treated as little endian
-        unsafe {
-            let str_map = edss_unsafe::sm_new(self.cal_option_dict.len().try_into().unwrap());
-            debug!("Begin conversion to C struct");
-            for key in self.cal_option_dict.keys() {
-                let key_c = key.to_owned() + "\0";
-                let val_c = self.cal_option_dict[key].to_owned() + "\0";
-                let ret = edss_unsafe::sm_put(
-                    str_map,
-                    key_c.as_ptr() as *const c_char,
-                    val_c.as_ptr() as *const c_char,
-                );
-                debug!("sm_put returns {}, key_c {}, val_c {}", ret, key_c, val_c);
-            }
+    // Drop does this once there is an adapter
+    fn close_cal() {
+        let result = unsafe { edss_unsafe::edssCloseCAL() };
+        if result != edss_unsafe::EDSS_STATUS_EDSS_OK {
+            warn!("Failed to close the CAL, status {}", result);
+        }
+        capture_source::close();
+    }
 
-            // Big brain: just loop through string chars and push it in
-            let mut srtp_out_params_c: [c_char; 41] = ['\0' as c_char; 41];
-            for (i, ch) in self.srtp_out_params[..40].chars().enumerate() {
-                srtp_out_params_c[i] = ch as c_char;
-            }
+    /// The returned StrMap backs `calOptionDict`, so it has to be kept alive
+    /// for as long as the struct is used.
+    fn to_c_struct(&self) -> Result<(edss_unsafe::edssConfig_t, StrMap), EdssError> {
+        debug!("Begin conversion to C struct");
+        let cal_option_dict = StrMap::from_hash_map(&self.cal_option_dict)?;
 
-            let mut socket_addr_c: [c_char; 46] = ['\0' as c_char; 46];
-            for (i, ch) in self.ip.to_string().chars().enumerate() {
-                socket_addr_c[i] = ch as c_char;
-            }
+        // A zero sample rate tells EDSS not to send audio
+        let (audio_sample_rate, audio_bitrate, audio_channels, audio_source) = match &self.audio {
+            Some(audio) => (
+                audio.params.sample_rate,
+                audio.params.bitrate,
+                audio.params.channels as u8,
+                str_to_c_buf(&audio.source)?,
+            ),
+            None => (0, 0, 0, [0; 32]),
+        };
 
-            edss_unsafe::edssConfig_t {
-                socketAddr: socket_addr_c,
-                port: self.port,
-                bitrate: self.bitrate,
-                framerate: self.framerate,
-                srtpOutParams: srtp_out_params_c,
-                calOptionDict: str_map,
-            }
-        }
+        let config = edss_unsafe::edssConfig_t {
+            // EDSS adds the port itself
+            socketAddr: str_to_c_buf(&self.ip.ip().to_string())?,
+            port: self.port,
+            bitrate: self.bitrate,
+            framerate: self.framerate,
+            srtpOutParams: str_to_c_buf(&self.srtp_out_params)?,
+            calOptionDict: cal_option_dict.as_ptr(),
+            audioSampleRate: audio_sample_rate,
+            audioBitrate: audio_bitrate,
+            audioChannels: audio_channels,
+            audioSource: audio_source,
+        };
+        Ok((config, cal_option_dict))
     }
 
-    extern "C" fn strmap_enum_callback(
-        key: *const c_char,
-        value: *const c_char,
-        p_hash_map: *const c_void,
-    ) {
-        trace!("In strmap_enum_callback");
-        let hash_map: &mut HashMap<String, String> = unsafe { std::mem::transmute(p_hash_map) };
-        let key_rs = unsafe { CStr::from_ptr(key) }
-            .to_str()
-            .expect("Failed to get strmap key")
-            .to_owned();
-        let value_rs = unsafe { CStr::from_ptr(value) }
-            .to_str()
-            .expect("Failed to get strmap value")
-            .to_owned();
-        hash_map.insert(key_rs, value_rs);
-    }
-
-    fn strmap_to_hashmap(
-        in_map: *mut edss_unsafe::StrMap,
-    ) -> anyhow::Result<HashMap<String, String>, EdssError> {
-        trace!("In strmap_to_hashmap");
-        let hash_map: HashMap<String, String> = HashMap::new();
-        let hash_map_void: *const c_void = unsafe { std::mem::transmute(&hash_map) };
-        unsafe {
-            if edss_unsafe::sm_enum(in_map, Some(Self::strmap_enum_callback), hash_map_void) < 1 {
-                return Err(EdssError(edss_unsafe::EDSS_STATUS_EDSS_STRMAP_FAILURE));
-                // TODO, is this really the best error for us to return?
+    unsafe fn convert_cal_option(
+        option: &edss_unsafe::edssCALOption_t,
+    ) -> Result<EdcsCalOption, EdssError> {
+        let option_type = match option.type_ {
+            edss_unsafe::edssCALOptionType_t_CAL_OPTION_STRING => {
+                EdcsCalOptionType::CalOptionString
             }
+            edss_unsafe::edssCALOptionType_t_CAL_OPTION_INT => EdcsCalOptionType::CalOptionInt,
+            edss_unsafe::edssCALOptionType_t_CAL_OPTION_FLOAT => EdcsCalOptionType::CalOptionFloat,
+            edss_unsafe::edssCALOptionType_t_CAL_OPTION_BOOL => EdcsCalOptionType::CalOptionBool,
+            edss_unsafe::edssCALOptionType_t_CAL_OPTION_ENUM => EdcsCalOptionType::CalOptionEnum,
+            _ => return Err(EdssError(edss_unsafe::EDSS_STATUS_EDSS_INVALID_CAL)),
         };
-        Ok(hash_map)
+        let mut choices = vec![];
+        if !option.choices.is_null() {
+            let mut choice = option.choices;
+            while !(*choice).is_null() {
+                choices.push(c_str_to_string(*choice));
+                choice = choice.add(1);
+            }
+        }
+        Ok(EdcsCalOption {
+            name: c_str_to_string(option.name),
+            description: c_str_to_string(option.description),
+            option_type: option_type as i32,
+            default_value: c_str_to_string(option.defaultValue),
+            choices,
+            has_range: option.hasRange,
+            min: option.min,
+            max: option.max,
+            required: option.required,
+        })
+    }
+
+    // CALs without a schema get a plain string option for every key they have
+    fn read_cal_option_schema(
+        cal_option_dict: &HashMap<String, String>,
+    ) -> Result<Vec<EdcsCalOption>, EdssError> {
+        let mut options: *const edss_unsafe::edssCALOption_t = std::ptr::null();
+        let mut count = 0u32;
+        let result = unsafe { edss_unsafe::edssGetCALOptionSchema(&mut options, &mut count) };
+        if result == edss_unsafe::EDSS_STATUS_EDSS_CAL_UNSUPPORTED {
+            return Ok(cal_option_dict
+                .iter()
+                .map(|(name, value)| EdcsCalOption {
+                    name: name.clone(),
+                    default_value: value.clone(),
+                    ..Default::default()
+                })
+                .collect());
+        }
+        if result != edss_unsafe::EDSS_STATUS_EDSS_OK {
+            return Err(EdssError(result));
+        }
+        if options.is_null() || count == 0 {
+            return Ok(vec![]);
+        }
+        let options = unsafe { std::slice::from_raw_parts(options, count as usize) };
+        options
+            .iter()
+            .map(|option| unsafe { Self::convert_cal_option(option) })
+            .collect()
     }
 
     pub fn new(
-        mut plugin_name: String,
+        plugin_name: String,
         ip: SocketAddr,
         port: u16,
         bitrate: u32,
         framerate: u32,
     ) -> anyhow::Result<Self, EdssError> {
-        let config = unsafe {
-            plugin_name += "\0"; // If you don't do this, the strings will become garbled.
-
+        let cal_option_dict = unsafe {
             let mut config: *mut edss_unsafe::StrMap = std::ptr::null_mut();
 
-            trace!("strmap address is {:p}", config);
-            let cal_open_result =
-                edss_unsafe::edssOpenCAL(plugin_name.as_ptr() as *mut c_char, &mut config);
+            // Sources registered from Rust take precedence over CAL libraries
+            let cal_open_result = match capture_source::open(&plugin_name, &mut config) {
+                Some(result) => result,
+                None => {
+                    let plugin_name = str_to_cstring(&plugin_name)?;
+                    edss_unsafe::edssOpenCAL(plugin_name.as_ptr() as *mut c_char, &mut config)
+                }
+            };
             if cal_open_result != edss_unsafe::EDSS_STATUS_EDSS_OK {
                 return Err(EdssError(cal_open_result));
             }
-            trace!("strmap address is {:p}", config);
-            config
+            // The CAL allocates the map and hands it over to us
+            StrMap::from_raw(config)
+        };
+
+        // Nothing owns the CAL until the adapter exists, so until then it is
+        // closed here
+        let adapter = match cal_option_dict {
+            Some(cal_option_dict) => {
+                Self::with_open_cal(cal_option_dict, ip, port, bitrate, framerate)
+            }
+            None => Err(EdssError(edss_unsafe::EDSS_STATUS_EDSS_STRMAP_FAILURE)),
         };
+        if adapter.is_err() {
+            Self::close_cal();
+        }
+        adapter
+    }
 
-        // Generate a random 40-digit base64;
-        let mut srtp_out_params_buf = [0u8; 40];
-        OsRng.fill_bytes(&mut srtp_out_params_buf);
-        let srtp_out_params = base64::encode(&srtp_out_params_buf);
+    fn with_open_cal(
+        cal_option_dict: StrMap,
+        ip: SocketAddr,
+        port: u16,
+        bitrate: u32,
+        framerate: u32,
+    ) -> Result<Self, EdssError> {
+        let mut cal_option_dict = cal_option_dict.to_hash_map()?;
+        let mut cal_option_schema = Self::read_cal_option_schema(&cal_option_dict)?;
+        Self::add_edcs_option(
+            &mut cal_option_dict,
+            &mut cal_option_schema,
+            EdcsCalOption {
+                name: INPUT_SINK_OPTION.to_owned(),
+                description: "Where input goes, the CAL or straight to the VM or host".to_owned(),
+                option_type: EdcsCalOptionType::CalOptionEnum as i32,
+                default_value: input_sink::CAL_SINK.to_owned(),
+                choices: vec![
+                    input_sink::CAL_SINK.to_owned(),
+                    input_sink::QMP_SINK.to_owned(),
+                    input_sink::UINPUT_SINK.to_owned(),
+                ],
+                required: true,
+                ..Default::default()
+            },
+        );
+        Self::add_edcs_option(
+            &mut cal_option_dict,
+            &mut cal_option_schema,
+            Self::string_option(
+                QMP_SOCKET_OPTION,
+                "QMP socket of the VM, for the qmp input sink",
+            ),
+        );
+
+        let input_capabilities = unsafe {
+            let mut caps_c = edss_unsafe::edssInputCapabilities_t {
+                touch: false,
+                pen: false,
+                maxTouchContacts: 0,
+            };
+            let result = edss_unsafe::edssGetInputCapabilities(&mut caps_c as *mut _);
+            if result != edss_unsafe::EDSS_STATUS_EDSS_OK {
+                return Err(EdssError(result));
+            }
+            EdcsInputCapabilities {
+                touch: caps_c.touch,
+                pen: caps_c.pen,
+                max_touch_contacts: caps_c.maxTouchContacts as u32,
+            }
+        };
+
+        let srtp_out_params = new_srtp_out_params();
 
         Ok(Self {
             ip,
@@ -148,111 +358,366 @@ impl EdssAdapter {
             bitrate,
             framerate,
             srtp_out_params,
-            cal_option_dict: Self::strmap_to_hashmap(config)?,
+            cal_option_dict,
+            cal_option_schema,
             sdp: None,
-            streaming: false,
-            stream_setup: false,
+            stream_resolution: None,
+            input_capabilities: input_capabilities.clone(),
+            audio: None,
+            cal_input_capabilities: input_capabilities,
+            input_sink: None,
+            evdev_proxy_config: None,
+            // Opened in new
+            state: StreamState::CalOpened,
         })
     }
+    fn string_option(name: &str, description: &str) -> EdcsCalOption {
+        EdcsCalOption {
+            name: name.to_owned(),
+            description: description.to_owned(),
+            option_type: EdcsCalOptionType::CalOptionString as i32,
+            ..Default::default()
+        }
+    }
+
+    // CALs only look up their own options, so the extra ones are harmless
+    fn add_edcs_option(
+        cal_option_dict: &mut HashMap<String, String>,
+        cal_option_schema: &mut Vec<EdcsCalOption>,
+        option: EdcsCalOption,
+    ) {
+        if cal_option_dict.contains_key(&option.name) {
+            return;
+        }
+        cal_option_dict.insert(option.name.clone(), option.default_value.clone());
+        cal_option_schema.push(option);
+    }
+
+    /// Offer evdev-proxy as an input sink, with the VM named in the
+    /// evdevProxyVm option.
+    pub fn enable_evdev_proxy(&mut self, config: EvdevProxyConfig) {
+        if let Some(option) = self
+            .cal_option_schema
+            .iter_mut()
+            .find(|o| o.name == INPUT_SINK_OPTION)
+        {
+            option.choices.push(input_sink::EVDEV_PROXY_SINK.to_owned());
+        }
+        Self::add_edcs_option(
+            &mut self.cal_option_dict,
+            &mut self.cal_option_schema,
+            Self::string_option(
+                EVDEV_PROXY_VM_OPTION,
+                "evdev-proxy VM, for the evdev-proxy input sink",
+            ),
+        );
+        self.evdev_proxy_config = Some(config);
+    }
+
+    /// Replace the input sink picked by init_server, eg. with a
+    /// `RecordingSink` in tests.
+    pub fn set_input_sink(&mut self, sink: Box<dyn InputSink>) {
+        self.input_capabilities = sink.input_capabilities();
+        self.input_sink = Some(sink);
+    }
+
+    // Everything but the CAL sink, which needs the stream resolution
+    fn open_input_sink(&self) -> Result<Option<Box<dyn InputSink>>, EdssError> {
+        let option = |name| {
+            self.cal_option_dict
+                .get(name)
+                .map(String::as_str)
+                .filter(|value| !value.is_empty())
+        };
+        let required = |name| {
+            option(name).ok_or_else(|| {
+                warn!(
+                    "The {} input sink needs {}",
+                    option(INPUT_SINK_OPTION).unwrap_or(""),
+                    name
+                );
+                EdssError::invalid_cal_options()
+            })
+        };
+        Ok(
+            match option(INPUT_SINK_OPTION).unwrap_or(input_sink::CAL_SINK) {
+                input_sink::CAL_SINK => None,
+                input_sink::QMP_SINK => {
+                    Some(Box::new(QmpSink::connect(required(QMP_SOCKET_OPTION)?)?))
+                }
+                input_sink::UINPUT_SINK => Some(Box::new(UinputSink::create()?)),
+                input_sink::EVDEV_PROXY_SINK => {
+                    // Only offered with a config, but the client can send it anyway
+                    let config = self
+                        .evdev_proxy_config
+                        .as_ref()
+                        .ok_or_else(EdssError::invalid_cal_options)?;
+                    Some(Box::new(EvdevProxySink::connect(
+                        config,
+                        required(EVDEV_PROXY_VM_OPTION)?,
+                    )?))
+                }
+                sink => {
+                    warn!("Unknown input sink {}", sink);
+                    return Err(EdssError::invalid_cal_options());
+                }
+            },
+        )
+    }
+
     // TODO implement more robust error handling from these functions
     pub fn init_server(&mut self) -> Result<(), EdssError> {
+        // A second edssInitServer would leak the first one
+        let configured = self.next_state(StreamEvent::InitServer)?;
+        // Before EDSS, so a wrong path doesn't leave a stream behind
+        let input_sink = self.open_input_sink()?;
+        let (mut config, _cal_option_dict) = self.to_c_struct()?;
         unsafe {
             let mut sdp_cstr: *mut c_char = std::ptr::null_mut();
-            let result =
-                edss_unsafe::edssInitServer(&mut self.to_c_struct() as *mut _, &mut sdp_cstr);
+            let result = edss_unsafe::edssInitServer(&mut config as *mut _, &mut sdp_cstr);
             if result != edss_unsafe::EDSS_STATUS_EDSS_OK {
+                // Whatever was set up before the failure has to go
+                edss_unsafe::edssCloseServer();
                 return Err(EdssError(result));
             }
-            self.sdp = Some(
-                CStr::from_ptr(sdp_cstr)
-                    .to_str()
-                    .expect("Invalid SDP from EDSS")
-                    .to_owned(),
-            );
-            self.stream_setup = true;
+            // From here on the server has to be closed, even if the rest fails
+            self.state = configured;
+            // EDSS mallocs the SDP and leaves it to us
+            let sdp = CStr::from_ptr(sdp_cstr).to_string_lossy().into_owned();
+            libc::free(sdp_cstr as *mut c_void);
+            self.sdp = Some(sdp);
+
+            let (mut width, mut height) = (0u16, 0u16);
+            let result = edss_unsafe::edssGetStreamResolution(&mut width, &mut height);
+            if result != edss_unsafe::EDSS_STATUS_EDSS_OK {
+                return Err(EdssError(result));
+            }
+            self.stream_resolution = Some((width as u32, height as u32));
+            self.set_input_sink(input_sink.unwrap_or_else(|| {
+                Box::new(CalSink::new(
+                    (width as u32, height as u32),
+                    self.cal_input_capabilities.clone(),
+                ))
+            }));
 
             trace!("EdcsAdapter SDP field:\n{}", self.sdp.as_ref().unwrap());
+            trace!("EdcsAdapter stream resolution {}x{}", width, height);
         }
         Ok(())
     }
     pub fn init_streaming(&mut self) -> Result<(), EdssError> {
-        unsafe {
-            edss_unsafe::edssInitStreaming();
-            self.streaming = true;
+        // Some of the threads may have started even if it fails, so closing
+        // has to happen anyway
+        self.advance(StreamEvent::InitStreaming)?;
+        let result = unsafe { edss_unsafe::edssInitStreaming() };
+        if result != edss_unsafe::EDSS_STATUS_EDSS_OK {
+            return Err(EdssError(result));
         }
         Ok(())
     }
-    pub fn close_streaming(&mut self) -> Result<(), EdssError> {
-        unsafe {
-            edss_unsafe::edssCloseStreaming();
-            self.streaming = false;
-            self.stream_setup = false;
-            // TODO destroy all stream variables
+    /// Tear down however far the stream got, back to just the CAL being open.
+    /// EDSS is done with the stream whether it succeeds or not.
+    pub fn close(&mut self) -> Result<(), EdssError> {
+        let teardown = self.state.teardown();
+        if teardown == Teardown::Nothing {
+            return Ok(());
+        }
+        self.advance(StreamEvent::Close)?;
+        let result = unsafe {
+            if teardown == Teardown::CloseStreaming {
+                edss_unsafe::edssCloseStreaming()
+            } else {
+                edss_unsafe::edssCloseServer()
+            }
+        };
+        self.advance(StreamEvent::Closed)?;
+        if result != edss_unsafe::EDSS_STATUS_EDSS_OK {
+            return Err(EdssError(result));
         }
         Ok(())
     }
+    fn write_input(&mut self, ev: InputEvent) -> Result<(), EdssError> {
+        match &mut self.input_sink {
+            Some(sink) => sink.write(ev),
+            None => Err(EdssError(edss_unsafe::EDSS_STATUS_EDSS_UNINITIALISED)),
+        }
+    }
+
     pub fn write_mouse_event(&mut self, ev: EdcsMouseEvent) -> Result<(), EdssError> {
-        let mut edss_event = match ev.payload {
+        let ev = match ev.payload {
             Some(edcs_mouse_event::Payload::Button(EdcsKeyData { btn_typ, pressed })) => {
-                edss_unsafe::edssMouseEvent_t {
-                    type_: edss_unsafe::edssMouseEventType_t_CLICK,
-                    payload: edss_unsafe::edssMouseEvent_t__bindgen_ty_1 {
-                        button: edss_unsafe::edssKeyData_t {
-                            pressed,
-                            button: match EdcsMouseButton::from_i32(btn_typ) {
-                                Some(EdcsMouseButton::MouseButtonLeft) => {
-                                    input_event_codes::BTN_LEFT!()
-                                }
-                                Some(EdcsMouseButton::MouseButtonRight) => {
-                                    input_event_codes::BTN_RIGHT!()
-                                }
-                                Some(EdcsMouseButton::MouseButtonMiddle) => {
-                                    input_event_codes::BTN_MIDDLE!()
-                                }
-                                _ => {
-                                    return Err(EdssError(
-                                        edss_unsafe::EDSS_STATUS_EDSS_INVALID_MOUSE_DATA,
-                                    ))
-                                }
-                            },
-                        },
+                InputEvent::MouseButton {
+                    button: match EdcsMouseButton::from_i32(btn_typ) {
+                        Some(EdcsMouseButton::MouseButtonLeft) => input_event_codes::BTN_LEFT!(),
+                        Some(EdcsMouseButton::MouseButtonRight) => input_event_codes::BTN_RIGHT!(),
+                        Some(EdcsMouseButton::MouseButtonMiddle) => {
+                            input_event_codes::BTN_MIDDLE!()
+                        }
+                        _ => {
+                            return Err(EdssError(edss_unsafe::EDSS_STATUS_EDSS_INVALID_MOUSE_DATA))
+                        }
                     },
+                    pressed,
                 }
             }
-            Some(edcs_mouse_event::Payload::Move(m)) => edss_unsafe::edssMouseEvent_t {
-                type_: edss_unsafe::edssMouseEventType_t_MOVE,
-
-                payload: edss_unsafe::edssMouseEvent_t__bindgen_ty_1 {
-                    move_: edss_unsafe::edssMouseEvent_t__bindgen_ty_1_move { x: m.x, y: m.y },
-                },
+            Some(edcs_mouse_event::Payload::Move(m)) => {
+                if !m.x.is_finite() || !m.y.is_finite() {
+                    return Err(EdssError(edss_unsafe::EDSS_STATUS_EDSS_INVALID_MOUSE_DATA));
+                }
+                InputEvent::MouseMove { x: m.x, y: m.y }
+            }
+            Some(edcs_mouse_event::Payload::Wheel(w)) => InputEvent::Wheel {
+                vertical: w.vertical.clamp(-MAX_WHEEL_CLICKS, MAX_WHEEL_CLICKS),
+                horizontal: w.horizontal.clamp(-MAX_WHEEL_CLICKS, MAX_WHEEL_CLICKS),
             },
+            Some(edcs_mouse_event::Payload::RelMove(m)) => {
+                InputEvent::RelMotion { dx: m.dx, dy: m.dy }
+            }
             _ => return Err(EdssError(edss_unsafe::EDSS_STATUS_EDSS_INVALID_MOUSE_DATA)),
         };
-        unsafe {
-            edss_unsafe::edssWriteMouseEvent(&mut edss_event as *mut _);
-            // TODO destroy all stream variables
+        self.write_input(ev)
+    }
+
+    pub fn write_touch_event(&mut self, tev: EdcsTouchEvent) -> Result<(), EdssError> {
+        if !tev.x.is_finite() || !tev.y.is_finite() || !tev.pressure.is_finite() {
+            return Err(EdssError(edss_unsafe::EDSS_STATUS_EDSS_INVALID_INPUT_DATA));
         }
-        Ok(())
+        self.write_input(InputEvent::Touch {
+            contact_id: tev.contact_id,
+            phase: match EdcsTouchPhase::from_i32(tev.phase) {
+                Some(EdcsTouchPhase::TouchStarted) => TouchPhase::Start,
+                Some(EdcsTouchPhase::TouchMoved) => TouchPhase::Move,
+                Some(EdcsTouchPhase::TouchEnded) => TouchPhase::End,
+                Some(EdcsTouchPhase::TouchCancelled) => TouchPhase::Cancel,
+                None => return Err(EdssError(edss_unsafe::EDSS_STATUS_EDSS_INVALID_INPUT_DATA)),
+            },
+            x: tev.x,
+            y: tev.y,
+            pressure: tev.pressure.clamp(0.0, 1.0),
+        })
+    }
+
+    pub fn write_pen_event(&mut self, pev: EdcsPenEvent) -> Result<(), EdssError> {
+        if ![pev.x, pev.y, pev.pressure, pev.tilt_x, pev.tilt_y]
+            .iter()
+            .all(|v| v.is_finite())
+        {
+            return Err(EdssError(edss_unsafe::EDSS_STATUS_EDSS_INVALID_INPUT_DATA));
+        }
+        self.write_input(InputEvent::Pen {
+            x: pev.x,
+            y: pev.y,
+            pressure: pev.pressure.clamp(0.0, 1.0),
+            tilt_x: pev.tilt_x.clamp(-90.0, 90.0),
+            tilt_y: pev.tilt_y.clamp(-90.0, 90.0),
+            in_range: pev.in_range,
+            touching: pev.touching,
+            eraser: pev.eraser,
+            barrel_button: pev.barrel_button,
+        })
     }
 
     pub fn write_keyboard_event(&mut self, kev: EdcsKeyboardEvent) -> Result<(), EdssError> {
-        let key_dat = kev.key_dat.unwrap();
-        let mut kev_c = edss_unsafe::edssKeyboardEvent_t {
-            keyData: edss_unsafe::edssKeyData_t {
-                button: key_dat.btn_typ,
-                pressed: key_dat.pressed,
-            },
+        let key_dat = kev
+            .key_dat
+            .ok_or(EdssError(edss_unsafe::EDSS_STATUS_EDSS_INVALID_INPUT_DATA))?;
+        // Key codes are never negative, anything that is can't be a key
+        let code = u32::try_from(key_dat.btn_typ)
+            .map_err(|_| EdssError(edss_unsafe::EDSS_STATUS_EDSS_INVALID_INPUT_DATA))?;
+        self.write_input(InputEvent::Key {
+            code,
+            pressed: key_dat.pressed,
+        })
+    }
+
+    pub fn read_cursor(&self, last_serial: Option<u32>) -> Result<CursorState, EdssError> {
+        let mut cursor_c = edss_unsafe::edssCursorData_t {
+            x: 0,
+            y: 0,
+            visible: false,
+            serial: 0,
+            width: 0,
+            height: 0,
+            hotspotX: 0,
+            hotspotY: 0,
+            image: std::ptr::null_mut(),
         };
-        unsafe {
-            edss_unsafe::edssWriteKeyboardEvent(&mut kev_c as *mut _);
+        let result = unsafe { edss_unsafe::edssReadCursor(&mut cursor_c as *mut _) };
+        if result != edss_unsafe::EDSS_STATUS_EDSS_OK {
+            return Err(EdssError(result));
         }
-        Ok(())
+
+        let image = if last_serial != Some(cursor_c.serial) && !cursor_c.image.is_null() {
+            let len = cursor_c.width as usize * cursor_c.height as usize * 4;
+            // The image is owned by the CAL, so copy it out before it changes again
+            Some(unsafe { std::slice::from_raw_parts(cursor_c.image, len) }.to_vec())
+        } else {
+            None
+        };
+
+        Ok(CursorState {
+            x: cursor_c.x,
+            y: cursor_c.y,
+            visible: cursor_c.visible,
+            serial: cursor_c.serial,
+            width: cursor_c.width as u32,
+            height: cursor_c.height as u32,
+            hotspot_x: cursor_c.hotspotX as u32,
+            hotspot_y: cursor_c.hotspotY as u32,
+            image,
+        })
     }
 
-    pub fn update_streaming(&self) -> Result<(), EdssError> {
-        unsafe {
-            edss_unsafe::edssUpdateStreaming(&mut self.to_c_struct() as *mut _);
+    /// Send the stream to another client too. Every viewer gets its own SRTP
+    /// keys, which end up in the returned SDP along with the viewer id.
+    pub fn add_viewer(&self, ip: SocketAddr, port: u16) -> Result<(u32, String), EdssError> {
+        if !self.state.is_set_up() {
+            return Err(EdssError(edss_unsafe::EDSS_STATUS_EDSS_UNINITIALISED));
+        }
+        let socket_addr = str_to_cstring(&ip.ip().to_string())?;
+        let srtp_out_params = str_to_cstring(&new_srtp_out_params())?;
+        let mut viewer_id = 0u32;
+        let mut sdp_cstr: *mut c_char = std::ptr::null_mut();
+        let result = unsafe {
+            edss_unsafe::edssAddViewer(
+                socket_addr.as_ptr(),
+                port,
+                srtp_out_params.as_ptr(),
+                &mut viewer_id,
+                &mut sdp_cstr,
+            )
+        };
+        if result != edss_unsafe::EDSS_STATUS_EDSS_OK {
+            return Err(EdssError(result));
+        }
+        // Same as in init_server, the SDP is ours to free
+        let sdp = unsafe {
+            let sdp = c_str_to_string(sdp_cstr);
+            libc::free(sdp_cstr as *mut c_void);
+            sdp
+        };
+        trace!("Viewer {} SDP field:\n{}", viewer_id, sdp);
+        Ok((viewer_id, sdp))
+    }
+
+    pub fn remove_viewer(&self, viewer_id: u32) -> Result<(), EdssError> {
+        let result = unsafe { edss_unsafe::edssRemoveViewer(viewer_id) };
+        if result != edss_unsafe::EDSS_STATUS_EDSS_OK {
+            return Err(EdssError(result));
         }
         Ok(())
     }
 }
+
+impl Drop for EdssAdapter {
+    // EDSS keeps its state in globals, so whatever stage the stream got to has
+    // to be undone before the next adapter opens the CAL again.
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            warn!("Failed to close the EDSS stream: {}", e);
+        }
+        if self.advance(StreamEvent::CloseCal).is_ok() {
+            Self::close_cal();
+        }
+    }
+}
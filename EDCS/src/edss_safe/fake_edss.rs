@@ -0,0 +1,102 @@
+//! EDSS's globals without EDSS, for the tests. Every call is checked against
+//! what edssInterface.c does with it, so calls that would leak or free
+//! something twice come back as errors instead.
+
+#[derive(Debug, Default)]
+pub struct FakeEdss {
+    cal_open: bool,
+    // Also set by a failed edssInitServer, which leaves whatever it got to
+    server_up: bool,
+    // Also set by a failed edssInitStreaming, some of the threads may run
+    threads_started: bool,
+    viewers: Vec<u32>,
+    last_viewer_id: u32,
+}
+
+impl FakeEdss {
+    pub fn open_cal(&mut self) -> Result<(), String> {
+        if self.cal_open {
+            return Err("edssOpenCAL while the CAL is open leaks the first handle".to_string());
+        }
+        self.cal_open = true;
+        Ok(())
+    }
+
+    pub fn init_server(&mut self, fail: bool) -> Result<bool, String> {
+        if !self.cal_open {
+            return Err("edssInitServer without the CAL".to_string());
+        }
+        if self.server_up {
+            return Err("edssInitServer twice leaks the first server".to_string());
+        }
+        self.server_up = true;
+        Ok(!fail)
+    }
+
+    pub fn init_streaming(&mut self, fail: bool) -> Result<bool, String> {
+        if !self.server_up {
+            return Err("edssInitStreaming without a server".to_string());
+        }
+        if self.threads_started {
+            return Err("edssInitStreaming twice leaks the first threads".to_string());
+        }
+        self.threads_started = true;
+        Ok(!fail)
+    }
+
+    pub fn close_streaming(&mut self) -> Result<(), String> {
+        if !self.threads_started {
+            return Err("edssCloseStreaming joins threads that were never started".to_string());
+        }
+        self.threads_started = false;
+        self.close_server()
+    }
+
+    // Everything in it is NULL checked, so closing twice is fine
+    pub fn close_server(&mut self) -> Result<(), String> {
+        if self.threads_started {
+            return Err("edssCloseServer refuses while streaming, the server leaks".to_string());
+        }
+        self.server_up = false;
+        self.viewers.clear();
+        Ok(())
+    }
+
+    pub fn close_cal(&mut self) -> Result<(), String> {
+        if !self.cal_open {
+            return Err("edssCloseCAL without the CAL".to_string());
+        }
+        // The CAL is unloaded whatever closing the rest did
+        let closed = if self.threads_started {
+            self.close_streaming()
+        } else {
+            self.close_server()
+        };
+        self.cal_open = false;
+        closed
+    }
+
+    pub fn add_viewer(&mut self) -> Result<u32, String> {
+        if !self.server_up {
+            return Err("edssAddViewer without a server".to_string());
+        }
+        self.last_viewer_id += 1;
+        self.viewers.push(self.last_viewer_id);
+        Ok(self.last_viewer_id)
+    }
+
+    /// Whether the viewer was there, EDSS fails the call otherwise.
+    pub fn remove_viewer(&mut self, viewer_id: u32) -> bool {
+        let before = self.viewers.len();
+        self.viewers.retain(|id| *id != viewer_id);
+        self.viewers.len() != before
+    }
+
+    /// What is still held, if anything.
+    pub fn leaked(&self) -> Option<String> {
+        if self.cal_open || self.server_up || self.threads_started || !self.viewers.is_empty() {
+            return Some(format!("{:?}", self));
+        }
+        None
+    }
+}
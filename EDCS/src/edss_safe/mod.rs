@@ -1,2 +1,11 @@
+mod c_buf;
+mod cal_sink;
+pub mod capture_source;
 pub mod edss;
-mod edss_unsafe;
+pub(crate) mod edss_unsafe;
+#[cfg(test)]
+pub(crate) mod fake_edss;
+mod str_map;
+pub mod stream_state;
+pub mod worker;
+mod worker_proto;
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::ptr::NonNull;
+
+use super::c_buf::str_to_cstring;
+use super::edss::EdssError;
+use super::edss_unsafe;
+#[cfg(not(miri))]
+use super::edss_unsafe::{sm_delete, sm_enum, sm_new, sm_put};
+#[cfg(miri)]
+use miri_strmap::{sm_delete, sm_enum, sm_new, sm_put};
+
+/// An owned StrMap from the strmap library vendored in EDSS. It is deleted when
+/// dropped, so it has to outlive any C struct it was put in.
+#[derive(Debug)]
+pub struct StrMap(NonNull<edss_unsafe::StrMap>);
+
+impl StrMap {
+    pub fn new(capacity: u32) -> Result<Self, EdssError> {
+        // strmap hashes modulo the capacity, so it must not be 0
+        let map = unsafe { sm_new(capacity.max(1)) };
+        NonNull::new(map)
+            .map(Self)
+            .ok_or(EdssError(edss_unsafe::EDSS_STATUS_EDSS_ALLOCATION_FAILURE))
+    }
+
+    /// Take ownership of a map allocated by EDSS or a CAL.
+    ///
+    /// # Safety
+    /// `map` has to come from `sm_new` and nothing else may delete it.
+    pub unsafe fn from_raw(map: *mut edss_unsafe::StrMap) -> Option<Self> {
+        NonNull::new(map).map(Self)
+    }
+
+    pub fn from_hash_map(map: &HashMap<String, String>) -> Result<Self, EdssError> {
+        let capacity = map
+            .len()
+            .try_into()
+            .map_err(|_| EdssError(edss_unsafe::EDSS_STATUS_EDSS_STRMAP_FAILURE))?;
+        let mut str_map = Self::new(capacity)?;
+        for (key, value) in map {
+            str_map.put(key, value)?;
+        }
+        Ok(str_map)
+    }
+
+    pub fn put(&mut self, key: &str, value: &str) -> Result<(), EdssError> {
+        let key_c = str_to_cstring(key)?;
+        let value_c = str_to_cstring(value)?;
+        // strmap copies both strings
+        let ret = unsafe { sm_put(self.as_ptr(), key_c.as_ptr(), value_c.as_ptr()) };
+        if ret == 0 {
+            return Err(EdssError(edss_unsafe::EDSS_STATUS_EDSS_STRMAP_FAILURE));
+        }
+        Ok(())
+    }
+
+    extern "C" fn enum_callback(key: *const c_char, value: *const c_char, obj: *const c_void) {
+        // Panicking here would unwind into C, so bad UTF-8 is replaced instead
+        let hash_map = unsafe { &mut *(obj as *mut HashMap<String, String>) };
+        let key = unsafe { CStr::from_ptr(key) }
+            .to_string_lossy()
+            .into_owned();
+        let value = unsafe { CStr::from_ptr(value) }
+            .to_string_lossy()
+            .into_owned();
+        hash_map.insert(key, value);
+    }
+
+    pub fn to_hash_map(&self) -> Result<HashMap<String, String>, EdssError> {
+        let mut hash_map = HashMap::new();
+        let ret = unsafe {
+            sm_enum(
+                self.as_ptr(),
+                Some(Self::enum_callback),
+                &mut hash_map as *mut HashMap<String, String> as *const c_void,
+            )
+        };
+        if ret == 0 {
+            return Err(EdssError(edss_unsafe::EDSS_STATUS_EDSS_STRMAP_FAILURE));
+        }
+        Ok(hash_map)
+    }
+
+    /// Give up ownership, eg. to return the map to EDSS from a CAL function.
+    pub fn into_raw(self) -> *mut edss_unsafe::StrMap {
+        let map = self.0.as_ptr();
+        std::mem::forget(self);
+        map
+    }
+
+    /// Only valid for as long as `self` is
+    pub fn as_ptr(&self) -> *mut edss_unsafe::StrMap {
+        self.0.as_ptr()
+    }
+}
+
+impl Drop for StrMap {
+    fn drop(&mut self) {
+        unsafe { sm_delete(self.0.as_ptr()) }
+    }
+}
+
+/// Miri can't call into C, so under Miri strmap is a boxed HashMap that is
+/// owned the same way.
+#[cfg(miri)]
+mod miri_strmap {
+    use std::collections::HashMap;
+    use std::ffi::{CStr, CString};
+    use std::os::raw::{c_char, c_int, c_uint, c_void};
+
+    use super::edss_unsafe::{sm_enum_func, StrMap};
+
+    type Map = HashMap<CString, CString>;
+
+    pub unsafe fn sm_new(_capacity: c_uint) -> *mut StrMap {
+        Box::into_raw(Box::<Map>::default()) as *mut StrMap
+    }
+
+    pub unsafe fn sm_delete(map: *mut StrMap) {
+        drop(Box::from_raw(map as *mut Map));
+    }
+
+    pub unsafe fn sm_put(map: *mut StrMap, key: *const c_char, value: *const c_char) -> c_int {
+        let map = &mut *(map as *mut Map);
+        map.insert(CStr::from_ptr(key).into(), CStr::from_ptr(value).into());
+        1
+    }
+
+    pub unsafe fn sm_enum(
+        map: *const StrMap,
+        enum_func: sm_enum_func,
+        obj: *const c_void,
+    ) -> c_int {
+        let enum_func = match enum_func {
+            Some(enum_func) => enum_func,
+            None => return 0,
+        };
+        for (key, value) in &*(map as *const Map) {
+            enum_func(key.as_ptr(), value.as_ptr(), obj);
+        }
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_map(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_hash_maps() {
+        for map in [
+            hash_map(&[]),
+            hash_map(&[("width", "1920"), ("height", "1080"), ("", "")]),
+            hash_map(&[("näme", "välue ✓")]),
+        ] {
+            assert_eq!(StrMap::from_hash_map(&map).unwrap().to_hash_map(), Ok(map));
+        }
+    }
+
+    #[test]
+    fn put_replaces_values() {
+        let mut map = StrMap::new(0).unwrap();
+        map.put("key", "old").unwrap();
+        map.put("key", "new").unwrap();
+        assert_eq!(map.to_hash_map(), Ok(hash_map(&[("key", "new")])));
+    }
+
+    #[test]
+    fn refuses_interior_nuls() {
+        let mut map = StrMap::new(1).unwrap();
+        assert!(map.put("a\0b", "value").is_err());
+        assert!(map.put("key", "a\0b").is_err());
+        assert_eq!(map.to_hash_map(), Ok(HashMap::new()));
+    }
+
+    #[test]
+    fn ownership_survives_a_trip_through_a_raw_pointer() {
+        let mut map = StrMap::new(1).unwrap();
+        map.put("key", "value").unwrap();
+        let raw = map.into_raw();
+        // Dropped once here, Miri notices if into_raw didn't give it up
+        let map = unsafe { StrMap::from_raw(raw) }.unwrap();
+        assert_eq!(map.to_hash_map(), Ok(hash_map(&[("key", "value")])));
+    }
+
+    #[test]
+    fn null_is_not_a_map() {
+        assert!(unsafe { StrMap::from_raw(std::ptr::null_mut()) }.is_none());
+    }
+}
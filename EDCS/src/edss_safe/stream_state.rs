@@ -0,0 +1,208 @@
+//! Where a stream is in its life. EDSS keeps its state in globals, so doing
+//! things out of order either fails in C or leaks what the earlier call set up.
+//! The adapters and the handler only ever change their state through
+//! `StreamState::transition`.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamState {
+    /// Nothing set up, the CAL isn't open.
+    #[default]
+    Connected,
+    /// SETUP_EDCS opened the CAL.
+    CalOpened,
+    /// SETUP_STREAM initialised the EDSS server, nothing is sent yet.
+    StreamConfigured,
+    Streaming,
+    /// Being torn down. The adapters go back to CalOpened, the handler drops
+    /// the stream and with it the CAL.
+    Closing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamEvent {
+    OpenCal,
+    InitServer,
+    InitStreaming,
+    Close,
+    Closed,
+    CloseCal,
+}
+
+/// What has to be undone to get from a state back to CalOpened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Teardown {
+    CloseStreaming,
+    CloseServer,
+    Nothing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTransition {
+    pub from: StreamState,
+    pub event: StreamEvent,
+}
+
+impl fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "can't {:?} while {:?}", self.event, self.from)
+    }
+}
+
+impl std::error::Error for InvalidTransition {}
+
+impl StreamState {
+    pub fn transition(self, event: StreamEvent) -> Result<StreamState, InvalidTransition> {
+        use StreamEvent::*;
+        use StreamState::*;
+        match (self, event) {
+            (Connected, OpenCal) => Ok(CalOpened),
+            (CalOpened, InitServer) => Ok(StreamConfigured),
+            (StreamConfigured, InitStreaming) => Ok(Streaming),
+            // Every state that holds anything can be torn down
+            (CalOpened | StreamConfigured | Streaming, Close) => Ok(Closing),
+            (Closing, Closed) => Ok(CalOpened),
+            (CalOpened, CloseCal) => Ok(Connected),
+            (from, event) => Err(InvalidTransition { from, event }),
+        }
+    }
+
+    pub fn teardown(self) -> Teardown {
+        match self {
+            StreamState::Streaming => Teardown::CloseStreaming,
+            StreamState::StreamConfigured => Teardown::CloseServer,
+            // A teardown that is already underway isn't started again
+            StreamState::Connected | StreamState::CalOpened | StreamState::Closing => {
+                Teardown::Nothing
+            }
+        }
+    }
+
+    /// Whether the EDSS server is up, which viewers need.
+    pub fn is_set_up(self) -> bool {
+        matches!(self, StreamState::StreamConfigured | StreamState::Streaming)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edss_safe::fake_edss::FakeEdss;
+    use proptest::prelude::*;
+
+    fn event() -> impl Strategy<Value = (StreamEvent, bool)> {
+        let event = prop_oneof![
+            Just(StreamEvent::OpenCal),
+            Just(StreamEvent::InitServer),
+            Just(StreamEvent::InitStreaming),
+            Just(StreamEvent::Close),
+            Just(StreamEvent::Closed),
+            Just(StreamEvent::CloseCal),
+        ];
+        // Whether the EDSS call behind it fails
+        (event, any::<bool>())
+    }
+
+    // Does what EdssAdapter does for the event, the same way it does it
+    fn apply(
+        state: &mut StreamState,
+        edss: &mut FakeEdss,
+        event: StreamEvent,
+        fail: bool,
+    ) -> Result<(), String> {
+        match event {
+            StreamEvent::OpenCal => {
+                if let Ok(next) = state.transition(event) {
+                    edss.open_cal()?;
+                    *state = next;
+                }
+            }
+            StreamEvent::InitServer => {
+                if let Ok(next) = state.transition(event) {
+                    if edss.init_server(fail)? {
+                        *state = next;
+                    } else {
+                        edss.close_server()?;
+                    }
+                }
+            }
+            StreamEvent::InitStreaming => {
+                if let Ok(next) = state.transition(event) {
+                    *state = next;
+                    edss.init_streaming(fail)?;
+                }
+            }
+            StreamEvent::Close => close(state, edss)?,
+            // Only ever part of Close
+            StreamEvent::Closed => {}
+            StreamEvent::CloseCal => {
+                if let Ok(next) = state.transition(event) {
+                    edss.close_cal()?;
+                    *state = next;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn close(state: &mut StreamState, edss: &mut FakeEdss) -> Result<(), String> {
+        let teardown = state.teardown();
+        if teardown == Teardown::Nothing {
+            return Ok(());
+        }
+        let closing = state
+            .transition(StreamEvent::Close)
+            .map_err(|e| e.to_string())?;
+        match teardown {
+            Teardown::CloseStreaming => edss.close_streaming()?,
+            Teardown::CloseServer => edss.close_server()?,
+            Teardown::Nothing => unreachable!(),
+        }
+        *state = closing
+            .transition(StreamEvent::Closed)
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    #[test]
+    fn only_set_up_states_are_torn_down() {
+        use StreamState::*;
+        for state in [Connected, CalOpened, StreamConfigured, Streaming, Closing] {
+            assert_eq!(
+                state.is_set_up(),
+                state.teardown() != Teardown::Nothing,
+                "{:?}",
+                state
+            );
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn no_event_sequence_leaks_or_double_frees(
+            events in proptest::collection::vec(event(), 0..32)
+        ) {
+            let mut state = StreamState::default();
+            let mut edss = FakeEdss::default();
+            for (event, fail) in events {
+                let before = state;
+                if let Err(e) = apply(&mut state, &mut edss, event, fail) {
+                    return Err(TestCaseError::fail(format!("{:?} in {:?}: {}", event, before, e)));
+                }
+                prop_assert_ne!(state, StreamState::Closing);
+            }
+
+            // What dropping the adapter does
+            if let Err(e) = close(&mut state, &mut edss) {
+                return Err(TestCaseError::fail(e));
+            }
+            if let Ok(next) = state.transition(StreamEvent::CloseCal) {
+                prop_assert!(edss.close_cal().is_ok());
+                state = next;
+            }
+            prop_assert_eq!(state, StreamState::Connected);
+            prop_assert_eq!(edss.leaked(), None);
+        }
+    }
+}
@@ -0,0 +1,740 @@
+//! EDSS keeps its state in globals and a crash in it or in a CAL takes the
+//! whole process down, so every stream gets a worker process that owns it.
+//! The worker is `edcs` itself, started with the hidden `edss-worker`
+//! subcommand. It takes length delimited `WorkerRequest`s on stdin and answers
+//! every one of them with a `WorkerResponse` on stdout.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use prost::Message;
+
+use super::edss::{CursorState, EdssAdapter, EdssError};
+use super::edss_unsafe;
+use super::stream_state::{StreamEvent, StreamState, Teardown};
+use super::worker_proto::{
+    worker_request, worker_response, WorkerAddViewerParams, WorkerCursor, WorkerInitServerParams,
+    WorkerOpenParams, WorkerOpened, WorkerReadCursorParams, WorkerRequest, WorkerRequestType,
+    WorkerResponse, WorkerServerInit, WorkerViewer,
+};
+use crate::edcs_server::audio::NegotiatedAudio;
+use crate::edcs_server::config::EvdevProxyConfig;
+use crate::edcs_server::edcs_proto::{
+    EdcsCalOption, EdcsInputCapabilities, EdcsKeyboardEvent, EdcsMouseEvent, EdcsPenEvent,
+    EdcsTouchEvent,
+};
+use crate::edcs_server::frame;
+
+pub const WORKER_SUBCOMMAND: &str = "edss-worker";
+
+// How long a worker gets to close its stream before it is killed
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+// A worker that doesn't answer for this long is taken for hung and killed.
+// Opening the CAL and starting the encoder are the slowest calls.
+const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Cursor images are the largest thing either side sends, this leaves plenty
+// of room for them
+const MAX_MESSAGE_SIZE: usize = 16 << 20;
+
+fn write_message<M: Message>(writer: &mut impl Write, message: &M) -> io::Result<()> {
+    writer.write_all(&message.encode_length_delimited_to_vec())?;
+    writer.flush()
+}
+
+/// None if the other side closed the pipe between messages.
+fn read_message<M: Message + Default, R: Read>(reader: &mut R) -> io::Result<Option<M>> {
+    // The length delimiter is a varint of at most 10 bytes
+    let mut delimiter = Vec::with_capacity(10);
+    loop {
+        let mut byte = [0u8];
+        if reader.read(&mut byte)? == 0 {
+            if delimiter.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        delimiter.push(byte[0]);
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        if delimiter.len() == 10 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid length delimiter",
+            ));
+        }
+    }
+    let len = frame::message_len(&delimiter, MAX_MESSAGE_SIZE)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf)?;
+    Ok(Some(M::decode(&buf[..])?))
+}
+
+/// The worker's stdout, where a read fails with `TimedOut` if the worker
+/// stays quiet for longer than `timeout`.
+#[derive(Debug)]
+struct WorkerStdout {
+    stdout: ChildStdout,
+    timeout: Duration,
+}
+
+impl Read for WorkerStdout {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut fd = libc::pollfd {
+            fd: self.stdout.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = self.timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        loop {
+            match unsafe { libc::poll(&mut fd, 1, timeout) } {
+                0 => return Err(io::ErrorKind::TimedOut.into()),
+                // Readable, or closed, which the read reports
+                1.. => return self.stdout.read(buf),
+                _ => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Same interface as `EdssAdapter`, which lives in the worker process. Once the
+/// worker is gone every call fails, and `crashed` says why.
+#[derive(Debug)]
+pub struct WorkerAdapter {
+    pub bitrate: u32,
+    pub framerate: u32,
+    pub cal_option_dict: HashMap<String, String>,
+    pub cal_option_schema: Vec<EdcsCalOption>,
+    pub sdp: Option<String>, // Only Some if init_server was called
+    pub stream_resolution: Option<(u32, u32)>, // Only Some if init_server was called
+    pub input_capabilities: EdcsInputCapabilities,
+    pub audio: Option<NegotiatedAudio>, // Set before init_server, None for video only
+    // Follows the worker's EdssAdapter, which refuses the same transitions
+    state: StreamState,
+    child: Child,
+    // Only None while dropping, closing it is what tells the worker to exit
+    stdin: Option<ChildStdin>,
+    stdout: BufReader<WorkerStdout>,
+    crashed: Option<String>,
+}
+
+impl WorkerAdapter {
+    /// Starts a worker, which opens the CAL the same way `EdssAdapter::new`
+    /// does.
+    pub fn spawn(
+        worker_path: &Path,
+        plugin_name: String,
+        ip: SocketAddr,
+        port: u16,
+        bitrate: u32,
+        framerate: u32,
+        evdev_proxy: Option<&EvdevProxyConfig>,
+    ) -> Result<Self, EdssError> {
+        let mut child = Command::new(worker_path)
+            .arg(WORKER_SUBCOMMAND)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                warn!(
+                    "Failed to start the stream worker {}: {:?}",
+                    worker_path.display(),
+                    e
+                );
+                EdssError::uninitialised()
+            })?;
+        info!("Started stream worker {}", child.id());
+        // Both are piped, so they are always there
+        let stdin = child.stdin.take();
+        let stdout = BufReader::new(WorkerStdout {
+            stdout: child.stdout.take().unwrap(),
+            timeout: CALL_TIMEOUT,
+        });
+        let mut worker = Self {
+            bitrate,
+            framerate,
+            cal_option_dict: Default::default(),
+            cal_option_schema: vec![],
+            sdp: None,
+            stream_resolution: None,
+            input_capabilities: Default::default(),
+            audio: None,
+            state: StreamState::Connected,
+            child,
+            stdin,
+            stdout,
+            crashed: None,
+        };
+
+        let (socket_path, token_file) = match evdev_proxy {
+            Some(config) => (
+                config.socket_path.to_string_lossy().into_owned(),
+                config.token_file.to_string_lossy().into_owned(),
+            ),
+            None => (String::new(), String::new()),
+        };
+        let response = worker.call(
+            WorkerRequestType::Open,
+            Some(worker_request::Payload::OpenParams(WorkerOpenParams {
+                plugin_name,
+                addr: ip.to_string(),
+                port: port as u32,
+                bitrate,
+                framerate,
+                evdev_proxy_socket_path: socket_path,
+                evdev_proxy_token_file: token_file,
+            })),
+        )?;
+        match response {
+            Some(worker_response::Payload::Opened(opened)) => {
+                worker.cal_option_dict = opened.cal_option_dict;
+                worker.cal_option_schema = opened.cal_option_schema;
+                worker.input_capabilities = opened.input_capabilities.unwrap_or_default();
+                worker.advance(StreamEvent::OpenCal)?;
+                Ok(worker)
+            }
+            _ => Err(worker.protocol_error()),
+        }
+    }
+
+    pub fn state(&self) -> StreamState {
+        self.state
+    }
+
+    // The worker would refuse it anyway, this saves asking
+    fn next_state(&self, event: StreamEvent) -> Result<StreamState, EdssError> {
+        self.state.transition(event).map_err(|e| {
+            debug!("Refusing to go on with the stream: {}", e);
+            EdssError::uninitialised()
+        })
+    }
+
+    fn advance(&mut self, event: StreamEvent) -> Result<(), EdssError> {
+        self.state = self.next_state(event)?;
+        Ok(())
+    }
+
+    /// Why the worker is gone, None while it is running.
+    pub fn crashed(&self) -> Option<&str> {
+        self.crashed.as_deref()
+    }
+
+    fn exchange(&mut self, request: &WorkerRequest) -> io::Result<Option<WorkerResponse>> {
+        let stdin = self
+            .stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        write_message(stdin, request)?;
+        read_message(&mut self.stdout)
+    }
+
+    // A worker that answers with something that makes no sense can't be
+    // trusted with the stream either
+    fn protocol_error(&mut self) -> EdssError {
+        warn!(
+            "Stream worker {} sent an unexpected response",
+            self.child.id()
+        );
+        let _ = self.child.kill();
+        self.crashed = Some("The stream worker sent an unexpected response".to_owned());
+        EdssError::uninitialised()
+    }
+
+    fn call(
+        &mut self,
+        request_type: WorkerRequestType,
+        payload: Option<worker_request::Payload>,
+    ) -> Result<Option<worker_response::Payload>, EdssError> {
+        if self.crashed.is_some() {
+            // The EDSS state went with the worker
+            return Err(EdssError::uninitialised());
+        }
+        let request = WorkerRequest {
+            request_type: request_type as i32,
+            payload,
+        };
+        match self.exchange(&request) {
+            Ok(Some(response)) if response.status == edss_unsafe::EDSS_STATUS_EDSS_OK => {
+                Ok(response.payload)
+            }
+            Ok(Some(response)) => Err(EdssError(response.status)),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                let reason = format!(
+                    "The stream worker didn't answer {:?} within {:?}",
+                    request_type,
+                    self.stdout.get_ref().timeout
+                );
+                warn!("{}, killing it", reason);
+                let _ = self.child.kill();
+                let _ = self.child.wait();
+                self.crashed = Some(reason);
+                Err(EdssError::uninitialised())
+            }
+            Ok(None) | Err(_) => {
+                // Whatever broke the pipe, the worker has exited or is about to
+                let reason = match self.child.wait() {
+                    Ok(status) => format!("The stream worker exited with {}", status),
+                    Err(e) => format!("The stream worker is gone: {}", e),
+                };
+                warn!("{} during {:?}", reason, request_type);
+                self.crashed = Some(reason);
+                Err(EdssError::uninitialised())
+            }
+        }
+    }
+
+    pub fn init_server(&mut self) -> Result<(), EdssError> {
+        let configured = self.next_state(StreamEvent::InitServer)?;
+        let (audio_source, audio_params) = match &self.audio {
+            Some(audio) => (audio.source.clone(), Some(audio.params.clone())),
+            None => (String::new(), None),
+        };
+        let response = self.call(
+            WorkerRequestType::InitServer,
+            Some(worker_request::Payload::InitServerParams(
+                WorkerInitServerParams {
+                    cal_option_dict: self.cal_option_dict.clone(),
+                    audio_source,
+                    audio_params,
+                },
+            )),
+        )?;
+        match response {
+            Some(worker_response::Payload::ServerInit(init)) => {
+                self.sdp = Some(init.sdp);
+                self.stream_resolution = Some((init.width, init.height));
+                self.input_capabilities = init.input_capabilities.unwrap_or_default();
+                self.state = configured;
+                Ok(())
+            }
+            _ => Err(self.protocol_error()),
+        }
+    }
+
+    pub fn init_streaming(&mut self) -> Result<(), EdssError> {
+        // Like EdssAdapter, a failed start still has to be closed
+        self.advance(StreamEvent::InitStreaming)?;
+        self.call(WorkerRequestType::InitStreaming, None)
+            .map(|_| ())
+    }
+
+    /// See `EdssAdapter::close`.
+    pub fn close(&mut self) -> Result<(), EdssError> {
+        if self.state.teardown() == Teardown::Nothing {
+            return Ok(());
+        }
+        self.advance(StreamEvent::Close)?;
+        let result = self.call(WorkerRequestType::Close, None);
+        self.advance(StreamEvent::Closed)?;
+        result.map(|_| ())
+    }
+
+    pub fn write_mouse_event(&mut self, ev: EdcsMouseEvent) -> Result<(), EdssError> {
+        self.call(
+            WorkerRequestType::WriteMouseEvent,
+            Some(worker_request::Payload::MouseEvent(ev)),
+        )
+        .map(|_| ())
+    }
+
+    pub fn write_keyboard_event(&mut self, kev: EdcsKeyboardEvent) -> Result<(), EdssError> {
+        self.call(
+            WorkerRequestType::WriteKeyboardEvent,
+            Some(worker_request::Payload::KeyboardEvent(kev)),
+        )
+        .map(|_| ())
+    }
+
+    pub fn write_touch_event(&mut self, tev: EdcsTouchEvent) -> Result<(), EdssError> {
+        self.call(
+            WorkerRequestType::WriteTouchEvent,
+            Some(worker_request::Payload::TouchEvent(tev)),
+        )
+        .map(|_| ())
+    }
+
+    pub fn write_pen_event(&mut self, pev: EdcsPenEvent) -> Result<(), EdssError> {
+        self.call(
+            WorkerRequestType::WritePenEvent,
+            Some(worker_request::Payload::PenEvent(pev)),
+        )
+        .map(|_| ())
+    }
+
+    pub fn read_cursor(&mut self, last_serial: Option<u32>) -> Result<CursorState, EdssError> {
+        let response = self.call(
+            WorkerRequestType::ReadCursor,
+            Some(worker_request::Payload::ReadCursorParams(
+                WorkerReadCursorParams {
+                    has_last_serial: last_serial.is_some(),
+                    last_serial: last_serial.unwrap_or_default(),
+                },
+            )),
+        )?;
+        match response {
+            Some(worker_response::Payload::Cursor(cursor)) => Ok(CursorState {
+                x: cursor.x,
+                y: cursor.y,
+                visible: cursor.visible,
+                serial: cursor.serial,
+                width: cursor.width,
+                height: cursor.height,
+                hotspot_x: cursor.hotspot_x,
+                hotspot_y: cursor.hotspot_y,
+                image: cursor.has_image.then_some(cursor.image),
+            }),
+            _ => Err(self.protocol_error()),
+        }
+    }
+
+    pub fn add_viewer(&mut self, ip: SocketAddr, port: u16) -> Result<(u32, String), EdssError> {
+        let response = self.call(
+            WorkerRequestType::AddViewer,
+            Some(worker_request::Payload::AddViewerParams(
+                WorkerAddViewerParams {
+                    addr: ip.to_string(),
+                    port: port as u32,
+                },
+            )),
+        )?;
+        match response {
+            Some(worker_response::Payload::Viewer(viewer)) => Ok((viewer.viewer_id, viewer.sdp)),
+            _ => Err(self.protocol_error()),
+        }
+    }
+
+    pub fn remove_viewer(&mut self, viewer_id: u32) -> Result<(), EdssError> {
+        self.call(
+            WorkerRequestType::RemoveViewer,
+            Some(worker_request::Payload::RemoveViewerId(viewer_id)),
+        )
+        .map(|_| ())
+    }
+}
+
+impl Drop for WorkerAdapter {
+    // The worker closes whatever the stream got to when its stdin goes away,
+    // the same way dropping an EdssAdapter does
+    fn drop(&mut self) {
+        drop(self.stdin.take());
+        let deadline = Instant::now() + SHUTDOWN_GRACE_PERIOD;
+        loop {
+            match self.child.try_wait() {
+                Ok(Some(status)) => {
+                    debug!("Stream worker {} exited with {}", self.child.id(), status);
+                    return;
+                }
+                Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                _ => break,
+            }
+        }
+        warn!(
+            "Stream worker {} didn't exit in time, killing it",
+            self.child.id()
+        );
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn invalid_request() -> EdssError {
+    EdssError(edss_unsafe::EDSS_STATUS_EDSS_INVALID_CONFIG)
+}
+
+fn handle_request(
+    adapter: &mut Option<EdssAdapter>,
+    request: WorkerRequest,
+) -> Result<Option<worker_response::Payload>, EdssError> {
+    let request_type = request.request_type();
+    if request_type == WorkerRequestType::Open {
+        let params = match request.payload {
+            Some(worker_request::Payload::OpenParams(params)) => params,
+            _ => return Err(invalid_request()),
+        };
+        let ip = params.addr.parse().map_err(|_| invalid_request())?;
+        let port = u16::try_from(params.port).map_err(|_| invalid_request())?;
+        // The old adapter has to close the CAL before the new one opens it
+        *adapter = None;
+        let mut opened = EdssAdapter::new(
+            params.plugin_name,
+            ip,
+            port,
+            params.bitrate,
+            params.framerate,
+        )?;
+        if !params.evdev_proxy_socket_path.is_empty() {
+            opened.enable_evdev_proxy(EvdevProxyConfig {
+                socket_path: params.evdev_proxy_socket_path.into(),
+                token_file: params.evdev_proxy_token_file.into(),
+            });
+        }
+        let response = WorkerOpened {
+            cal_option_dict: opened.cal_option_dict.clone(),
+            cal_option_schema: opened.cal_option_schema.clone(),
+            input_capabilities: Some(opened.input_capabilities.clone()),
+        };
+        *adapter = Some(opened);
+        return Ok(Some(worker_response::Payload::Opened(response)));
+    }
+
+    let adapter = adapter.as_mut().ok_or_else(EdssError::uninitialised)?;
+    match (request_type, request.payload) {
+        (
+            WorkerRequestType::InitServer,
+            Some(worker_request::Payload::InitServerParams(params)),
+        ) => {
+            adapter.cal_option_dict = params.cal_option_dict;
+            adapter.audio = params.audio_params.map(|audio_params| NegotiatedAudio {
+                source: params.audio_source,
+                params: audio_params,
+            });
+            adapter.init_server()?;
+            // init_server sets both
+            let (width, height) = adapter.stream_resolution.unwrap();
+            Ok(Some(worker_response::Payload::ServerInit(
+                WorkerServerInit {
+                    sdp: adapter.sdp.clone().unwrap(),
+                    width,
+                    height,
+                    input_capabilities: Some(adapter.input_capabilities.clone()),
+                },
+            )))
+        }
+        (WorkerRequestType::InitStreaming, _) => adapter.init_streaming().map(|_| None),
+        (WorkerRequestType::Close, _) => adapter.close().map(|_| None),
+        (WorkerRequestType::WriteMouseEvent, Some(worker_request::Payload::MouseEvent(ev))) => {
+            adapter.write_mouse_event(ev).map(|_| None)
+        }
+        (
+            WorkerRequestType::WriteKeyboardEvent,
+            Some(worker_request::Payload::KeyboardEvent(kev)),
+        ) => adapter.write_keyboard_event(kev).map(|_| None),
+        (WorkerRequestType::WriteTouchEvent, Some(worker_request::Payload::TouchEvent(tev))) => {
+            adapter.write_touch_event(tev).map(|_| None)
+        }
+        (WorkerRequestType::WritePenEvent, Some(worker_request::Payload::PenEvent(pev))) => {
+            adapter.write_pen_event(pev).map(|_| None)
+        }
+        (
+            WorkerRequestType::ReadCursor,
+            Some(worker_request::Payload::ReadCursorParams(params)),
+        ) => {
+            let cursor =
+                adapter.read_cursor(params.has_last_serial.then_some(params.last_serial))?;
+            Ok(Some(worker_response::Payload::Cursor(WorkerCursor {
+                x: cursor.x,
+                y: cursor.y,
+                visible: cursor.visible,
+                serial: cursor.serial,
+                width: cursor.width,
+                height: cursor.height,
+                hotspot_x: cursor.hotspot_x,
+                hotspot_y: cursor.hotspot_y,
+                has_image: cursor.image.is_some(),
+                image: cursor.image.unwrap_or_default(),
+            })))
+        }
+        (WorkerRequestType::AddViewer, Some(worker_request::Payload::AddViewerParams(params))) => {
+            let ip = params.addr.parse().map_err(|_| invalid_request())?;
+            let port = u16::try_from(params.port).map_err(|_| invalid_request())?;
+            let (viewer_id, sdp) = adapter.add_viewer(ip, port)?;
+            Ok(Some(worker_response::Payload::Viewer(WorkerViewer {
+                viewer_id,
+                sdp,
+            })))
+        }
+        (WorkerRequestType::RemoveViewer, Some(worker_request::Payload::RemoveViewerId(id))) => {
+            adapter.remove_viewer(id).map(|_| None)
+        }
+        _ => Err(invalid_request()),
+    }
+}
+
+/// The worker side, run by `edcs edss-worker`. Returns once EDCS closes stdin,
+/// after closing the stream.
+pub fn run() -> anyhow::Result<()> {
+    // Always available, like in server::start
+    crate::test_sources::register();
+    crate::vnc::register();
+
+    // EDSS and the CALs printf their logs, so stdout is kept for the responses
+    // and everything else written to it goes to stderr instead
+    let mut responses = unsafe {
+        let fd = libc::dup(libc::STDOUT_FILENO);
+        if fd < 0 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        File::from_raw_fd(fd)
+    };
+    let stdin = io::stdin();
+    let mut requests = stdin.lock();
+
+    let mut adapter = None;
+    while let Some(request) = read_message::<WorkerRequest, _>(&mut requests)? {
+        let response = match handle_request(&mut adapter, request) {
+            Ok(payload) => WorkerResponse {
+                status: edss_unsafe::EDSS_STATUS_EDSS_OK,
+                payload,
+            },
+            Err(e) => WorkerResponse {
+                status: e.0,
+                payload: None,
+            },
+        };
+        write_message(&mut responses, &response)?;
+    }
+    // Dropping the adapter closes whatever the stream got to
+    drop(adapter);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    static NEXT_WORKER: AtomicUsize = AtomicUsize::new(0);
+
+    // A worker that answers OPEN and then does nothing, not even exit when its
+    // stdin is closed. Removed again afterwards.
+    struct HungWorker(PathBuf);
+
+    impl HungWorker {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "edcs-worker-{}-{}",
+                std::process::id(),
+                NEXT_WORKER.fetch_add(1, Ordering::Relaxed)
+            ));
+            // A WorkerResponse with an empty WorkerOpened, after its length
+            fs::write(
+                &path,
+                "#!/bin/sh\nprintf '\\002\\022\\000'\nexec sleep 60\n",
+            )
+            .unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            HungWorker(path)
+        }
+
+        fn spawn(&self) -> WorkerAdapter {
+            WorkerAdapter::spawn(
+                &self.0,
+                "hung".to_owned(),
+                "127.0.0.1:9000".parse().unwrap(),
+                9001,
+                1000,
+                30,
+                None,
+            )
+            .unwrap()
+        }
+    }
+
+    impl Drop for HungWorker {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    // Only true until the process is waited on
+    fn is_running(pid: u32) -> bool {
+        unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+    }
+
+    fn read(bytes: &[u8]) -> io::Result<Option<WorkerRequest>> {
+        read_message(&mut &bytes[..])
+    }
+
+    #[test]
+    fn messages_make_the_round_trip() {
+        let request = WorkerRequest {
+            request_type: WorkerRequestType::RemoveViewer as i32,
+            payload: Some(worker_request::Payload::RemoveViewerId(3)),
+        };
+        let mut bytes = vec![];
+        write_message(&mut bytes, &request).unwrap();
+        assert_eq!(read(&bytes).unwrap(), Some(request));
+        // A closed pipe between messages is the end, not an error
+        assert_eq!(read(&[]).unwrap(), None);
+    }
+
+    #[test]
+    fn messages_over_the_max_are_rejected_before_reading_them() {
+        let mut bytes = vec![];
+        prost::encode_length_delimiter(MAX_MESSAGE_SIZE + 1, &mut bytes).unwrap();
+        assert_eq!(read(&bytes).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // Exactly the max is fine, it just isn't all there
+        let mut bytes = vec![];
+        prost::encode_length_delimiter(MAX_MESSAGE_SIZE, &mut bytes).unwrap();
+        assert_eq!(
+            read(&bytes).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn broken_delimiters_are_rejected() {
+        assert_eq!(
+            read(&[0xff; 10]).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            read(&[0x80, 0x80]).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn a_worker_that_stops_answering_is_killed() {
+        let script = HungWorker::new();
+        let mut worker = script.spawn();
+        let pid = worker.child.id();
+        worker.stdout.get_mut().timeout = Duration::from_millis(100);
+
+        assert!(worker.write_keyboard_event(Default::default()).is_err());
+        assert!(
+            worker
+                .crashed()
+                .unwrap()
+                .starts_with("The stream worker didn't answer"),
+            "{:?}",
+            worker.crashed()
+        );
+        assert!(!is_running(pid));
+        // The stream is gone with it
+        assert!(worker.write_keyboard_event(Default::default()).is_err());
+    }
+
+    #[test]
+    fn a_worker_that_doesnt_exit_is_killed_after_the_grace_period() {
+        let script = HungWorker::new();
+        let worker = script.spawn();
+        let pid = worker.child.id();
+
+        let start = Instant::now();
+        drop(worker);
+        assert!(start.elapsed() < SHUTDOWN_GRACE_PERIOD + Duration::from_secs(1));
+        assert!(!is_running(pid));
+    }
+}
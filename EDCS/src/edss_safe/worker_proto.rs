@@ -0,0 +1,3 @@
+#![allow(warnings, unused)] // Be quiet about annoying warnings
+
+include!(concat!(env!("OUT_DIR"), "/worker/edss_worker.rs"));
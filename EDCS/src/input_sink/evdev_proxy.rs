@@ -0,0 +1,118 @@
+use evdev_proxy::client::ProxyClient;
+use log::warn;
+
+use super::{sink_failure, unsupported, EdssError, InputEvent, InputSink};
+use crate::edcs_server::config::EvdevProxyConfig;
+
+/// Sends input to one VM's devices in evdev-proxy.
+#[derive(Debug)]
+pub struct EvdevProxySink {
+    client: ProxyClient,
+}
+
+impl EvdevProxySink {
+    pub fn connect(config: &EvdevProxyConfig, vm: &str) -> Result<Self, EdssError> {
+        let token = std::fs::read(&config.token_file).map_err(|e| {
+            warn!(
+                "Failed to read evdev-proxy token file {}: {}",
+                config.token_file.display(),
+                e
+            );
+            EdssError::cal_file_not_found()
+        })?;
+        let token = String::from_utf8_lossy(&token).trim().to_owned();
+        let client = ProxyClient::connect(&config.socket_path, token.as_bytes(), vm)
+            .map_err(sink_failure)?;
+        Ok(Self { client })
+    }
+}
+
+impl InputSink for EvdevProxySink {
+    fn write(&mut self, ev: InputEvent) -> Result<(), EdssError> {
+        match ev {
+            // The proxy takes normalised coordinates as well
+            InputEvent::MouseMove { x, y } => self.client.move_abs(x, y),
+            InputEvent::MouseButton { button, pressed } => self.client.button(button, pressed),
+            InputEvent::Wheel {
+                vertical,
+                horizontal,
+            } => self.client.wheel(vertical, horizontal),
+            InputEvent::RelMotion { dx, dy } => self.client.move_rel(dx, dy),
+            InputEvent::Key { code, pressed } => self.client.key(code, pressed),
+            InputEvent::Touch { .. } | InputEvent::Pen { .. } => return unsupported(&ev),
+        }
+        .map_err(sink_failure)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use evdev_proxy::protocol::{
+        proxy_input_event::Payload, read_message, write_message, ProxyHello, ProxyHelloResponse,
+        ProxyInputEvent, ProxyRelMotion, ProxyWheel,
+    };
+
+    use super::*;
+
+    // Stands in for evdev-proxy, taking one client and keeping what it sends
+    fn fake_proxy(listener: UnixListener) -> thread::JoinHandle<(ProxyHello, Vec<Payload>)> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let hello: ProxyHello = read_message(&mut stream).unwrap().unwrap();
+            write_message(
+                &mut stream,
+                &ProxyHelloResponse {
+                    ok: true,
+                    error: String::new(),
+                },
+            )
+            .unwrap();
+            let mut events = Vec::new();
+            while let Some(ev) = read_message::<ProxyInputEvent>(&mut stream).unwrap() {
+                events.extend(ev.payload);
+            }
+            (hello, events)
+        })
+    }
+
+    #[test]
+    fn sends_wheel_and_relative_motion_to_the_proxy() {
+        let base = std::env::temp_dir().join(format!("edcs-evdev-proxy-{}", std::process::id()));
+        let config = EvdevProxyConfig {
+            socket_path: base.with_extension("sock"),
+            token_file: base.with_extension("token"),
+        };
+        let _ = fs::remove_file(&config.socket_path);
+        fs::write(&config.token_file, "secret\n").unwrap();
+        let proxy = fake_proxy(UnixListener::bind(&config.socket_path).unwrap());
+
+        let mut sink = EvdevProxySink::connect(&config, "vm0").unwrap();
+        sink.write(InputEvent::Wheel {
+            vertical: -1,
+            horizontal: 2,
+        })
+        .unwrap();
+        sink.write(InputEvent::RelMotion { dx: 4, dy: -3 }).unwrap();
+        drop(sink);
+        let (hello, events) = proxy.join().unwrap();
+        let _ = fs::remove_file(&config.socket_path);
+        let _ = fs::remove_file(&config.token_file);
+
+        assert_eq!(hello.token, b"secret");
+        assert_eq!(hello.vm, "vm0");
+        assert_eq!(
+            events,
+            vec![
+                Payload::Wheel(ProxyWheel {
+                    vertical: -1,
+                    horizontal: 2,
+                }),
+                Payload::Rel(ProxyRelMotion { dx: 4, dy: -3 }),
+            ]
+        );
+    }
+}
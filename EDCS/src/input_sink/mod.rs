@@ -0,0 +1,107 @@
+//! Where a session's input goes. Capture and input used to both be the CAL's
+//! job, now the CAL is only one of the sinks, picked with the `inputSink`
+//! option, so eg. a VNC or vGPU capture can be paired with QMP input.
+
+mod evdev_proxy;
+mod qmp;
+mod recording;
+mod uinput;
+
+use std::fmt;
+
+use log::{debug, warn};
+
+pub use crate::capture_source::EdssError;
+use crate::edcs_server::edcs_proto::EdcsInputCapabilities;
+
+pub use evdev_proxy::EvdevProxySink;
+pub use qmp::QmpSink;
+pub use recording::RecordingSink;
+pub use uinput::UinputSink;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TouchPhase {
+    Start,
+    Move,
+    End,
+    Cancel,
+}
+
+/// Input after it has been checked, in a form independent of the protocol.
+/// Positions are normalised to [0, 1] relative to the video frame, sinks that
+/// need pixels scale them themselves.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputEvent {
+    MouseMove {
+        x: f64,
+        y: f64,
+    },
+    /// Linux button code, eg. BTN_LEFT
+    MouseButton {
+        button: u32,
+        pressed: bool,
+    },
+    /// In wheel clicks, positive is away from the user and to the right
+    Wheel {
+        vertical: i32,
+        horizontal: i32,
+    },
+    /// In CAL pixels
+    RelMotion {
+        dx: i32,
+        dy: i32,
+    },
+    /// Linux key code, eg. KEY_A
+    Key {
+        code: u32,
+        pressed: bool,
+    },
+    Touch {
+        contact_id: u32,
+        phase: TouchPhase,
+        x: f64,
+        y: f64,
+        pressure: f64,
+    },
+    Pen {
+        x: f64,
+        y: f64,
+        pressure: f64,
+        tilt_x: f64,
+        tilt_y: f64,
+        in_range: bool,
+        touching: bool,
+        eraser: bool,
+        barrel_button: bool,
+    },
+}
+
+pub trait InputSink: Send + fmt::Debug {
+    /// Touch and pen input the sink takes, mouse and keyboard are a given.
+    fn input_capabilities(&self) -> EdcsInputCapabilities {
+        EdcsInputCapabilities::default()
+    }
+
+    fn write(&mut self, ev: InputEvent) -> Result<(), EdssError>;
+}
+
+/// Wheel events are cut down to this many clicks per axis, so a client can't
+/// have a sink click billions of times.
+pub const MAX_WHEEL_CLICKS: i32 = 32;
+
+/// The `inputSink` option values.
+pub const CAL_SINK: &str = "cal";
+pub const QMP_SINK: &str = "qmp";
+pub const UINPUT_SINK: &str = "uinput";
+pub const EVDEV_PROXY_SINK: &str = "evdev-proxy";
+
+// Shared by the sinks that can't do anything with some kinds of input
+fn unsupported(ev: &InputEvent) -> Result<(), EdssError> {
+    debug!("Dropping unsupported input {:?}", ev);
+    Err(EdssError::unsupported())
+}
+
+fn sink_failure(e: anyhow::Error) -> EdssError {
+    warn!("{:#}", e);
+    EdssError::cal_library_failure()
+}
@@ -0,0 +1,114 @@
+use std::path::Path;
+
+use log::warn;
+
+use super::{sink_failure, unsupported, EdssError, InputEvent, InputSink};
+use crate::qmp::{Axis, QmpClient, QmpInputEvent};
+
+/// Sends input to a QEMU VM over its QMP socket.
+#[derive(Debug)]
+pub struct QmpSink {
+    client: QmpClient,
+}
+
+impl QmpSink {
+    pub fn connect(socket_path: impl AsRef<Path>) -> Result<Self, EdssError> {
+        let client = QmpClient::connect(socket_path).map_err(|e| {
+            warn!("{:#}", e);
+            EdssError::cal_file_not_found()
+        })?;
+        Ok(Self { client })
+    }
+}
+
+impl InputSink for QmpSink {
+    fn write(&mut self, ev: InputEvent) -> Result<(), EdssError> {
+        let events = match ev {
+            // QMP takes normalised coordinates as well, no need for the resolution
+            InputEvent::MouseMove { x, y } => vec![
+                QmpInputEvent::Abs {
+                    axis: Axis::X,
+                    value: x,
+                },
+                QmpInputEvent::Abs {
+                    axis: Axis::Y,
+                    value: y,
+                },
+            ],
+            InputEvent::MouseButton { button, pressed } => vec![QmpInputEvent::Button {
+                code: button as i32,
+                down: pressed,
+            }],
+            InputEvent::Wheel {
+                vertical,
+                horizontal,
+            } => vec![QmpInputEvent::Wheel {
+                vertical,
+                horizontal,
+            }],
+            InputEvent::RelMotion { dx, dy } => vec![
+                QmpInputEvent::Rel {
+                    axis: Axis::X,
+                    value: dx.into(),
+                },
+                QmpInputEvent::Rel {
+                    axis: Axis::Y,
+                    value: dy.into(),
+                },
+            ],
+            InputEvent::Key { code, pressed } => vec![QmpInputEvent::Key {
+                code: code as i32,
+                down: pressed,
+            }],
+            InputEvent::Touch { .. } | InputEvent::Pen { .. } => return unsupported(&ev),
+        };
+        self.client.send_input(&events).map_err(sink_failure)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::qmp::mock::MockQmpServer;
+
+    fn connect() -> (MockQmpServer, QmpSink) {
+        let server = MockQmpServer::start().unwrap();
+        let sink = QmpSink::connect(server.path()).unwrap();
+        (server, sink)
+    }
+
+    #[test]
+    fn sends_a_wheel_click_as_a_button_press_and_release() {
+        let (server, mut sink) = connect();
+        sink.write(InputEvent::Wheel {
+            vertical: 1,
+            horizontal: 0,
+        })
+        .unwrap();
+        assert_eq!(
+            server.commands().last(),
+            Some(&json!({
+                "execute": "input-send-event",
+                "arguments": { "events": [
+                    { "type": "btn", "data": { "down": true, "button": "wheel-up" } },
+                    { "type": "btn", "data": { "down": false, "button": "wheel-up" } },
+                ] }
+            }))
+        );
+    }
+
+    #[test]
+    fn sends_relative_motion_on_both_axes() {
+        let (server, mut sink) = connect();
+        sink.write(InputEvent::RelMotion { dx: 5, dy: -2 }).unwrap();
+        assert_eq!(
+            server.input_events(),
+            vec![
+                json!({ "type": "rel", "data": { "axis": "x", "value": 5 } }),
+                json!({ "type": "rel", "data": { "axis": "y", "value": -2 } }),
+            ]
+        );
+    }
+}
@@ -0,0 +1,47 @@
+use std::sync::{Arc, Mutex};
+
+use super::{EdssError, InputEvent, InputSink};
+use crate::edcs_server::edcs_proto::EdcsInputCapabilities;
+
+/// Keeps every event instead of sending it anywhere, for tests. Clones share
+/// the same events, so one can be handed to the adapter and the other kept.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingSink {
+    events: Arc<Mutex<Vec<InputEvent>>>,
+}
+
+impl RecordingSink {
+    /// Everything written so far, oldest first.
+    pub fn events(&self) -> Vec<InputEvent> {
+        self.events
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn clear(&self) {
+        self.events
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+}
+
+impl InputSink for RecordingSink {
+    // Takes everything, so every kind of event can be tested
+    fn input_capabilities(&self) -> EdcsInputCapabilities {
+        EdcsInputCapabilities {
+            touch: true,
+            pen: true,
+            max_touch_contacts: 10,
+        }
+    }
+
+    fn write(&mut self, ev: InputEvent) -> Result<(), EdssError> {
+        self.events
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(ev);
+        Ok(())
+    }
+}
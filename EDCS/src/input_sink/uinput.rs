@@ -0,0 +1,125 @@
+use input_event_codes::*;
+
+use super::{sink_failure, unsupported, EdssError, InputEvent, InputSink};
+use evdev_proxy::uinput::{AbsAxis, DeviceId, UinputDevice};
+
+// Absolute positions are scaled to this, the same range QEMU uses
+const ABS_MAX: i32 = 0x7fff;
+const DEVICE_ID: DeviceId = DeviceId {
+    vendor: 0x1209,
+    product: 0xedc6,
+};
+const BUTTONS: [u16; 3] = [BTN_LEFT!(), BTN_RIGHT!(), BTN_MIDDLE!()];
+
+/// A keyboard, a relative mouse and an absolute pointer created by EDCS
+/// itself, for when the host's devices are what gets captured, or are passed
+/// through to a VM. The pointers are separate like in evdev-proxy.
+#[derive(Debug)]
+pub struct UinputSink {
+    keyboard: UinputDevice,
+    mouse: UinputDevice,
+    pointer: UinputDevice,
+    // Buttons go to whichever pointer moved last
+    mouse_active: bool,
+}
+
+impl UinputSink {
+    pub fn create() -> Result<Self, EdssError> {
+        let keys: Vec<u16> = (KEY_ESC!()..=KEY_MICMUTE!()).collect();
+        let keyboard = UinputDevice::create("EDCS Keyboard", DEVICE_ID, &keys, &[], &[])
+            .map_err(sink_failure)?;
+        let mouse = UinputDevice::create(
+            "EDCS Mouse",
+            DEVICE_ID,
+            &BUTTONS,
+            &[REL_X!(), REL_Y!(), REL_WHEEL!(), REL_HWHEEL!()],
+            &[],
+        )
+        .map_err(sink_failure)?;
+        let axis = |code| AbsAxis {
+            code,
+            min: 0,
+            max: ABS_MAX,
+            flat: 0,
+        };
+        let pointer = UinputDevice::create(
+            "EDCS Pointer",
+            DEVICE_ID,
+            &BUTTONS,
+            &[],
+            &[axis(ABS_X!()), axis(ABS_Y!())],
+        )
+        .map_err(sink_failure)?;
+        Ok(Self {
+            keyboard,
+            mouse,
+            pointer,
+            mouse_active: false,
+        })
+    }
+
+    fn emit(device: &mut UinputDevice, events: &[(u16, u16, i32)]) -> Result<(), EdssError> {
+        for (type_, code, value) in events {
+            device
+                .emit(*type_, *code, *value)
+                .map_err(|e| sink_failure(e.into()))?;
+        }
+        device.sync().map_err(|e| sink_failure(e.into()))
+    }
+}
+
+impl InputSink for UinputSink {
+    fn write(&mut self, ev: InputEvent) -> Result<(), EdssError> {
+        match ev {
+            InputEvent::MouseMove { x, y } => {
+                let scale = |v: f64| (v.clamp(0.0, 1.0) * ABS_MAX as f64).round() as i32;
+                self.mouse_active = false;
+                Self::emit(
+                    &mut self.pointer,
+                    &[
+                        (EV_ABS!(), ABS_X!(), scale(x)),
+                        (EV_ABS!(), ABS_Y!(), scale(y)),
+                    ],
+                )
+            }
+            InputEvent::MouseButton { button, pressed } => {
+                if !BUTTONS.iter().any(|b| *b as u32 == button) {
+                    return Err(EdssError::unsupported());
+                }
+                let device = if self.mouse_active {
+                    &mut self.mouse
+                } else {
+                    &mut self.pointer
+                };
+                Self::emit(device, &[(EV_KEY!(), button as u16, pressed as i32)])
+            }
+            InputEvent::Wheel {
+                vertical,
+                horizontal,
+            } => Self::emit(
+                &mut self.mouse,
+                &[
+                    (EV_REL!(), REL_WHEEL!(), vertical),
+                    (EV_REL!(), REL_HWHEEL!(), horizontal),
+                ],
+            ),
+            InputEvent::RelMotion { dx, dy } => {
+                self.mouse_active = true;
+                Self::emit(
+                    &mut self.mouse,
+                    &[(EV_REL!(), REL_X!(), dx), (EV_REL!(), REL_Y!(), dy)],
+                )
+            }
+            InputEvent::Key { code, pressed } => {
+                if !(KEY_ESC!()..=KEY_MICMUTE!()).contains(&code) {
+                    return Err(EdssError::unsupported());
+                }
+                Self::emit(
+                    &mut self.keyboard,
+                    &[(EV_KEY!(), code as u16, pressed as i32)],
+                )
+            }
+            InputEvent::Touch { .. } | InputEvent::Pen { .. } => unsupported(&ev),
+        }
+    }
+}
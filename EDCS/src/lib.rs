@@ -1,2 +1,9 @@
 pub mod edcs_server;
 mod edss_safe;
+pub mod input_sink;
+pub mod qmp;
+pub mod test_sources;
+pub mod vnc;
+
+pub use edss_safe::capture_source;
+pub use edss_safe::worker;
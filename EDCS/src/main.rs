@@ -2,18 +2,61 @@ extern crate edcs;
 
 use std::{io::Write, path::PathBuf};
 
-use clap::Parser;
-use edcs::edcs_server::server;
-use log::{error, info, warn};
+use chrono::{DateTime, FixedOffset};
+use clap::{Parser, Subcommand};
+use edcs::edcs_server::{audit, server};
+use edcs::worker;
+use log::{error, info};
 
 #[derive(Parser, Debug)]
 struct CLIArgs {
     #[clap(short, long, default_value = "edcsConfig.toml")]
     config_file: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print audit records from the log in the config, oldest first
+    Audit {
+        /// RFC 3339, eg. 2022-06-01T00:00:00Z
+        #[clap(long, parse(try_from_str = DateTime::parse_from_rfc3339))]
+        since: Option<DateTime<FixedOffset>>,
+        #[clap(long, parse(try_from_str = DateTime::parse_from_rfc3339))]
+        until: Option<DateTime<FixedOffset>>,
+        #[clap(long)]
+        user: Option<String>,
+        #[clap(long)]
+        vm: Option<String>,
+    },
+    /// Runs one stream for EDCS, which starts it
+    #[clap(name = "edss-worker", hide = true)]
+    EdssWorker,
 }
 
 fn main() {
     let args = CLIArgs::parse();
+    let edss_worker = matches!(args.command, Some(Command::EdssWorker));
+    if let Some(Command::Audit {
+        since,
+        until,
+        user,
+        vm,
+    }) = args.command
+    {
+        let filter = audit::AuditFilter {
+            since,
+            until,
+            user,
+            vm,
+        };
+        if let Err(e) = audit::print(args.config_file, &filter) {
+            eprintln!("Failed to read the audit log: {:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
     // https://stackoverflow.com/questions/61810740/log-source-file-and-line-numbers
 
     env_logger::Builder::new()
@@ -44,6 +87,14 @@ fn main() {
         })
         .init();
 
+    if edss_worker {
+        if let Err(e) = worker::run() {
+            error!("The stream worker failed: {:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     info!("Starting EDCS server");
 
     match server::start(args.config_file) {
@@ -0,0 +1,156 @@
+//! A stand-in for QEMU's QMP socket, so input injection can be tested without
+//! a VM. It accepts every command and records it.
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use log::warn;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+static NEXT_SOCKET: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Default)]
+struct MockState {
+    commands: Vec<Value>,
+    // Returned as the error description of every command after capabilities
+    error: Option<String>,
+}
+
+pub struct MockQmpServer {
+    path: PathBuf,
+    state: Arc<Mutex<MockState>>,
+    stopping: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+fn serve(stream: UnixStream, state: &Mutex<MockState>) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    writeln!(
+        writer,
+        "{}",
+        json!({ "QMP": { "version": { "qemu": { "major": 7, "minor": 0, "micro": 0 } }, "capabilities": [] } })
+    )?;
+    for line in BufReader::new(stream).lines() {
+        let command: Value = match serde_json::from_str(&line?) {
+            Ok(command) => command,
+            Err(e) => {
+                writeln!(
+                    writer,
+                    "{}",
+                    json!({ "error": { "class": "GenericError", "desc": e.to_string() } })
+                )?;
+                continue;
+            }
+        };
+        let mut state = state.lock();
+        let response = match &state.error {
+            Some(desc) if command["execute"] != "qmp_capabilities" => {
+                json!({ "error": { "class": "GenericError", "desc": desc } })
+            }
+            _ => json!({ "return": {} }),
+        };
+        state.commands.push(command);
+        drop(state);
+        // Real QEMU interleaves events with responses, clients have to cope
+        writeln!(
+            writer,
+            "{}",
+            json!({ "event": "MOCK", "timestamp": { "seconds": 0, "microseconds": 0 } })
+        )?;
+        writeln!(writer, "{}", response)?;
+    }
+    Ok(())
+}
+
+impl MockQmpServer {
+    /// Listen on a new socket in the temporary directory.
+    pub fn start() -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "edcs-mock-qmp-{}-{}.sock",
+            std::process::id(),
+            NEXT_SOCKET.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let state = state.clone();
+            let stopping = stopping.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopping.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let state = state.clone();
+                    match stream {
+                        Ok(stream) => {
+                            thread::spawn(move || {
+                                if let Err(e) = serve(stream, &state) {
+                                    warn!("Mock QMP connection failed: {}", e);
+                                }
+                            });
+                        }
+                        Err(e) => warn!("Mock QMP accept failed: {}", e),
+                    }
+                }
+            })
+        };
+
+        Ok(Self {
+            path,
+            state,
+            stopping,
+            thread: Some(thread),
+        })
+    }
+
+    /// What to set as the `qmpSocket` option.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Every command received so far, oldest first.
+    pub fn commands(&self) -> Vec<Value> {
+        self.state.lock().commands.clone()
+    }
+
+    /// The `events` of every `input-send-event`, flattened.
+    pub fn input_events(&self) -> Vec<Value> {
+        self.state
+            .lock()
+            .commands
+            .iter()
+            .filter(|c| c["execute"] == "input-send-event")
+            .flat_map(|c| {
+                c["arguments"]["events"]
+                    .as_array()
+                    .cloned()
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// Make every following command fail with `desc`, or succeed again.
+    pub fn set_error(&self, desc: Option<&str>) {
+        self.state.lock().error = desc.map(str::to_owned);
+    }
+}
+
+impl Drop for MockQmpServer {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        // Wake up the accept loop so it sees the flag
+        let _ = UnixStream::connect(&self.path);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
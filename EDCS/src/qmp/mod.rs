@@ -0,0 +1,372 @@
+//! Input injection through a VM's QMP socket, with `input-send-event`. Unlike
+//! uinput on the host this reaches the VM's own virtual input devices, so it
+//! works whatever the CAL is. See
+//! https://www.qemu.org/docs/master/interop/qemu-qmp-ref.html
+
+#[cfg(test)]
+pub(crate) mod mock;
+mod qcode;
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use log::debug;
+use serde_json::{json, Value};
+
+// Input is sent from the client handler, which can't wait on a stuck VM for long
+const QMP_TIMEOUT: Duration = Duration::from_secs(1);
+// INPUT_EVENT_ABS_MAX in QEMU, absolute axes go from 0 to this
+pub const ABS_MAX: i64 = 0x7fff;
+// Each click is two QMP events, so the count from the client can't go unbounded
+const MAX_WHEEL_CLICKS: u32 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    X,
+    Y,
+}
+
+impl Axis {
+    fn name(self) -> &'static str {
+        match self {
+            Axis::X => "x",
+            Axis::Y => "y",
+        }
+    }
+}
+
+/// One of QEMU's `InputEvent`s.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QmpInputEvent {
+    /// A Linux key code, eg. KEY_A
+    Key {
+        code: i32,
+        down: bool,
+    },
+    /// A Linux button code, eg. BTN_LEFT
+    Button {
+        code: i32,
+        down: bool,
+    },
+    /// Normalised to [0, 1]
+    Abs {
+        axis: Axis,
+        value: f64,
+    },
+    Rel {
+        axis: Axis,
+        value: i64,
+    },
+    /// Positive is away from the user, in wheel clicks. At most
+    /// `MAX_WHEEL_CLICKS` are sent per axis.
+    Wheel {
+        vertical: i32,
+        horizontal: i32,
+    },
+}
+
+fn button_name(code: i32) -> Option<&'static str> {
+    Some(match code as u32 {
+        input_event_codes::BTN_LEFT!() => "left",
+        input_event_codes::BTN_MIDDLE!() => "middle",
+        input_event_codes::BTN_RIGHT!() => "right",
+        input_event_codes::BTN_SIDE!() => "side",
+        input_event_codes::BTN_EXTRA!() => "extra",
+        _ => return None,
+    })
+}
+
+fn btn_json(button: &str, down: bool) -> Value {
+    json!({ "type": "btn", "data": { "down": down, "button": button } })
+}
+
+impl QmpInputEvent {
+    /// The QMP form of the event. Wheel clicks are button presses in QEMU,
+    /// which is why this can be more than one event.
+    fn to_json(self) -> anyhow::Result<Vec<Value>> {
+        Ok(match self {
+            QmpInputEvent::Key { code, down } => {
+                let qcode = qcode::from_linux_key(code)
+                    .ok_or_else(|| anyhow!("No QEMU key code for key {}", code))?;
+                vec![json!({
+                    "type": "key",
+                    "data": { "down": down, "key": { "type": "qcode", "data": qcode } }
+                })]
+            }
+            QmpInputEvent::Button { code, down } => {
+                let button =
+                    button_name(code).ok_or_else(|| anyhow!("No QEMU button for {}", code))?;
+                vec![btn_json(button, down)]
+            }
+            QmpInputEvent::Abs { axis, value } => {
+                if !value.is_finite() {
+                    bail!("Absolute position {} isn't a number", value);
+                }
+                let value = (value.clamp(0.0, 1.0) * ABS_MAX as f64).round() as i64;
+                vec![json!({ "type": "abs", "data": { "axis": axis.name(), "value": value } })]
+            }
+            QmpInputEvent::Rel { axis, value } => {
+                vec![json!({ "type": "rel", "data": { "axis": axis.name(), "value": value } })]
+            }
+            QmpInputEvent::Wheel {
+                vertical,
+                horizontal,
+            } => {
+                let clicks = |count: i32, positive: &'static str, negative: &'static str| {
+                    let button = if count > 0 { positive } else { negative };
+                    (0..count.unsigned_abs().min(MAX_WHEEL_CLICKS))
+                        .flat_map(move |_| [btn_json(button, true), btn_json(button, false)])
+                };
+                clicks(vertical, "wheel-up", "wheel-down")
+                    .chain(clicks(horizontal, "wheel-right", "wheel-left"))
+                    .collect()
+            }
+        })
+    }
+}
+
+/// A QMP connection in command mode.
+#[derive(Debug)]
+pub struct QmpClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl QmpClient {
+    pub fn connect(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("Failed to connect to QMP socket {}", path.display()))?;
+        stream.set_read_timeout(Some(QMP_TIMEOUT))?;
+        stream.set_write_timeout(Some(QMP_TIMEOUT))?;
+        let mut client = Self {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+        };
+
+        let greeting = client.read_message()?;
+        if greeting.get("QMP").is_none() {
+            bail!("Expected a QMP greeting, got {}", greeting);
+        }
+        client.execute("qmp_capabilities", None)?;
+        Ok(client)
+    }
+
+    fn read_message(&mut self) -> anyhow::Result<Value> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            bail!("QMP socket closed");
+        }
+        serde_json::from_str(&line).with_context(|| format!("Invalid QMP message {:?}", line))
+    }
+
+    /// Run a command and wait for its result. Events that arrive in between
+    /// are dropped, nothing here subscribes to them.
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> anyhow::Result<Value> {
+        let mut msg = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            msg["arguments"] = arguments;
+        }
+        writeln!(self.writer, "{}", msg)?;
+        loop {
+            let mut response = self.read_message()?;
+            if let Some(event) = response.get("event") {
+                debug!("Ignoring QMP event {}", event);
+                continue;
+            }
+            if let Some(error) = response.get("error") {
+                bail!(
+                    "QMP command {} failed: {}",
+                    command,
+                    error["desc"].as_str().unwrap_or("no description")
+                );
+            }
+            return match response.get_mut("return") {
+                Some(result) => Ok(result.take()),
+                None => bail!("Unexpected QMP response {}", response),
+            };
+        }
+    }
+
+    /// Send the events to the VM as one batch, which QEMU applies together.
+    pub fn send_input(&mut self, events: &[QmpInputEvent]) -> anyhow::Result<()> {
+        let mut json_events = vec![];
+        for ev in events {
+            json_events.extend(ev.to_json()?);
+        }
+        if json_events.is_empty() {
+            return Ok(());
+        }
+        self.execute("input-send-event", Some(json!({ "events": json_events })))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockQmpServer;
+    use super::*;
+
+    fn connect() -> (MockQmpServer, QmpClient) {
+        let server = MockQmpServer::start().unwrap();
+        let client = QmpClient::connect(server.path()).unwrap();
+        (server, client)
+    }
+
+    #[test]
+    fn negotiates_capabilities() {
+        let (server, _client) = connect();
+        assert_eq!(
+            server.commands(),
+            vec![json!({ "execute": "qmp_capabilities" })]
+        );
+    }
+
+    #[test]
+    fn sends_keys_and_buttons() {
+        let (server, mut client) = connect();
+        client
+            .send_input(&[
+                QmpInputEvent::Key {
+                    code: input_event_codes::KEY_A!(),
+                    down: true,
+                },
+                QmpInputEvent::Button {
+                    code: input_event_codes::BTN_RIGHT!(),
+                    down: false,
+                },
+            ])
+            .unwrap();
+        assert_eq!(
+            server.input_events(),
+            vec![
+                json!({
+                    "type": "key",
+                    "data": { "down": true, "key": { "type": "qcode", "data": "a" } }
+                }),
+                btn_json("right", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn sends_pointer_motion() {
+        let (server, mut client) = connect();
+        client
+            .send_input(&[
+                QmpInputEvent::Abs {
+                    axis: Axis::X,
+                    value: 0.5,
+                },
+                // Off the screen is clamped to its edge
+                QmpInputEvent::Abs {
+                    axis: Axis::Y,
+                    value: 1.5,
+                },
+                QmpInputEvent::Rel {
+                    axis: Axis::X,
+                    value: -3,
+                },
+            ])
+            .unwrap();
+        assert_eq!(
+            server.input_events(),
+            vec![
+                json!({ "type": "abs", "data": { "axis": "x", "value": 16384 } }),
+                json!({ "type": "abs", "data": { "axis": "y", "value": ABS_MAX } }),
+                json!({ "type": "rel", "data": { "axis": "x", "value": -3 } }),
+            ]
+        );
+    }
+
+    #[test]
+    fn sends_wheel_clicks_as_buttons() {
+        let (server, mut client) = connect();
+        client
+            .send_input(&[QmpInputEvent::Wheel {
+                vertical: -1,
+                horizontal: 1,
+            }])
+            .unwrap();
+        assert_eq!(
+            server.input_events(),
+            vec![
+                btn_json("wheel-down", true),
+                btn_json("wheel-down", false),
+                btn_json("wheel-right", true),
+                btn_json("wheel-right", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn limits_wheel_clicks() {
+        let (server, mut client) = connect();
+        client
+            .send_input(&[QmpInputEvent::Wheel {
+                vertical: i32::MAX,
+                horizontal: i32::MIN,
+            }])
+            .unwrap();
+        let events = server.input_events();
+        assert_eq!(events.len(), 4 * MAX_WHEEL_CLICKS as usize);
+        assert_eq!(events[0], btn_json("wheel-up", true));
+        assert_eq!(events.last(), Some(&btn_json("wheel-left", false)));
+    }
+
+    #[test]
+    fn sends_nothing_for_no_events() {
+        let (server, mut client) = connect();
+        client
+            .send_input(&[QmpInputEvent::Wheel {
+                vertical: 0,
+                horizontal: 0,
+            }])
+            .unwrap();
+        assert_eq!(server.commands().len(), 1);
+    }
+
+    #[test]
+    fn refuses_events_qemu_has_no_name_for() {
+        let (server, mut client) = connect();
+        assert!(client
+            .send_input(&[QmpInputEvent::Key {
+                code: 0x2ff,
+                down: true,
+            }])
+            .is_err());
+        assert!(client
+            .send_input(&[QmpInputEvent::Abs {
+                axis: Axis::X,
+                value: f64::NAN,
+            }])
+            .is_err());
+        assert!(server.input_events().is_empty());
+    }
+
+    #[test]
+    fn reports_command_errors() {
+        let (server, mut client) = connect();
+        server.set_error(Some("No VM"));
+        let e = client
+            .send_input(&[QmpInputEvent::Rel {
+                axis: Axis::Y,
+                value: 1,
+            }])
+            .unwrap_err();
+        assert!(e.to_string().contains("No VM"), "{}", e);
+
+        // The connection is still usable afterwards
+        server.set_error(None);
+        client
+            .send_input(&[QmpInputEvent::Rel {
+                axis: Axis::Y,
+                value: 1,
+            }])
+            .unwrap();
+        assert_eq!(server.input_events().len(), 2);
+    }
+}
@@ -0,0 +1,473 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+
+use log::error;
+
+use super::input_log::InputRecorder;
+use crate::capture_source::{
+    CaptureSource, EdssError, FrameConfig, KeyInput, MouseInput, PenInput, PixelFormat, TouchInput,
+};
+use crate::edcs_server::edcs_proto::{EdcsCalOption, EdcsInputCapabilities};
+
+const Y4M_MAGIC: &str = "YUV4MPEG2";
+
+#[derive(Clone, Copy)]
+enum Chroma {
+    C420,
+    C444,
+    Mono,
+}
+
+#[derive(Clone, Copy)]
+enum FileFormat {
+    Y4m(Chroma),
+    // Packed frames straight in the output format, without any header
+    Raw,
+}
+
+struct Playback {
+    reader: BufReader<File>,
+    format: FileFormat,
+    width: usize,
+    height: usize,
+    // Where the first frame starts, for looping
+    data_start: u64,
+    // Only used for Y4M, which has to be converted
+    yuv: Vec<u8>,
+}
+
+/// Loops the frames of a Y4M file, or of a raw file in one of the 32-bit pixel
+/// formats EDSS takes.
+#[derive(Default)]
+pub struct FilePlayback {
+    playback: Option<Playback>,
+    recorder: InputRecorder,
+}
+
+fn io_error(path: &str, e: io::Error) -> EdssError {
+    error!("File playback failed on {}: {}", path, e);
+    EdssError::cal_library_failure()
+}
+
+fn y4m_frame_len(chroma: Chroma, width: usize, height: usize) -> usize {
+    match chroma {
+        Chroma::C420 => width * height + 2 * width.div_ceil(2) * height.div_ceil(2),
+        Chroma::C444 => 3 * width * height,
+        Chroma::Mono => width * height,
+    }
+}
+
+// BT.601 limited range, which is what Y4M files almost always are
+fn yuv_to_bgra(y: u8, u: u8, v: u8) -> [u8; 4] {
+    let c = 298 * (y as i32 - 16);
+    let d = u as i32 - 128;
+    let e = v as i32 - 128;
+    let clamp = |x: i32| ((x + 128) >> 8).clamp(0, 255) as u8;
+    [
+        clamp(c + 516 * d),
+        clamp(c - 100 * d - 208 * e),
+        clamp(c + 409 * e),
+        255,
+    ]
+}
+
+/// Parse the stream header, eg. "YUV4MPEG2 W1280 H720 F30:1 Ip A1:1 C420jpeg".
+/// Returns the width, height, framerate and chroma subsampling.
+fn parse_y4m_header(header: &str) -> Option<(u16, u16, u16, Chroma)> {
+    let mut params = header.trim_end().split(' ');
+    if params.next()? != Y4M_MAGIC {
+        return None;
+    }
+    let (mut width, mut height, mut framerate) = (None, None, 30);
+    // 4:2:0 is the default when the header doesn't say
+    let mut chroma = Chroma::C420;
+    for param in params {
+        let mut chars = param.chars();
+        let tag = match chars.next() {
+            Some(tag) => tag,
+            None => continue,
+        };
+        let value = chars.as_str();
+        match tag {
+            'W' => width = value.parse().ok(),
+            'H' => height = value.parse().ok(),
+            'F' => {
+                let (num, den) = value.split_once(':')?;
+                let (num, den): (u32, u32) = (num.parse().ok()?, den.parse().ok()?);
+                if den == 0 {
+                    return None;
+                }
+                framerate = ((num + den / 2) / den).clamp(1, u16::MAX as u32) as u16;
+            }
+            'C' => {
+                chroma = match value {
+                    "420" | "420jpeg" | "420paldv" | "420mpeg2" => Chroma::C420,
+                    "444" => Chroma::C444,
+                    "mono" => Chroma::Mono,
+                    // Higher bit depths and other subsamplings
+                    _ => return None,
+                }
+            }
+            _ => (),
+        }
+    }
+    Some((width?, height?, framerate, chroma))
+}
+
+impl Playback {
+    fn open(options: &HashMap<String, String>) -> Result<(Self, FrameConfig), EdssError> {
+        let path: String = super::parse_option(options, "path")?;
+        let file = File::open(&path).map_err(|e| {
+            error!("Failed to open {} for playback: {}", path, e);
+            EdssError::cal_file_not_found()
+        })?;
+        let mut reader = BufReader::new(file);
+
+        let (format, config) = match options.get("format").map(String::as_str) {
+            Some("raw") => {
+                let pixel_format = match options.get("pixelFormat").map(String::as_str) {
+                    Some("rgba") => PixelFormat::Rgba,
+                    Some("argb") => PixelFormat::Argb,
+                    Some("abgr") => PixelFormat::Abgr,
+                    _ => PixelFormat::Bgra,
+                };
+                let config = FrameConfig {
+                    width: super::parse_option(options, "width")?,
+                    height: super::parse_option(options, "height")?,
+                    framerate: super::parse_option(options, "framerate")?,
+                    pixel_format,
+                };
+                (FileFormat::Raw, config)
+            }
+            _ => {
+                let mut header = String::new();
+                reader
+                    .read_line(&mut header)
+                    .map_err(|e| io_error(&path, e))?;
+                let (width, height, framerate, chroma) =
+                    parse_y4m_header(&header).ok_or_else(|| {
+                        error!("{} isn't a supported Y4M file", path);
+                        EdssError::invalid_cal_options()
+                    })?;
+                let config = FrameConfig {
+                    width,
+                    height,
+                    framerate,
+                    pixel_format: PixelFormat::Bgra,
+                };
+                (FileFormat::Y4m(chroma), config)
+            }
+        };
+
+        let data_start = reader.stream_position().map_err(|e| io_error(&path, e))?;
+        let (width, height) = (config.width as usize, config.height as usize);
+        let yuv = match format {
+            FileFormat::Y4m(chroma) => vec![0; y4m_frame_len(chroma, width, height)],
+            FileFormat::Raw => vec![],
+        };
+        let playback = Self {
+            reader,
+            format,
+            width,
+            height,
+            data_start,
+            yuv,
+        };
+        Ok((playback, config))
+    }
+
+    // Returns false at the end of the file. A cut off last frame counts as the end.
+    fn read_next(&mut self, frame: &mut [u8]) -> io::Result<bool> {
+        let data: &mut [u8] = match self.format {
+            FileFormat::Raw => &mut *frame,
+            FileFormat::Y4m(_) => {
+                let mut frame_header = String::new();
+                if self.reader.read_line(&mut frame_header)? == 0 {
+                    return Ok(false);
+                }
+                if !frame_header.starts_with("FRAME") {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "missing FRAME marker",
+                    ));
+                }
+                &mut self.yuv
+            }
+        };
+        match self.reader.read_exact(data) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        if let FileFormat::Y4m(chroma) = self.format {
+            self.convert_yuv(chroma, frame);
+        }
+        Ok(true)
+    }
+
+    fn convert_yuv(&self, chroma: Chroma, frame: &mut [u8]) {
+        let (width, height) = (self.width, self.height);
+        let luma = &self.yuv[..width * height];
+        let chroma_width = match chroma {
+            Chroma::C420 => width.div_ceil(2),
+            _ => width,
+        };
+        let chroma_len = match chroma {
+            Chroma::C420 => chroma_width * height.div_ceil(2),
+            Chroma::C444 => width * height,
+            Chroma::Mono => 0,
+        };
+        let (u_plane, v_plane) = self.yuv[width * height..].split_at(chroma_len);
+        for (i, pixel) in frame.chunks_exact_mut(4).enumerate() {
+            let (x, y) = (i % width, i / width);
+            let (u, v) = match chroma {
+                Chroma::C420 => {
+                    let c = (y / 2) * chroma_width + x / 2;
+                    (u_plane[c], v_plane[c])
+                }
+                Chroma::C444 => (u_plane[i], v_plane[i]),
+                Chroma::Mono => (128, 128),
+            };
+            pixel.copy_from_slice(&yuv_to_bgra(luma[i], u, v));
+        }
+    }
+}
+
+impl FilePlayback {
+    fn schema() -> Vec<EdcsCalOption> {
+        vec![
+            super::string_option("path", "File to play on the server", true),
+            super::enum_option(
+                "format",
+                "Y4M files describe themselves, raw ones need the options below",
+                &["y4m", "raw"],
+            ),
+            super::int_option("width", "Frame width of raw files", 1280, 16, 4096),
+            super::int_option("height", "Frame height of raw files", 720, 16, 4096),
+            super::int_option("framerate", "Frames per second of raw files", 60, 1, 240),
+            super::enum_option(
+                "pixelFormat",
+                "Pixel format of raw files",
+                &["bgra", "rgba", "argb", "abgr"],
+            ),
+            super::input_log_option(),
+        ]
+    }
+}
+
+impl CaptureSource for FilePlayback {
+    fn options(&self) -> HashMap<String, String> {
+        super::default_options(&Self::schema())
+    }
+
+    fn option_schema(&self) -> Option<Vec<EdcsCalOption>> {
+        Some(Self::schema())
+    }
+
+    fn input_capabilities(&self) -> EdcsInputCapabilities {
+        super::input_capabilities()
+    }
+
+    fn init(&mut self, options: HashMap<String, String>) -> Result<FrameConfig, EdssError> {
+        let (playback, config) = Playback::open(&options)?;
+        self.recorder = InputRecorder::new(&options)?;
+        self.playback = Some(playback);
+        Ok(config)
+    }
+
+    fn read_frame(&mut self, frame: &mut [u8]) -> Result<(), EdssError> {
+        let playback = self
+            .playback
+            .as_mut()
+            .ok_or_else(EdssError::cal_library_failure)?;
+        let failed = |e| {
+            error!("File playback failed: {}", e);
+            EdssError::cal_library_failure()
+        };
+        if playback.read_next(frame).map_err(failed)? {
+            return Ok(());
+        }
+        // Go back to the first frame, a file without one is an error
+        playback
+            .reader
+            .seek(SeekFrom::Start(playback.data_start))
+            .map_err(failed)?;
+        if playback.read_next(frame).map_err(failed)? {
+            return Ok(());
+        }
+        error!("File playback has no complete frames to play");
+        Err(EdssError::cal_library_failure())
+    }
+
+    fn write_mouse_event(&mut self, ev: MouseInput) -> Result<(), EdssError> {
+        self.recorder.record(ev)
+    }
+
+    fn write_keyboard_event(&mut self, ev: KeyInput) -> Result<(), EdssError> {
+        self.recorder.record(ev)
+    }
+
+    fn write_touch_event(&mut self, ev: TouchInput) -> Result<(), EdssError> {
+        self.recorder.record(ev)
+    }
+
+    fn write_pen_event(&mut self, ev: PenInput) -> Result<(), EdssError> {
+        self.recorder.record(ev)
+    }
+
+    fn shutdown(&mut self) -> Result<(), EdssError> {
+        self.playback = None;
+        self.recorder = InputRecorder::default();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_sources::TempFile;
+
+    const BLACK: [u8; 4] = [0, 0, 0, 255];
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+
+    fn play(
+        contents: &[u8],
+        options: &[(&str, &str)],
+    ) -> (TempFile, FilePlayback, Result<FrameConfig, EdssError>) {
+        let file = TempFile::new("playback");
+        fs::write(&file.0, contents).unwrap();
+        let mut source = FilePlayback::default();
+        let mut all_options = source.options();
+        all_options.insert("path".to_string(), file.path());
+        for (name, value) in options {
+            all_options.insert(name.to_string(), value.to_string());
+        }
+        let config = source.init(all_options);
+        (file, source, config)
+    }
+
+    // A 3x3 4:2:0 frame has 9 luma and 2 chroma planes of 2x2
+    fn grey_420_frame(luma: u8) -> Vec<u8> {
+        let mut frame = b"FRAME\n".to_vec();
+        frame.extend([luma; 9]);
+        frame.extend([128; 8]);
+        frame
+    }
+
+    fn next_frame(source: &mut FilePlayback, config: &FrameConfig) -> Vec<u8> {
+        let mut frame = vec![0; config.frame_len()];
+        source.read_frame(&mut frame).unwrap();
+        frame
+    }
+
+    #[test]
+    fn parses_y4m_headers() {
+        let (width, height, framerate, chroma) =
+            parse_y4m_header("YUV4MPEG2 W1280 H720 F30000:1001 Ip A1:1 C420jpeg\n").unwrap();
+        assert_eq!((width, height, framerate), (1280, 720, 30));
+        assert!(matches!(chroma, Chroma::C420));
+
+        let (_, _, framerate, chroma) = parse_y4m_header("YUV4MPEG2 W3 H3  Cmono").unwrap();
+        assert_eq!(framerate, 30);
+        assert!(matches!(chroma, Chroma::Mono));
+    }
+
+    #[test]
+    fn rejects_short_or_malformed_y4m_headers() {
+        for header in [
+            "",
+            "\n",
+            "YUV4MPEG2",
+            "YUV4MPEG2 W1280",
+            "YUV4MPEG2 H720",
+            "YUV4MPEG W1280 H720",
+            "YUV4MPEG2 Wide H720",
+            "YUV4MPEG2 W-1 H720",
+            "YUV4MPEG2 W70000 H720",
+            "YUV4MPEG2 W1280 H720 F30",
+            "YUV4MPEG2 W1280 H720 F30:0",
+            "YUV4MPEG2 W1280 H720 Fx:1",
+            "YUV4MPEG2 W1280 H720 C420p10",
+            "YUV4MPEG2 W1280 H720 C422",
+        ] {
+            assert!(parse_y4m_header(header).is_none(), "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn refuses_files_with_bad_headers() {
+        for contents in [&b""[..], b"YUV4MPEG2 W3\n", b"RIFF W3 H3\n"] {
+            let (_file, _source, config) = play(contents, &[]);
+            assert!(config.is_err(), "{:?}", contents);
+        }
+    }
+
+    #[test]
+    fn loops_odd_sized_frames() {
+        let mut contents = b"YUV4MPEG2 W3 H3 F25:1 C420\n".to_vec();
+        contents.extend(grey_420_frame(16));
+        contents.extend(grey_420_frame(235));
+        let (_file, mut source, config) = play(&contents, &[]);
+        let config = config.unwrap();
+        assert_eq!((config.width, config.height, config.framerate), (3, 3, 25));
+
+        assert_eq!(next_frame(&mut source, &config), BLACK.repeat(9));
+        assert_eq!(next_frame(&mut source, &config), WHITE.repeat(9));
+        assert_eq!(next_frame(&mut source, &config), BLACK.repeat(9));
+    }
+
+    #[test]
+    fn skips_a_cut_off_last_frame() {
+        let mut contents = b"YUV4MPEG2 W3 H3 C420\n".to_vec();
+        contents.extend(grey_420_frame(16));
+        contents.extend(&grey_420_frame(235)[..10]);
+        let (_file, mut source, config) = play(&contents, &[]);
+        let config = config.unwrap();
+
+        assert_eq!(next_frame(&mut source, &config), BLACK.repeat(9));
+        assert_eq!(next_frame(&mut source, &config), BLACK.repeat(9));
+    }
+
+    #[test]
+    fn fails_without_a_complete_frame() {
+        let mut contents = b"YUV4MPEG2 W3 H3 C420\n".to_vec();
+        contents.extend(&grey_420_frame(16)[..12]);
+        let (_file, mut source, config) = play(&contents, &[]);
+        let mut frame = vec![0; config.unwrap().frame_len()];
+        assert!(source.read_frame(&mut frame).is_err());
+
+        let (_file, mut source, config) = play(b"YUV4MPEG2 W3 H3 C420\n", &[]);
+        let mut frame = vec![0; config.unwrap().frame_len()];
+        assert!(source.read_frame(&mut frame).is_err());
+    }
+
+    #[test]
+    fn fails_without_frame_markers() {
+        let mut contents = b"YUV4MPEG2 W3 H3 C420\n".to_vec();
+        contents.extend(&grey_420_frame(16)[6..]);
+        let (_file, mut source, config) = play(&contents, &[]);
+        let mut frame = vec![0; config.unwrap().frame_len()];
+        assert!(source.read_frame(&mut frame).is_err());
+    }
+
+    #[test]
+    fn plays_raw_files_up_to_the_last_whole_frame() {
+        let options = [
+            ("format", "raw"),
+            ("width", "2"),
+            ("height", "1"),
+            ("framerate", "10"),
+        ];
+        let contents = [[1; 8], [2; 8]].concat();
+        let (_file, mut source, config) = play(&[&contents[..], &[3; 3]].concat(), &options);
+        let config = config.unwrap();
+        assert_eq!(config.pixel_format, PixelFormat::Bgra);
+
+        assert_eq!(next_frame(&mut source, &config), [1; 8]);
+        assert_eq!(next_frame(&mut source, &config), [2; 8]);
+        assert_eq!(next_frame(&mut source, &config), [1; 8]);
+    }
+}
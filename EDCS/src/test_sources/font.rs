@@ -0,0 +1,93 @@
+//! Just enough of a bitmap font to burn counters and timestamps into frames.
+
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+
+// One row per entry, the most significant of the 3 bits is the leftmost pixel
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        _ => [0; GLYPH_HEIGHT],
+    }
+}
+
+/// Draw `text` in white on a black box into a BGRA frame, with every font
+/// pixel `scale` pixels wide. Whatever doesn't fit is cut off.
+pub fn draw_text(
+    frame: &mut [u8],
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+    scale: usize,
+    text: &str,
+) {
+    // A pixel of padding around and between the glyphs
+    let box_width = (text.chars().count() * (GLYPH_WIDTH + 1) + 1) * scale;
+    let box_height = (GLYPH_HEIGHT + 2) * scale;
+    fill_rect(
+        frame,
+        width,
+        height,
+        x,
+        y,
+        box_width,
+        box_height,
+        [0, 0, 0, 255],
+    );
+
+    for (i, c) in text.chars().enumerate() {
+        let glyph_x = x + (i * (GLYPH_WIDTH + 1) + 1) * scale;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) != 0 {
+                    fill_rect(
+                        frame,
+                        width,
+                        height,
+                        glyph_x + col * scale,
+                        y + (row + 1) * scale,
+                        scale,
+                        scale,
+                        [255, 255, 255, 255],
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Fill a rectangle of a BGRA frame, clipped to the frame.
+#[allow(clippy::too_many_arguments)]
+pub fn fill_rect(
+    frame: &mut [u8],
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+    rect_width: usize,
+    rect_height: usize,
+    bgra: [u8; 4],
+) {
+    let x_end = (x + rect_width).min(width);
+    let y_end = (y + rect_height).min(height);
+    for row in y.min(y_end)..y_end {
+        let start = (row * width + x.min(x_end)) * 4;
+        let end = (row * width + x_end) * 4;
+        for pixel in frame[start..end].chunks_exact_mut(4) {
+            pixel.copy_from_slice(&bgra);
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! Input received by the test sources, kept in memory for tests running in the
+//! same process, and optionally appended to a file for everything else.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+
+use log::{error, warn};
+
+use crate::capture_source::{EdssError, KeyInput, MouseInput, PenInput, TouchInput};
+
+// Old events are dropped past this, a test pattern can run for a long time
+const MAX_RECORDED_INPUT: usize = 4096;
+
+static RECORDED_INPUT: Mutex<VecDeque<RecordedInput>> = Mutex::new(VecDeque::new());
+
+/// A single input event as the source saw it, so in CAL pixels.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordedInput {
+    MouseMove {
+        x: f64,
+        y: f64,
+    },
+    MouseButton {
+        button: i32,
+        pressed: bool,
+    },
+    MouseWheel {
+        vertical: i32,
+        horizontal: i32,
+    },
+    MouseRelMove {
+        dx: i32,
+        dy: i32,
+    },
+    Key {
+        key: i32,
+        pressed: bool,
+    },
+    Touch {
+        contact_id: u32,
+        phase: u32,
+        x: f64,
+        y: f64,
+        pressure: f64,
+    },
+    Pen {
+        x: f64,
+        y: f64,
+        pressure: f64,
+        touching: bool,
+        eraser: bool,
+    },
+}
+
+// This is the line format of the input log file
+impl fmt::Display for RecordedInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordedInput::MouseMove { x, y } => write!(f, "mouse_move {} {}", x, y),
+            RecordedInput::MouseButton { button, pressed } => {
+                write!(f, "mouse_button {} {}", button, *pressed as u8)
+            }
+            RecordedInput::MouseWheel {
+                vertical,
+                horizontal,
+            } => write!(f, "mouse_wheel {} {}", vertical, horizontal),
+            RecordedInput::MouseRelMove { dx, dy } => write!(f, "mouse_rel_move {} {}", dx, dy),
+            RecordedInput::Key { key, pressed } => write!(f, "key {} {}", key, *pressed as u8),
+            RecordedInput::Touch {
+                contact_id,
+                phase,
+                x,
+                y,
+                pressure,
+            } => write!(f, "touch {} {} {} {} {}", contact_id, phase, x, y, pressure),
+            RecordedInput::Pen {
+                x,
+                y,
+                pressure,
+                touching,
+                eraser,
+            } => write!(
+                f,
+                "pen {} {} {} {} {}",
+                x, y, pressure, *touching as u8, *eraser as u8
+            ),
+        }
+    }
+}
+
+impl From<MouseInput> for RecordedInput {
+    fn from(ev: MouseInput) -> Self {
+        match ev {
+            MouseInput::Move { x, y } => RecordedInput::MouseMove { x, y },
+            MouseInput::Button { button, pressed } => {
+                RecordedInput::MouseButton { button, pressed }
+            }
+            MouseInput::Wheel {
+                vertical,
+                horizontal,
+            } => RecordedInput::MouseWheel {
+                vertical,
+                horizontal,
+            },
+            MouseInput::RelMove { dx, dy } => RecordedInput::MouseRelMove { dx, dy },
+        }
+    }
+}
+
+impl From<KeyInput> for RecordedInput {
+    fn from(ev: KeyInput) -> Self {
+        RecordedInput::Key {
+            key: ev.button,
+            pressed: ev.pressed,
+        }
+    }
+}
+
+impl From<TouchInput> for RecordedInput {
+    fn from(ev: TouchInput) -> Self {
+        RecordedInput::Touch {
+            contact_id: ev.contactId,
+            phase: ev.phase,
+            x: ev.x,
+            y: ev.y,
+            pressure: ev.pressure,
+        }
+    }
+}
+
+impl From<PenInput> for RecordedInput {
+    fn from(ev: PenInput) -> Self {
+        RecordedInput::Pen {
+            x: ev.x,
+            y: ev.y,
+            pressure: ev.pressure,
+            touching: ev.touching,
+            eraser: ev.eraser,
+        }
+    }
+}
+
+/// Everything the test sources received since the last clear, oldest first.
+pub fn recorded_input() -> Vec<RecordedInput> {
+    let recorded = RECORDED_INPUT.lock().unwrap_or_else(|e| e.into_inner());
+    recorded.iter().cloned().collect()
+}
+
+pub fn clear_recorded_input() {
+    RECORDED_INPUT
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clear();
+}
+
+#[derive(Default)]
+pub struct InputRecorder {
+    log_file: Option<File>,
+}
+
+impl InputRecorder {
+    /// Open the `inputLog` option if it is set.
+    pub fn new(options: &HashMap<String, String>) -> Result<Self, EdssError> {
+        let log_file = match options.get("inputLog").filter(|path| !path.is_empty()) {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| {
+                        error!("Failed to open input log {}: {}", path, e);
+                        EdssError::cal_file_not_found()
+                    })?,
+            ),
+            None => None,
+        };
+        Ok(Self { log_file })
+    }
+
+    pub fn record(&mut self, input: impl Into<RecordedInput>) -> Result<(), EdssError> {
+        let input = input.into();
+        if let Some(file) = &mut self.log_file {
+            // Losing a line only matters to whoever reads the log, not the stream
+            if let Err(e) = writeln!(file, "{}", input) {
+                warn!("Failed to write to the input log: {}", e);
+            }
+        }
+        let mut recorded = RECORDED_INPUT.lock().unwrap_or_else(|e| e.into_inner());
+        if recorded.len() == MAX_RECORDED_INPUT {
+            recorded.pop_front();
+        }
+        recorded.push_back(input);
+        Ok(())
+    }
+}
+
+// The recorded input is shared by the whole process, so tests that look at it
+// take turns
+#[cfg(test)]
+pub(super) fn lock_recorded_input() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_sources::TempFile;
+
+    #[test]
+    fn writes_one_line_per_event() {
+        let _lock = lock_recorded_input();
+        clear_recorded_input();
+        let log = TempFile::new("input.log");
+        let options = HashMap::from([("inputLog".to_string(), log.path())]);
+        let mut recorder = InputRecorder::new(&options).unwrap();
+
+        recorder
+            .record(MouseInput::Move { x: 1.5, y: 2.0 })
+            .unwrap();
+        recorder
+            .record(MouseInput::Button {
+                button: 272,
+                pressed: true,
+            })
+            .unwrap();
+        recorder
+            .record(KeyInput {
+                button: 30,
+                pressed: false,
+            })
+            .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&log.0).unwrap(),
+            "mouse_move 1.5 2\nmouse_button 272 1\nkey 30 0\n"
+        );
+        assert_eq!(
+            recorded_input(),
+            vec![
+                RecordedInput::MouseMove { x: 1.5, y: 2.0 },
+                RecordedInput::MouseButton {
+                    button: 272,
+                    pressed: true
+                },
+                RecordedInput::Key {
+                    key: 30,
+                    pressed: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_the_oldest_input() {
+        let _lock = lock_recorded_input();
+        clear_recorded_input();
+        let mut recorder = InputRecorder::default();
+        for key in 0..=MAX_RECORDED_INPUT as i32 {
+            recorder
+                .record(KeyInput {
+                    button: key,
+                    pressed: true,
+                })
+                .unwrap();
+        }
+
+        let recorded = recorded_input();
+        assert_eq!(recorded.len(), MAX_RECORDED_INPUT);
+        assert_eq!(
+            recorded[0],
+            RecordedInput::Key {
+                key: 1,
+                pressed: true
+            }
+        );
+        clear_recorded_input();
+        assert!(recorded_input().is_empty());
+    }
+
+    #[test]
+    fn an_unopenable_log_fails_init() {
+        let options = HashMap::from([(
+            "inputLog".to_string(),
+            "/nonexistent/dir/input.log".to_string(),
+        )]);
+        assert!(InputRecorder::new(&options).is_err());
+        // Left empty, the option means no log
+        let options = HashMap::from([("inputLog".to_string(), String::new())]);
+        assert!(InputRecorder::new(&options).unwrap().log_file.is_none());
+    }
+}
@@ -0,0 +1,130 @@
+//! Capture sources that don't need a vGPU or uinput, so the whole pipeline can
+//! run on a dev machine or in CI. Both record the input they are sent, see
+//! `input_log`.
+
+mod file_playback;
+mod font;
+mod input_log;
+mod test_pattern;
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::capture_source::{self, EdssError};
+use crate::edcs_server::edcs_proto::{EdcsCalOption, EdcsCalOptionType, EdcsInputCapabilities};
+
+pub use input_log::{clear_recorded_input, recorded_input, RecordedInput};
+
+pub const TEST_PATTERN_SOURCE: &str = "test-pattern";
+pub const FILE_PLAYBACK_SOURCE: &str = "file-playback";
+
+/// Make the sources available as `plugin_name`s.
+pub fn register() {
+    capture_source::register(TEST_PATTERN_SOURCE, || {
+        Box::new(test_pattern::TestPattern::default())
+    });
+    capture_source::register(FILE_PLAYBACK_SOURCE, || {
+        Box::new(file_playback::FilePlayback::default())
+    });
+}
+
+// Both sources take touch and pen input, just so there is something to record
+fn input_capabilities() -> EdcsInputCapabilities {
+    EdcsInputCapabilities {
+        touch: true,
+        pen: true,
+        max_touch_contacts: 10,
+    }
+}
+
+fn int_option(name: &str, description: &str, default: i64, min: i64, max: i64) -> EdcsCalOption {
+    EdcsCalOption {
+        name: name.to_owned(),
+        description: description.to_owned(),
+        option_type: EdcsCalOptionType::CalOptionInt as i32,
+        default_value: default.to_string(),
+        has_range: true,
+        min: min as f64,
+        max: max as f64,
+        required: true,
+        ..Default::default()
+    }
+}
+
+fn enum_option(name: &str, description: &str, choices: &[&str]) -> EdcsCalOption {
+    EdcsCalOption {
+        name: name.to_owned(),
+        description: description.to_owned(),
+        option_type: EdcsCalOptionType::CalOptionEnum as i32,
+        default_value: choices[0].to_owned(),
+        choices: choices.iter().map(|c| c.to_string()).collect(),
+        required: true,
+        ..Default::default()
+    }
+}
+
+fn string_option(name: &str, description: &str, required: bool) -> EdcsCalOption {
+    EdcsCalOption {
+        name: name.to_owned(),
+        description: description.to_owned(),
+        option_type: EdcsCalOptionType::CalOptionString as i32,
+        required,
+        ..Default::default()
+    }
+}
+
+fn input_log_option() -> EdcsCalOption {
+    string_option(
+        "inputLog",
+        "File to append received input to, one event per line",
+        false,
+    )
+}
+
+fn default_options(schema: &[EdcsCalOption]) -> HashMap<String, String> {
+    schema
+        .iter()
+        .map(|o| (o.name.clone(), o.default_value.clone()))
+        .collect()
+}
+
+// EDCS checks options against the schema before init, this is only for the
+// ones that are left out
+fn parse_option<T: FromStr>(options: &HashMap<String, String>, name: &str) -> Result<T, EdssError> {
+    options
+        .get(name)
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(EdssError::invalid_cal_options)
+}
+
+// A file in the temp dir that is removed again when the test is done
+#[cfg(test)]
+struct TempFile(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempFile {
+    fn new(name: &str) -> Self {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "edcs-test-sources-{}-{}-{}",
+            std::process::id(),
+            NEXT_FILE.fetch_add(1, Ordering::Relaxed),
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        TempFile(path)
+    }
+
+    fn path(&self) -> String {
+        self.0.to_string_lossy().into_owned()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
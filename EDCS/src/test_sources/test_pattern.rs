@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+
+use super::font::{draw_text, fill_rect};
+use super::input_log::InputRecorder;
+use crate::capture_source::{
+    CaptureSource, EdssError, FrameConfig, KeyInput, MouseInput, PenInput, PixelFormat, TouchInput,
+};
+use crate::edcs_server::edcs_proto::{EdcsCalOption, EdcsInputCapabilities};
+
+// BGRA, in the order of SMPTE colour bars
+const BARS: [[u8; 4]; 8] = [
+    [255, 255, 255, 255],
+    [0, 255, 255, 255],
+    [255, 255, 0, 255],
+    [0, 255, 0, 255],
+    [255, 0, 255, 255],
+    [0, 0, 255, 255],
+    [255, 0, 0, 255],
+    [0, 0, 0, 255],
+];
+// Pixels the pattern moves every frame
+const SCROLL_SPEED: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Default)]
+enum Pattern {
+    #[default]
+    Bars,
+    Checkerboard,
+}
+
+/// Scrolling bars or a checkerboard with the frame number and wall clock time
+/// burned in, and a marker where the mouse last was.
+#[derive(Default)]
+pub struct TestPattern {
+    config: Option<FrameConfig>,
+    pattern: Pattern,
+    frame_count: u64,
+    mouse: Option<(f64, f64)>,
+    recorder: InputRecorder,
+}
+
+impl TestPattern {
+    fn schema() -> Vec<EdcsCalOption> {
+        vec![
+            super::int_option("width", "Frame width in pixels", 1280, 16, 4096),
+            super::int_option("height", "Frame height in pixels", 720, 16, 4096),
+            super::int_option("framerate", "Frames per second", 60, 1, 240),
+            super::enum_option("pattern", "What to draw", &["bars", "checkerboard"]),
+            super::input_log_option(),
+        ]
+    }
+
+    fn draw_pattern(&self, frame: &mut [u8], width: usize, height: usize) {
+        let offset = self.frame_count as usize * SCROLL_SPEED;
+        match self.pattern {
+            Pattern::Checkerboard => {
+                let square = (height / 8).max(1);
+                for (i, pixel) in frame.chunks_exact_mut(4).enumerate() {
+                    let (x, y) = ((i % width + offset) / square, (i / width + offset) / square);
+                    let shade = if (x + y) % 2 == 0 { 200 } else { 55 };
+                    pixel.copy_from_slice(&[shade, shade, shade, 255]);
+                }
+            }
+            Pattern::Bars => {
+                let bar_width = (width / BARS.len()).max(1);
+                for row in frame.chunks_exact_mut(width * 4) {
+                    for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                        pixel.copy_from_slice(&BARS[(x + offset) / bar_width % BARS.len()]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl CaptureSource for TestPattern {
+    fn options(&self) -> HashMap<String, String> {
+        super::default_options(&Self::schema())
+    }
+
+    fn option_schema(&self) -> Option<Vec<EdcsCalOption>> {
+        Some(Self::schema())
+    }
+
+    fn input_capabilities(&self) -> EdcsInputCapabilities {
+        super::input_capabilities()
+    }
+
+    fn init(&mut self, options: HashMap<String, String>) -> Result<FrameConfig, EdssError> {
+        let config = FrameConfig {
+            width: super::parse_option(&options, "width")?,
+            height: super::parse_option(&options, "height")?,
+            framerate: super::parse_option(&options, "framerate")?,
+            pixel_format: PixelFormat::Bgra,
+        };
+        self.pattern = match options.get("pattern").map(String::as_str) {
+            Some("checkerboard") => Pattern::Checkerboard,
+            _ => Pattern::Bars,
+        };
+        self.recorder = InputRecorder::new(&options)?;
+        self.frame_count = 0;
+        self.mouse = None;
+        self.config = Some(config);
+        Ok(config)
+    }
+
+    fn read_frame(&mut self, frame: &mut [u8]) -> Result<(), EdssError> {
+        let config = self.config.ok_or_else(EdssError::cal_library_failure)?;
+        let (width, height) = (config.width as usize, config.height as usize);
+        self.draw_pattern(frame, width, height);
+
+        // A box moving along the bottom, so dropped or repeated frames stand out
+        let box_size = (height / 10).max(1);
+        let box_x = (self.frame_count as usize * SCROLL_SPEED) % width;
+        fill_rect(
+            frame,
+            width,
+            height,
+            box_x,
+            height - box_size,
+            box_size,
+            box_size,
+            [255, 255, 255, 255],
+        );
+
+        if let Some((x, y)) = self.mouse {
+            fill_rect(
+                frame,
+                width,
+                height,
+                (x as usize).saturating_sub(4),
+                (y as usize).saturating_sub(4),
+                9,
+                9,
+                [0, 0, 255, 255],
+            );
+        }
+
+        let scale = (height / 120).max(1);
+        draw_text(
+            frame,
+            width,
+            height,
+            scale * 2,
+            scale * 2,
+            scale,
+            &format!("{:08}", self.frame_count),
+        );
+        draw_text(
+            frame,
+            width,
+            height,
+            scale * 2,
+            scale * 10,
+            scale,
+            &chrono::Local::now().format("%H:%M:%S%.3f").to_string(),
+        );
+
+        self.frame_count += 1;
+        Ok(())
+    }
+
+    fn write_mouse_event(&mut self, ev: MouseInput) -> Result<(), EdssError> {
+        match ev {
+            MouseInput::Move { x, y } => self.mouse = Some((x, y)),
+            MouseInput::RelMove { dx, dy } => {
+                if let Some((x, y)) = &mut self.mouse {
+                    *x += dx as f64;
+                    *y += dy as f64;
+                }
+            }
+            _ => {}
+        }
+        self.recorder.record(ev)
+    }
+
+    fn write_keyboard_event(&mut self, ev: KeyInput) -> Result<(), EdssError> {
+        self.recorder.record(ev)
+    }
+
+    fn write_touch_event(&mut self, ev: TouchInput) -> Result<(), EdssError> {
+        self.recorder.record(ev)
+    }
+
+    fn write_pen_event(&mut self, ev: PenInput) -> Result<(), EdssError> {
+        self.recorder.record(ev)
+    }
+
+    fn shutdown(&mut self) -> Result<(), EdssError> {
+        self.config = None;
+        self.recorder = InputRecorder::default();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::edss_safe::edss_unsafe::edssTouchPhase_t_TOUCH_START;
+    use crate::test_sources::input_log::{
+        clear_recorded_input, lock_recorded_input, recorded_input, RecordedInput,
+    };
+    use crate::test_sources::TempFile;
+
+    fn init(source: &mut TestPattern, changes: &[(&str, String)]) -> FrameConfig {
+        let mut options = source.options();
+        for (name, value) in changes {
+            options.insert(name.to_string(), value.clone());
+        }
+        source.init(options).unwrap()
+    }
+
+    #[test]
+    fn records_what_it_is_sent() {
+        let _lock = lock_recorded_input();
+        clear_recorded_input();
+        let log = TempFile::new("input.log");
+        let mut source = TestPattern::default();
+        init(&mut source, &[("inputLog", log.path())]);
+
+        source
+            .write_mouse_event(MouseInput::Move { x: 10.0, y: 20.0 })
+            .unwrap();
+        source
+            .write_mouse_event(MouseInput::Wheel {
+                vertical: -2,
+                horizontal: 1,
+            })
+            .unwrap();
+        source
+            .write_mouse_event(MouseInput::RelMove { dx: 3, dy: -4 })
+            .unwrap();
+        source
+            .write_keyboard_event(KeyInput {
+                button: 30,
+                pressed: true,
+            })
+            .unwrap();
+        source
+            .write_touch_event(TouchInput {
+                contactId: 2,
+                phase: edssTouchPhase_t_TOUCH_START,
+                x: 3.0,
+                y: 4.0,
+                pressure: 0.5,
+            })
+            .unwrap();
+        source
+            .write_pen_event(PenInput {
+                x: 5.0,
+                y: 6.0,
+                pressure: 0.25,
+                tiltX: 0.0,
+                tiltY: 0.0,
+                inRange: true,
+                touching: true,
+                eraser: false,
+                barrelButton: false,
+            })
+            .unwrap();
+        source.shutdown().unwrap();
+
+        assert_eq!(
+            recorded_input(),
+            vec![
+                RecordedInput::MouseMove { x: 10.0, y: 20.0 },
+                RecordedInput::MouseWheel {
+                    vertical: -2,
+                    horizontal: 1
+                },
+                RecordedInput::MouseRelMove { dx: 3, dy: -4 },
+                RecordedInput::Key {
+                    key: 30,
+                    pressed: true
+                },
+                RecordedInput::Touch {
+                    contact_id: 2,
+                    phase: edssTouchPhase_t_TOUCH_START,
+                    x: 3.0,
+                    y: 4.0,
+                    pressure: 0.5
+                },
+                RecordedInput::Pen {
+                    x: 5.0,
+                    y: 6.0,
+                    pressure: 0.25,
+                    touching: true,
+                    eraser: false
+                },
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(&log.0).unwrap(),
+            "mouse_move 10 20\nmouse_wheel -2 1\nmouse_rel_move 3 -4\nkey 30 1\ntouch 2 0 3 4 0.5\npen 5 6 0.25 1 0\n"
+        );
+    }
+
+    #[test]
+    fn marks_the_mouse() {
+        let _lock = lock_recorded_input();
+        let mut source = TestPattern::default();
+        let config = init(
+            &mut source,
+            &[("width", "64".to_string()), ("height", "48".to_string())],
+        );
+        source
+            .write_mouse_event(MouseInput::Move { x: 32.0, y: 24.0 })
+            .unwrap();
+
+        let mut frame = vec![0; config.frame_len()];
+        source.read_frame(&mut frame).unwrap();
+        let pixel = (24 * 64 + 32) * 4;
+        assert_eq!(frame[pixel..pixel + 4], [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn needs_init_before_frames() {
+        let mut source = TestPattern::default();
+        assert!(source.read_frame(&mut [0; 16]).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//! RFB key events carry X keysyms, while EDSS passes on Linux key codes. The
+//! keysym is always the unshifted one, the server applies modifiers itself.
+
+use input_event_codes::*;
+
+/// The X keysym for a Linux key code, if the key has one.
+pub fn from_linux_key(code: i32) -> Option<u32> {
+    let code = code as u32;
+    Some(match code {
+        KEY_ESC!() => 0xff1b,
+        KEY_1!() => b'1' as u32,
+        KEY_2!() => b'2' as u32,
+        KEY_3!() => b'3' as u32,
+        KEY_4!() => b'4' as u32,
+        KEY_5!() => b'5' as u32,
+        KEY_6!() => b'6' as u32,
+        KEY_7!() => b'7' as u32,
+        KEY_8!() => b'8' as u32,
+        KEY_9!() => b'9' as u32,
+        KEY_0!() => b'0' as u32,
+        KEY_MINUS!() => b'-' as u32,
+        KEY_EQUAL!() => b'=' as u32,
+        KEY_BACKSPACE!() => 0xff08,
+        KEY_TAB!() => 0xff09,
+        KEY_Q!() => b'q' as u32,
+        KEY_W!() => b'w' as u32,
+        KEY_E!() => b'e' as u32,
+        KEY_R!() => b'r' as u32,
+        KEY_T!() => b't' as u32,
+        KEY_Y!() => b'y' as u32,
+        KEY_U!() => b'u' as u32,
+        KEY_I!() => b'i' as u32,
+        KEY_O!() => b'o' as u32,
+        KEY_P!() => b'p' as u32,
+        KEY_LEFTBRACE!() => b'[' as u32,
+        KEY_RIGHTBRACE!() => b']' as u32,
+        KEY_ENTER!() => 0xff0d,
+        KEY_LEFTCTRL!() => 0xffe3,
+        KEY_A!() => b'a' as u32,
+        KEY_S!() => b's' as u32,
+        KEY_D!() => b'd' as u32,
+        KEY_F!() => b'f' as u32,
+        KEY_G!() => b'g' as u32,
+        KEY_H!() => b'h' as u32,
+        KEY_J!() => b'j' as u32,
+        KEY_K!() => b'k' as u32,
+        KEY_L!() => b'l' as u32,
+        KEY_SEMICOLON!() => b';' as u32,
+        KEY_APOSTROPHE!() => b'\'' as u32,
+        KEY_GRAVE!() => b'`' as u32,
+        KEY_LEFTSHIFT!() => 0xffe1,
+        KEY_BACKSLASH!() => b'\\' as u32,
+        KEY_Z!() => b'z' as u32,
+        KEY_X!() => b'x' as u32,
+        KEY_C!() => b'c' as u32,
+        KEY_V!() => b'v' as u32,
+        KEY_B!() => b'b' as u32,
+        KEY_N!() => b'n' as u32,
+        KEY_M!() => b'm' as u32,
+        KEY_COMMA!() => b',' as u32,
+        KEY_DOT!() => b'.' as u32,
+        KEY_SLASH!() => b'/' as u32,
+        KEY_RIGHTSHIFT!() => 0xffe2,
+        KEY_KPASTERISK!() => 0xffaa,
+        KEY_LEFTALT!() => 0xffe9,
+        KEY_SPACE!() => b' ' as u32,
+        KEY_CAPSLOCK!() => 0xffe5,
+        KEY_F1!() => 0xffbe,
+        KEY_F2!() => 0xffbf,
+        KEY_F3!() => 0xffc0,
+        KEY_F4!() => 0xffc1,
+        KEY_F5!() => 0xffc2,
+        KEY_F6!() => 0xffc3,
+        KEY_F7!() => 0xffc4,
+        KEY_F8!() => 0xffc5,
+        KEY_F9!() => 0xffc6,
+        KEY_F10!() => 0xffc7,
+        KEY_NUMLOCK!() => 0xff7f,
+        KEY_SCROLLLOCK!() => 0xff14,
+        KEY_KP7!() => 0xffb7,
+        KEY_KP8!() => 0xffb8,
+        KEY_KP9!() => 0xffb9,
+        KEY_KPMINUS!() => 0xffad,
+        KEY_KP4!() => 0xffb4,
+        KEY_KP5!() => 0xffb5,
+        KEY_KP6!() => 0xffb6,
+        KEY_KPPLUS!() => 0xffab,
+        KEY_KP1!() => 0xffb1,
+        KEY_KP2!() => 0xffb2,
+        KEY_KP3!() => 0xffb3,
+        KEY_KP0!() => 0xffb0,
+        KEY_KPDOT!() => 0xffae,
+        KEY_102ND!() => b'<' as u32,
+        KEY_F11!() => 0xffc8,
+        KEY_F12!() => 0xffc9,
+        KEY_KPENTER!() => 0xff8d,
+        KEY_RIGHTCTRL!() => 0xffe4,
+        KEY_KPSLASH!() => 0xffaf,
+        KEY_SYSRQ!() => 0xff61,
+        KEY_RIGHTALT!() => 0xffea,
+        KEY_HOME!() => 0xff50,
+        KEY_UP!() => 0xff52,
+        KEY_PAGEUP!() => 0xff55,
+        KEY_LEFT!() => 0xff51,
+        KEY_RIGHT!() => 0xff53,
+        KEY_END!() => 0xff57,
+        KEY_DOWN!() => 0xff54,
+        KEY_PAGEDOWN!() => 0xff56,
+        KEY_INSERT!() => 0xff63,
+        KEY_DELETE!() => 0xffff,
+        KEY_KPEQUAL!() => 0xffbd,
+        KEY_PAUSE!() => 0xff13,
+        KEY_LEFTMETA!() => 0xffeb,
+        KEY_RIGHTMETA!() => 0xffec,
+        KEY_COMPOSE!() => 0xff67,
+        _ => return None,
+    })
+}
@@ -0,0 +1,357 @@
+//! A capture source that is a VNC client, so anything with an RFB server can
+//! be streamed, eg. a QEMU, libvirt or VirtualBox VM without a vGPU. Frames
+//! come from framebuffer updates, input is sent back as pointer and key
+//! events.
+
+mod keysym;
+mod rfb;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use log::{error, info, warn};
+use parking_lot::Mutex;
+
+use crate::capture_source::{
+    self, CaptureSource, EdssError, FrameConfig, KeyInput, MouseInput, PixelFormat,
+};
+use crate::edcs_server::edcs_proto::{EdcsCalOption, EdcsCalOptionType};
+use rfb::{Rect, RfbConnection, RfbWriter, Update, BYTES_PER_PIXEL};
+
+pub const VNC_SOURCE: &str = "vnc";
+
+/// Make the source available as a `plugin_name`.
+pub fn register() {
+    capture_source::register(VNC_SOURCE, || Box::new(VncSource::default()));
+}
+
+/// The latest state of the remote framebuffer. It keeps the size it had on
+/// connect, as the stream can't change resolution.
+struct Framebuffer {
+    width: usize,
+    height: usize,
+    data: Vec<u8>,
+}
+
+impl Framebuffer {
+    fn clip(&self, rect: &Rect) -> (usize, usize) {
+        (
+            (rect.width as usize).min(self.width.saturating_sub(rect.x as usize)),
+            (rect.height as usize).min(self.height.saturating_sub(rect.y as usize)),
+        )
+    }
+
+    fn apply_raw(&mut self, rect: &Rect, pixels: &[u8]) {
+        let (width, height) = self.clip(rect);
+        let src_stride = rect.width as usize * BYTES_PER_PIXEL;
+        for row in 0..height {
+            let dst = ((rect.y as usize + row) * self.width + rect.x as usize) * BYTES_PER_PIXEL;
+            let src = row * src_stride;
+            self.data[dst..dst + width * BYTES_PER_PIXEL]
+                .copy_from_slice(&pixels[src..src + width * BYTES_PER_PIXEL]);
+        }
+    }
+
+    fn apply_copy_rect(&mut self, dst: &Rect, src_x: u16, src_y: u16) {
+        let (width, height) = self.clip(dst);
+        let src_rect = Rect {
+            x: src_x,
+            y: src_y,
+            ..*dst
+        };
+        let (src_width, src_height) = self.clip(&src_rect);
+        let (width, height) = (width.min(src_width), height.min(src_height));
+        let row_len = width * BYTES_PER_PIXEL;
+        // Rows can overlap when moving down, so go bottom up then
+        let rows: Box<dyn Iterator<Item = usize>> = if dst.y > src_y {
+            Box::new((0..height).rev())
+        } else {
+            Box::new(0..height)
+        };
+        for row in rows {
+            let src = ((src_y as usize + row) * self.width + src_x as usize) * BYTES_PER_PIXEL;
+            let dst = ((dst.y as usize + row) * self.width + dst.x as usize) * BYTES_PER_PIXEL;
+            self.data.copy_within(src..src + row_len, dst);
+        }
+    }
+}
+
+struct Connection {
+    writer: Arc<Mutex<RfbWriter>>,
+    framebuffer: Arc<Mutex<Framebuffer>>,
+    failed: Arc<AtomicBool>,
+    reader: JoinHandle<()>,
+    width: u16,
+    height: u16,
+    button_mask: u8,
+    pointer: (u16, u16),
+}
+
+#[derive(Default)]
+pub struct VncSource {
+    connection: Option<Connection>,
+}
+
+impl VncSource {
+    fn schema() -> Vec<EdcsCalOption> {
+        vec![
+            EdcsCalOption {
+                name: "host".to_owned(),
+                description: "Host name or address of the VNC server".to_owned(),
+                option_type: EdcsCalOptionType::CalOptionString as i32,
+                default_value: "localhost".to_owned(),
+                required: true,
+                ..Default::default()
+            },
+            EdcsCalOption {
+                name: "port".to_owned(),
+                description: "TCP port of the VNC server, display :N is 5900 + N".to_owned(),
+                option_type: EdcsCalOptionType::CalOptionInt as i32,
+                default_value: "5900".to_owned(),
+                has_range: true,
+                min: 1.0,
+                max: 65535.0,
+                required: true,
+                ..Default::default()
+            },
+            EdcsCalOption {
+                name: "password".to_owned(),
+                description: "VNC password, only the first 8 characters are used".to_owned(),
+                option_type: EdcsCalOptionType::CalOptionString as i32,
+                ..Default::default()
+            },
+            EdcsCalOption {
+                name: "framerate".to_owned(),
+                description: "Frames per second to stream".to_owned(),
+                option_type: EdcsCalOptionType::CalOptionInt as i32,
+                default_value: "30".to_owned(),
+                has_range: true,
+                min: 1.0,
+                max: 240.0,
+                required: true,
+                ..Default::default()
+            },
+        ]
+    }
+
+    fn connection(&mut self) -> Result<&mut Connection, EdssError> {
+        let connection = self
+            .connection
+            .as_mut()
+            .ok_or_else(EdssError::cal_library_failure)?;
+        if connection.failed.load(Ordering::Relaxed) {
+            return Err(EdssError::cal_library_failure());
+        }
+        Ok(connection)
+    }
+}
+
+fn read_updates(
+    mut connection: RfbConnection,
+    writer: Arc<Mutex<RfbWriter>>,
+    framebuffer: Arc<Mutex<Framebuffer>>,
+) -> anyhow::Result<()> {
+    let (width, height) = (connection.width, connection.height);
+    loop {
+        let updates = connection.read_update()?;
+        {
+            let mut framebuffer = framebuffer.lock();
+            for update in &updates {
+                match update {
+                    Update::Raw(rect, pixels) => framebuffer.apply_raw(rect, pixels),
+                    Update::CopyRect { dst, src_x, src_y } => {
+                        framebuffer.apply_copy_rect(dst, *src_x, *src_y)
+                    }
+                    Update::DesktopSize { width, height } => warn!(
+                        "VNC server resized to {}x{}, the stream stays {}x{}",
+                        width, height, framebuffer.width, framebuffer.height
+                    ),
+                }
+            }
+        }
+        writer.lock().request_update(true, width, height)?;
+    }
+}
+
+impl CaptureSource for VncSource {
+    fn options(&self) -> HashMap<String, String> {
+        Self::schema()
+            .into_iter()
+            .map(|o| (o.name, o.default_value))
+            .collect()
+    }
+
+    fn option_schema(&self) -> Option<Vec<EdcsCalOption>> {
+        Some(Self::schema())
+    }
+
+    fn init(&mut self, options: HashMap<String, String>) -> Result<FrameConfig, EdssError> {
+        let host = options.get("host").cloned().unwrap_or_default();
+        let port = options
+            .get("port")
+            .and_then(|p| p.parse::<u16>().ok())
+            .ok_or_else(EdssError::invalid_cal_options)?;
+        let framerate = options
+            .get("framerate")
+            .and_then(|f| f.parse::<u16>().ok())
+            .ok_or_else(EdssError::invalid_cal_options)?;
+        let password = options.get("password").cloned().unwrap_or_default();
+
+        let connection = RfbConnection::connect(&host, port, &password).map_err(|e| {
+            error!("Failed to connect to VNC server {}:{}: {:#}", host, port, e);
+            EdssError::cal_library_failure()
+        })?;
+        info!(
+            "Connected to VNC server \"{}\", {}x{}",
+            connection.name, connection.width, connection.height
+        );
+        let (width, height) = (connection.width, connection.height);
+        let writer = connection.try_clone().map_err(|e| {
+            error!("Failed to clone the VNC connection: {}", e);
+            EdssError::cal_library_failure()
+        })?;
+        let writer = Arc::new(Mutex::new(writer));
+        let framebuffer = Arc::new(Mutex::new(Framebuffer {
+            width: width as usize,
+            height: height as usize,
+            data: vec![0; width as usize * height as usize * BYTES_PER_PIXEL],
+        }));
+        let failed = Arc::new(AtomicBool::new(false));
+
+        writer
+            .lock()
+            .request_update(false, width, height)
+            .map_err(|e| {
+                error!("Failed to request a VNC framebuffer update: {}", e);
+                EdssError::cal_library_failure()
+            })?;
+
+        let reader = {
+            let writer = writer.clone();
+            let framebuffer = framebuffer.clone();
+            let failed = failed.clone();
+            thread::spawn(move || {
+                if let Err(e) = read_updates(connection, writer, framebuffer) {
+                    // Shutting down closes the socket, which ends up here too
+                    if !failed.swap(true, Ordering::Relaxed) {
+                        error!("VNC connection lost: {:#}", e);
+                    }
+                }
+            })
+        };
+
+        self.connection = Some(Connection {
+            writer,
+            framebuffer,
+            failed,
+            reader,
+            width,
+            height,
+            button_mask: 0,
+            pointer: (0, 0),
+        });
+        Ok(FrameConfig {
+            width,
+            height,
+            framerate,
+            pixel_format: PixelFormat::Bgra,
+        })
+    }
+
+    fn read_frame(&mut self, frame: &mut [u8]) -> Result<(), EdssError> {
+        let connection = self.connection()?;
+        frame.copy_from_slice(&connection.framebuffer.lock().data);
+        Ok(())
+    }
+
+    fn write_mouse_event(&mut self, ev: MouseInput) -> Result<(), EdssError> {
+        let connection = self.connection()?;
+        let clamp_x = |x: f64| x.clamp(0.0, connection.width.saturating_sub(1) as f64) as u16;
+        let clamp_y = |y: f64| y.clamp(0.0, connection.height.saturating_sub(1) as f64) as u16;
+        // Each wheel click is a press and release of its own
+        let mut clicks = Vec::new();
+        match ev {
+            MouseInput::Move { x, y } => {
+                connection.pointer = (clamp_x(x), clamp_y(y));
+            }
+            MouseInput::RelMove { dx, dy } => {
+                let (x, y) = connection.pointer;
+                connection.pointer = (clamp_x(x as f64 + dx as f64), clamp_y(y as f64 + dy as f64));
+            }
+            MouseInput::Button { button, pressed } => {
+                let bit = match button as u32 {
+                    input_event_codes::BTN_LEFT!() => 1 << 0,
+                    input_event_codes::BTN_MIDDLE!() => 1 << 1,
+                    input_event_codes::BTN_RIGHT!() => 1 << 2,
+                    _ => return Err(EdssError::unsupported()),
+                };
+                if pressed {
+                    connection.button_mask |= bit;
+                } else {
+                    connection.button_mask &= !bit;
+                }
+            }
+            // Buttons 4 to 7 in RFB are up, down, left and right
+            MouseInput::Wheel {
+                vertical,
+                horizontal,
+            } => {
+                for (count, positive, negative) in
+                    [(vertical, 1 << 3, 1 << 4), (horizontal, 1 << 6, 1 << 5)]
+                {
+                    let bit = if count > 0 { positive } else { negative };
+                    clicks.extend((0..count.unsigned_abs()).map(|_| bit));
+                }
+            }
+        }
+        let (x, y) = connection.pointer;
+        let button_mask = connection.button_mask;
+        let mut writer = connection.writer.lock();
+        let mut send = |button_mask| {
+            writer.pointer_event(button_mask, x, y).map_err(|e| {
+                error!("Failed to send VNC pointer event: {}", e);
+                EdssError::cal_library_failure()
+            })
+        };
+        if clicks.is_empty() {
+            return send(button_mask);
+        }
+        for bit in clicks {
+            send(button_mask | bit)?;
+            send(button_mask)?;
+        }
+        Ok(())
+    }
+
+    fn write_keyboard_event(&mut self, ev: KeyInput) -> Result<(), EdssError> {
+        let connection = self.connection()?;
+        let keysym = match keysym::from_linux_key(ev.button) {
+            Some(keysym) => keysym,
+            None => {
+                warn!("No keysym for key code {}, dropping it", ev.button);
+                return Ok(());
+            }
+        };
+        connection
+            .writer
+            .lock()
+            .key_event(keysym, ev.pressed)
+            .map_err(|e| {
+                error!("Failed to send VNC key event: {}", e);
+                EdssError::cal_library_failure()
+            })
+    }
+
+    fn shutdown(&mut self) -> Result<(), EdssError> {
+        if let Some(connection) = self.connection.take() {
+            connection.failed.store(true, Ordering::Relaxed);
+            connection.writer.lock().shutdown();
+            if connection.reader.join().is_err() {
+                error!("VNC reader thread panicked");
+            }
+        }
+        Ok(())
+    }
+}
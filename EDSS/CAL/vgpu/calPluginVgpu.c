@@ -6,6 +6,7 @@
 #include <linux/uinput.h>
 #include <stdint.h>
 #include <stdio.h>
+#include <string.h>
 #include <sys/mman.h>
 #include <unistd.h>
 
@@ -14,25 +15,139 @@
 // get the width/height, so we will use these in the future.
 #define IMGBUF_WIDTH 1920
 #define IMGBUF_HEIGHT 1080
+#define IMGBUF_SIZE (IMGBUF_WIDTH * IMGBUF_HEIGHT * 4)
 #define VGPU_MMAP_CONSOLE_OFFSET 0x10000000000
 
+#define VGPU_MAX_TOUCH_CONTACTS 10
+#define VGPU_PEN_PRESSURE_MAX 4096
+#define VGPU_TOUCH_PRESSURE_MAX 255
+
 struct vgpuCALRTCfg {
     int vgpuFd;
     int inputFd;
+    int touchFd;
+    int penFd;
     calConfig_t *calCfg;
+    // Contact ID held by each multitouch slot, -1 if the slot is free
+    int64_t touchSlots[VGPU_MAX_TOUCH_CONTACTS];
+    int touchTrackingId;
+    // BTN_TOOL_PEN or BTN_TOOL_RUBBER while the pen is in range, 0 otherwise
+    int penTool;
 };
 
 static struct vgpuCALRTCfg rtCfg;
 
+// vgpuIdValueCh in calInit only fits 3 digits
+static const edssCALOption_t vgpuOptionSchema[] = {
+    {
+        .name = "vgpuId",
+        .description = "Number of the vGPU to capture, as in /dev/nvidia-vgpuN",
+        .type = CAL_OPTION_INT,
+        .defaultValue = "0",
+        .hasRange = true,
+        .min = 0,
+        .max = 999,
+        .required = true,
+    },
+};
+
 EDSS_STATUS calOptions(StrMap **calOptionDict) {
     *calOptionDict = sm_new(1);
-    if (!sm_put(*calOptionDict, "vgpuId", "")) {
+    if (!sm_put(*calOptionDict, "vgpuId", vgpuOptionSchema[0].defaultValue)) {
         return EDSS_STRMAP_FAILURE;
     }
 
     return EDSS_OK;
 }
 
+EDSS_STATUS calOptionSchema(const edssCALOption_t **options, uint32_t *count) {
+    *options = vgpuOptionSchema;
+    *count = sizeof(vgpuOptionSchema) / sizeof(vgpuOptionSchema[0]);
+    return EDSS_OK;
+}
+
+EDSS_STATUS calInputCapabilities(edssInputCapabilities_t *caps) {
+    caps->touch = true;
+    caps->pen = true;
+    caps->maxTouchContacts = VGPU_MAX_TOUCH_CONTACTS;
+
+    return EDSS_OK;
+}
+
+static void setupAbs(int fd, int code, int min, int max) {
+    struct uinput_abs_setup abssetup = {
+        .code = code, .absinfo.minimum = min, .absinfo.maximum = max};
+    ioctl(fd, UI_SET_ABSBIT, code);
+    ioctl(fd, UI_ABS_SETUP, &abssetup);
+}
+
+static void createDevice(int fd, const char *name, int product) {
+    struct uinput_setup usetup = {.id.bustype = BUS_USB,
+                                  .id.vendor = 0x1234,
+                                  .id.product = product};
+    strncpy(usetup.name, name, UINPUT_MAX_NAME_SIZE - 1);
+
+    ioctl(fd, UI_DEV_SETUP, &usetup);
+    ioctl(fd, UI_DEV_CREATE);
+}
+
+// Multitouch devices use the type B (slot) protocol, see
+// https://www.kernel.org/doc/html/latest/input/multi-touch-protocol.html
+static EDSS_STATUS setupTouch() {
+    rtCfg.touchFd = open("/dev/uinput", O_WRONLY);
+    if (rtCfg.touchFd < 0) {
+        EDSS_LOGE("open /dev/uinput for touch: %s\n", strerror(errno));
+        return EDSS_CAL_LIBRARY_FAILURE;
+    }
+
+    ioctl(rtCfg.touchFd, UI_SET_PROPBIT, INPUT_PROP_DIRECT);
+    ioctl(rtCfg.touchFd, UI_SET_EVBIT, EV_KEY);
+    ioctl(rtCfg.touchFd, UI_SET_KEYBIT, BTN_TOUCH);
+    ioctl(rtCfg.touchFd, UI_SET_EVBIT, EV_ABS);
+    setupAbs(rtCfg.touchFd, ABS_X, 0, IMGBUF_WIDTH);
+    setupAbs(rtCfg.touchFd, ABS_Y, 0, IMGBUF_HEIGHT);
+    setupAbs(rtCfg.touchFd, ABS_MT_SLOT, 0, VGPU_MAX_TOUCH_CONTACTS - 1);
+    setupAbs(rtCfg.touchFd, ABS_MT_TRACKING_ID, 0, 65535);
+    setupAbs(rtCfg.touchFd, ABS_MT_POSITION_X, 0, IMGBUF_WIDTH);
+    setupAbs(rtCfg.touchFd, ABS_MT_POSITION_Y, 0, IMGBUF_HEIGHT);
+    setupAbs(rtCfg.touchFd, ABS_MT_PRESSURE, 0, VGPU_TOUCH_PRESSURE_MAX);
+
+    createDevice(rtCfg.touchFd, "EDSSVTouch", 0x5679);
+
+    for (int i = 0; i < VGPU_MAX_TOUCH_CONTACTS; ++i) {
+        rtCfg.touchSlots[i] = -1;
+    }
+    rtCfg.touchTrackingId = 0;
+
+    return EDSS_OK;
+}
+
+static EDSS_STATUS setupPen() {
+    rtCfg.penFd = open("/dev/uinput", O_WRONLY);
+    if (rtCfg.penFd < 0) {
+        EDSS_LOGE("open /dev/uinput for pen: %s\n", strerror(errno));
+        return EDSS_CAL_LIBRARY_FAILURE;
+    }
+
+    ioctl(rtCfg.penFd, UI_SET_PROPBIT, INPUT_PROP_DIRECT);
+    ioctl(rtCfg.penFd, UI_SET_EVBIT, EV_KEY);
+    ioctl(rtCfg.penFd, UI_SET_KEYBIT, BTN_TOOL_PEN);
+    ioctl(rtCfg.penFd, UI_SET_KEYBIT, BTN_TOOL_RUBBER);
+    ioctl(rtCfg.penFd, UI_SET_KEYBIT, BTN_TOUCH);
+    ioctl(rtCfg.penFd, UI_SET_KEYBIT, BTN_STYLUS);
+    ioctl(rtCfg.penFd, UI_SET_EVBIT, EV_ABS);
+    setupAbs(rtCfg.penFd, ABS_X, 0, IMGBUF_WIDTH);
+    setupAbs(rtCfg.penFd, ABS_Y, 0, IMGBUF_HEIGHT);
+    setupAbs(rtCfg.penFd, ABS_PRESSURE, 0, VGPU_PEN_PRESSURE_MAX);
+    setupAbs(rtCfg.penFd, ABS_TILT_X, -90, 90);
+    setupAbs(rtCfg.penFd, ABS_TILT_Y, -90, 90);
+
+    createDevice(rtCfg.penFd, "EDSSVPen", 0x567a);
+    rtCfg.penTool = 0;
+
+    return EDSS_OK;
+}
+
 EDSS_STATUS calInit(StrMap *calOptionDict, calConfig_t *calCfg) {
 
     char gid_path[32]; // QEMU uses this as the max size for a vfio device, so..
@@ -57,12 +172,15 @@ EDSS_STATUS calInit(StrMap *calOptionDict, calConfig_t *calCfg) {
 
     rtCfg.vgpuFd = vgpuFd;
     rtCfg.calCfg = calCfg;
-    rtCfg.calCfg->frame =
-        (uint8_t *)mmap(0, IMGBUF_WIDTH * IMGBUF_HEIGHT * 4, PROT_READ,
-                        MAP_PRIVATE, rtCfg.vgpuFd, VGPU_MMAP_CONSOLE_OFFSET);
-    if (rtCfg.calCfg->frame < 0) {
+    void *frame = mmap(0, IMGBUF_SIZE, PROT_READ, MAP_PRIVATE, rtCfg.vgpuFd,
+                       VGPU_MMAP_CONSOLE_OFFSET);
+    if (frame == MAP_FAILED) {
+        EDSS_LOGE("mmap /dev/nvidia-vgpu%d: %s\n", vgpuIdValue,
+                  strerror(errno));
+        close(vgpuFd);
         return EDSS_CAL_LIBRARY_FAILURE;
     }
+    rtCfg.calCfg->frame = frame;
 
     rtCfg.calCfg->width = IMGBUF_WIDTH;
     rtCfg.calCfg->height = IMGBUF_HEIGHT;
@@ -73,7 +191,13 @@ EDSS_STATUS calInit(StrMap *calOptionDict, calConfig_t *calCfg) {
     rtCfg.calCfg->framerate = 60;
 
     // Setup uinput
+    int ret;
     rtCfg.inputFd = open("/dev/uinput", O_WRONLY);
+    if (rtCfg.inputFd < 0) {
+        EDSS_LOGE("open /dev/uinput: %s\n", strerror(errno));
+        ret = EDSS_CAL_LIBRARY_FAILURE;
+        goto unmapFrame;
+    }
     ioctl(rtCfg.inputFd, UI_SET_EVBIT, EV_KEY);
     ioctl(rtCfg.inputFd, UI_SET_KEYBIT, BTN_LEFT);
     ioctl(rtCfg.inputFd, UI_SET_KEYBIT, BTN_RIGHT);
@@ -86,6 +210,11 @@ EDSS_STATUS calInit(StrMap *calOptionDict, calConfig_t *calCfg) {
     ioctl(rtCfg.inputFd, UI_SET_EVBIT, EV_ABS);
     ioctl(rtCfg.inputFd, UI_SET_ABSBIT, ABS_X);
     ioctl(rtCfg.inputFd, UI_SET_ABSBIT, ABS_Y);
+    // Absolute pointers with a wheel are common, relative motion would need
+    // a device of its own
+    ioctl(rtCfg.inputFd, UI_SET_EVBIT, EV_REL);
+    ioctl(rtCfg.inputFd, UI_SET_RELBIT, REL_WHEEL);
+    ioctl(rtCfg.inputFd, UI_SET_RELBIT, REL_HWHEEL);
 
     struct uinput_abs_setup abssetup = {.code = ABS_X, .absinfo.maximum = 1920};
     ioctl(rtCfg.inputFd, UI_ABS_SETUP, &abssetup);
@@ -103,7 +232,27 @@ EDSS_STATUS calInit(StrMap *calOptionDict, calConfig_t *calCfg) {
     ioctl(rtCfg.inputFd, UI_DEV_SETUP, &usetup);
     ioctl(rtCfg.inputFd, UI_DEV_CREATE);
 
+    if ((ret = setupTouch()) != EDSS_OK) {
+        goto destroyInput;
+    }
+    if ((ret = setupPen()) != EDSS_OK) {
+        goto destroyTouch;
+    }
+
     return EDSS_OK;
+
+    // calShutdown isn't called when calInit fails, so undo what was set up
+destroyTouch:
+    ioctl(rtCfg.touchFd, UI_DEV_DESTROY);
+    close(rtCfg.touchFd);
+destroyInput:
+    ioctl(rtCfg.inputFd, UI_DEV_DESTROY);
+    close(rtCfg.inputFd);
+unmapFrame:
+    munmap(frame, IMGBUF_SIZE);
+    close(vgpuFd);
+    rtCfg.calCfg->frame = NULL;
+    return ret;
 }
 
 EDSS_STATUS calReadFrame() {
@@ -117,6 +266,8 @@ EDSS_STATUS calShutdown() {
     int ret;
 
     ioctl(rtCfg.inputFd, UI_DEV_DESTROY);
+    ioctl(rtCfg.touchFd, UI_DEV_DESTROY);
+    ioctl(rtCfg.penFd, UI_DEV_DESTROY);
 
     ret = close(rtCfg.vgpuFd);
     if (ret < 0) {
@@ -126,6 +277,14 @@ EDSS_STATUS calShutdown() {
     if (ret < 0) {
         return EDSS_CAL_LIBRARY_FAILURE;
     }
+    ret = close(rtCfg.touchFd);
+    if (ret < 0) {
+        return EDSS_CAL_LIBRARY_FAILURE;
+    }
+    ret = close(rtCfg.penFd);
+    if (ret < 0) {
+        return EDSS_CAL_LIBRARY_FAILURE;
+    }
 
     rtCfg.calCfg->frame = NULL;
     rtCfg.calCfg->height = 0;
@@ -136,7 +295,7 @@ EDSS_STATUS calShutdown() {
 }
 
 // https://01.org/linuxgraphics/gfx-docs/drm/input/uinput.html#mouse-movements
-void send_ev(int type, int code, int val) {
+void send_ev(int fd, int type, int code, int val) {
     struct input_event e = {
         .type = type,
         .code = code,
@@ -145,30 +304,149 @@ void send_ev(int type, int code, int val) {
         .time.tv_usec = 0,
     };
 
-    write(rtCfg.inputFd, &e, sizeof(e));
+    write(fd, &e, sizeof(e));
 }
 
 EDSS_STATUS calWriteMouseEvent(edssMouseEvent_t *ev) {
     EDSS_LOGD("vGPU CAL plugin writing mouse event\n");
     switch (ev->type) {
     case CLICK:
-        send_ev(EV_KEY, ev->payload.button.button, ev->payload.button.pressed);
+        send_ev(rtCfg.inputFd, EV_KEY, ev->payload.button.button,
+                ev->payload.button.pressed);
         break;
     case MOVE:
-        send_ev(EV_ABS, ABS_X, ev->payload.move.x);
-        send_ev(EV_ABS, ABS_Y, ev->payload.move.y);
+        send_ev(rtCfg.inputFd, EV_ABS, ABS_X, ev->payload.move.x);
+        send_ev(rtCfg.inputFd, EV_ABS, ABS_Y, ev->payload.move.y);
+        break;
+    case WHEEL:
+        send_ev(rtCfg.inputFd, EV_REL, REL_WHEEL, ev->payload.wheel.vertical);
+        send_ev(rtCfg.inputFd, EV_REL, REL_HWHEEL,
+                ev->payload.wheel.horizontal);
         break;
+    case REL_MOVE:
+        return EDSS_CAL_UNSUPPORTED;
+    default:
+        return EDSS_INVALID_MOUSE_DATA;
     }
     // Report X/Y together
-    send_ev(EV_SYN, SYN_REPORT, 0);
+    send_ev(rtCfg.inputFd, EV_SYN, SYN_REPORT, 0);
 
     return EDSS_OK;
 }
 
 EDSS_STATUS calWriteKeyboardEvent(edssKeyboardEvent_t *ev) {
     EDSS_LOGD("vGPU CAL plugin writing keyboard event\n");
-    send_ev(EV_KEY, ev->keyData.button, ev->keyData.pressed);
-    send_ev(EV_SYN, SYN_REPORT, 0);
+    send_ev(rtCfg.inputFd, EV_KEY, ev->keyData.button, ev->keyData.pressed);
+    send_ev(rtCfg.inputFd, EV_SYN, SYN_REPORT, 0);
+
+    return EDSS_OK;
+}
+
+static int findTouchSlot(int64_t contactId) {
+    for (int i = 0; i < VGPU_MAX_TOUCH_CONTACTS; ++i) {
+        if (rtCfg.touchSlots[i] == contactId) {
+            return i;
+        }
+    }
+    return -1;
+}
+
+static bool touchActive() {
+    for (int i = 0; i < VGPU_MAX_TOUCH_CONTACTS; ++i) {
+        if (rtCfg.touchSlots[i] != -1) {
+            return true;
+        }
+    }
+    return false;
+}
+
+EDSS_STATUS calWriteTouchEvent(edssTouchEvent_t *ev) {
+    int slot;
+    bool wasActive = touchActive();
+
+    switch (ev->phase) {
+    case TOUCH_START:
+        if (findTouchSlot(ev->contactId) >= 0) {
+            // Already down, treat it as a move
+            return calWriteTouchEvent(&(edssTouchEvent_t){
+                .contactId = ev->contactId,
+                .phase = TOUCH_MOVE,
+                .x = ev->x,
+                .y = ev->y,
+                .pressure = ev->pressure,
+            });
+        }
+        if ((slot = findTouchSlot(-1)) < 0) {
+            EDSS_LOGW("vGPU CAL out of touch slots, dropping contact %u\n",
+                      ev->contactId);
+            return EDSS_INVALID_INPUT_DATA;
+        }
+        rtCfg.touchSlots[slot] = ev->contactId;
+        send_ev(rtCfg.touchFd, EV_ABS, ABS_MT_SLOT, slot);
+        send_ev(rtCfg.touchFd, EV_ABS, ABS_MT_TRACKING_ID,
+                rtCfg.touchTrackingId++ & 0xffff);
+        // fallthrough
+    case TOUCH_MOVE:
+        if ((slot = findTouchSlot(ev->contactId)) < 0) {
+            return EDSS_INVALID_INPUT_DATA;
+        }
+        send_ev(rtCfg.touchFd, EV_ABS, ABS_MT_SLOT, slot);
+        send_ev(rtCfg.touchFd, EV_ABS, ABS_MT_POSITION_X, ev->x);
+        send_ev(rtCfg.touchFd, EV_ABS, ABS_MT_POSITION_Y, ev->y);
+        send_ev(rtCfg.touchFd, EV_ABS, ABS_MT_PRESSURE,
+                ev->pressure * VGPU_TOUCH_PRESSURE_MAX);
+        // Single touch emulation for older software
+        if (slot == 0) {
+            send_ev(rtCfg.touchFd, EV_ABS, ABS_X, ev->x);
+            send_ev(rtCfg.touchFd, EV_ABS, ABS_Y, ev->y);
+        }
+        break;
+    case TOUCH_END:
+    case TOUCH_CANCEL:
+        if ((slot = findTouchSlot(ev->contactId)) < 0) {
+            return EDSS_INVALID_INPUT_DATA;
+        }
+        rtCfg.touchSlots[slot] = -1;
+        send_ev(rtCfg.touchFd, EV_ABS, ABS_MT_SLOT, slot);
+        send_ev(rtCfg.touchFd, EV_ABS, ABS_MT_TRACKING_ID, -1);
+        break;
+    }
+
+    if (wasActive != touchActive()) {
+        send_ev(rtCfg.touchFd, EV_KEY, BTN_TOUCH, touchActive());
+    }
+    send_ev(rtCfg.touchFd, EV_SYN, SYN_REPORT, 0);
+
+    return EDSS_OK;
+}
+
+EDSS_STATUS calWritePenEvent(edssPenEvent_t *ev) {
+    int tool = ev->eraser ? BTN_TOOL_RUBBER : BTN_TOOL_PEN;
+
+    // Switching between the tip and the eraser takes the old tool out of range
+    if (rtCfg.penTool && (!ev->inRange || rtCfg.penTool != tool)) {
+        send_ev(rtCfg.penFd, EV_KEY, BTN_TOUCH, 0);
+        send_ev(rtCfg.penFd, EV_KEY, rtCfg.penTool, 0);
+        send_ev(rtCfg.penFd, EV_SYN, SYN_REPORT, 0);
+        rtCfg.penTool = 0;
+    }
+    if (!ev->inRange) {
+        return EDSS_OK;
+    }
+    if (!rtCfg.penTool) {
+        send_ev(rtCfg.penFd, EV_KEY, tool, 1);
+        rtCfg.penTool = tool;
+    }
+
+    send_ev(rtCfg.penFd, EV_ABS, ABS_X, ev->x);
+    send_ev(rtCfg.penFd, EV_ABS, ABS_Y, ev->y);
+    send_ev(rtCfg.penFd, EV_ABS, ABS_PRESSURE,
+            ev->touching ? ev->pressure * VGPU_PEN_PRESSURE_MAX : 0);
+    send_ev(rtCfg.penFd, EV_ABS, ABS_TILT_X, ev->tiltX);
+    send_ev(rtCfg.penFd, EV_ABS, ABS_TILT_Y, ev->tiltY);
+    send_ev(rtCfg.penFd, EV_KEY, BTN_TOUCH, ev->touching);
+    send_ev(rtCfg.penFd, EV_KEY, BTN_STYLUS, ev->barrelButton);
+    send_ev(rtCfg.penFd, EV_SYN, SYN_REPORT, 0);
 
     return EDSS_OK;
 }
@@ -180,4 +458,8 @@ calPlugin_t calPlugin = {
     .calShutdown = calShutdown,
     .calWriteMouseEvent = calWriteMouseEvent,
     .calWriteKeyboardEvent = calWriteKeyboardEvent,
+    .calWriteTouchEvent = calWriteTouchEvent,
+    .calWritePenEvent = calWritePenEvent,
+    .calInputCapabilities = calInputCapabilities,
+    .calOptionSchema = calOptionSchema,
 };
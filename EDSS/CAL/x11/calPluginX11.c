@@ -0,0 +1,472 @@
+#include "../../inc/edssCALInterface.h"
+#include "../../inc/edssInterface.h"
+#include "../../inc/edssLog.h"
+#include <X11/Xlib.h>
+#include <X11/Xutil.h>
+#include <X11/extensions/XShm.h>
+#include <X11/extensions/XTest.h>
+#include <X11/extensions/Xfixes.h>
+#include <errno.h>
+#include <linux/input-event-codes.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/ipc.h>
+#include <sys/shm.h>
+
+// X servers using the evdev rules (including Xvfb) number their keycodes as
+// the Linux ones plus 8
+#define X11_EVDEV_KEYCODE_OFFSET 8
+#define X11_MAX_DISPLAY_NAME 256
+
+struct x11CALRTCfg {
+    // Capture and input happen on different threads, so they get a connection
+    // each
+    Display *captureDpy;
+    Display *inputDpy;
+    int screen;
+    Window root;
+    XImage *image;
+    XShmSegmentInfo shmInfo;
+    bool useShm;
+    bool shmAttached;
+    bool hasXFixes;
+    bool drawCursor;
+    // Straight alpha RGBA copy of the cursor for calReadCursor
+    uint8_t *cursorImage;
+    size_t cursorImageSize;
+    calConfig_t *calCfg;
+};
+
+static struct x11CALRTCfg rtCfg;
+
+static const edssCALOption_t x11OptionSchema[] = {
+    {
+        .name = "display",
+        .description = "X display to capture, eg. :0",
+        .type = CAL_OPTION_STRING,
+        .defaultValue = ":0",
+        .required = true,
+    },
+    {
+        .name = "screen",
+        .description = "Screen of the display to capture",
+        .type = CAL_OPTION_INT,
+        .defaultValue = "0",
+        .hasRange = true,
+        .min = 0,
+        .max = 255,
+        .required = true,
+    },
+    {
+        .name = "drawCursor",
+        .description = "Draw the cursor into the frames, otherwise only the "
+                       "client shows it",
+        .type = CAL_OPTION_BOOL,
+        .defaultValue = "true",
+        .required = true,
+    },
+};
+
+EDSS_STATUS calOptions(StrMap **calOptionDict) {
+    // Whatever display EDCS runs on is the most likely one to be wanted
+    const char *display = getenv("DISPLAY");
+    if (!display || !*display) {
+        display = x11OptionSchema[0].defaultValue;
+    }
+
+    *calOptionDict = sm_new(3);
+    if (!sm_put(*calOptionDict, "display", display) ||
+        !sm_put(*calOptionDict, "screen", x11OptionSchema[1].defaultValue) ||
+        !sm_put(*calOptionDict, "drawCursor",
+                x11OptionSchema[2].defaultValue)) {
+        return EDSS_STRMAP_FAILURE;
+    }
+
+    return EDSS_OK;
+}
+
+EDSS_STATUS calOptionSchema(const edssCALOption_t **options, uint32_t *count) {
+    *options = x11OptionSchema;
+    *count = sizeof(x11OptionSchema) / sizeof(x11OptionSchema[0]);
+    return EDSS_OK;
+}
+
+static EDSS_STATUS createImage(int width, int height) {
+    Visual *visual = DefaultVisual(rtCfg.captureDpy, rtCfg.screen);
+    int depth = DefaultDepth(rtCfg.captureDpy, rtCfg.screen);
+
+    // MIT-SHM only works when EDSS runs on the same machine as the X server
+    rtCfg.useShm = XShmQueryExtension(rtCfg.captureDpy);
+    if (rtCfg.useShm) {
+        rtCfg.image =
+            XShmCreateImage(rtCfg.captureDpy, visual, depth, ZPixmap, NULL,
+                            &rtCfg.shmInfo, width, height);
+        if (!rtCfg.image) {
+            return EDSS_CAL_LIBRARY_FAILURE;
+        }
+        rtCfg.shmInfo.shmid =
+            shmget(IPC_PRIVATE, rtCfg.image->bytes_per_line * height,
+                   IPC_CREAT | 0600);
+        if (rtCfg.shmInfo.shmid < 0) {
+            EDSS_LOGE("shmget: %s\n", strerror(errno));
+            return EDSS_ALLOCATION_FAILURE;
+        }
+        rtCfg.shmInfo.shmaddr = shmat(rtCfg.shmInfo.shmid, NULL, 0);
+        // The segment is freed once both sides detach, even if EDSS crashes
+        shmctl(rtCfg.shmInfo.shmid, IPC_RMID, NULL);
+        if (rtCfg.shmInfo.shmaddr == (char *)-1) {
+            EDSS_LOGE("shmat: %s\n", strerror(errno));
+            rtCfg.shmInfo.shmaddr = NULL;
+            return EDSS_ALLOCATION_FAILURE;
+        }
+        rtCfg.image->data = rtCfg.shmInfo.shmaddr;
+        rtCfg.shmInfo.readOnly = False;
+        if (!XShmAttach(rtCfg.captureDpy, &rtCfg.shmInfo)) {
+            EDSS_LOGE("XShmAttach failed\n");
+            return EDSS_CAL_LIBRARY_FAILURE;
+        }
+        rtCfg.shmAttached = true;
+        XSync(rtCfg.captureDpy, False);
+    } else {
+        EDSS_LOGW("MIT-SHM isn't available, falling back to XGetImage\n");
+        char *data = malloc(width * height * 4);
+        if (!data) {
+            return EDSS_ALLOCATION_FAILURE;
+        }
+        rtCfg.image = XCreateImage(rtCfg.captureDpy, visual, depth, ZPixmap, 0,
+                                   data, width, height, 32, 0);
+        if (!rtCfg.image) {
+            free(data);
+            return EDSS_CAL_LIBRARY_FAILURE;
+        }
+    }
+
+    // The capture thread copies exactly width * height * 4 bytes
+    if (rtCfg.image->bits_per_pixel != 32 ||
+        rtCfg.image->bytes_per_line != width * 4) {
+        EDSS_LOGE("Unsupported X11 visual with %d bits per pixel\n",
+                  rtCfg.image->bits_per_pixel);
+        return EDSS_CAL_LIBRARY_FAILURE;
+    }
+    if (rtCfg.image->red_mask == 0xff0000) {
+        rtCfg.calCfg->pixFmt = AV_PIX_FMT_BGR0;
+    } else if (rtCfg.image->red_mask == 0xff) {
+        rtCfg.calCfg->pixFmt = AV_PIX_FMT_RGB0;
+    } else {
+        EDSS_LOGE("Unsupported X11 visual with red mask %lx\n",
+                  rtCfg.image->red_mask);
+        return EDSS_CAL_LIBRARY_FAILURE;
+    }
+
+    return EDSS_OK;
+}
+
+static EDSS_STATUS x11Init(StrMap *calOptionDict, calConfig_t *calCfg) {
+    char display[X11_MAX_DISPLAY_NAME];
+    char screenCh[4];
+    char drawCursorCh[6];
+    int eventBase, errorBase, major, minor;
+
+    rtCfg.calCfg = calCfg;
+    if (!sm_get(calOptionDict, "display", display, sizeof(display)) ||
+        !sm_get(calOptionDict, "screen", screenCh, sizeof(screenCh)) ||
+        !sm_get(calOptionDict, "drawCursor", drawCursorCh,
+                sizeof(drawCursorCh))) {
+        return EDSS_INVALID_CAL_OPTIONS;
+    }
+    rtCfg.screen = atoi(screenCh);
+    rtCfg.drawCursor = strcmp(drawCursorCh, "false") != 0;
+
+    // The cursor is read from whichever thread the control server polls it
+    // on, which isn't the input one
+    XInitThreads();
+
+    rtCfg.captureDpy = XOpenDisplay(display);
+    rtCfg.inputDpy = XOpenDisplay(display);
+    if (!rtCfg.captureDpy || !rtCfg.inputDpy) {
+        EDSS_LOGE("Failed to open X display %s\n", display);
+        return EDSS_CAL_LIBRARY_FAILURE;
+    }
+    if (rtCfg.screen >= ScreenCount(rtCfg.captureDpy)) {
+        EDSS_LOGE("X display %s has no screen %d\n", display, rtCfg.screen);
+        return EDSS_INVALID_CAL_OPTIONS;
+    }
+    if (!XTestQueryExtension(rtCfg.inputDpy, &eventBase, &errorBase, &major,
+                             &minor)) {
+        EDSS_LOGE("X display %s doesn't support XTest\n", display);
+        return EDSS_CAL_LIBRARY_FAILURE;
+    }
+    rtCfg.hasXFixes =
+        XFixesQueryExtension(rtCfg.captureDpy, &eventBase, &errorBase) &&
+        XFixesQueryExtension(rtCfg.inputDpy, &eventBase, &errorBase);
+    if (!rtCfg.hasXFixes) {
+        EDSS_LOGW("XFixes isn't available, the cursor won't be captured\n");
+    }
+
+    rtCfg.root = RootWindow(rtCfg.captureDpy, rtCfg.screen);
+    calCfg->width = DisplayWidth(rtCfg.captureDpy, rtCfg.screen);
+    calCfg->height = DisplayHeight(rtCfg.captureDpy, rtCfg.screen);
+    // X doesn't have a refresh rate of its own without XRandR, so go with
+    // what the capture thread does
+    calCfg->framerate = 60;
+
+    int ret;
+    if ((ret = createImage(calCfg->width, calCfg->height)) != EDSS_OK) {
+        return ret;
+    }
+    calCfg->frame = (uint8_t *)rtCfg.image->data;
+
+    return EDSS_OK;
+}
+
+EDSS_STATUS calShutdown();
+
+EDSS_STATUS calInit(StrMap *calOptionDict, calConfig_t *calCfg) {
+    int ret;
+    // EDSS only calls calShutdown after a successful calInit
+    if ((ret = x11Init(calOptionDict, calCfg)) != EDSS_OK) {
+        calShutdown();
+    }
+    return ret;
+}
+
+// XFixes cursors are premultiplied ARGB, one pixel per unsigned long
+static void compositeCursor(XFixesCursorImage *cursor) {
+    int width = rtCfg.calCfg->width;
+    int height = rtCfg.calCfg->height;
+    int left = cursor->x - cursor->xhot;
+    int top = cursor->y - cursor->yhot;
+
+    for (int cy = 0; cy < cursor->height; ++cy) {
+        int y = top + cy;
+        if (y < 0 || y >= height) {
+            continue;
+        }
+        for (int cx = 0; cx < cursor->width; ++cx) {
+            int x = left + cx;
+            if (x < 0 || x >= width) {
+                continue;
+            }
+            unsigned long argb = cursor->pixels[cy * cursor->width + cx];
+            uint8_t alpha = argb >> 24;
+            if (!alpha) {
+                continue;
+            }
+            uint8_t *dst = (uint8_t *)rtCfg.image->data + (y * width + x) * 4;
+            // Both BGR0 and RGB0 have the colour channels in the first 3
+            // bytes, only the order differs
+            uint8_t src[3];
+            if (rtCfg.calCfg->pixFmt == AV_PIX_FMT_BGR0) {
+                src[0] = argb;
+                src[2] = argb >> 16;
+            } else {
+                src[0] = argb >> 16;
+                src[2] = argb;
+            }
+            src[1] = argb >> 8;
+            for (int i = 0; i < 3; ++i) {
+                dst[i] = src[i] + dst[i] * (255 - alpha) / 255;
+            }
+        }
+    }
+}
+
+EDSS_STATUS calReadFrame() {
+    Bool ok;
+    if (rtCfg.useShm) {
+        ok = XShmGetImage(rtCfg.captureDpy, rtCfg.root, rtCfg.image, 0, 0,
+                          AllPlanes);
+    } else {
+        ok = XGetSubImage(rtCfg.captureDpy, rtCfg.root, 0, 0,
+                          rtCfg.calCfg->width, rtCfg.calCfg->height,
+                          AllPlanes, ZPixmap, rtCfg.image, 0, 0) != NULL;
+    }
+    if (!ok) {
+        return EDSS_CAL_LIBRARY_FAILURE;
+    }
+
+    if (rtCfg.drawCursor && rtCfg.hasXFixes) {
+        XFixesCursorImage *cursor = XFixesGetCursorImage(rtCfg.captureDpy);
+        if (cursor) {
+            compositeCursor(cursor);
+            XFree(cursor);
+        }
+    }
+
+    return EDSS_OK;
+}
+
+EDSS_STATUS calReadCursor(edssCursorData_t *cursorData) {
+    if (!rtCfg.inputDpy) {
+        return EDSS_UNINITIALISED;
+    }
+    if (!rtCfg.hasXFixes) {
+        return EDSS_CAL_UNSUPPORTED;
+    }
+    XFixesCursorImage *cursor = XFixesGetCursorImage(rtCfg.inputDpy);
+    if (!cursor) {
+        return EDSS_CAL_LIBRARY_FAILURE;
+    }
+
+    size_t size = cursor->width * cursor->height * 4;
+    if (size > rtCfg.cursorImageSize) {
+        uint8_t *image = realloc(rtCfg.cursorImage, size);
+        if (!image) {
+            XFree(cursor);
+            return EDSS_ALLOCATION_FAILURE;
+        }
+        rtCfg.cursorImage = image;
+        rtCfg.cursorImageSize = size;
+    }
+    for (size_t i = 0; i < (size_t)cursor->width * cursor->height; ++i) {
+        unsigned long argb = cursor->pixels[i];
+        uint8_t alpha = argb >> 24;
+        uint8_t *dst = rtCfg.cursorImage + i * 4;
+        // Clients want straight alpha
+        dst[0] = alpha ? ((argb >> 16) & 0xff) * 255 / alpha : 0;
+        dst[1] = alpha ? ((argb >> 8) & 0xff) * 255 / alpha : 0;
+        dst[2] = alpha ? (argb & 0xff) * 255 / alpha : 0;
+        dst[3] = alpha;
+    }
+
+    cursorData->x = cursor->x;
+    cursorData->y = cursor->y;
+    // Already in the frames, the client drawing it too would double it
+    cursorData->visible = !rtCfg.drawCursor;
+    cursorData->serial = cursor->cursor_serial;
+    cursorData->width = cursor->width;
+    cursorData->height = cursor->height;
+    cursorData->hotspotX = cursor->xhot;
+    cursorData->hotspotY = cursor->yhot;
+    cursorData->image = rtCfg.cursorImage;
+    XFree(cursor);
+
+    return EDSS_OK;
+}
+
+EDSS_STATUS calShutdown() {
+    if (rtCfg.shmAttached) {
+        XShmDetach(rtCfg.captureDpy, &rtCfg.shmInfo);
+        rtCfg.shmAttached = false;
+    }
+    if (rtCfg.image) {
+        // Shared memory images don't free their data
+        XDestroyImage(rtCfg.image);
+        rtCfg.image = NULL;
+    }
+    if (rtCfg.shmInfo.shmaddr) {
+        shmdt(rtCfg.shmInfo.shmaddr);
+        rtCfg.shmInfo.shmaddr = NULL;
+    }
+    if (rtCfg.captureDpy) {
+        XCloseDisplay(rtCfg.captureDpy);
+        rtCfg.captureDpy = NULL;
+    }
+    if (rtCfg.inputDpy) {
+        XCloseDisplay(rtCfg.inputDpy);
+        rtCfg.inputDpy = NULL;
+    }
+    free(rtCfg.cursorImage);
+    rtCfg.cursorImage = NULL;
+    rtCfg.cursorImageSize = 0;
+
+    rtCfg.calCfg->frame = NULL;
+    rtCfg.calCfg->height = 0;
+    rtCfg.calCfg->width = 0;
+    rtCfg.calCfg->pixFmt = 0;
+
+    return EDSS_OK;
+}
+
+static void clickWheel(int32_t clicks, unsigned int positive,
+                       unsigned int negative) {
+    unsigned int button = clicks > 0 ? positive : negative;
+    int64_t count = clicks > 0 ? (int64_t)clicks : -(int64_t)clicks;
+    for (int64_t i = 0; i < count; ++i) {
+        XTestFakeButtonEvent(rtCfg.inputDpy, button, True, CurrentTime);
+        XTestFakeButtonEvent(rtCfg.inputDpy, button, False, CurrentTime);
+    }
+}
+
+EDSS_STATUS calWriteMouseEvent(edssMouseEvent_t *ev) {
+    EDSS_LOGD("X11 CAL plugin writing mouse event\n");
+    if (!rtCfg.inputDpy) {
+        return EDSS_UNINITIALISED;
+    }
+    switch (ev->type) {
+    case CLICK: {
+        unsigned int button;
+        switch (ev->payload.button.button) {
+        case BTN_LEFT:
+            button = Button1;
+            break;
+        case BTN_MIDDLE:
+            button = Button2;
+            break;
+        case BTN_RIGHT:
+            button = Button3;
+            break;
+        // Back and forward
+        case BTN_SIDE:
+            button = 8;
+            break;
+        case BTN_EXTRA:
+            button = 9;
+            break;
+        default:
+            return EDSS_INVALID_MOUSE_DATA;
+        }
+        XTestFakeButtonEvent(rtCfg.inputDpy, button, ev->payload.button.pressed,
+                             CurrentTime);
+        break;
+    }
+    case MOVE:
+        XTestFakeMotionEvent(rtCfg.inputDpy, rtCfg.screen, ev->payload.move.x,
+                             ev->payload.move.y, CurrentTime);
+        break;
+    case WHEEL:
+        // X has no wheel axes, each click is a press and release of buttons
+        // 4 to 7
+        clickWheel(ev->payload.wheel.vertical, 4, 5);
+        clickWheel(ev->payload.wheel.horizontal, 7, 6);
+        break;
+    case REL_MOVE:
+        XTestFakeRelativeMotionEvent(rtCfg.inputDpy, ev->payload.relMove.dx,
+                                     ev->payload.relMove.dy, CurrentTime);
+        break;
+    default:
+        return EDSS_INVALID_MOUSE_DATA;
+    }
+    XFlush(rtCfg.inputDpy);
+
+    return EDSS_OK;
+}
+
+EDSS_STATUS calWriteKeyboardEvent(edssKeyboardEvent_t *ev) {
+    EDSS_LOGD("X11 CAL plugin writing keyboard event\n");
+    if (!rtCfg.inputDpy) {
+        return EDSS_UNINITIALISED;
+    }
+    int keycode = ev->keyData.button + X11_EVDEV_KEYCODE_OFFSET;
+    // X keycodes are a single byte
+    if (ev->keyData.button < 0 || keycode > 255) {
+        return EDSS_INVALID_INPUT_DATA;
+    }
+    XTestFakeKeyEvent(rtCfg.inputDpy, keycode, ev->keyData.pressed,
+                      CurrentTime);
+    XFlush(rtCfg.inputDpy);
+
+    return EDSS_OK;
+}
+
+calPlugin_t calPlugin = {
+    .calOptions = calOptions,
+    .calInit = calInit,
+    .calReadFrame = calReadFrame,
+    .calShutdown = calShutdown,
+    .calWriteMouseEvent = calWriteMouseEvent,
+    .calWriteKeyboardEvent = calWriteKeyboardEvent,
+    .calReadCursor = calReadCursor,
+    .calOptionSchema = calOptionSchema,
+};
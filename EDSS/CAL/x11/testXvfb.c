@@ -0,0 +1,137 @@
+/*
+ * Starts Xvfb, captures a frame through the CAL and moves the pointer with it.
+ * This needs Xvfb, so it only runs with EDSS_TEST_XVFB set:
+ *
+ *   EDSS_TEST_XVFB=1 meson test -C build
+ */
+
+#include "../../inc/edssCALInterface.h"
+#include <X11/Xlib.h>
+#include <signal.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+// What meson counts as a skipped test
+#define TEST_SKIPPED 77
+
+#define TEST_WIDTH 320
+#define TEST_HEIGHT 240
+#define TEST_BACKGROUND 0x336699
+
+extern calPlugin_t calPlugin;
+
+static pid_t xvfb;
+
+#define CHECK(cond)                                                            \
+    do {                                                                       \
+        if (!(cond)) {                                                         \
+            fprintf(stderr, "%s:%d: %s failed\n", __FILE__, __LINE__, #cond);  \
+            return 1;                                                          \
+        }                                                                      \
+    } while (0)
+
+// Xvfb picks a free display itself and writes its number to the pipe once it
+// accepts connections
+static int startXvfb(char *display, size_t size) {
+    int fds[2];
+    char fd[16];
+    char number[16] = {0};
+
+    if (pipe(fds) < 0) {
+        return -1;
+    }
+    snprintf(fd, sizeof(fd), "%d", fds[1]);
+
+    xvfb = fork();
+    if (xvfb < 0) {
+        return -1;
+    }
+    if (xvfb == 0) {
+        close(fds[0]);
+        execlp("Xvfb", "Xvfb", "-displayfd", fd, "-screen", "0",
+               "320x240x24", "-nolisten", "tcp", NULL);
+        _exit(127);
+    }
+
+    close(fds[1]);
+    ssize_t len = read(fds[0], number, sizeof(number) - 1);
+    close(fds[0]);
+    if (len <= 0) {
+        return -1;
+    }
+    number[strcspn(number, "\n")] = '\0';
+    snprintf(display, size, ":%s", number);
+    return 0;
+}
+
+static void stopXvfb() {
+    if (xvfb > 0) {
+        kill(xvfb, SIGTERM);
+        waitpid(xvfb, NULL, 0);
+    }
+}
+
+static int run(const char *display) {
+    // The CAL calls it too, and it has to come before any other Xlib call
+    XInitThreads();
+    Display *dpy = XOpenDisplay(display);
+    CHECK(dpy);
+    Window root = DefaultRootWindow(dpy);
+    XSetWindowBackground(dpy, root, TEST_BACKGROUND);
+    XClearWindow(dpy, root);
+    XSync(dpy, False);
+
+    StrMap *options;
+    calConfig_t cfg = {0};
+    CHECK(calPlugin.calOptions(&options) == EDSS_OK);
+    CHECK(sm_put(options, "display", display));
+    // The cursor would land on the pixel that is checked
+    CHECK(sm_put(options, "drawCursor", "false"));
+    CHECK(calPlugin.calInit(options, &cfg) == EDSS_OK);
+    sm_delete(options);
+
+    CHECK(cfg.width == TEST_WIDTH && cfg.height == TEST_HEIGHT);
+    CHECK(cfg.pixFmt == AV_PIX_FMT_BGR0);
+    CHECK(calPlugin.calReadFrame() == EDSS_OK);
+    const uint8_t *pixel =
+        cfg.frame + (TEST_HEIGHT / 2 * TEST_WIDTH + TEST_WIDTH / 2) * 4;
+    CHECK(pixel[0] == 0x99 && pixel[1] == 0x66 && pixel[2] == 0x33);
+
+    edssMouseEvent_t move = {.type = MOVE, .payload.move = {.x = 10, .y = 20}};
+    CHECK(calPlugin.calWriteMouseEvent(&move) == EDSS_OK);
+    // The CAL moves the pointer on a connection of its own, so it can take a
+    // moment to show up on this one
+    Window rootReturn, child;
+    int x = -1, y = -1, winX, winY;
+    unsigned int mask;
+    for (int i = 0; i < 100 && (x != 10 || y != 20); ++i) {
+        usleep(10000);
+        CHECK(XQueryPointer(dpy, root, &rootReturn, &child, &x, &y, &winX,
+                            &winY, &mask));
+    }
+    CHECK(x == 10 && y == 20);
+
+    CHECK(calPlugin.calShutdown() == EDSS_OK);
+    XCloseDisplay(dpy);
+    return 0;
+}
+
+int main() {
+    char display[32];
+
+    if (!getenv("EDSS_TEST_XVFB")) {
+        fprintf(stderr, "Set EDSS_TEST_XVFB to run the Xvfb test\n");
+        return TEST_SKIPPED;
+    }
+    if (startXvfb(display, sizeof(display)) < 0) {
+        fprintf(stderr, "Failed to start Xvfb\n");
+        stopXvfb();
+        return 1;
+    }
+
+    int ret = run(display);
+    stopXvfb();
+    return ret;
+}
@@ -0,0 +1,43 @@
+#pragma once
+
+#include "edssStatus.h"
+#include <stdint.h>
+
+/** @file
+ * Audio sources feed EDSS interleaved signed 16-bit PCM, which is encoded to
+ * Opus and sent alongside the video. The control server picks a source by name
+ * through `edssConfig_t`.
+ */
+
+/**
+ * Audio source structure. Built-in sources live in edssAudio.c, and CALs can
+ * provide their own through `calAudioSource`.
+ */
+typedef struct {
+    /// The name the control server uses to pick this source.
+    const char *name;
+
+    /**
+     * Prepare the source to produce samples in the given format. Whatever is
+     * stored in `ctx` is handed back to `read` and `close`.
+     */
+    EDSS_STATUS (*open)(uint32_t sampleRate, uint8_t channels, void **ctx);
+
+    /**
+     * Fill `samples` with `frames` interleaved frames. This should block until
+     * the samples are available, since it is what paces the audio thread.
+     */
+    EDSS_STATUS (*read)(void *ctx, int16_t *samples, int frames);
+
+    /**
+     * Free whatever was allocated in `open`.
+     */
+    void (*close)(void *ctx);
+} edssAudioSource_t;
+
+/**
+ * Find an audio source by name. "cal" refers to `calSource`, which may be NULL
+ * if the CAL has no audio. Returns NULL if there is no such source.
+ */
+const edssAudioSource_t *edssFindAudioSource(const char *name,
+                                             const edssAudioSource_t *calSource);
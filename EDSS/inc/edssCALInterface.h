@@ -1,6 +1,7 @@
 #pragma once
 
 #include "../vendor/strmap/strmap.h"
+#include "edssAudio.h"
 #include "edssInterface.h"
 #include "edssStatus.h"
 #include <libavutil/pixfmt.h>
@@ -41,6 +42,8 @@ typedef struct {
 
 /**
  * CAL plugin structure. Define the functions here for the CAL plugin to work.
+ * New members go at the end, so CALs built against an older version of this
+ * header still line up.
  */
 typedef struct {
 
@@ -82,5 +85,48 @@ typedef struct {
      */
     EDSS_STATUS (*calShutdown)();
 
+    /**
+     * Read the cursor position and image (optional). CALs that cannot report
+     * the remote cursor can leave this as NULL.
+     */
+    EDSS_STATUS (*calReadCursor)(edssCursorData_t *cursor);
+
+    /**
+     * Write a touch event to CAL (optional).
+     */
+    EDSS_STATUS (*calWriteTouchEvent)(edssTouchEvent_t *ev);
+
+    /**
+     * Write a pen event to CAL (optional).
+     */
+    EDSS_STATUS (*calWritePenEvent)(edssPenEvent_t *ev);
+
+    /**
+     * Report which input types beyond mouse and keyboard the CAL accepts
+     * (optional). This is called right after `calOptions`, before `calInit`.
+     */
+    EDSS_STATUS (*calInputCapabilities)(edssInputCapabilities_t *caps);
+
+    /**
+     * Audio captured alongside the frames (optional). Used when the control
+     * server asks for the "cal" audio source.
+     */
+    const edssAudioSource_t *calAudioSource;
+
+    /**
+     * Describe the options returned by `calOptions` (optional). The array is
+     * owned by the CAL and has to live until `calShutdown`.
+     */
+    EDSS_STATUS (*calOptionSchema)(const edssCALOption_t **options,
+                                   uint32_t *count);
+
     // TODO add an "update configuration" handler
 } calPlugin_t;
+
+/**
+ * Open a CAL that isn't loaded with dlopen, eg. one implemented by the control
+ * server itself. This works the same way as `edssOpenCAL` otherwise. `plugin`
+ * has to stay valid until `edssCloseCAL`.
+ */
+EDSS_STATUS edssOpenCALPlugin(const calPlugin_t *plugin,
+                              StrMap **calOptionDict);
@@ -5,6 +5,9 @@
 #include <arpa/inet.h>
 #include <stdbool.h>
 
+/// How many viewers `edssAddViewer` takes on top of the main destination
+#define EDSS_MAX_VIEWERS 8
+
 /** @file
  * Configuration enum for control server to set, influenced by ED client.
  */
@@ -28,6 +31,13 @@ typedef struct {
                             // terminator.
     StrMap *calOptionDict;  // Sent to CAL
 
+    // Opus audio is sent on port + 2 when audioSampleRate is non-zero. The
+    // sample rate has to be one Opus supports (8, 12, 16, 24 or 48kHz).
+    uint32_t audioSampleRate;
+    uint32_t audioBitrate;
+    uint8_t audioChannels;
+    char audioSource[32]; // See edssFindAudioSource
+
     // NOTE that some things like
     // width/height will get set here.
     // Only some CALs can support changing
@@ -41,9 +51,10 @@ typedef struct {
 } edssKeyData_t;
 
 /**
- * Mouse event struct
+ * Mouse event struct. CALs return EDSS_CAL_UNSUPPORTED for the types they can't
+ * do anything with.
  */
-typedef enum { CLICK, MOVE } edssMouseEventType_t;
+typedef enum { CLICK, MOVE, WHEEL, REL_MOVE } edssMouseEventType_t;
 
 typedef struct {
     edssMouseEventType_t type;
@@ -55,6 +66,18 @@ typedef struct {
         } move;
 
         edssKeyData_t button;
+
+        /// In wheel clicks, positive is away from the user and to the right
+        struct wheel {
+            int32_t vertical;
+            int32_t horizontal;
+        } wheel;
+
+        /// In CAL pixels
+        struct relMove {
+            int32_t dx;
+            int32_t dy;
+        } relMove;
     } payload;
 
 } edssMouseEvent_t;
@@ -66,9 +89,107 @@ typedef struct {
     edssKeyData_t keyData;
 } edssKeyboardEvent_t;
 
+/**
+ * Touch event struct. Each finger is identified by a contact ID that stays the
+ * same from TOUCH_START until TOUCH_END or TOUCH_CANCEL. Coordinates are in
+ * CAL pixels and pressure is normalised to [0, 1].
+ */
+typedef enum { TOUCH_START, TOUCH_MOVE, TOUCH_END, TOUCH_CANCEL } edssTouchPhase_t;
+
+typedef struct {
+    uint32_t contactId;
+    edssTouchPhase_t phase;
+    double x;
+    double y;
+    double pressure;
+} edssTouchEvent_t;
+
+/**
+ * Pen/tablet event struct. Coordinates are in CAL pixels, pressure is
+ * normalised to [0, 1] and tilt is in degrees from -90 to 90.
+ */
+typedef struct {
+    double x;
+    double y;
+    double pressure;
+    double tiltX;
+    double tiltY;
+    /// The pen is hovering within range of the tablet
+    bool inRange;
+    /// The pen tip is touching the tablet
+    bool touching;
+    bool eraser;
+    bool barrelButton;
+} edssPenEvent_t;
+
+/**
+ * Input capabilities of the CAL beyond mouse and keyboard, so that clients can
+ * tell whether touch and pen input will go anywhere.
+ */
+typedef struct {
+    bool touch;
+    bool pen;
+    uint8_t maxTouchContacts;
+} edssInputCapabilities_t;
+
+/**
+ * Remote cursor struct, filled in by CALs that can report their cursor. The
+ * image is an RGBA bitmap of `width * height` pixels owned by the CAL. It only
+ * has to be re-read when `serial` changes.
+ */
+typedef struct {
+    int32_t x;
+    int32_t y;
+    bool visible;
+    uint32_t serial;
+    uint16_t width;
+    uint16_t height;
+    uint16_t hotspotX;
+    uint16_t hotspotY;
+    uint8_t *image;
+} edssCursorData_t;
+
+/**
+ * Type of a CAL option. Option values are always passed around as strings,
+ * this tells clients and the control server how to interpret them.
+ */
+typedef enum {
+    CAL_OPTION_STRING,
+    /// A base 10 integer
+    CAL_OPTION_INT,
+    CAL_OPTION_FLOAT,
+    /// "true" or "false"
+    CAL_OPTION_BOOL,
+    /// One of `choices`
+    CAL_OPTION_ENUM,
+} edssCALOptionType_t;
+
+/**
+ * Description of a single CAL option, so that clients can show a proper form
+ * and the control server can reject invalid values before `calInit` sees
+ * them. Everything is owned by the CAL and has to stay valid until the CAL is
+ * closed.
+ */
+typedef struct {
+    const char *name;
+    const char *description;
+    edssCALOptionType_t type;
+    /// Used when the client leaves the option out
+    const char *defaultValue;
+    /// NULL terminated list of values for CAL_OPTION_ENUM
+    const char *const *choices;
+    /// Inclusive range for CAL_OPTION_INT and CAL_OPTION_FLOAT
+    bool hasRange;
+    double min;
+    double max;
+    /// The option can't be left empty
+    bool required;
+} edssCALOption_t;
+
 /**
  * Initialize the server. This will allocate and initialize various FFmpeg
- * structures with the values provided from the provided `cfg` variable.
+ * structures with the values provided from the provided `cfg` variable. When
+ * audio is enabled the SDP carries a second, Opus media line.
  * Furthermore, any capture abstraction libraries will have their initializers
  * called, for example initing Xlib, PipeWire, or acquiring a framebuffer. This
  * function takes a pointer to a char* (sdpBuffer), which will be allocated in
@@ -82,19 +203,88 @@ edssInitServer(edssConfig_t *cfg, char **sdpBuffer);
  * encoder thread automatically. */
 EDSS_STATUS edssInitStreaming();
 
-/** Stop the SRTP server main loop, and stop the capture thread. */
+/** Stop the SRTP server main loop, and stop the capture thread. This also
+ * does everything `edssCloseServer` does. */
 EDSS_STATUS edssCloseStreaming();
 
+/**
+ * Free everything `edssInitServer` allocated and shut the CAL down, for a
+ * server that was initialised but never started streaming, or whose
+ * initialisation failed halfway. Does nothing if there is nothing to free.
+ */
+EDSS_STATUS edssCloseServer();
+
+/**
+ * Close the CAL opened with `edssOpenCAL`, closing the stream and the server
+ * first if needed. The CAL is unloaded even if that fails, in which case the
+ * error is returned. `edssOpenCAL` has to be called again before EDSS can be
+ * used.
+ */
+EDSS_STATUS edssCloseCAL();
+
 /** Write a mouse event to the CAL */
 EDSS_STATUS edssWriteMouseEvent(edssMouseEvent_t *ev);
 
 /** Write a keyboard event to the CAL */
 EDSS_STATUS edssWriteKeyboardEvent(edssKeyboardEvent_t *ev);
 
+/** Write a touch event to the CAL */
+EDSS_STATUS edssWriteTouchEvent(edssTouchEvent_t *ev);
+
+/** Write a pen event to the CAL */
+EDSS_STATUS edssWritePenEvent(edssPenEvent_t *ev);
+
+/**
+ * Retrieve the input capabilities of the opened CAL. This is valid after
+ * `edssOpenCAL` has returned EDSS_OK. CALs that don't report their
+ * capabilities only accept mouse and keyboard input, and touch or pen is only
+ * reported if the CAL has the entry point to write it.
+ */
+EDSS_STATUS edssGetInputCapabilities(edssInputCapabilities_t *caps);
+
 /**
  * Update the SRTP stream's to the new cfg pointer (we only pass a new pointer
  * since it makes Rust FFI easier). */
 EDSS_STATUS edssUpdateStreaming(edssConfig_t *cfg);
+
+/**
+ * Send the stream to another viewer as well, on top of the destination from
+ * `edssConfig_t`. The viewer gets the same video (and audio, on `port + 2`)
+ * with its own SRTP keys, and the next frame is encoded as a keyframe so it
+ * doesn't have to wait for one. `sdpBuffer` is allocated like in
+ * `edssInitServer`. Valid after `edssInitServer` has returned EDSS_OK, and at
+ * most EDSS_MAX_VIEWERS viewers can be added.
+ */
+EDSS_STATUS edssAddViewer(const char *socketAddr, uint16_t port,
+                          const char *srtpOutParams, uint32_t *viewerId,
+                          char **sdpBuffer);
+
+/**
+ * Stop sending the stream to a viewer added with `edssAddViewer`. Viewers are
+ * removed by `edssCloseServer` as well.
+ */
+EDSS_STATUS edssRemoveViewer(uint32_t viewerId);
+
+/**
+ * Retrieve the resolution of the captured frames, as reported by the CAL in
+ * `calInit`. This is only valid after `edssInitServer` has returned EDSS_OK,
+ * otherwise EDSS_UNINITIALISED is returned. The control server uses this to
+ * scale client mouse coordinates to CAL pixels.
+ */
+EDSS_STATUS edssGetStreamResolution(uint16_t *width, uint16_t *height);
+
+/**
+ * Read the remote cursor position and image from the CAL. Returns
+ * EDSS_CAL_UNSUPPORTED if the CAL cannot report its cursor.
+ */
+EDSS_STATUS edssReadCursor(edssCursorData_t *cursor);
+/**
+ * Retrieve the option schema of the opened CAL. This is valid after
+ * `edssOpenCAL` has returned EDSS_OK. Returns EDSS_CAL_UNSUPPORTED if the CAL
+ * doesn't describe its options, in which case they are all plain strings.
+ */
+EDSS_STATUS edssGetCALOptionSchema(const edssCALOption_t **options,
+                                   uint32_t *count);
 /**
  * Capture abstraction libraries (CALs) may expose options to the client which
  * they can set. This function allows the control server to retrieve CAL options
@@ -25,6 +25,6 @@ typedef struct {
 struct captureThreadArgs {
     captureCtx_t *captureCtx;
     fbEncoderCtx_t *fbEncoderCtx;
-    calPlugin_t *calPlugin;
+    const calPlugin_t *calPlugin;
     calConfig_t *calCfg;
 };
@@ -34,4 +34,13 @@ typedef enum {
     EDSS_STRMAP_FAILURE,
     /// When EDSS receives invalid mouse data
     EDSS_INVALID_MOUSE_DATA,
+    /// When the CAL does not implement an optional function.
+    EDSS_CAL_UNSUPPORTED,
+    /// When EDSS receives invalid touch or pen data
+    EDSS_INVALID_INPUT_DATA,
+    /// When the requested audio source does not exist or cannot be opened.
+    EDSS_INVALID_AUDIO_SOURCE,
+    /// When `edssAddViewer` is called with EDSS_MAX_VIEWERS viewers already
+    /// added.
+    EDSS_TOO_MANY_VIEWERS,
 } EDSS_STATUS;
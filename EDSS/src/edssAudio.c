@@ -0,0 +1,91 @@
+#include "../inc/edssAudio.h"
+#include "../inc/edssLog.h"
+
+#include <math.h>
+#include <stdlib.h>
+#include <string.h>
+#include <time.h>
+
+#define TONE_FREQUENCY 440.0
+#define TONE_AMPLITUDE 0.2
+
+typedef struct {
+    uint32_t sampleRate;
+    uint8_t channels;
+    uint64_t framesRead;
+    struct timespec start;
+} toneCtx_t;
+
+static EDSS_STATUS toneOpen(uint32_t sampleRate, uint8_t channels,
+                            void **ctx) {
+    toneCtx_t *tone = calloc(1, sizeof(toneCtx_t));
+    if (!tone) {
+        return EDSS_ALLOCATION_FAILURE;
+    }
+    tone->sampleRate = sampleRate;
+    tone->channels = channels;
+    clock_gettime(CLOCK_MONOTONIC, &tone->start);
+    *ctx = tone;
+    return EDSS_OK;
+}
+
+// Nothing is actually captured, so sleep until the samples would have been
+// played to keep the audio thread running in real time.
+static void toneWait(toneCtx_t *tone) {
+    uint64_t ns = tone->framesRead * 1000000000ull / tone->sampleRate;
+    struct timespec deadline = {
+        .tv_sec = tone->start.tv_sec + ns / 1000000000ull,
+        .tv_nsec = tone->start.tv_nsec + ns % 1000000000ull,
+    };
+    if (deadline.tv_nsec >= 1000000000) {
+        deadline.tv_sec++;
+        deadline.tv_nsec -= 1000000000;
+    }
+    clock_nanosleep(CLOCK_MONOTONIC, TIMER_ABSTIME, &deadline, NULL);
+}
+
+static EDSS_STATUS toneRead(void *ctx, int16_t *samples, int frames) {
+    toneCtx_t *tone = ctx;
+    for (int i = 0; i < frames; i++) {
+        double t = (double)(tone->framesRead + i) / tone->sampleRate;
+        int16_t sample =
+            (int16_t)(sin(2.0 * M_PI * TONE_FREQUENCY * t) * TONE_AMPLITUDE *
+                      INT16_MAX);
+        for (int c = 0; c < tone->channels; c++) {
+            samples[i * tone->channels + c] = sample;
+        }
+    }
+    tone->framesRead += frames;
+    toneWait(tone);
+    return EDSS_OK;
+}
+
+static void toneClose(void *ctx) { free(ctx); }
+
+/// A 440Hz sine, for checking the audio path without a real source.
+static const edssAudioSource_t toneSource = {
+    .name = "tone",
+    .open = toneOpen,
+    .read = toneRead,
+    .close = toneClose,
+};
+
+static const edssAudioSource_t *builtinSources[] = {&toneSource};
+
+const edssAudioSource_t *
+edssFindAudioSource(const char *name, const edssAudioSource_t *calSource) {
+    if (strcmp(name, "cal") == 0) {
+        if (!calSource) {
+            EDSS_LOGE("The CAL does not provide an audio source\n");
+        }
+        return calSource;
+    }
+    for (size_t i = 0; i < sizeof(builtinSources) / sizeof(builtinSources[0]);
+         i++) {
+        if (strcmp(name, builtinSources[i]->name) == 0) {
+            return builtinSources[i];
+        }
+    }
+    EDSS_LOGE("Unknown audio source %s\n", name);
+    return NULL;
+}
@@ -11,7 +11,7 @@ void *edssCaptureThreadFunction(void *threadArgs) {
     struct captureThreadArgs *args = (struct captureThreadArgs *)threadArgs;
     captureCtx_t *captureCtx = args->captureCtx;
     calConfig_t *calCfg = args->calCfg;
-    calPlugin_t *calPlugin = args->calPlugin;
+    const calPlugin_t *calPlugin = args->calPlugin;
 
     int dataLen;
 
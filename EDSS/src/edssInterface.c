@@ -1,4 +1,5 @@
 #include "../inc/edssInterface.h"
+#include "../inc/edssAudio.h"
 #include "../inc/edssCALInterface.h"
 #include "../inc/edssCapture.h"
 #include "../inc/edssInterfaceInternal.h"
@@ -15,6 +16,7 @@
 #include <libswscale/swscale.h>
 #include <pthread.h>
 #include <semaphore.h>
+#include <stdatomic.h>
 #include <stdint.h>
 #include <stdio.h>
 #include <stdlib.h>
@@ -27,7 +29,10 @@ static captureCtx_t captureCtx;
 static calConfig_t *calCfg;
 static pthread_t captureTh;
 static pthread_t streamTh;
-static calPlugin_t *calPlugin;
+static const calPlugin_t *calPlugin;
+static void *calHandle;
+static bool calInitialised;
+static bool streaming;
 
 // Encoder variables
 static AVCodecContext *cdcCtx;
@@ -37,15 +42,141 @@ static AVFormatContext *fmtCtx;
 AVDictionary *opts;
 char rtpAddress[61]; // maximum ip:port length
 
+// Audio variables. The RTP muxer only takes one stream, so audio gets its own
+// format context on port + 2.
+static bool audioEnabled;
+static pthread_t audioTh;
+static const edssAudioSource_t *audioSource;
+static void *audioSourceCtx;
+static AVCodecContext *audioCdcCtx;
+static AVFrame *audioFrame;
+static AVPacket *audioPkt;
+static AVStream *audioS;
+static AVFormatContext *audioFmtCtx;
+AVDictionary *audioOpts;
+char audioRtpAddress[61];
+
+// Extra destinations from edssAddViewer. EDCS adds and removes them while the
+// stream and audio threads write to them, so they are behind a mutex.
+typedef struct {
+    bool used;
+    uint32_t id;
+    AVFormatContext *fmtCtx;
+    AVFormatContext *audioFmtCtx; // NULL without audio
+} edssViewer_t;
+static edssViewer_t viewers[EDSS_MAX_VIEWERS];
+static pthread_mutex_t viewersMutex = PTHREAD_MUTEX_INITIALIZER;
+static uint32_t nextViewerId = 1;
+// Set when a viewer is added, so it gets a picture without waiting a whole GOP
+static atomic_bool forceKeyframe;
+
 #define SDP_BUFLEN 3000
 
+// Opens an SRTP destination for the output of codecCtx and writes its header.
+// Only used for viewers, the main destination is set up in edssInitServer.
+static EDSS_STATUS edssOpenRtpOutput(const char *socketAddr, uint16_t port,
+                                     const char *srtpOutParams,
+                                     const AVCodecContext *codecCtx,
+                                     AVFormatContext **out) {
+    char address[61];
+    const AVOutputFormat *rtpFmt;
+    AVFormatContext *ctx = NULL;
+    AVStream *stream;
+    AVDictionary *outOpts = NULL;
+    int ret;
+
+    snprintf(address, sizeof(address), "srtp://%s:%d/", socketAddr, port);
+    rtpFmt = av_guess_format("rtp", NULL, NULL);
+    if (!rtpFmt) {
+        EDSS_LOGE("Failed to guess format srtp\n");
+        return EDSS_LIBAV_FAILURE;
+    }
+    avformat_alloc_output_context2(&ctx, rtpFmt, rtpFmt->name, address);
+    if (!ctx) {
+        EDSS_LOGE("Failed to allocate viewer AVFormatContext\n");
+        return EDSS_LIBAV_FAILURE;
+    }
+    stream = avformat_new_stream(ctx, NULL);
+    if (!stream) {
+        EDSS_LOGE("Failed to allocate viewer AVStream\n");
+        goto fail;
+    }
+    stream->time_base = codecCtx->time_base;
+    if (avcodec_parameters_from_context(stream->codecpar, codecCtx) < 0) {
+        EDSS_LOGE("Failed to copy AVCodecParameters into viewer AVStream\n");
+        goto fail;
+    }
+
+    // Same suite as the main destination, only the keys differ
+    av_dict_set(&outOpts, "srtp_out_suite", "AES_CM_128_HMAC_SHA1_80", 0);
+    av_dict_set(&outOpts, "srtp_out_params", srtpOutParams, 0);
+    ret = avio_open2(&ctx->pb, address, AVIO_FLAG_WRITE, NULL, &outOpts);
+    av_dict_free(&outOpts);
+    if (ret < 0) {
+        EDSS_LOGE("Failed to open viewer output %s: %s\n", address,
+                  av_err2str(ret));
+        goto fail;
+    }
+    ret = avformat_write_header(ctx, NULL);
+    if (ret < 0) {
+        EDSS_LOGE("Failed to write header to viewer output %s\n", address);
+        goto fail;
+    }
+
+    *out = ctx;
+    return EDSS_OK;
+
+fail:
+    avio_closep(&ctx->pb);
+    avformat_free_context(ctx);
+    return EDSS_LIBAV_FAILURE;
+}
+
+// Only for outputs from edssOpenRtpOutput, which always have their header
+static void edssCloseRtpOutput(AVFormatContext **ctx) {
+    if (!*ctx) {
+        return;
+    }
+    av_write_trailer(*ctx);
+    avio_closep(&(*ctx)->pb);
+    avformat_free_context(*ctx);
+    *ctx = NULL;
+}
+
+// av_interleaved_write_frame takes the packet, so the viewers get their copies
+// before the main destination gets it.
+static void edssWriteToViewers(AVPacket *pkt, const AVStream *from,
+                               bool audio) {
+    pthread_mutex_lock(&viewersMutex);
+    for (int i = 0; i < EDSS_MAX_VIEWERS; i++) {
+        AVFormatContext *ctx =
+            audio ? viewers[i].audioFmtCtx : viewers[i].fmtCtx;
+        if (!viewers[i].used || !ctx) {
+            continue;
+        }
+        AVPacket *copy = av_packet_clone(pkt);
+        if (!copy) {
+            EDSS_LOGE("Failed to copy a packet for viewer %u\n", viewers[i].id);
+            continue;
+        }
+        av_packet_rescale_ts(copy, from->time_base, ctx->streams[0]->time_base);
+        copy->stream_index = 0;
+        // A viewer that went away shouldn't stop everyone else's stream
+        if (av_interleaved_write_frame(ctx, copy) < 0) {
+            EDSS_LOGW("Failed to write to viewer %u\n", viewers[i].id);
+        }
+        av_packet_free(&copy);
+    }
+    pthread_mutex_unlock(&viewersMutex);
+}
+
 EDSS_STATUS
 edssInterfaceSetupSwscale(edssConfig_t *edssCfg) {
 
     // avpicture_alloc(vgpuFbEncoderCtx->picToEncode, AV_PIX_FMT_YUV420P,
     // IMGBUF_WIDTH, IMGBUF_HEIGHT);
     EDSS_LOGD("Begin edssInterfaceSetupSwscale\n");
-    fbEncoderCtx = malloc(sizeof(fbEncoderCtx_t));
+    fbEncoderCtx = calloc(1, sizeof(fbEncoderCtx_t));
     fbEncoderCtx->picToEncode = av_frame_alloc();
 
     fbEncoderCtx->picToEncode->format = AV_PIX_FMT_YUV420P;
@@ -70,6 +201,106 @@ edssInterfaceSetupSwscale(edssConfig_t *edssCfg) {
     return EDSS_OK;
 }
 
+EDSS_STATUS edssInitAudio(edssConfig_t *edssCfg) {
+    int ret;
+    const AVCodec *cdc;
+    const AVOutputFormat *rtpFmt;
+
+    EDSS_LOGD("Initialising audio source %s\n", edssCfg->audioSource);
+    audioSource =
+        edssFindAudioSource(edssCfg->audioSource, calPlugin->calAudioSource);
+    if (!audioSource) {
+        return EDSS_INVALID_AUDIO_SOURCE;
+    }
+    if ((ret = audioSource->open(edssCfg->audioSampleRate,
+                                 edssCfg->audioChannels, &audioSourceCtx)) !=
+        EDSS_OK) {
+        EDSS_LOGE("Failed to open audio source %s\n", edssCfg->audioSource);
+        return ret;
+    }
+
+    snprintf(audioRtpAddress, sizeof(audioRtpAddress), "srtp://%s:%d/",
+             edssCfg->socketAddr, edssCfg->port + 2);
+    rtpFmt = av_guess_format("rtp", NULL, NULL);
+    if (!rtpFmt) {
+        EDSS_LOGE("Failed to guess format srtp\n");
+        return EDSS_LIBAV_FAILURE;
+    }
+    avformat_alloc_output_context2(&audioFmtCtx, rtpFmt, rtpFmt->name,
+                                   audioRtpAddress);
+    if (!audioFmtCtx) {
+        EDSS_LOGE("Failed to allocate audio AVFormatContext\n");
+        return EDSS_LIBAV_FAILURE;
+    }
+    audioS = avformat_new_stream(audioFmtCtx, NULL);
+    if (!audioS) {
+        EDSS_LOGE("Failed to allocate audio AVStream\n");
+        return EDSS_LIBAV_FAILURE;
+    }
+
+    cdc = avcodec_find_encoder_by_name("libopus");
+    if (!cdc) {
+        EDSS_LOGE("Failed to find AvCodec for encoding opus\n");
+        return EDSS_LIBAV_FAILURE;
+    }
+    audioCdcCtx = avcodec_alloc_context3(cdc);
+    if (!audioCdcCtx) {
+        EDSS_LOGE("Failed to allocate audio AVCodecContext\n");
+        return EDSS_LIBAV_FAILURE;
+    }
+    audioCdcCtx->sample_fmt = AV_SAMPLE_FMT_S16;
+    audioCdcCtx->sample_rate = edssCfg->audioSampleRate;
+    audioCdcCtx->channels = edssCfg->audioChannels;
+    audioCdcCtx->channel_layout =
+        av_get_default_channel_layout(edssCfg->audioChannels);
+    audioCdcCtx->bit_rate = edssCfg->audioBitrate;
+    audioCdcCtx->time_base = (AVRational){1, edssCfg->audioSampleRate};
+    av_opt_set(audioCdcCtx->priv_data, "application", "lowdelay", 0);
+
+    ret = avcodec_open2(audioCdcCtx, cdc, NULL);
+    if (ret < 0) {
+        EDSS_LOGE("Failed to open the audio codec: %s\n", av_err2str(ret));
+        return EDSS_LIBAV_FAILURE;
+    }
+
+    // The encoder decides how many samples go in a frame (20ms for Opus)
+    audioFrame = av_frame_alloc();
+    audioPkt = av_packet_alloc();
+    if (!audioFrame || !audioPkt) {
+        EDSS_LOGE("Failed to allocate audio AVFrame/AVPacket\n");
+        return EDSS_ALLOCATION_FAILURE;
+    }
+    audioFrame->format = audioCdcCtx->sample_fmt;
+    audioFrame->channel_layout = audioCdcCtx->channel_layout;
+    audioFrame->sample_rate = audioCdcCtx->sample_rate;
+    audioFrame->nb_samples = audioCdcCtx->frame_size;
+    if (av_frame_get_buffer(audioFrame, 0) < 0) {
+        EDSS_LOGE("Failed to allocate audio frame buffer\n");
+        return EDSS_ALLOCATION_FAILURE;
+    }
+
+    audioS->time_base = audioCdcCtx->time_base;
+    ret = avcodec_parameters_from_context(audioS->codecpar, audioCdcCtx);
+    if (ret < 0) {
+        EDSS_LOGE("Failed to copy audio AVCodecParameters into AVStream\n");
+        return EDSS_LIBAV_FAILURE;
+    }
+
+    // Same keys as the video
+    audioOpts = NULL;
+    av_dict_set(&audioOpts, "srtp_out_suite", "AES_CM_128_HMAC_SHA1_80", 0);
+    av_dict_set(&audioOpts, "srtp_out_params", edssCfg->srtpOutParams, 0);
+    ret = avio_open2(&audioFmtCtx->pb, audioRtpAddress, AVIO_FLAG_WRITE, NULL,
+                     &audioOpts);
+    if (ret < 0) {
+        EDSS_LOGE("Failed to open the audio output for writing: %s\n",
+                  av_err2str(ret));
+        return EDSS_LIBAV_FAILURE;
+    }
+
+    return EDSS_OK;
+}
+
 int edssCaptureInit() {
     int ret;
 
@@ -89,8 +320,6 @@ EDSS_STATUS edssOpenCAL(char calPluginName[100], StrMap **calOptionDict) {
     // Setup CAL. edssCfg does not have to contain anything other than the CAL
     // plugin name.
 
-    int ret;
-    void *calHandle;
     EDSS_LOGD("edssOpenCAL called with calPluginName %s\n", calPluginName);
 
     calHandle = dlopen(calPluginName, RTLD_LAZY);
@@ -100,13 +329,29 @@ EDSS_STATUS edssOpenCAL(char calPluginName[100], StrMap **calOptionDict) {
         return EDSS_INVALID_CAL;
     }
 
-    calPlugin = dlsym(calHandle, "calPlugin");
-    if (!calPlugin) {
+    const calPlugin_t *plugin = dlsym(calHandle, "calPlugin");
+    if (!plugin) {
         EDSS_LOGE("edssOpenCAL dlsym failed. Check that the plugin exports the "
                   "calPlugin structure.\n");
         return EDSS_INVALID_CAL;
     }
 
+    return edssOpenCALPlugin(plugin, calOptionDict);
+}
+
+EDSS_STATUS edssOpenCALPlugin(const calPlugin_t *plugin,
+                              StrMap **calOptionDict) {
+    int ret;
+
+    // These are the functions EDSS calls without checking
+    if (!plugin->calOptions || !plugin->calInit || !plugin->calReadFrame ||
+        !plugin->calShutdown || !plugin->calWriteMouseEvent ||
+        !plugin->calWriteKeyboardEvent) {
+        EDSS_LOGE("edssOpenCALPlugin: the CAL is missing required functions\n");
+        return EDSS_INVALID_CAL;
+    }
+    calPlugin = plugin;
+
     if ((ret = calPlugin->calOptions(calOptionDict)) != EDSS_OK) {
         EDSS_LOGE("edssOpenCAL failed to retreive calOptions\n");
         return ret;
@@ -129,6 +374,7 @@ EDSS_STATUS edssInitServer(edssConfig_t *edssCfg, char **sdpBuffer) {
     if ((ret = calPlugin->calInit(edssCfg->calOptionDict, calCfg)) != EDSS_OK) {
         return ret;
     }
+    calInitialised = true;
 
     /*
      * ENCODER SETUP SETCTION
@@ -255,10 +501,16 @@ EDSS_STATUS edssInitServer(edssConfig_t *edssCfg, char **sdpBuffer) {
         return EDSS_LIBAV_FAILURE;
     }
 
+    audioEnabled = edssCfg->audioSampleRate != 0;
+    if (audioEnabled && (ret = edssInitAudio(edssCfg)) != EDSS_OK) {
+        return ret;
+    }
+
     // thanks stackoverflow
+    // Every format context becomes its own media line
     *sdpBuffer = malloc(SDP_BUFLEN);
-    AVFormatContext *ac[] = {fmtCtx};
-    av_sdp_create(ac, 1, *sdpBuffer, SDP_BUFLEN);
+    AVFormatContext *ac[] = {fmtCtx, audioFmtCtx};
+    av_sdp_create(ac, audioEnabled ? 2 : 1, *sdpBuffer, SDP_BUFLEN);
 
     return EDSS_OK;
 }
@@ -318,6 +570,10 @@ void *edssStreamThreadFunction(void *threadArgs) {
             fbBgraToYuv(calCfg, fbEncoderCtx);
             pthread_mutex_unlock(&copiedFbPointer->mutex);
 
+            fbEncoderCtx->picToEncode->pict_type =
+                atomic_exchange(&forceKeyframe, false) ? AV_PICTURE_TYPE_I
+                                                       : AV_PICTURE_TYPE_NONE;
+
             ret = avcodec_send_frame(cdcCtx, fbEncoderCtx->picToEncode);
 
             if (ret < 0) {
@@ -340,6 +596,7 @@ void *edssStreamThreadFunction(void *threadArgs) {
                 // 60 * 1;
                 av_packet_rescale_ts(encPkt, (AVRational){1, calCfg->framerate},
                                      avS->time_base);
+                edssWriteToViewers(encPkt, avS, false);
                 av_interleaved_write_frame(fmtCtx, encPkt);
                 av_packet_unref(encPkt);
             }
@@ -355,6 +612,57 @@ void *edssStreamThreadFunction(void *threadArgs) {
     return (void *)EDSS_OK;
 }
 
+// Audio is paced by the source, so it runs separately from the video.
+void *edssAudioThreadFunction(void *threadArgs) {
+    int ret;
+
+    ret = avformat_write_header(audioFmtCtx, NULL);
+    if (ret < 0) {
+        EDSS_LOGE("Failed to write header to audio output\n");
+        return (void *)EDSS_LIBAV_FAILURE;
+    }
+    audioFrame->pts = 0;
+
+    EDSS_LOGI("Audio thread main loop starting\n");
+    while (!captureCtx.encodingFinished) {
+        if (av_frame_make_writable(audioFrame) < 0) {
+            EDSS_LOGE("Failed to make the audio frame writable\n");
+            return (void *)EDSS_ALLOCATION_FAILURE;
+        }
+        ret = audioSource->read(audioSourceCtx, (int16_t *)audioFrame->data[0],
+                                audioFrame->nb_samples);
+        if (ret != EDSS_OK) {
+            EDSS_LOGE("Failed to read from audio source %s\n",
+                      audioSource->name);
+            return (void *)(intptr_t)ret;
+        }
+
+        ret = avcodec_send_frame(audioCdcCtx, audioFrame);
+        if (ret < 0) {
+            EDSS_LOGE("Failed to send audio AVFrame to encoder\n");
+            return (void *)EDSS_ENCODE_FAILURE;
+        }
+        while (ret >= 0) {
+            ret = avcodec_receive_packet(audioCdcCtx, audioPkt);
+            if (ret == AVERROR(EAGAIN) || ret == AVERROR_EOF) {
+                break;
+            } else if (ret < 0) {
+                EDSS_LOGE("Failed to receive audio AVPacket\n");
+                return (void *)EDSS_ENCODE_FAILURE;
+            }
+            av_packet_rescale_ts(audioPkt, audioCdcCtx->time_base,
+                                 audioS->time_base);
+            edssWriteToViewers(audioPkt, audioS, true);
+            av_interleaved_write_frame(audioFmtCtx, audioPkt);
+            av_packet_unref(audioPkt);
+        }
+        audioFrame->pts += audioFrame->nb_samples;
+    }
+
+    EDSS_LOGW("AUDIO THREAD EXIT\n");
+    return (void *)EDSS_OK;
+}
+
 EDSS_STATUS edssInitStreaming() {
 
     struct captureThreadArgs ctArgs;
@@ -376,6 +684,12 @@ EDSS_STATUS edssInitStreaming() {
         EDSS_LOGE("stream thread pthread_create failed %s", strerror(errno));
         return EDSS_PTHREAD_FAILURE;
     }
+    if (audioEnabled &&
+        pthread_create(&audioTh, NULL, &edssAudioThreadFunction, NULL) != 0) {
+        EDSS_LOGE("audio thread pthread_create failed %s", strerror(errno));
+        return EDSS_PTHREAD_FAILURE;
+    }
+    streaming = true;
 
     return EDSS_OK;
 }
@@ -399,30 +713,262 @@ EDSS_STATUS edssCloseStreaming() {
     av_write_trailer(fmtCtx);
     avio_closep(&fmtCtx->pb);
 
+    if (audioEnabled) {
+        ret = pthread_join(audioTh, NULL);
+        if (ret != 0) {
+            EDSS_LOGE("audio thread pthread_join failed %s", strerror(errno));
+            return EDSS_PTHREAD_FAILURE;
+        }
+        av_write_trailer(audioFmtCtx);
+    }
+    streaming = false;
+
     /*
      * END ENCODING SECTION
      * -------------------------------------------------------------------------------------------
      */
 
-    // Free all the variables
+    return edssCloseServer();
+}
+
+EDSS_STATUS edssCloseServer() {
+    EDSS_STATUS ret = EDSS_OK;
+
+    if (streaming) {
+        EDSS_LOGE("edssCloseServer called while streaming\n");
+        return EDSS_INVALID_CONFIG;
+    }
+
+    // Viewers need the codecs, so they go first
+    for (int i = 0; i < EDSS_MAX_VIEWERS; i++) {
+        if (viewers[i].used) {
+            edssRemoveViewer(viewers[i].id);
+        }
+    }
+
+    // Everything is NULL checked since edssInitServer may have failed halfway
+    if (audioSource) {
+        audioSource->close(audioSourceCtx);
+        audioSource = NULL;
+        audioSourceCtx = NULL;
+    }
+    if (audioFmtCtx) {
+        avio_closep(&audioFmtCtx->pb);
+        avformat_free_context(audioFmtCtx); // Frees audioS too
+        audioFmtCtx = NULL;
+        audioS = NULL;
+    }
+    av_dict_free(&audioOpts);
+    av_frame_free(&audioFrame);
+    av_packet_free(&audioPkt);
+    avcodec_free_context(&audioCdcCtx);
+    audioEnabled = false;
+
+    if (fmtCtx) {
+        avio_closep(&fmtCtx->pb);
+        avformat_free_context(fmtCtx); // Frees avS too
+        fmtCtx = NULL;
+        avS = NULL;
+    }
+    av_dict_free(&opts);
+    av_packet_free(&encPkt);
+    avcodec_free_context(&cdcCtx);
+    if (fbEncoderCtx) {
+        sws_freeContext(fbEncoderCtx->swsCtx);
+        if (fbEncoderCtx->picToEncode) {
+            av_freep(&fbEncoderCtx->picToEncode->data[0]);
+            av_frame_free(&fbEncoderCtx->picToEncode);
+        }
+        free(fbEncoderCtx);
+        fbEncoderCtx = NULL;
+    }
+
+    if (calInitialised) {
+        if ((ret = calPlugin->calShutdown()) != EDSS_OK) {
+            EDSS_LOGE("calShutdown failed with %d\n", ret);
+        }
+        calInitialised = false;
+    }
     free(calCfg);
-    free(fbEncoderCtx);
-    EDSS_LOGD("here\n");
-    calPlugin = NULL; // This is a pointer to a static variable in a shared
-                      // library, so you can't really free it IIUC
-    free(calPlugin);
-    av_free(opts);
-    av_free(fmtCtx);
-    av_free(avS);
-    av_free(cdcCtx);
-    av_free(encPkt);
+    calCfg = NULL;
+
+    return ret;
+}
+
+EDSS_STATUS edssCloseCAL() {
+    EDSS_STATUS ret;
+
+    // The threads run CAL code, so they have to be gone before it is unloaded
+    if (streaming) {
+        ret = edssCloseStreaming();
+    } else {
+        ret = edssCloseServer();
+    }
+    // Unloaded even if shutting down failed, otherwise the handle leaks and
+    // the next edssOpenCAL gets the old plugin's state
+    calPlugin = NULL;
+    if (calHandle) {
+        dlclose(calHandle);
+        calHandle = NULL;
+    }
+    return ret;
+}
+
+EDSS_STATUS edssAddViewer(const char *socketAddr, uint16_t port,
+                          const char *srtpOutParams, uint32_t *viewerId,
+                          char **sdpBuffer) {
+    EDSS_STATUS ret;
+    edssViewer_t viewer = {0};
+    int slot = -1;
+
+    *sdpBuffer = NULL;
+    if (!fmtCtx || !cdcCtx) {
+        return EDSS_UNINITIALISED;
+    }
 
+    pthread_mutex_lock(&viewersMutex);
+    for (int i = 0; i < EDSS_MAX_VIEWERS && slot < 0; i++) {
+        if (!viewers[i].used) {
+            slot = i;
+        }
+    }
+    pthread_mutex_unlock(&viewersMutex);
+    if (slot < 0) {
+        return EDSS_TOO_MANY_VIEWERS;
+    }
+
+    if ((ret = edssOpenRtpOutput(socketAddr, port, srtpOutParams, cdcCtx,
+                                 &viewer.fmtCtx)) != EDSS_OK) {
+        return ret;
+    }
+    if (audioEnabled &&
+        (ret = edssOpenRtpOutput(socketAddr, port + 2, srtpOutParams,
+                                 audioCdcCtx, &viewer.audioFmtCtx)) != EDSS_OK) {
+        edssCloseRtpOutput(&viewer.fmtCtx);
+        return ret;
+    }
+
+    *sdpBuffer = malloc(SDP_BUFLEN);
+    if (!*sdpBuffer) {
+        edssCloseRtpOutput(&viewer.fmtCtx);
+        edssCloseRtpOutput(&viewer.audioFmtCtx);
+        return EDSS_ALLOCATION_FAILURE;
+    }
+    AVFormatContext *ac[] = {viewer.fmtCtx, viewer.audioFmtCtx};
+    av_sdp_create(ac, audioEnabled ? 2 : 1, *sdpBuffer, SDP_BUFLEN);
+
+    // Only EDCS adds viewers, so nobody took the slot in the meantime
+    pthread_mutex_lock(&viewersMutex);
+    viewer.used = true;
+    viewer.id = nextViewerId++;
+    viewers[slot] = viewer;
+    pthread_mutex_unlock(&viewersMutex);
+
+    *viewerId = viewer.id;
+    atomic_store(&forceKeyframe, true);
+    EDSS_LOGI("Added viewer %u at %s:%d\n", viewer.id, socketAddr, port);
+    return EDSS_OK;
+}
+
+EDSS_STATUS edssRemoveViewer(uint32_t viewerId) {
+    edssViewer_t viewer = {0};
+
+    pthread_mutex_lock(&viewersMutex);
+    for (int i = 0; i < EDSS_MAX_VIEWERS; i++) {
+        if (viewers[i].used && viewers[i].id == viewerId) {
+            viewer = viewers[i];
+            viewers[i] = (edssViewer_t){0};
+            break;
+        }
+    }
+    pthread_mutex_unlock(&viewersMutex);
+    if (!viewer.used) {
+        return EDSS_INVALID_CONFIG;
+    }
+
+    // The threads can't see it any more, so this doesn't need the lock
+    edssCloseRtpOutput(&viewer.fmtCtx);
+    edssCloseRtpOutput(&viewer.audioFmtCtx);
+    EDSS_LOGI("Removed viewer %u\n", viewerId);
     return EDSS_OK;
 }
 
 /// Not implemented for now
 EDSS_STATUS edssUpdateStreaming(edssConfig_t *cfg) { return EDSS_OK; }
 
+EDSS_STATUS edssGetStreamResolution(uint16_t *width, uint16_t *height) {
+    if (!calCfg) {
+        return EDSS_UNINITIALISED;
+    }
+    *width = calCfg->width;
+    *height = calCfg->height;
+
+    return EDSS_OK;
+}
+
+EDSS_STATUS edssWriteTouchEvent(edssTouchEvent_t *ev) {
+    if (!calPlugin) {
+        return EDSS_UNINITIALISED;
+    }
+    if (!calPlugin->calWriteTouchEvent) {
+        return EDSS_CAL_UNSUPPORTED;
+    }
+    return calPlugin->calWriteTouchEvent(ev);
+}
+
+EDSS_STATUS edssWritePenEvent(edssPenEvent_t *ev) {
+    if (!calPlugin) {
+        return EDSS_UNINITIALISED;
+    }
+    if (!calPlugin->calWritePenEvent) {
+        return EDSS_CAL_UNSUPPORTED;
+    }
+    return calPlugin->calWritePenEvent(ev);
+}
+
+EDSS_STATUS edssGetInputCapabilities(edssInputCapabilities_t *caps) {
+    if (!calPlugin) {
+        return EDSS_UNINITIALISED;
+    }
+
+    caps->touch = false;
+    caps->pen = false;
+    caps->maxTouchContacts = 0;
+    if (!calPlugin->calInputCapabilities) {
+        return EDSS_OK;
+    }
+
+    int ret = calPlugin->calInputCapabilities(caps);
+    // Input the CAL has no entry point for is never advertised
+    caps->touch = caps->touch && calPlugin->calWriteTouchEvent;
+    caps->pen = caps->pen && calPlugin->calWritePenEvent;
+    if (!caps->touch) {
+        caps->maxTouchContacts = 0;
+    }
+    return ret;
+}
+
+EDSS_STATUS edssGetCALOptionSchema(const edssCALOption_t **options,
+                                   uint32_t *count) {
+    if (!calPlugin) {
+        return EDSS_UNINITIALISED;
+    }
+    if (!calPlugin->calOptionSchema) {
+        return EDSS_CAL_UNSUPPORTED;
+    }
+    return calPlugin->calOptionSchema(options, count);
+}
+
+EDSS_STATUS edssReadCursor(edssCursorData_t *cursor) {
+    if (!calPlugin) {
+        return EDSS_UNINITIALISED;
+    }
+    if (!calPlugin->calReadCursor) {
+        return EDSS_CAL_UNSUPPORTED;
+    }
+    return calPlugin->calReadCursor(cursor);
+}
+
 EDSS_STATUS edssWriteMouseEvent(edssMouseEvent_t *ev) {
     return calPlugin->calWriteMouseEvent(ev);
 }
@@ -0,0 +1,11 @@
+use std::path::PathBuf;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let proto_path = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("proto");
+    println!("cargo:rerun-if-changed={}", proto_path.display());
+
+    prost_build::compile_protos(&[proto_path.join("evdev_proxy.proto")], &[proto_path])
+        .expect("Failed to compile protobufs");
+}
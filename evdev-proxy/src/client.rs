@@ -0,0 +1,90 @@
+//! The EDCS side of a connection to the proxy.
+
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::protocol::{
+    proxy_input_event, read_message, write_message, ProxyAbsMotion, ProxyHello, ProxyHelloResponse,
+    ProxyInputEvent, ProxyKeyEvent, ProxyRelMotion, ProxyWheel, PROTOCOL_VERSION,
+};
+
+// Events are sent from EDCS' client handler, which can't wait long on a stuck proxy
+const PROXY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A connection to one VM's devices.
+#[derive(Debug)]
+pub struct ProxyClient {
+    stream: UnixStream,
+}
+
+impl ProxyClient {
+    pub fn connect(socket_path: impl AsRef<Path>, token: &[u8], vm: &str) -> anyhow::Result<Self> {
+        let socket_path = socket_path.as_ref();
+        let mut stream = UnixStream::connect(socket_path).with_context(|| {
+            format!(
+                "Failed to connect to evdev-proxy at {}",
+                socket_path.display()
+            )
+        })?;
+        stream.set_read_timeout(Some(PROXY_TIMEOUT))?;
+        stream.set_write_timeout(Some(PROXY_TIMEOUT))?;
+
+        write_message(
+            &mut stream,
+            &ProxyHello {
+                version: PROTOCOL_VERSION,
+                token: token.to_vec(),
+                vm: vm.to_owned(),
+            },
+        )?;
+        let response: ProxyHelloResponse = read_message(&mut stream)?
+            .ok_or_else(|| anyhow!("evdev-proxy closed the connection"))?;
+        if !response.ok {
+            bail!("evdev-proxy refused the connection: {}", response.error);
+        }
+        Ok(Self { stream })
+    }
+
+    pub fn send(&mut self, payload: proxy_input_event::Payload) -> anyhow::Result<()> {
+        write_message(
+            &mut self.stream,
+            &ProxyInputEvent {
+                payload: Some(payload),
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn key(&mut self, code: u32, pressed: bool) -> anyhow::Result<()> {
+        self.send(proxy_input_event::Payload::Key(ProxyKeyEvent {
+            code,
+            pressed,
+        }))
+    }
+
+    pub fn button(&mut self, code: u32, pressed: bool) -> anyhow::Result<()> {
+        self.send(proxy_input_event::Payload::Button(ProxyKeyEvent {
+            code,
+            pressed,
+        }))
+    }
+
+    /// `x` and `y` are normalised to [0, 1].
+    pub fn move_abs(&mut self, x: f64, y: f64) -> anyhow::Result<()> {
+        self.send(proxy_input_event::Payload::Abs(ProxyAbsMotion { x, y }))
+    }
+
+    pub fn move_rel(&mut self, dx: i32, dy: i32) -> anyhow::Result<()> {
+        self.send(proxy_input_event::Payload::Rel(ProxyRelMotion { dx, dy }))
+    }
+
+    pub fn wheel(&mut self, vertical: i32, horizontal: i32) -> anyhow::Result<()> {
+        self.send(proxy_input_event::Payload::Wheel(ProxyWheel {
+            vertical,
+            horizontal,
+        }))
+    }
+}
@@ -0,0 +1,26 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct ProxyConfig {
+    pub socket_path: PathBuf,
+    // Only EDCS should be able to connect, so keep it to the owner and group
+    #[serde(default = "default_socket_mode")]
+    pub socket_mode: u32,
+    /// Holds the shared secret clients send in ProxyHello. Surrounding
+    /// whitespace is ignored, so it can be written with a trailing newline.
+    pub token_file: PathBuf,
+    #[serde(rename = "vm")]
+    pub vms: Vec<VmConfig>,
+}
+
+fn default_socket_mode() -> u32 {
+    0o660
+}
+
+#[derive(Deserialize)]
+pub struct VmConfig {
+    /// What clients ask for in ProxyHello, also used in the device names
+    pub name: String,
+}
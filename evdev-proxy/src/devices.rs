@@ -0,0 +1,171 @@
+//! The virtual devices of one VM. The VM is expected to pick them up from the
+//! host with evdev passthrough, eg. QEMU's input-linux object.
+
+use anyhow::bail;
+use input_event_codes::*;
+use log::info;
+
+use crate::protocol::proxy_input_event::Payload;
+use crate::uinput::{AbsAxis, DeviceId, UinputDevice};
+
+// Absolute positions are scaled to this, the same range QEMU uses
+pub const ABS_MAX: i32 = 0x7fff;
+
+const DEVICE_ID: DeviceId = DeviceId {
+    vendor: 0x1209,
+    product: 0xedc5,
+};
+const BUTTONS: [u16; 5] = [
+    BTN_LEFT!(),
+    BTN_RIGHT!(),
+    BTN_MIDDLE!(),
+    BTN_SIDE!(),
+    BTN_EXTRA!(),
+];
+
+/// Where a VM's events end up.
+pub trait VmDevices: Send {
+    fn handle(&mut self, ev: &Payload) -> anyhow::Result<()>;
+}
+
+fn is_key(code: u32) -> bool {
+    (KEY_ESC!()..=KEY_MICMUTE!()).contains(&code)
+}
+
+fn is_button(code: u32) -> bool {
+    BUTTONS.iter().any(|b| *b as u32 == code)
+}
+
+/// A keyboard, a relative mouse and an absolute tablet, since a single device
+/// with both kinds of axes confuses most guests.
+pub struct UinputDevices {
+    keyboard: UinputDevice,
+    mouse: UinputDevice,
+    tablet: UinputDevice,
+    // Buttons go to whichever pointer moved last, so clicks land where the
+    // cursor is
+    tablet_active: bool,
+}
+
+impl UinputDevices {
+    pub fn create(vm: &str) -> anyhow::Result<Self> {
+        let keys: Vec<u16> = (KEY_ESC!()..=KEY_MICMUTE!()).collect();
+        let keyboard = UinputDevice::create(
+            &format!("evdev-proxy Keyboard ({})", vm),
+            DEVICE_ID,
+            &keys,
+            &[],
+            &[],
+        )?;
+        let mouse = UinputDevice::create(
+            &format!("evdev-proxy Mouse ({})", vm),
+            DEVICE_ID,
+            &BUTTONS,
+            &[REL_X!(), REL_Y!(), REL_WHEEL!(), REL_HWHEEL!()],
+            &[],
+        )?;
+        let axis = |code| AbsAxis {
+            code,
+            min: 0,
+            max: ABS_MAX,
+            flat: 0,
+        };
+        let tablet = UinputDevice::create(
+            &format!("evdev-proxy Tablet ({})", vm),
+            DEVICE_ID,
+            &BUTTONS,
+            &[],
+            &[axis(ABS_X!()), axis(ABS_Y!())],
+        )?;
+        Ok(Self {
+            keyboard,
+            mouse,
+            tablet,
+            tablet_active: false,
+        })
+    }
+
+    fn pointer(&mut self) -> &mut UinputDevice {
+        if self.tablet_active {
+            &mut self.tablet
+        } else {
+            &mut self.mouse
+        }
+    }
+}
+
+impl VmDevices for UinputDevices {
+    fn handle(&mut self, ev: &Payload) -> anyhow::Result<()> {
+        match ev {
+            Payload::Key(key) => {
+                if !is_key(key.code) {
+                    bail!("{} isn't a key code", key.code);
+                }
+                self.keyboard
+                    .emit(EV_KEY!(), key.code as u16, key.pressed as i32)?;
+                self.keyboard.sync()?;
+            }
+            Payload::Button(button) => {
+                if !is_button(button.code) {
+                    bail!("{} isn't a supported button", button.code);
+                }
+                let pointer = self.pointer();
+                pointer.emit(EV_KEY!(), button.code as u16, button.pressed as i32)?;
+                pointer.sync()?;
+            }
+            Payload::Abs(abs) => {
+                if !abs.x.is_finite() || !abs.y.is_finite() {
+                    bail!("Absolute position {}, {} isn't a number", abs.x, abs.y);
+                }
+                let scale = |v: f64| (v.clamp(0.0, 1.0) * ABS_MAX as f64).round() as i32;
+                self.tablet_active = true;
+                self.tablet.emit(EV_ABS!(), ABS_X!(), scale(abs.x))?;
+                self.tablet.emit(EV_ABS!(), ABS_Y!(), scale(abs.y))?;
+                self.tablet.sync()?;
+            }
+            Payload::Rel(rel) => {
+                self.tablet_active = false;
+                self.mouse.emit(EV_REL!(), REL_X!(), rel.dx)?;
+                self.mouse.emit(EV_REL!(), REL_Y!(), rel.dy)?;
+                self.mouse.sync()?;
+            }
+            Payload::Wheel(wheel) => {
+                self.mouse.emit(EV_REL!(), REL_WHEEL!(), wheel.vertical)?;
+                self.mouse
+                    .emit(EV_REL!(), REL_HWHEEL!(), wheel.horizontal)?;
+                self.mouse.sync()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Logs events instead of creating devices, for testing without uinput or
+/// root. The same checks apply, so bad events fail like they would for real.
+pub struct DryRunDevices {
+    vm: String,
+}
+
+impl DryRunDevices {
+    pub fn new(vm: &str) -> Self {
+        info!("Dry run, not creating devices for {}", vm);
+        Self { vm: vm.to_owned() }
+    }
+}
+
+impl VmDevices for DryRunDevices {
+    fn handle(&mut self, ev: &Payload) -> anyhow::Result<()> {
+        match ev {
+            Payload::Key(key) if !is_key(key.code) => bail!("{} isn't a key code", key.code),
+            Payload::Button(button) if !is_button(button.code) => {
+                bail!("{} isn't a supported button", button.code)
+            }
+            Payload::Abs(abs) if !abs.x.is_finite() || !abs.y.is_finite() => {
+                bail!("Absolute position {}, {} isn't a number", abs.x, abs.y)
+            }
+            _ => (),
+        }
+        info!("{}: {:?}", self.vm, ev);
+        Ok(())
+    }
+}
@@ -0,0 +1,6 @@
+pub mod client;
+pub mod config;
+pub mod devices;
+pub mod protocol;
+pub mod server;
+pub mod uinput;
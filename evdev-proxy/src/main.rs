@@ -0,0 +1,34 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::Parser;
+use evdev_proxy::config::ProxyConfig;
+use log::{error, info};
+
+#[derive(Parser, Debug)]
+struct CLIArgs {
+    #[clap(short, long, default_value = "evdevProxy.toml")]
+    config_file: PathBuf,
+    /// Log events instead of creating uinput devices
+    #[clap(long)]
+    dry_run: bool,
+}
+
+fn run(args: CLIArgs) -> anyhow::Result<()> {
+    let config: ProxyConfig = toml::from_str(
+        &std::fs::read_to_string(&args.config_file)
+            .with_context(|| "Failed to read evdev-proxy config file")?,
+    )?;
+    evdev_proxy::server::run(config, args.dry_run)
+}
+
+fn main() {
+    let args = CLIArgs::parse();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    info!("Starting evdev-proxy");
+    if let Err(e) = run(args) {
+        error!("evdev-proxy failed: {:?}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,129 @@
+//! The wire format between EDCS and the proxy, see proto/evdev_proxy.proto.
+
+use std::io::{self, Read, Write};
+
+use prost::Message;
+
+include!(concat!(env!("OUT_DIR"), "/evdev_proxy.rs"));
+
+pub const PROTOCOL_VERSION: u32 = 1;
+// Nothing in the protocol comes close, anything bigger is garbage
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+pub fn write_message(stream: &mut impl Write, msg: &impl Message) -> io::Result<()> {
+    stream.write_all(&msg.encode_length_delimited_to_vec())
+}
+
+/// Read one length delimited message. `Ok(None)` means the other side closed
+/// the connection between messages.
+pub fn read_message<M: Message + Default>(stream: &mut impl Read) -> io::Result<Option<M>> {
+    let mut len = 0usize;
+    for i in 0..3 {
+        let mut byte = [0u8; 1];
+        if stream.read(&mut byte)? == 0 {
+            if i == 0 {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        len |= ((byte[0] & 0x7f) as usize) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        if i == 2 {
+            // A fourth varint byte is already way past MAX_MESSAGE_LEN
+            len = usize::MAX;
+        }
+    }
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Message of {} bytes is over the limit", len),
+        ));
+    }
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf)?;
+    M::decode(buf.as_slice())
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_hello(bytes: &[u8]) -> io::Result<Option<ProxyHello>> {
+        read_message(&mut &bytes[..])
+    }
+
+    // A ProxyHello that is exactly `len` bytes long, all of it token
+    fn hello_of_len(len: usize) -> (Vec<u8>, usize) {
+        // One byte of tag, then the token length and the token
+        let token_len = (1..=3)
+            .map(|delimiter_len| len - 1 - delimiter_len)
+            .find(|token_len| prost::length_delimiter_len(*token_len) == len - 1 - token_len)
+            .unwrap();
+        let hello = ProxyHello {
+            token: vec![b'a'; token_len],
+            ..Default::default()
+        };
+        assert_eq!(hello.encoded_len(), len);
+        let mut bytes = Vec::new();
+        write_message(&mut bytes, &hello).unwrap();
+        (bytes, token_len)
+    }
+
+    #[test]
+    fn reads_what_was_written() {
+        let hello = ProxyHello {
+            version: PROTOCOL_VERSION,
+            token: b"secret".to_vec(),
+            vm: "vm0".to_owned(),
+        };
+        let mut bytes = Vec::new();
+        write_message(&mut bytes, &hello).unwrap();
+        assert_eq!(read_hello(&bytes).unwrap(), Some(hello));
+    }
+
+    #[test]
+    fn closing_between_messages_is_not_an_error() {
+        assert_eq!(read_hello(&[]).unwrap(), None);
+    }
+
+    #[test]
+    fn takes_messages_up_to_the_limit() {
+        let (bytes, token_len) = hello_of_len(MAX_MESSAGE_LEN);
+        let hello = read_hello(&bytes).unwrap().unwrap();
+        assert_eq!(hello.token.len(), token_len);
+    }
+
+    #[test]
+    fn refuses_messages_over_the_limit() {
+        // Nothing after the delimiter is read, so there doesn't need to be a body
+        let mut bytes = Vec::new();
+        prost::encode_length_delimiter(MAX_MESSAGE_LEN + 1, &mut bytes).unwrap();
+        let err = read_hello(&bytes).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Four or more varint bytes are over the limit whatever they say
+        let err = read_hello(&[0x80, 0x80, 0x80, 0x00]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn refuses_a_truncated_delimiter() {
+        // The continuation bit promises another byte that never comes
+        let err = read_hello(&[0x80]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = read_hello(&[0xff, 0x81]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn refuses_a_truncated_body() {
+        let (mut bytes, _) = hello_of_len(16);
+        bytes.pop();
+        let err = read_hello(&bytes).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use log::{error, info, warn};
+
+use crate::config::ProxyConfig;
+use crate::devices::{DryRunDevices, UinputDevices, VmDevices};
+use crate::protocol::{
+    proxy_input_event::Payload, read_message, write_message, ProxyHello, ProxyHelloResponse,
+    ProxyInputEvent, ProxyKeyEvent, PROTOCOL_VERSION,
+};
+
+// A client that connects has to say who it is promptly
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+type SharedDevices = Arc<Mutex<Box<dyn VmDevices>>>;
+
+// Compare without an early exit, so the time taken doesn't give the token away
+fn token_matches(expected: &[u8], given: &[u8]) -> bool {
+    expected.len() == given.len()
+        && expected
+            .iter()
+            .zip(given)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn authenticate(
+    stream: &mut UnixStream,
+    token: &[u8],
+    devices: &HashMap<String, SharedDevices>,
+) -> anyhow::Result<(String, SharedDevices)> {
+    stream.set_read_timeout(Some(HELLO_TIMEOUT))?;
+    let hello: ProxyHello =
+        read_message(stream)?.ok_or_else(|| anyhow!("Closed before ProxyHello"))?;
+    let result = if hello.version != PROTOCOL_VERSION {
+        Err(format!(
+            "Protocol version {} isn't supported, expected {}",
+            hello.version, PROTOCOL_VERSION
+        ))
+    } else if !token_matches(token, &hello.token) {
+        Err("Wrong token".to_owned())
+    } else {
+        devices
+            .get(&hello.vm)
+            .map(|d| (hello.vm.clone(), d.clone()))
+            .ok_or_else(|| format!("No VM named {:?}", hello.vm))
+    };
+    let response = match &result {
+        Ok(_) => ProxyHelloResponse {
+            ok: true,
+            error: String::new(),
+        },
+        Err(e) => ProxyHelloResponse {
+            ok: false,
+            error: e.clone(),
+        },
+    };
+    write_message(stream, &response)?;
+    stream.set_read_timeout(None)?;
+    result.map_err(|e| anyhow!(e))
+}
+
+fn serve(
+    mut stream: UnixStream,
+    token: &[u8],
+    devices: &HashMap<String, SharedDevices>,
+) -> anyhow::Result<()> {
+    let (vm, devices) = authenticate(&mut stream, token, devices)?;
+    info!("Client connected to {}", vm);
+
+    // Whatever is still held when the client goes away is released, otherwise
+    // the VM would see a key stuck down
+    let mut held_keys = HashSet::new();
+    let mut held_buttons = HashSet::new();
+    let result = loop {
+        let ev: ProxyInputEvent = match read_message(&mut stream) {
+            Ok(Some(ev)) => ev,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e.into()),
+        };
+        let payload = match ev.payload {
+            Some(payload) => payload,
+            None => continue,
+        };
+        match &payload {
+            Payload::Key(key) if key.pressed => held_keys.insert(key.code),
+            Payload::Key(key) => held_keys.remove(&key.code),
+            Payload::Button(button) if button.pressed => held_buttons.insert(button.code),
+            Payload::Button(button) => held_buttons.remove(&button.code),
+            _ => false,
+        };
+        // A bad event is the client's problem, the connection can carry on
+        if let Err(e) = devices
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .handle(&payload)
+        {
+            warn!("Dropping event for {}: {:#}", vm, e);
+        }
+    };
+
+    let mut devices = devices.lock().unwrap_or_else(|e| e.into_inner());
+    let releases = held_keys
+        .into_iter()
+        .map(|code| {
+            Payload::Key(ProxyKeyEvent {
+                code,
+                pressed: false,
+            })
+        })
+        .chain(held_buttons.into_iter().map(|code| {
+            Payload::Button(ProxyKeyEvent {
+                code,
+                pressed: false,
+            })
+        }));
+    for release in releases {
+        if let Err(e) = devices.handle(&release) {
+            warn!("Failed to release input on {}: {:#}", vm, e);
+        }
+    }
+    info!("Client disconnected from {}", vm);
+    result
+}
+
+/// Create every VM's devices and serve clients until the listener fails.
+pub fn run(config: ProxyConfig, dry_run: bool) -> anyhow::Result<()> {
+    let token = fs::read(&config.token_file)
+        .with_context(|| format!("Failed to read token file {}", config.token_file.display()))?;
+    let token = String::from_utf8_lossy(&token).trim().as_bytes().to_vec();
+    if token.is_empty() {
+        return Err(anyhow!(
+            "Token file {} is empty",
+            config.token_file.display()
+        ));
+    }
+
+    let mut devices = HashMap::new();
+    for vm in &config.vms {
+        let vm_devices: Box<dyn VmDevices> = if dry_run {
+            Box::new(DryRunDevices::new(&vm.name))
+        } else {
+            Box::new(
+                UinputDevices::create(&vm.name)
+                    .with_context(|| format!("Failed to create devices for {}", vm.name))?,
+            )
+        };
+        if devices
+            .insert(vm.name.clone(), Arc::new(Mutex::new(vm_devices)))
+            .is_some()
+        {
+            return Err(anyhow!("VM {} is in the config twice", vm.name));
+        }
+    }
+
+    // A socket left behind by a previous run would make bind fail
+    let _ = fs::remove_file(&config.socket_path);
+    let listener = UnixListener::bind(&config.socket_path)
+        .with_context(|| format!("Failed to bind {}", config.socket_path.display()))?;
+    fs::set_permissions(
+        &config.socket_path,
+        fs::Permissions::from_mode(config.socket_mode),
+    )?;
+    info!(
+        "Listening on {} for {} VMs",
+        config.socket_path.display(),
+        devices.len()
+    );
+
+    let token = Arc::new(token);
+    let devices = Arc::new(devices);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let token = token.clone();
+        let devices = devices.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(stream, &token, &devices) {
+                error!("Client connection failed: {:#}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ProxyAbsMotion, ProxyWheel};
+
+    const TOKEN: &[u8] = b"secret";
+
+    // Keeps what DryRunDevices took, so the tests can see it
+    struct Recorded {
+        devices: DryRunDevices,
+        handled: Arc<Mutex<Vec<Payload>>>,
+    }
+
+    impl VmDevices for Recorded {
+        fn handle(&mut self, ev: &Payload) -> anyhow::Result<()> {
+            self.devices.handle(ev)?;
+            self.handled.lock().unwrap().push(ev.clone());
+            Ok(())
+        }
+    }
+
+    fn vm0() -> (HashMap<String, SharedDevices>, Arc<Mutex<Vec<Payload>>>) {
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let devices: Box<dyn VmDevices> = Box::new(Recorded {
+            devices: DryRunDevices::new("vm0"),
+            handled: handled.clone(),
+        });
+        (
+            HashMap::from([("vm0".to_owned(), Arc::new(Mutex::new(devices)))]),
+            handled,
+        )
+    }
+
+    fn hello(client: &mut UnixStream, token: &[u8], vm: &str) -> ProxyHelloResponse {
+        write_message(
+            client,
+            &ProxyHello {
+                version: PROTOCOL_VERSION,
+                token: token.to_vec(),
+                vm: vm.to_owned(),
+            },
+        )
+        .unwrap();
+        read_message(client).unwrap().unwrap()
+    }
+
+    fn send(client: &mut UnixStream, payload: Payload) {
+        write_message(
+            client,
+            &ProxyInputEvent {
+                payload: Some(payload),
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn compares_tokens_in_full() {
+        assert!(token_matches(TOKEN, b"secret"));
+        assert!(!token_matches(TOKEN, b"secreT"));
+        assert!(!token_matches(TOKEN, b"secre"));
+        assert!(!token_matches(TOKEN, b""));
+    }
+
+    #[test]
+    fn refuses_a_wrong_token() {
+        let (devices, handled) = vm0();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let serving = thread::spawn(move || serve(server, TOKEN, &devices));
+
+        let response = hello(&mut client, b"guess", "vm0");
+        assert!(!response.ok);
+        assert_eq!(response.error, "Wrong token");
+        // Nothing the client sends afterwards goes anywhere
+        let _ = write_message(
+            &mut client,
+            &ProxyInputEvent {
+                payload: Some(Payload::Key(ProxyKeyEvent {
+                    code: input_event_codes::KEY_A!(),
+                    pressed: true,
+                })),
+            },
+        );
+        assert!(serving.join().unwrap().is_err());
+        assert!(handled.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_an_unknown_vm() {
+        let (devices, _) = vm0();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let serving = thread::spawn(move || serve(server, TOKEN, &devices));
+
+        let response = hello(&mut client, TOKEN, "vm1");
+        assert!(!response.ok);
+        assert!(serving.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn passes_input_on_with_the_right_token() {
+        let (devices, handled) = vm0();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let serving = thread::spawn(move || serve(server, TOKEN, &devices));
+
+        let response = hello(&mut client, TOKEN, "vm0");
+        assert!(response.ok, "{}", response.error);
+        send(
+            &mut client,
+            Payload::Abs(ProxyAbsMotion { x: 0.5, y: 0.25 }),
+        );
+        send(
+            &mut client,
+            Payload::Wheel(ProxyWheel {
+                vertical: 1,
+                horizontal: 0,
+            }),
+        );
+        drop(client);
+        serving.join().unwrap().unwrap();
+
+        assert_eq!(
+            *handled.lock().unwrap(),
+            vec![
+                Payload::Abs(ProxyAbsMotion { x: 0.5, y: 0.25 }),
+                Payload::Wheel(ProxyWheel {
+                    vertical: 1,
+                    horizontal: 0,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn releases_held_input_on_disconnect() {
+        let key = |code, pressed| Payload::Key(ProxyKeyEvent { code, pressed });
+        let button = |code, pressed| Payload::Button(ProxyKeyEvent { code, pressed });
+        let (devices, handled) = vm0();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let serving = thread::spawn(move || serve(server, TOKEN, &devices));
+
+        assert!(hello(&mut client, TOKEN, "vm0").ok);
+        send(&mut client, key(input_event_codes::KEY_A!(), true));
+        send(&mut client, key(input_event_codes::KEY_B!(), true));
+        send(&mut client, key(input_event_codes::KEY_B!(), false));
+        send(&mut client, button(input_event_codes::BTN_LEFT!(), true));
+        drop(client);
+        serving.join().unwrap().unwrap();
+
+        // What the client sent, then a release of whatever was still down,
+        // keys first
+        assert_eq!(
+            handled.lock().unwrap()[4..],
+            [
+                key(input_event_codes::KEY_A!(), false),
+                button(input_event_codes::BTN_LEFT!(), false),
+            ]
+        );
+    }
+}
@@ -0,0 +1,208 @@
+//! Just enough of uinput to create virtual input devices, for the proxy and for
+//! the devices EDCS creates itself. See
+//! https://www.kernel.org/doc/html/latest/input/uinput.html
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    mem,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+};
+
+use anyhow::{anyhow, Context};
+
+// From linux/uinput.h, computed with _IO/_IOW for 'U'
+const UI_DEV_CREATE: libc::c_ulong = 0x5501;
+const UI_DEV_DESTROY: libc::c_ulong = 0x5502;
+const UI_DEV_SETUP: libc::c_ulong = 0x405c5503;
+const UI_ABS_SETUP: libc::c_ulong = 0x401c5504;
+const UI_SET_EVBIT: libc::c_ulong = 0x40045564;
+const UI_SET_KEYBIT: libc::c_ulong = 0x40045565;
+const UI_SET_RELBIT: libc::c_ulong = 0x40045566;
+const UI_SET_ABSBIT: libc::c_ulong = 0x40045567;
+
+const UINPUT_MAX_NAME_SIZE: usize = 80;
+const BUS_VIRTUAL: u16 = 0x06;
+
+#[repr(C)]
+struct InputId {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+#[repr(C)]
+struct UinputSetup {
+    id: InputId,
+    name: [libc::c_char; UINPUT_MAX_NAME_SIZE],
+    ff_effects_max: u32,
+}
+
+#[repr(C)]
+struct InputAbsinfo {
+    value: i32,
+    minimum: i32,
+    maximum: i32,
+    fuzz: i32,
+    flat: i32,
+    resolution: i32,
+}
+
+#[repr(C)]
+struct UinputAbsSetup {
+    code: u16,
+    absinfo: InputAbsinfo,
+}
+
+#[repr(C)]
+struct InputEvent {
+    time: libc::timeval,
+    type_: u16,
+    code: u16,
+    value: i32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AbsAxis {
+    pub code: u16,
+    pub min: i32,
+    pub max: i32,
+    pub flat: i32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceId {
+    pub vendor: u16,
+    pub product: u16,
+}
+
+/// A virtual input device. The device is destroyed when this is dropped.
+#[derive(Debug)]
+pub struct UinputDevice {
+    file: File,
+}
+
+fn ioctl_int(file: &File, request: libc::c_ulong, arg: libc::c_int) -> io::Result<()> {
+    if unsafe { libc::ioctl(file.as_raw_fd(), request, arg) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn ioctl_ptr<T>(file: &File, request: libc::c_ulong, arg: &T) -> io::Result<()> {
+    if unsafe { libc::ioctl(file.as_raw_fd(), request, arg as *const T) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl UinputDevice {
+    pub fn create(
+        name: &str,
+        id: DeviceId,
+        keys: &[u16],
+        rel_axes: &[u16],
+        axes: &[AbsAxis],
+    ) -> anyhow::Result<UinputDevice> {
+        if name.contains('\0') {
+            return Err(anyhow!("uinput device name {:?} contains a NUL", name));
+        }
+        let file = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/uinput")
+            .context("Failed to open /dev/uinput")?;
+
+        if !keys.is_empty() {
+            ioctl_int(&file, UI_SET_EVBIT, input_event_codes::EV_KEY!())?;
+            for key in keys {
+                ioctl_int(&file, UI_SET_KEYBIT, *key as libc::c_int)?;
+            }
+        }
+        if !rel_axes.is_empty() {
+            ioctl_int(&file, UI_SET_EVBIT, input_event_codes::EV_REL!())?;
+            for axis in rel_axes {
+                ioctl_int(&file, UI_SET_RELBIT, *axis as libc::c_int)?;
+            }
+        }
+        if !axes.is_empty() {
+            ioctl_int(&file, UI_SET_EVBIT, input_event_codes::EV_ABS!())?;
+            for axis in axes {
+                ioctl_int(&file, UI_SET_ABSBIT, axis.code as libc::c_int)?;
+                ioctl_ptr(
+                    &file,
+                    UI_ABS_SETUP,
+                    &UinputAbsSetup {
+                        code: axis.code,
+                        absinfo: InputAbsinfo {
+                            value: 0,
+                            minimum: axis.min,
+                            maximum: axis.max,
+                            fuzz: 0,
+                            flat: axis.flat,
+                            resolution: 0,
+                        },
+                    },
+                )?;
+            }
+        }
+
+        let mut setup = UinputSetup {
+            id: InputId {
+                bustype: BUS_VIRTUAL,
+                vendor: id.vendor,
+                product: id.product,
+                version: 1,
+            },
+            name: [0; UINPUT_MAX_NAME_SIZE],
+            ff_effects_max: 0,
+        };
+        // Long names are cut off, the last byte stays NUL
+        for (dst, src) in setup.name[..UINPUT_MAX_NAME_SIZE - 1]
+            .iter_mut()
+            .zip(name.as_bytes())
+        {
+            *dst = *src as libc::c_char;
+        }
+        ioctl_ptr(&file, UI_DEV_SETUP, &setup)?;
+        if unsafe { libc::ioctl(file.as_raw_fd(), UI_DEV_CREATE) } < 0 {
+            return Err(io::Error::last_os_error()).context("UI_DEV_CREATE failed");
+        }
+
+        Ok(UinputDevice { file })
+    }
+
+    pub fn emit(&mut self, type_: u16, code: u16, value: i32) -> io::Result<()> {
+        let ev = InputEvent {
+            time: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_,
+            code,
+            value,
+        };
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &ev as *const InputEvent as *const u8,
+                mem::size_of::<InputEvent>(),
+            )
+        };
+        self.file.write_all(bytes)
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.emit(
+            input_event_codes::EV_SYN!(),
+            input_event_codes::SYN_REPORT!(),
+            0,
+        )
+    }
+}
+
+impl Drop for UinputDevice {
+    fn drop(&mut self) {
+        unsafe { libc::ioctl(self.file.as_raw_fd(), UI_DEV_DESTROY) };
+    }
+}